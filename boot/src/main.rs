@@ -3,13 +3,16 @@
 
 use core::fmt::Write;
 use core::mem;
-use mantra_bootinfo::{BootInfo, MemoryRegion, PixelFormat as MantraPixelFormat, RegionKind};
+use mantra_bootinfo::{
+    BootInfo, MemoryRegion, PixelFormat as MantraPixelFormat, RegionKind, KERNEL_MAP_SIZE,
+    KERNEL_VIRT_OFFSET,
+};
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
 use uefi::proto::console::gop::PixelFormat as UefiPixelFormat;
 use uefi::proto::media::file::{File, FileAttribute, FileMode, FileType};
 use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::boot::{AllocateType, MemoryAttribute, MemoryType};
 use uefi::Identify;
 use xmas_elf::program::Type;
 use xmas_elf::ElfFile;
@@ -36,10 +39,31 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
         let (w, h) = mode.resolution();
         let stride = mode.stride();
 
-        let format = match mode.pixel_format() {
-            UefiPixelFormat::Rgb => MantraPixelFormat::Rgb,
-            UefiPixelFormat::Bgr => MantraPixelFormat::Bgr,
-            _ => MantraPixelFormat::Unknown,
+        let (format, bpp, masks) = match mode.pixel_format() {
+            UefiPixelFormat::Rgb => (
+                MantraPixelFormat::Rgb,
+                4,
+                [0x0000_00ff, 0x0000_ff00, 0x00ff_0000],
+            ),
+            UefiPixelFormat::Bgr => (
+                MantraPixelFormat::Bgr,
+                4,
+                [0x00ff_0000, 0x0000_ff00, 0x0000_00ff],
+            ),
+            UefiPixelFormat::Bitmask => match mode.pixel_bitmask() {
+                Some(m) => {
+                    // Bytes per pixel from the highest bit used by any channel (incl. reserved).
+                    let all = m.red | m.green | m.blue | m.reserved;
+                    let bits = 32 - all.leading_zeros();
+                    (
+                        MantraPixelFormat::Bitmask,
+                        (bits + 7) / 8,
+                        [m.red, m.green, m.blue],
+                    )
+                }
+                None => (MantraPixelFormat::Unknown, 4, [0; 3]),
+            },
+            _ => (MantraPixelFormat::Unknown, 4, [0; 3]),
         };
 
         let mut fb = gop.frame_buffer();
@@ -50,12 +74,14 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             h as u32,
             stride as u32,
             format as u32,
+            bpp,
+            masks,
         )
     };
 
     // -------- FILE LOAD SCOPE --------
-    // Load kernel ELF file into a temporary buffer.
-    let (kernel_file_addr, file_size) = {
+    // Load kernel ELF file into a temporary buffer, and the command line if there is one.
+    let (kernel_file_addr, file_size, cmdline, cmdline_len) = {
         let bs = st.boot_services();
 
         let handles = bs
@@ -101,7 +127,22 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
 
         file.read(&mut buffer[..file_size]).unwrap();
 
-        (kernel_file_addr, file_size)
+        // Optional; a missing or unreadable file means an empty command line.
+        let mut cmdline = [0u8; BootInfo::CMDLINE_MAX];
+        let cmdline_len = match root
+            .open(
+                cstr16!("\\cmdline.txt"),
+                FileMode::Read,
+                FileAttribute::empty(),
+            )
+            .ok()
+            .and_then(|f| f.into_regular_file())
+        {
+            Some(mut f) => f.read(&mut cmdline).unwrap_or(0),
+            None => 0,
+        };
+
+        (kernel_file_addr, file_size, cmdline, cmdline_len)
     };
     // -------- END SCOPE (bs borrow dropped) --------
 
@@ -130,7 +171,8 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             let Ok(Type::Load) = ph.get_type() else {
                 continue;
             };
-            let start = ph.virtual_addr();
+            // Segments are linked in the higher half; place them at their physical address.
+            let start = ph.physical_addr();
             let end = start.saturating_add(ph.mem_size());
             min_addr = core::cmp::min(min_addr, start);
             max_addr = core::cmp::max(max_addr, end);
@@ -140,6 +182,10 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             writeln!(st.stdout(), "Kernel ELF had no PT_LOAD segments").ok();
             return Status::LOAD_ERROR;
         }
+        if max_addr > KERNEL_MAP_SIZE {
+            writeln!(st.stdout(), "Kernel ELF loads above {:#x}", KERNEL_MAP_SIZE).ok();
+            return Status::LOAD_ERROR;
+        }
 
         let load_base = min_addr & !0xfff;
         let load_end = (max_addr + 0xfff) & !0xfff;
@@ -169,7 +215,7 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             let Ok(Type::Load) = ph.get_type() else {
                 continue;
             };
-            let vaddr = ph.virtual_addr();
+            let paddr = ph.physical_addr();
             let memsz = ph.mem_size() as usize;
             let filesz = ph.file_size() as usize;
             let off = ph.offset() as usize;
@@ -183,7 +229,7 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
                 return Status::LOAD_ERROR;
             }
 
-            let dst_off = (vaddr - load_base) as usize;
+            let dst_off = (paddr - load_base) as usize;
             if dst_off.saturating_add(memsz) > load_mem.len() {
                 writeln!(st.stdout(), "Kernel segment out of load bounds").ok();
                 return Status::LOAD_ERROR;
@@ -203,9 +249,14 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     // Allocate memory for our stable boot info + translated memory regions.
     // Must be done before ExitBootServices.
     let regions_pages: usize = 8; // 32 KiB
-    let (boot_info_ptr, regions_addr, regions_cap) = {
+    let (boot_info_ptr, regions_addr, regions_cap, kernel_pt) = {
         let bs = st.boot_services();
 
+        // PML4 + PDPT + PD for the higher-half kernel mapping (filled after ExitBootServices).
+        let kernel_pt = bs
+            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 3)
+            .unwrap();
+
         let boot_info_addr = bs
             .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
             .unwrap();
@@ -233,24 +284,54 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             _reserved0: 0,
             kernel_phys_base: load_base,
             kernel_phys_end: load_end,
+            fb_bpp: fb_info.6,
+            fb_red_mask: fb_info.7[0],
+            fb_green_mask: fb_info.7[1],
+            fb_blue_mask: fb_info.7[2],
+            kernel_file_ptr: kernel_file_addr,
+            kernel_file_len: file_size as u64,
+            cmdline_len: cmdline_len as u32,
+            _reserved1: 0,
+            cmdline,
         };
 
         unsafe {
             core::ptr::write(boot_info_addr as *mut BootInfo, bi);
         }
 
-        (boot_info_addr as *mut BootInfo, regions_addr, regions_cap)
+        (
+            boot_info_addr as *mut BootInfo,
+            regions_addr,
+            regions_cap,
+            kernel_pt,
+        )
     };
 
-    // Exit boot services (returns the UEFI memory map).
-    let (_rt, mut mmap) = st.exit_boot_services(MemoryType::LOADER_DATA);
+    // Buffer for the final UEFI memory map, with slack for descriptors added by this
+    // allocation and by firmware between retries.
+    let (mmap_addr, mmap_len) = {
+        let bs = st.boot_services();
+        let sizes = bs.memory_map_size();
+        let len = sizes.map_size + 16 * sizes.entry_size;
+        let addr = bs
+            .allocate_pages(
+                AllocateType::AnyPages,
+                MemoryType::LOADER_DATA,
+                (len + 4095) / 4096,
+            )
+            .unwrap();
+        (addr, len)
+    };
+
+    // Exit boot services. From here on `st` must not be used.
+    let (desc_count, desc_size) = exit_boot_services(&mut st, image, mmap_addr, mmap_len);
 
     // Translate the UEFI memory map into a stable format for the kernel.
     let out_regions =
         unsafe { core::slice::from_raw_parts_mut(regions_addr as *mut MemoryRegion, regions_cap) };
 
     let mut out_len: usize = 0;
-    let mut push = |base: u64, len: u64, kind: RegionKind| {
+    let mut push = |base: u64, len: u64, kind: RegionKind, attr: u32| {
         if len == 0 || out_len >= out_regions.len() {
             return;
         }
@@ -258,16 +339,27 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             base,
             len,
             kind: kind as u32,
-            _reserved: 0,
+            attr,
         };
         out_len += 1;
     };
 
-    mmap.sort();
-    for desc in mmap.entries() {
+    for i in 0..desc_count {
+        // Descriptors are `desc_size` apart, which may exceed size_of::<MemoryDescriptor>().
+        let desc = unsafe {
+            &*((mmap_addr as usize + i * desc_size)
+                as *const uefi_raw::table::boot::MemoryDescriptor)
+        };
         let base = desc.phys_start as u64;
         let len = desc.page_count.saturating_mul(4096);
+        let attr = translate_attr(desc.att);
         let kind = match desc.ty {
+            // Runtime-services memory must survive into the OS, even if typed as conventional.
+            uefi::table::boot::MemoryType::CONVENTIONAL
+                if (attr & MemoryRegion::ATTR_RUNTIME) != 0 =>
+            {
+                RegionKind::Reserved
+            }
             uefi::table::boot::MemoryType::CONVENTIONAL => RegionKind::Usable,
             uefi::table::boot::MemoryType::ACPI_RECLAIM => RegionKind::AcpiReclaim,
             uefi::table::boot::MemoryType::ACPI_NON_VOLATILE => RegionKind::AcpiNvs,
@@ -275,7 +367,7 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
             | uefi::table::boot::MemoryType::MMIO_PORT_SPACE => RegionKind::Mmio,
             _ => RegionKind::Reserved,
         };
-        push(base, len, kind);
+        push(base, len, kind, attr);
     }
 
     // Add explicit reserved ranges used by our OS components.
@@ -283,19 +375,30 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
         load_base,
         load_end.saturating_sub(load_base),
         RegionKind::Kernel,
+        0,
+    );
+    push(fb_info.0, fb_info.1, RegionKind::Framebuffer, 0);
+    push(boot_info_ptr as u64, 4096, RegionKind::Boot, 0);
+    push(
+        kernel_file_addr,
+        ((file_size as u64) + 4095) & !4095,
+        RegionKind::Boot,
+        0,
     );
-    push(fb_info.0, fb_info.1, RegionKind::Framebuffer);
-    push(boot_info_ptr as u64, 4096, RegionKind::Boot);
     push(
         regions_addr,
         (regions_pages as u64) * 4096,
         RegionKind::Boot,
+        0,
     );
 
     unsafe {
         (*boot_info_ptr).regions_len = out_len as u32;
     }
 
+    // The entry point is a higher-half address the firmware never mapped.
+    unsafe { map_kernel_higher_half(kernel_pt) };
+
     // Jump to kernel
     // Use SysV ABI explicitly so it matches the kernel target.
     let entry: extern "sysv64" fn(*const BootInfo) -> ! =
@@ -303,3 +406,102 @@ fn main(image: Handle, mut st: SystemTable<Boot>) -> Status {
 
     entry(boot_info_ptr.cast_const());
 }
+
+// Switch to a copy of the firmware's page tables (keeping its identity map for the loader's own
+// code, stack and boot info) with physical [0, KERNEL_MAP_SIZE) added at KERNEL_VIRT_OFFSET
+// using 2 MiB pages. `tables` is three zeroable pages: PML4, PDPT, PD.
+unsafe fn map_kernel_higher_half(tables: u64) {
+    const P: u64 = 1 << 0;
+    const RW: u64 = 1 << 1;
+    const PS: u64 = 1 << 7;
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+
+    let pml4 = tables as *mut u64;
+    let pdpt = (tables + 4096) as *mut u64;
+    let pd = (tables + 2 * 4096) as *mut u64;
+
+    let cr3: u64;
+    core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    core::ptr::copy_nonoverlapping((cr3 & ADDR) as *const u64, pml4, 512);
+    core::ptr::write_bytes(pdpt, 0, 512);
+
+    for j in 0..512u64 {
+        *pd.add(j as usize) = (j * 2 * 1024 * 1024) | P | RW | PS;
+    }
+    let pml4_i = ((KERNEL_VIRT_OFFSET >> 39) & 0x1ff) as usize;
+    let pdpt_i = ((KERNEL_VIRT_OFFSET >> 30) & 0x1ff) as usize;
+    *pdpt.add(pdpt_i) = (pd as u64) | P | RW;
+    *pml4.add(pml4_i) = (pdpt as u64) | P | RW;
+
+    core::arch::asm!("mov cr3, {}", in(reg) pml4 as u64, options(nostack, preserves_flags));
+}
+
+// ExitBootServices fails with INVALID_PARAMETER if the map key is stale, and firmware may
+// change the memory map (timers, events) between GetMemoryMap and ExitBootServices. Real
+// hardware does this even when OVMF doesn't, so re-fetch the map and retry.
+const EXIT_BOOT_SERVICES_RETRIES: usize = 8;
+
+// Returns the final map as (descriptor count, descriptor stride) in the buffer at `map_addr`.
+fn exit_boot_services(
+    st: &mut SystemTable<Boot>,
+    image: Handle,
+    map_addr: u64,
+    map_len: usize,
+) -> (usize, usize) {
+    // `uefi::table::boot::BootServices` is a transparent wrapper over the raw table.
+    let bs = st.boot_services() as *const _ as *const uefi_raw::table::boot::BootServices;
+
+    for _ in 0..EXIT_BOOT_SERVICES_RETRIES {
+        let mut size = map_len;
+        let mut key: usize = 0;
+        let mut desc_size: usize = 0;
+        let mut desc_version: u32 = 0;
+        let status = unsafe {
+            ((*bs).get_memory_map)(
+                &mut size,
+                map_addr as *mut uefi_raw::table::boot::MemoryDescriptor,
+                &mut key,
+                &mut desc_size,
+                &mut desc_version,
+            )
+        };
+        if status != uefi_raw::Status::SUCCESS || desc_size == 0 {
+            break;
+        }
+
+        let status = unsafe { ((*bs).exit_boot_services)(image.as_ptr(), key) };
+        if status == uefi_raw::Status::SUCCESS {
+            return (size / desc_size, desc_size);
+        }
+        if status != uefi_raw::Status::INVALID_PARAMETER {
+            break;
+        }
+    }
+
+    // Console output may no longer work after a failed attempt; try anyway, then halt.
+    let _ = writeln!(
+        st.stdout(),
+        "MantraBoot: ExitBootServices failed after {} attempts",
+        EXIT_BOOT_SERVICES_RETRIES
+    );
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
+
+// Keep the cacheability and runtime bits of a UEFI descriptor in our stable encoding.
+fn translate_attr(att: MemoryAttribute) -> u32 {
+    let mut attr = 0;
+    for (efi, ours) in [
+        (MemoryAttribute::UNCACHEABLE, MemoryRegion::ATTR_UC),
+        (MemoryAttribute::WRITE_COMBINE, MemoryRegion::ATTR_WC),
+        (MemoryAttribute::WRITE_THROUGH, MemoryRegion::ATTR_WT),
+        (MemoryAttribute::WRITE_BACK, MemoryRegion::ATTR_WB),
+        (MemoryAttribute::RUNTIME, MemoryRegion::ATTR_RUNTIME),
+    ] {
+        if att.contains(efi) {
+            attr |= ours;
+        }
+    }
+    attr
+}
@@ -12,6 +12,29 @@ fn main() {
         .and_then(|p| fs::read(p).ok())
         .unwrap_or_default();
 
+    println!("cargo:rerun-if-env-changed=MANTRA_MEMTEST");
+    println!("cargo:rerun-if-env-changed=MANTRA_LOGLEVEL");
+    println!("cargo:rerun-if-env-changed=MANTRA_NOASLR");
+    println!("cargo:rerun-if-env-changed=MANTRA_MANIFEST");
+    println!("cargo:rerun-if-env-changed=MANTRA_HEAPTRACK");
+    println!("cargo:rerun-if-env-changed=MANTRA_HEAPPOISON");
+    println!("cargo:rerun-if-env-changed=MANTRA_DFTEST");
+    println!("cargo:rerun-if-env-changed=MANTRA_QEMUEXIT");
+    println!("cargo:rerun-if-env-changed=MANTRA_LIMITS");
+    println!("cargo:rerun-if-env-changed=MANTRA_SCHED");
+
+    // Debug-only heap aids (leak tracking, alloc/free fill patterns): compiled out
+    // entirely unless requested.
+    for (var, cfg) in [
+        ("MANTRA_HEAPTRACK", "mantra_heaptrack"),
+        ("MANTRA_HEAPPOISON", "mantra_heappoison"),
+    ] {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+        if env::var(var).as_deref() == Ok("1") {
+            println!("cargo:rustc-cfg={}", cfg);
+        }
+    }
+
     // Make rebuilds deterministic when the init ELF changes.
     if let Some(p) = init_path.as_deref() {
         println!("cargo:rerun-if-changed={}", p);
@@ -0,0 +1,169 @@
+use core::arch::x86_64::{CpuidResult, __cpuid_count};
+
+fn cpuid(leaf: u32, sub: u32) -> CpuidResult {
+    __cpuid_count(leaf, sub)
+}
+
+fn max_basic_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+fn max_ext_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+// Feature bit helpers return false when the leaf isn't implemented.
+fn basic(leaf: u32) -> Option<CpuidResult> {
+    if leaf <= max_basic_leaf() {
+        Some(cpuid(leaf, 0))
+    } else {
+        None
+    }
+}
+
+fn ext(leaf: u32) -> Option<CpuidResult> {
+    if leaf <= max_ext_leaf() {
+        Some(cpuid(leaf, 0))
+    } else {
+        None
+    }
+}
+
+/// 12-byte vendor ID, e.g. "GenuineIntel" / "AuthenticAMD".
+pub fn vendor() -> [u8; 12] {
+    let r = cpuid(0, 0);
+    let mut v = [0u8; 12];
+    v[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+    v[4..8].copy_from_slice(&r.edx.to_le_bytes());
+    v[8..12].copy_from_slice(&r.ecx.to_le_bytes());
+    v
+}
+
+/// 48-byte processor brand string (NUL padded), or all zeros if unsupported.
+pub fn brand_string() -> [u8; 48] {
+    let mut b = [0u8; 48];
+    if max_ext_leaf() < 0x8000_0004 {
+        return b;
+    }
+    for (i, leaf) in (0x8000_0002u32..=0x8000_0004).enumerate() {
+        let r = cpuid(leaf, 0);
+        let o = i * 16;
+        b[o..o + 4].copy_from_slice(&r.eax.to_le_bytes());
+        b[o + 4..o + 8].copy_from_slice(&r.ebx.to_le_bytes());
+        b[o + 8..o + 12].copy_from_slice(&r.ecx.to_le_bytes());
+        b[o + 12..o + 16].copy_from_slice(&r.edx.to_le_bytes());
+    }
+    b
+}
+
+pub fn has_tsc() -> bool {
+    basic(1).is_some_and(|r| (r.edx & (1 << 4)) != 0)
+}
+
+pub fn has_sse() -> bool {
+    basic(1).is_some_and(|r| (r.edx & (1 << 25)) != 0)
+}
+
+pub fn has_xsave() -> bool {
+    basic(1).is_some_and(|r| (r.ecx & (1 << 26)) != 0)
+}
+
+pub fn has_avx() -> bool {
+    basic(1).is_some_and(|r| (r.ecx & (1 << 28)) != 0)
+}
+
+pub fn has_apic() -> bool {
+    basic(1).is_some_and(|r| (r.edx & (1 << 9)) != 0)
+}
+
+pub fn has_x2apic() -> bool {
+    basic(1).is_some_and(|r| (r.ecx & (1 << 21)) != 0)
+}
+
+pub fn has_rdrand() -> bool {
+    basic(1).is_some_and(|r| (r.ecx & (1 << 30)) != 0)
+}
+
+pub fn has_rdseed() -> bool {
+    basic(7).is_some_and(|r| (r.ebx & (1 << 18)) != 0)
+}
+
+pub fn has_fsgsbase() -> bool {
+    basic(7).is_some_and(|r| (r.ebx & (1 << 0)) != 0)
+}
+
+pub fn has_smep() -> bool {
+    basic(7).is_some_and(|r| (r.ebx & (1 << 7)) != 0)
+}
+
+pub fn has_smap() -> bool {
+    basic(7).is_some_and(|r| (r.ebx & (1 << 20)) != 0)
+}
+
+pub fn has_nx() -> bool {
+    ext(0x8000_0001).is_some_and(|r| (r.edx & (1 << 20)) != 0)
+}
+
+pub fn has_gib_pages() -> bool {
+    ext(0x8000_0001).is_some_and(|r| (r.edx & (1 << 26)) != 0)
+}
+
+pub fn has_invariant_tsc() -> bool {
+    ext(0x8000_0007).is_some_and(|r| (r.edx & (1 << 8)) != 0)
+}
+
+/// Architectural performance monitoring: (version, fixed-function counters), if present.
+pub fn perfmon() -> Option<(u8, u8)> {
+    let r = basic(0xa)?;
+    let version = (r.eax & 0xff) as u8;
+    if version == 0 {
+        return None;
+    }
+    // Fixed counters are only enumerated from version 2.
+    let fixed = if version >= 2 {
+        (r.edx & 0x1f) as u8
+    } else {
+        0
+    };
+    Some((version, fixed))
+}
+
+// Printable prefix of a CPUID string (stops at NUL, trims padding).
+pub fn as_str(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).unwrap_or("?").trim()
+}
+
+pub fn log_features() {
+    use crate::serial;
+
+    let vendor = vendor();
+    let brand = brand_string();
+    serial::write_str("cpu: ");
+    serial::write_str(as_str(&vendor));
+    serial::write_str(" \"");
+    serial::write_str(as_str(&brand));
+    serial::write_str("\"\n");
+
+    let flags = [
+        ("sse", has_sse()),
+        ("avx", has_avx()),
+        ("nx", has_nx()),
+        ("1g-pages", has_gib_pages()),
+        ("x2apic", has_x2apic()),
+        ("invariant-tsc", has_invariant_tsc()),
+        ("fsgsbase", has_fsgsbase()),
+        ("smep", has_smep()),
+        ("smap", has_smap()),
+        ("rdrand", has_rdrand()),
+        ("rdseed", has_rdseed()),
+    ];
+    serial::write_str("cpu: features");
+    for (name, on) in flags {
+        if on {
+            serial::write_str(" ");
+            serial::write_str(name);
+        }
+    }
+    serial::write_str("\n");
+}
@@ -0,0 +1,67 @@
+// QEMU's debug console (port 0xE9) and isa-debug-exit device (port 0x501), so test runs
+// can finish unattended: build with MANTRA_QEMUEXIT=1 and start QEMU with
+// `-debugcon stdio -device isa-debug-exit,iobase=0x501,iosize=4` (tools/qemu/test.sh).
+// Without the flag nothing here touches those ports; on real hardware they may belong to
+// something else.
+
+use core::fmt::{self, Write};
+
+use super::port::Port;
+
+const DEBUGCON: Port<u8> = Port::new(0xe9);
+const EXIT: Port<u32> = Port::new(0x501);
+
+/// Exit statuses; QEMU itself exits with `(status << 1) | 1`.
+pub const PASS: u32 = 0;
+pub const FAIL: u32 = 1;
+
+pub fn enabled() -> bool {
+    option_env!("MANTRA_QEMUEXIT") == Some("1")
+}
+
+// QEMU (and Bochs) read back 0xE9 from the port when the debug console is attached.
+fn present() -> bool {
+    enabled() && unsafe { DEBUGCON.read() } == 0xe9
+}
+
+pub struct Writer;
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if present() {
+            for b in s.bytes() {
+                unsafe { DEBUGCON.write(b) };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write one `MANTRA-STATUS <pass|fail> <detail>` line and exit QEMU with the matching
+/// status. Returns only when disabled or when no exit device stopped the machine.
+pub fn finish(status: u32, detail: fmt::Arguments) {
+    if !enabled() {
+        return;
+    }
+    let verdict = if status == PASS { "pass" } else { "fail" };
+    let _ = writeln!(Writer, "MANTRA-STATUS {} {}", verdict, detail);
+    unsafe { EXIT.write(status) };
+}
+
+/// End of a boot run: the boot program has finished its checks (`sysinfo::TEST_REPORT`)
+/// and `failed` of them failed. A kernel failure would already have panicked and exited
+/// with FAIL. Report the verdict with a few memory figures and leave QEMU.
+pub fn checks_done(failed: u64) {
+    let pmm = crate::pmm::stats();
+    let (heap_used, heap_size) = crate::heap::usage();
+    finish(
+        if failed == 0 { PASS } else { FAIL },
+        format_args!(
+            "failed={} free_bytes={} heap_used={} heap_size={}",
+            failed, pmm.free_bytes, heap_used, heap_size
+        ),
+    );
+    if enabled() {
+        kwarn!("debugcon: exit device absent, booting on");
+    }
+}
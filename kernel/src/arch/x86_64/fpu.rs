@@ -0,0 +1,58 @@
+use super::cpuid;
+use crate::serial;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+// Enable SSE (and AVX when available). The kernel target is built with +sse2, so this
+// must run before any code that might touch XMM registers.
+// Note: XMM/YMM state is not yet saved across context switches.
+pub fn init() {
+    if !cpuid::has_sse() {
+        serial::write_str("fpu: no SSE\n");
+        return;
+    }
+
+    unsafe {
+        let mut cr0: u64;
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 &= !CR0_EM;
+        cr0 |= CR0_MP;
+        core::arch::asm!("mov cr0, {}", in(reg) cr0, options(nomem, nostack, preserves_flags));
+
+        let mut cr4: u64;
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if cpuid::has_xsave() {
+            cr4 |= CR4_OSXSAVE;
+        }
+        core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nomem, nostack, preserves_flags));
+
+        if cpuid::has_xsave() {
+            let mut xcr0 = XCR0_X87 | XCR0_SSE;
+            if cpuid::has_avx() {
+                xcr0 |= XCR0_AVX;
+            }
+            core::arch::asm!(
+                "xsetbv",
+                in("ecx") 0u32,
+                in("eax") xcr0 as u32,
+                in("edx") (xcr0 >> 32) as u32,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+
+    serial::write_str("fpu: sse enabled");
+    if cpuid::has_xsave() && cpuid::has_avx() {
+        serial::write_str(" avx enabled");
+    }
+    serial::write_str("\n");
+}
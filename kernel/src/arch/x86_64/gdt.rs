@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use crate::serial;
 
 #[repr(C, packed)]
@@ -47,10 +49,20 @@ impl Tss {
     }
 }
 
-// Simple single-core stacks (no guard pages yet).
-static mut DF_IST_STACK: [u8; 16 * 1024] = [0; 16 * 1024];
-static mut KERNEL_INT_STACK0: [u8; 16 * 1024] = [0; 16 * 1024];
-static mut TSS0: Tss = Tss::new();
+/// CPU slots with their own GDT, TSS and interrupt stacks. Slot 0 is the boot CPU.
+pub const MAX_CPUS: usize = 4;
+
+const STACK_SIZE: usize = 16 * 1024;
+const TSS_INIT: Tss = Tss::new();
+
+// Per-CPU stacks (no guard pages yet).
+static mut DF_IST_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
+static mut KERNEL_INT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
+static mut TSS: [Tss; MAX_CPUS] = [TSS_INIT; MAX_CPUS];
+
+// Set once a slot's GDT and TSS are filled in; later `init_cpu` calls only load them.
+static BUILT: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+static BUILDS: AtomicU32 = AtomicU32::new(0);
 
 // GDT layout:
 // 0: null
@@ -59,7 +71,7 @@ static mut TSS0: Tss = Tss::new();
 // 3-4: TSS (selector 0x18)
 // 5: user data  (selector 0x28 | RPL3)
 // 6: user code  (selector 0x30 | RPL3)
-static mut GDT: [u64; 7] = [0; 7];
+static mut GDT: [[u64; 7]; MAX_CPUS] = [[0; 7]; MAX_CPUS];
 
 pub const KCODE_SEL: u16 = 0x08;
 pub const KDATA_SEL: u16 = 0x10;
@@ -136,44 +148,98 @@ unsafe fn ltr(sel: u16) {
     core::arch::asm!("ltr {0:x}", in(reg) sel, options(nomem, nostack, preserves_flags));
 }
 
+unsafe fn str_sel() -> u16 {
+    let sel: u16;
+    core::arch::asm!("str {0:x}", out(reg) sel, options(nomem, nostack, preserves_flags));
+    sel
+}
+
+fn stack_top(stack: *const [u8; STACK_SIZE]) -> u64 {
+    stack as u64 + STACK_SIZE as u64
+}
+
+// Fill in `cpu`'s TSS (fresh stacks) and GDT.
+unsafe fn build(cpu: usize) {
+    TSS[cpu].ist1 = stack_top(&raw const DF_IST_STACKS[cpu]);
+    TSS[cpu].rsp0 = stack_top(&raw const KERNEL_INT_STACKS[cpu]);
+
+    GDT[cpu][0] = 0;
+    GDT[cpu][1] = gdt_code64();
+    GDT[cpu][2] = gdt_data();
+    let (tss_lo, tss_hi) = gdt_tss64(
+        (&raw const TSS[cpu]) as u64,
+        (core::mem::size_of::<Tss>() - 1) as u32,
+    );
+    GDT[cpu][3] = tss_lo;
+    GDT[cpu][4] = tss_hi;
+    GDT[cpu][5] = gdt_user_data();
+    GDT[cpu][6] = gdt_user_code64();
+    BUILDS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Boot CPU setup; same as `init_cpu(0)`.
 pub fn init() {
-    unsafe {
-        let df_top = (&raw const DF_IST_STACK as *const u8)
-            .add(core::mem::size_of::<[u8; 16 * 1024]>()) as u64;
-        TSS0.ist1 = df_top;
-
-        let rsp0_top = (&raw const KERNEL_INT_STACK0 as *const u8)
-            .add(core::mem::size_of::<[u8; 16 * 1024]>()) as u64;
-        TSS0.rsp0 = rsp0_top;
-
-        GDT[0] = 0;
-        GDT[1] = gdt_code64();
-        GDT[2] = gdt_data();
-        let (tss_lo, tss_hi) = gdt_tss64(
-            (&raw const TSS0) as u64,
-            (core::mem::size_of::<Tss>() - 1) as u32,
-        );
-        GDT[3] = tss_lo;
-        GDT[4] = tss_hi;
-        GDT[5] = gdt_user_data();
-        GDT[6] = gdt_user_code64();
+    init_cpu(0);
+    serial::write_str("mantracore: gdt/tss initialized\n");
+}
 
-        let gdt: &'static [u64; 7] = &*(&raw const GDT);
-        lgdt(gdt);
+/// Load slot `cpu`'s GDT and TSS on the calling CPU, building them on first use. Calling it
+/// again (on any CPU) never rebuilds a slot, so CPU 0's live `rsp0` survives an AP coming up.
+pub fn init_cpu(cpu: usize) {
+    kassert!(cpu < MAX_CPUS, "gdt: cpu slot {} out of range", cpu);
+    unsafe {
+        if !BUILT[cpu].swap(true, Ordering::AcqRel) {
+            build(cpu);
+        }
+        let gdt = &raw const GDT[cpu];
+        lgdt(&*gdt);
         load_segments();
-        ltr(TSS_SEL);
+        // `ltr` faults on a busy TSS, i.e. when this CPU already runs on it.
+        if str_sel() != TSS_SEL {
+            ltr(TSS_SEL);
+        }
     }
+}
 
-    serial::write_str("mantracore: gdt/tss initialized\n");
+/// How many times a slot's GDT/TSS has been built. Only the ktests ask so far.
+#[cfg(feature = "ktest")]
+pub fn builds() -> u32 {
+    BUILDS.load(Ordering::Relaxed)
+}
+
+ktest! {
+    fn reinit_keeps_the_tss() {
+        // Re-running `init` on the boot CPU must keep the TSS (and the `rsp0` the scheduler set)
+        // and must not rebuild anything.
+        let rsp0 = unsafe { TSS[0].rsp0 };
+        let builds_before = builds();
+        init_cpu(0);
+        let after = unsafe { TSS[0].rsp0 };
+        kassert!(
+            after == rsp0 && builds() == builds_before,
+            "reinit changed rsp0 {:#x}->{:#x} or rebuilt",
+            rsp0,
+            after
+        );
+    }
 }
 
 pub fn df_ist_index() -> u8 {
     1
 }
 
+/// True if `rsp` lies on the double-fault IST stack.
+pub fn on_df_stack(rsp: u64) -> bool {
+    (0..MAX_CPUS).any(|cpu| {
+        let base = unsafe { &raw const DF_IST_STACKS[cpu] } as u64;
+        rsp >= base && rsp <= base + STACK_SIZE as u64
+    })
+}
+
+// Only the boot CPU runs processes so far.
 pub fn set_rsp0(rsp0_top: u64) {
     unsafe {
-        TSS0.rsp0 = rsp0_top;
+        TSS[0].rsp0 = rsp0_top;
     }
 }
 
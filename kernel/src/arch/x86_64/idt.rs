@@ -1,5 +1,10 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use super::gdt;
-use super::isr;
+use super::isr::{self, TrapFrame};
+use super::lapic;
+use super::paging;
+use super::pic;
 use crate::serial;
 
 #[repr(C)]
@@ -57,6 +62,9 @@ struct Idtr {
 }
 
 static mut IDT: [IdtEntry; 256] = [IdtEntry::missing(); 256];
+// One table shared by every CPU: built once, then only `lidt`-ed.
+static BUILT: AtomicBool = AtomicBool::new(false);
+static BUILDS: AtomicU32 = AtomicU32::new(0);
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct InterruptStackFrame {
@@ -85,10 +93,10 @@ fn lidt(idt: &'static [IdtEntry; 256]) {
     }
 }
 
-pub fn init() {
+fn build() {
     unsafe {
         IDT[3].set_handler(breakpoint_handler as *const () as u64);
-        IDT[8].set_handler(double_fault_handler as *const () as u64);
+        IDT[8].set_handler(isr::mantra_double_fault_stub as *const () as u64);
         IDT[8].set_ist(gdt::df_ist_index());
         IDT[13].set_handler(gp_fault_handler as *const () as u64);
         IDT[14].set_handler(page_fault_handler as *const () as u64);
@@ -96,26 +104,85 @@ pub fn init() {
         // PIC IRQs (0..15) are remapped to 32..47.
         // Use an assembly stub so we can context-switch by swapping RSP + iretq.
         IDT[32].set_handler(isr::mantra_timer_irq_stub as *const () as u64);
+        // IRQ7 and IRQ15 stay masked, but each PIC can still raise them as spurious IRQs.
+        IDT[32 + 7].set_handler(pic_irq7_handler as *const () as u64);
+        IDT[32 + 15].set_handler(pic_irq15_handler as *const () as u64);
 
         // System call test: int 0x80 from ring3.
         IDT[0x80].set_handler(isr::mantra_syscall80_stub as *const () as u64);
         IDT[0x80].set_dpl(3);
+
+        // In-kernel yield from syscall safe points (`sched::preempt_point`).
+        IDT[isr::KYIELD_VECTOR as usize].set_handler(isr::mantra_kyield_stub as *const () as u64);
+
+        // LAPIC one-shot (fine-grained sleeps) and its spurious vector.
+        IDT[lapic::TIMER_VECTOR as usize].set_handler(isr::mantra_hrtimer_stub as *const () as u64);
+        IDT[lapic::SPURIOUS_VECTOR as usize].set_handler(spurious_handler as *const () as u64);
+    }
+    BUILDS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Build the shared table (first call only) and load it on this CPU.
+pub fn init() {
+    if !BUILT.swap(true, Ordering::AcqRel) {
+        build();
     }
+    load();
+    serial::write_str("mantracore: idt initialized\n");
+}
 
+/// Load the already-built table on the calling CPU (APs, or a reload on CPU 0).
+pub fn load() {
+    kassert!(BUILT.load(Ordering::Acquire), "idt: load before init");
     unsafe {
         // Avoid creating a shared reference to a `static mut`.
-        let idt: &'static [IdtEntry; 256] = &*(&raw const IDT);
-        lidt(idt);
+        let idt = &raw const IDT;
+        lidt(&*idt);
     }
-
-    serial::write_str("mantracore: idt initialized\n");
 }
 
-pub fn enable_interrupts() {
+// The IDTR as (limit, base); only the reload ktest reads it back.
+#[cfg(feature = "ktest")]
+fn sidt() -> (u16, u64) {
+    let mut idtr = Idtr { limit: 0, base: 0 };
     unsafe {
-        core::arch::asm!("sti", options(nomem, nostack, preserves_flags));
+        core::arch::asm!("sidt [{}]", in(reg) &mut idtr, options(nostack, preserves_flags));
+    }
+    (idtr.limit, idtr.base)
+}
+
+ktest! {
+    fn reload_keeps_the_table() {
+        // Loading twice (as a second CPU would) must leave IDTR on the same table and must not
+        // rebuild it.
+        let builds = BUILDS.load(Ordering::Relaxed);
+        let before = sidt();
+        load();
+        load();
+        let after = sidt();
+        kassert!(
+            after == before && after.1 == (&raw const IDT) as u64,
+            "reload moved IDTR {:?} -> {:?}",
+            before,
+            after
+        );
+        kassert!(
+            BUILDS.load(Ordering::Relaxed) == builds && builds == 1,
+            "table built {} times",
+            BUILDS.load(Ordering::Relaxed)
+        );
     }
-    serial::write_str("mantracore: interrupts enabled\n");
+}
+
+// Spurious LAPIC interrupts need no EOI.
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn pic_irq7_handler(_frame: InterruptStackFrame) {
+    pic::eoi(7);
+}
+
+extern "x86-interrupt" fn pic_irq15_handler(_frame: InterruptStackFrame) {
+    pic::eoi(15);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
@@ -124,15 +191,102 @@ extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
     serial::write_str("\n");
 }
 
-extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _err: u64) -> ! {
+fn write_regs(regs: &[(&str, u64)]) {
+    for (i, (name, v)) in regs.iter().enumerate() {
+        serial::write_str(if i % 4 == 0 { "  " } else { " " });
+        serial::write_str(name);
+        serial::write_str("=");
+        serial::write_hex_u64(*v);
+        if i % 4 == 3 || i == regs.len() - 1 {
+            serial::write_str("\n");
+        }
+    }
+}
+
+// A fault while delivering a fault, usually a blown or corrupt kernel stack. We are on
+// IST1 (see `isr::mantra_double_fault_stub`) with the interrupted GPRs in `tf`. Nothing
+// here is recoverable: dump everything that helps a post-mortem and halt.
+#[no_mangle]
+extern "C" fn mantra_double_fault_rust(tf: &TrapFrame) -> ! {
+    let (cr2, cr3, rsp): (u64, u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    let pid = crate::sched::current_pid() as u64;
+
     serial::write_str("EXC: double fault rip=");
-    serial::write_hex_u64(frame.rip);
-    serial::write_str("\n");
+    serial::write_hex_u64(tf.rip);
+    serial::write_str(if gdt::on_df_stack(rsp) {
+        " (on ist1)\n"
+    } else {
+        " (not on ist1)\n"
+    });
+    write_regs(&[
+        ("rax", tf.rax),
+        ("rbx", tf.rbx),
+        ("rcx", tf.rcx),
+        ("rdx", tf.rdx),
+        ("rsi", tf.rsi),
+        ("rdi", tf.rdi),
+        ("rbp", tf.rbp),
+        ("rsp", tf.rsp),
+        ("r8", tf.r8),
+        ("r9", tf.r9),
+        ("r10", tf.r10),
+        ("r11", tf.r11),
+        ("r12", tf.r12),
+        ("r13", tf.r13),
+        ("r14", tf.r14),
+        ("r15", tf.r15),
+        ("cs", tf.cs),
+        ("ss", tf.ss),
+        ("rflags", tf.rflags),
+        ("cr2", cr2),
+        ("cr3", cr3),
+        ("pid", pid),
+    ]);
+    crate::bug::backtrace(tf.rbp);
+    serial::write_str("EXC: double fault dump end\n");
+
+    super::debugcon::finish(
+        super::debugcon::FAIL,
+        format_args!("double fault rip={:#x} pid={}", tf.rip, pid),
+    );
+    crate::fb::panic_screen(format_args!(
+        "double fault rip={:#x} rsp={:#x} cr2={:#x} pid={}",
+        tf.rip, tf.rsp, cr2, pid
+    ));
     loop {
         unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)) };
     }
 }
 
+/// Boot test for the double-fault path (`MANTRA_DFTEST=1`): point RSP at an unmapped
+/// kernel page and push. The CPU cannot deliver the resulting #PF on that stack, so it
+/// raises #DF on IST1; serial should then show the full dump ending in
+/// "double fault dump end". Does not return.
+pub fn provoke_double_fault() -> ! {
+    let (_, kmap_end) = paging::kmap_range();
+    let bad = kmap_end - 4096;
+    kassert!(
+        !paging::is_mapped(bad - 8),
+        "idt: df test stack {:#x} is mapped",
+        bad
+    );
+    serial::write_str("idt: provoking a double fault\n");
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {}",
+            "push rax",
+            "ud2",
+            in(reg) bad,
+            options(noreturn)
+        )
+    }
+}
+
 extern "x86-interrupt" fn gp_fault_handler(frame: InterruptStackFrame, err: u64) -> ! {
     serial::write_str("EXC: #GP err=");
     serial::write_hex_u64(err);
@@ -150,13 +304,32 @@ extern "x86-interrupt" fn gp_fault_handler(frame: InterruptStackFrame, err: u64)
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, err: u64) -> ! {
+// #PF error code: the page was present / the access was a write.
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+
+extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, err: u64) {
     let cr2: u64;
     unsafe {
         core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
     }
+    // First write to a copy-on-write page: give it a private frame and retry the access.
+    if (err & (PF_PRESENT | PF_WRITE)) == (PF_PRESENT | PF_WRITE)
+        && crate::user::fault_in_cow(crate::user::current_pml4(), cr2)
+    {
+        return;
+    }
     serial::write_str("EXC: #PF cr2=");
     serial::write_hex_u64(cr2);
+    serial::write_str(if paging::is_hhdm_addr(cr2) {
+        " (hhdm)"
+    } else if paging::is_kmap_addr(cr2) {
+        " (kmap)"
+    } else if paging::is_kernel_addr(cr2) {
+        " (kernel)"
+    } else {
+        " (user)"
+    });
     serial::write_str(" err=");
     serial::write_hex_u64(err);
     serial::write_str(" rip=");
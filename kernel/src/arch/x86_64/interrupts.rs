@@ -0,0 +1,74 @@
+// Interrupt-flag control. Critical sections save RFLAGS.IF and put it back on exit, so a
+// nested section never turns interrupts on underneath an outer one.
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    (rflags & RFLAGS_IF) != 0
+}
+
+/// Restores the IF state seen by `disable` when dropped.
+#[must_use = "interrupts are restored when the guard is dropped"]
+pub struct Guard {
+    was_enabled: bool,
+}
+
+impl Guard {
+    #[cfg(feature = "ktest")]
+    pub fn was_enabled(&self) -> bool {
+        self.was_enabled
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Turn interrupts off until the returned guard is dropped.
+pub fn disable() -> Guard {
+    let was_enabled = are_enabled();
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+    Guard { was_enabled }
+}
+
+// Run `f` with interrupts disabled, restoring the previous IF state afterwards.
+// Syscalls and IRQs already enter with IF=0; this covers kernel-context callers.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = disable();
+    f()
+}
+
+ktest! {
+    fn nested_guards_restore_if() {
+        // Nest two guards starting from IF=1: dropping the inner one must leave interrupts off,
+        // dropping the outer one must turn them back on. Leaves interrupts disabled.
+        unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+        let outer = disable();
+        let inner = disable();
+        let saved = (outer.was_enabled(), inner.was_enabled());
+        drop(inner);
+        let after_inner = are_enabled();
+        drop(outer);
+        let after_outer = are_enabled();
+        unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+
+        kassert!(
+            saved == (true, false),
+            "guards saved {:?}",
+            saved
+        );
+        kassert!(
+            !after_inner,
+            "inner guard re-enabled interrupts"
+        );
+        kassert!(after_outer, "outer guard did not restore IF");
+    }
+}
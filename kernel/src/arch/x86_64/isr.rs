@@ -1,14 +1,15 @@
 use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use super::pic;
-use crate::arch::x86_64::paging;
 use crate::ipc;
 use crate::serial;
 use crate::user;
-use mantra_sys::syscall;
+use mantra_sys::{error, syscall, MsgHeader};
 
-// Trap frame layout produced by `mantra_timer_irq_stub`.
-// This is the pointer value passed to `mantra_timer_irq_rust`.
+// Trap frame layout shared by every entry stub (`mantra_timer_irq_stub`,
+// `mantra_syscall80_stub`) and by freshly built tasks: GPRs in the reverse of push order,
+// then the CPU-pushed RIP/CS/RFLAGS/RSP/SS. `mantra_trap_return` pops exactly this.
 #[repr(C)]
 pub struct TrapFrame {
     pub r15: u64,
@@ -27,7 +28,9 @@ pub struct TrapFrame {
     pub rbx: u64,
     pub rax: u64,
 
-    // CPU-pushed frame (ring3 -> ring0): RIP, CS, RFLAGS, RSP, SS.
+    // CPU-pushed frame: RIP, CS, RFLAGS, RSP, SS. In 64-bit mode SS:RSP are pushed for
+    // every interrupt, same-privilege included, so the shape is the same whether ring 3 or
+    // ring 0 was interrupted; only the values differ (see `is_user`).
     pub rip: u64,
     pub cs: u64,
     pub rflags: u64,
@@ -35,308 +38,1273 @@ pub struct TrapFrame {
     pub ss: u64,
 }
 
+impl TrapFrame {
+    /// True if the trap interrupted ring 3; false for kernel code (including idle).
+    pub fn is_user(&self) -> bool {
+        (self.cs & 3) == 3
+    }
+}
+
+// The asm stubs hard-code this layout; keep it in lockstep.
+const _: () = {
+    use core::mem::{offset_of, size_of};
+    assert!(size_of::<TrapFrame>() == 20 * 8);
+    assert!(offset_of!(TrapFrame, r15) == 0);
+    assert!(offset_of!(TrapFrame, rsi) == 8 * 8);
+    assert!(offset_of!(TrapFrame, rdi) == 9 * 8);
+    assert!(offset_of!(TrapFrame, rdx) == 11 * 8);
+    assert!(offset_of!(TrapFrame, rcx) == 12 * 8);
+    assert!(offset_of!(TrapFrame, rax) == 14 * 8);
+    assert!(offset_of!(TrapFrame, rip) == 15 * 8);
+    assert!(offset_of!(TrapFrame, ss) == 19 * 8);
+};
+
 extern "C" {
     pub fn mantra_timer_irq_stub();
     pub fn mantra_syscall80_stub();
+    pub fn mantra_kyield_stub();
+    pub fn mantra_hrtimer_stub();
+    pub fn mantra_double_fault_stub();
     pub fn mantra_trap_return() -> !;
 }
 
+/// Vector for in-kernel yields from syscall safe points (DPL 0: not reachable from user).
+pub const KYIELD_VECTOR: u8 = 0x81;
+
+// Bulk copies offer to reschedule after this many bytes (one page).
+const PREEMPT_EVERY: usize = 4096;
+
+// Timer IRQs that landed in ring 0 (idle, or kernel code running with IF=1).
+static KERNEL_TIMER_IRQS: AtomicU64 = AtomicU64::new(0);
+
 #[no_mangle]
 pub extern "C" fn mantra_timer_irq_rust(tf: *mut TrapFrame) -> u64 {
     // Acknowledge the interrupt early so we don't lose timer events if we run long.
     pic::eoi(0);
+    ipc::irq_fired(0);
+    if !unsafe { &*tf }.is_user() {
+        KERNEL_TIMER_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
     crate::sched::on_timer_irq(tf)
 }
 
-// Trap frame layout produced by `mantra_syscall80_stub` (ring3 -> ring0): GPRs + RIP/CS/RFLAGS/RSP/SS.
-#[repr(C)]
-pub struct SyscallFrame {
-    pub r15: u64,
-    pub r14: u64,
-    pub r13: u64,
-    pub r12: u64,
-    pub r11: u64,
-    pub r10: u64,
-    pub r9: u64,
-    pub r8: u64,
-    pub rsi: u64,
-    pub rdi: u64,
-    pub rbp: u64,
-    pub rdx: u64,
-    pub rcx: u64,
-    pub rbx: u64,
-    pub rax: u64,
+#[no_mangle]
+pub extern "C" fn mantra_hrtimer_rust(tf: *mut TrapFrame) -> u64 {
+    super::lapic::eoi();
+    crate::sched::on_hrtimer(tf)
+}
 
-    pub rip: u64,
-    pub cs: u64,
-    pub rflags: u64,
-    pub rsp: u64,
-    pub ss: u64,
+// A syscall gave up the CPU mid-operation: `tf` is a ring-0 frame on its kernel stack that
+// resumes inside `sched::preempt_point` when the proc is picked again.
+#[no_mangle]
+pub extern "C" fn mantra_kyield_rust(tf: *mut TrapFrame) -> u64 {
+    crate::sched::yield_from_syscall(tf as u64)
+}
+
+// One step of the register-heavy loop the ring-0 preemption ktest runs.
+#[cfg(feature = "ktest")]
+fn spin_step(acc: u64, i: u64) -> u64 {
+    acc.wrapping_mul(31).wrapping_add(i)
+}
+
+ktest! {
+    fn kernel_loop_survives_timer_irqs() {
+        // Run a kernel loop with interrupts on until the timer has interrupted it a couple of
+        // times, then check the loop's register state came back intact through the ring-0 frames.
+        const WANT_IRQS: u64 = 2;
+        const SPIN_LIMIT: u64 = 1 << 30;
+
+        let before = KERNEL_TIMER_IRQS.load(Ordering::Relaxed);
+        let mut acc = 0u64;
+        let mut i = 0u64;
+        unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+        while KERNEL_TIMER_IRQS.load(Ordering::Relaxed) - before < WANT_IRQS && i < SPIN_LIMIT {
+            acc = spin_step(acc, core::hint::black_box(i));
+            i += 1;
+        }
+        unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+
+        let irqs = KERNEL_TIMER_IRQS.load(Ordering::Relaxed) - before;
+        let expected = (0..i).fold(0u64, spin_step);
+        kassert!(acc == expected, "state corrupted across ring-0 timer IRQs");
+        kassert!(irqs >= WANT_IRQS, "only {} timer IRQs in {} iterations", irqs, i);
+    }
+}
+
+// A syscall handler decodes its own arguments from the caller's frame, writes its results
+// back into it and returns the frame to switch to (0 = return to the caller). `pid` and
+// `pml4` are the caller's, captured before a handler's yield changes CURRENT and CR3.
+type Handler = fn(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64;
+
+#[derive(Copy, Clone)]
+struct Syscall {
+    name: &'static str,
+    handler: Handler,
+}
+
+// Build the dispatch table from `NAME => handler` pairs, `NAME` being the
+// `mantra_sys::syscall` constant. Assigning a number twice fails the build.
+macro_rules! syscall_table {
+    ($($nr:ident => $handler:ident,)*) => {{
+        let mut table = [None; syscall::NR_SYSCALLS as usize];
+        $(
+            assert!(table[syscall::$nr as usize].is_none());
+            table[syscall::$nr as usize] = Some(Syscall {
+                name: stringify!($nr),
+                handler: $handler,
+            });
+        )*
+        table
+    }};
+}
+
+// Indexed by syscall number; None where no syscall is assigned.
+static SYSCALLS: [Option<Syscall>; syscall::NR_SYSCALLS as usize] = syscall_table! {
+    PUTC => sys_putc,
+    YIELD_ => sys_yield,
+    YIELD_HINT => sys_yield_hint,
+    WRITE => sys_write,
+    EXIT => sys_exit,
+    IPC_EP_CREATE => sys_ipc_ep_create,
+    IPC_SEND => sys_ipc_send,
+    IPC_RECV => sys_ipc_recv,
+    IPC_SEND_CAP => sys_ipc_send_cap,
+    IPC_SEND_MSG => sys_ipc_send_msg,
+    IPC_EP_DESTROY => sys_ipc_ep_destroy,
+    CAP_DROP => sys_cap_drop,
+    IPC_CALL => sys_ipc_call,
+    IPC_REPLY => sys_ipc_reply,
+    IPC_RECV_CAP => sys_ipc_recv_cap,
+    EP_REGISTER => sys_ep_register,
+    EP_LOOKUP => sys_ep_lookup,
+    CON_WRITE => sys_con_write,
+    FB_SET_GAMMA => sys_fb_set_gamma,
+    FB_GET_GAMMA => sys_fb_get_gamma,
+    CAP_LIST => sys_cap_list,
+    GETRANDOM => sys_getrandom,
+    NANOSLEEP => sys_nanosleep,
+    NICE => sys_nice,
+    SCHED_STATS => sys_sched_stats,
+    PROC_SPAWN => sys_proc_spawn,
+    TRANSLATE => sys_translate,
+    MEMMAP => sys_memmap,
+    PROC_INFO => sys_proc_info,
+    IRQ_BIND => sys_irq_bind,
+    PARK => sys_park,
+    UNPARK => sys_unpark,
+    PROC_REGS => sys_proc_regs,
+    SYSCALL_INFO => sys_syscall_info,
+};
+
+fn lookup(n: u64) -> Option<Syscall> {
+    SYSCALLS.get(usize::try_from(n).ok()?).copied().flatten()
 }
 
 #[no_mangle]
-pub extern "C" fn mantra_syscall80_rust(tf: *mut SyscallFrame) -> u64 {
+pub extern "C" fn mantra_syscall80_rust(tf: *mut TrapFrame) -> u64 {
     let tf = unsafe { &mut *tf };
     let n = tf.rax;
-    let mut switch_to: u64 = 0;
+    // The calling proc and its address space. Captured once: handlers that yield change
+    // CURRENT and CR3.
+    let pid = crate::sched::current_pid();
+    let pml4 = user::current_pml4();
 
-    match n {
-        syscall::PUTC => {
-            serial::write_byte(tf.rdi as u8);
-            tf.rax = 0;
+    match lookup(n) {
+        Some(call) => (call.handler)(tf, pid, pml4),
+        None => {
+            serial::write_str("SYS: unknown int80 n=");
+            serial::write_hex_u64(n);
+            serial::write_str("\n");
+            tf.rax = error::NOT_FOUND;
+            0
         }
-        syscall::YIELD_ => {
-            // Cooperative yield.
+    }
+}
+
+fn sys_putc(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    serial::write_byte(tf.rdi as u8);
+    tf.rax = 0;
+    0
+}
+
+fn sys_yield(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    // Cooperative yield.
+    tf.rax = 0;
+    crate::sched::yield_from_syscall(tf as *mut _ as u64)
+}
+
+fn sys_yield_hint(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (cap) -> 0 or err. Yield; with nothing queued on the endpoint, stay blocked
+    // until the next send there. The caller still receives the message itself.
+    let mut switch_to = 0;
+    match crate::sched::cap_lookup(pid, tf.rdi as u32) {
+        Some(ep_id) => {
             tf.rax = 0;
+            // The kernel's sysinfo endpoint is never sent to; just yield there.
+            if ipc::queued(ep_id) == 0 && !ipc::is_sysinfo(ep_id) && ipc::waiter_push(ep_id, pid) {
+                crate::sched::block_current_for_hint(ep_id);
+            }
             switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
         }
-        syscall::WRITE => {
-            // (ptr,len) -> bytes_written
-            let user_ptr = tf.rdi;
-            let user_len = tf.rsi as usize;
-            let max = 1024usize;
-            let n = core::cmp::min(user_len, max);
-
-            let mut written = 0usize;
-            while written < n {
-                let v = user_ptr.wrapping_add(written as u64);
-                if let Some(p) = user_virt_to_phys(v) {
-                    let b = unsafe {
-                        core::ptr::read_volatile(paging::phys_to_virt_ptr::<u8>(p))
-                    };
-                    serial::write_byte(b);
-                    written += 1;
-                } else {
-                    break;
-                }
-            }
-            tf.rax = written as u64;
-        }
-        syscall::IPC_EP_CREATE => {
-            tf.rax = ipc::ep_create();
-        }
-        syscall::IPC_SEND => {
-            // (cap, ptr, len) -> bytes_sent or err
-            let cap = tf.rdi as u32;
-            let user_ptr = tf.rsi;
-            let user_len = core::cmp::min(tf.rdx as usize, 1024usize);
-            let mut tmp = [0u8; 256];
-            let n = core::cmp::min(user_len, tmp.len());
-            if user_copy_in(&mut tmp[..n], user_ptr).is_none() {
-                tf.rax = u64::MAX;
-            } else {
-                // If a receiver is blocked waiting on this endpoint, deliver directly.
-                if let Some(ep_id) = crate::sched::cap_lookup_current(cap) {
-                    if let Some(pid) = ipc::waiter_pop(ep_id) {
-                        tf.rax = deliver_ipc(pid, &tmp[..n], 0);
-                    } else {
-                        tf.rax = ipc::ep_send_cap(cap, &tmp[..n], 0);
-                    }
-                } else {
-                    tf.rax = u64::MAX;
-                }
+        None => tf.rax = error::INVALID,
+    }
+    switch_to
+}
+
+fn sys_write(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (ptr,len) -> bytes_written
+    if user::checked_user_range(tf.rdi, tf.rsi).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let user_ptr = tf.rdi;
+    let user_len = tf.rsi as usize;
+    let max = 1024usize;
+    let n = core::cmp::min(user_len, max);
+
+    // Short count if the buffer runs into an unmapped page.
+    let mut tmp = [0u8; 256];
+    let mut written = 0usize;
+    while written < n {
+        let chunk = core::cmp::min(n - written, tmp.len());
+        let src = user_ptr.wrapping_add(written as u64);
+        let (got, fault) = match user::copy_from(pml4, &mut tmp[..chunk], src) {
+            Ok(got) => (got, false),
+            Err(f) => (f.done, true),
+        };
+        tmp[..got].iter().for_each(|&b| serial::write_byte(b));
+        written += got;
+        if fault {
+            break;
+        }
+    }
+    tf.rax = written as u64;
+    0
+}
+
+// Most bytes one CON_WRITE draws; the rest of the buffer is left for the next call.
+const CON_WRITE_MAX: usize = 4096;
+
+fn sys_con_write(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (ptr, len, color) -> bytes_written or err
+    let color = match tf.rdx {
+        u64::MAX => None,
+        c if c <= 0xff_ffff => Some(crate::fb::Rgb {
+            r: (c >> 16) as u8,
+            g: (c >> 8) as u8,
+            b: c as u8,
+        }),
+        _ => {
+            tf.rax = error::INVALID;
+            return 0;
+        }
+    };
+    if user::checked_user_range(tf.rdi, tf.rsi).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let user_ptr = tf.rdi;
+    let n = core::cmp::min(tf.rsi as usize, CON_WRITE_MAX);
+
+    // Stage a chunk at a time, never straddling a user page, and draw it in one go with
+    // the console held. Short count if the buffer runs into an unmapped page.
+    let mut tmp = [0u8; 256];
+    let mut written = 0usize;
+    let drew = crate::fb::with_console(|con| {
+        let saved = con.colors();
+        if let Some(fg) = color {
+            con.set_colors(fg, saved.1);
+        }
+        while written < n {
+            let src = user_ptr.wrapping_add(written as u64);
+            let to_page = (4096 - (src & 0xfff)) as usize;
+            let chunk = (n - written).min(tmp.len()).min(to_page);
+            let (got, fault) = match user::copy_from(pml4, &mut tmp[..chunk], src) {
+                Ok(got) => (got, false),
+                Err(f) => (f.done, true),
+            };
+            con.write_bytes(&tmp[..got]);
+            written += got;
+            if fault {
+                break;
             }
         }
-        syscall::IPC_RECV => {
-            // (cap, ptr, max_len) -> bytes_recv or err
-            let cap = tf.rdi as u32;
-            let user_ptr = tf.rsi;
-            let max_len = core::cmp::min(tf.rdx as usize, 1024usize);
-            let mut tmp = [0u8; 256];
-            let n = core::cmp::min(max_len, tmp.len());
-            let got = ipc::ep_recv(cap, &mut tmp[..n]);
-            if got == u64::MAX || got == u64::MAX - 2 {
-                // Empty: block (if possible) instead of spinning in userspace.
-                if got == u64::MAX - 2 && crate::sched::has_other_runnable() {
-                    if let Some(ep_id) = crate::sched::cap_lookup_current(cap) {
-                        if ipc::waiter_push(ep_id, crate::sched::current_pid()) {
-                            crate::sched::block_current_on_ep(ep_id);
-                            switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
-                            // Do not update tf.rax here; it will be filled in by the sender's delivery path.
-                        } else {
-                            tf.rax = got;
-                        }
-                    } else {
-                        tf.rax = u64::MAX;
-                    }
+        con.set_colors(saved.0, saved.1);
+    });
+    tf.rax = match drew {
+        Some(()) => written as u64,
+        None => error::NOT_FOUND,
+    };
+    0
+}
+
+fn sys_fb_set_gamma(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (*const Gamma) -> 0 or err; null restores the identity table
+    if !crate::sched::is_privileged(pid) {
+        tf.rax = error::PERMISSION;
+        return 0;
+    }
+    let mut table = mantra_sys::Gamma::IDENTITY;
+    if tf.rdi != 0 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                &mut table as *mut _ as *mut u8,
+                core::mem::size_of::<mantra_sys::Gamma>(),
+            )
+        };
+        if user::checked_user_range(tf.rdi, bytes.len() as u64).is_none()
+            || user::copy_from(pml4, bytes, tf.rdi).is_err()
+        {
+            tf.rax = error::INVALID;
+            return 0;
+        }
+    }
+    crate::fb::set_gamma(&table);
+    tf.rax = 0;
+    0
+}
+
+fn sys_fb_get_gamma(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (*mut Gamma) -> 0 or err
+    let size = core::mem::size_of::<mantra_sys::Gamma>();
+    if user::checked_user_range(tf.rdi, size as u64).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let table = crate::fb::gamma();
+    let bytes = unsafe { core::slice::from_raw_parts(&table as *const _ as *const u8, size) };
+    tf.rax = if user::copy_to(pml4, tf.rdi, bytes).is_ok() {
+        0
+    } else {
+        error::INVALID
+    };
+    0
+}
+
+fn sys_exit(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    // Switches to another proc (or idle); the zombie is reaped on a later switch.
+    crate::sched::exit_current();
+    crate::sched::yield_from_syscall(tf as *mut _ as u64)
+}
+
+fn sys_ipc_ep_create(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (depth, max_msg, flags) -> cap or err; 0 selects the default for either size.
+    tf.rax = ipc::ep_create(pid, tf.rdi as usize, tf.rsi as usize, tf.rdx);
+    0
+}
+
+fn sys_ipc_send(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, ptr, len) -> bytes_sent or err
+    if user::checked_user_range(tf.rsi, tf.rdx).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let cap = tf.rdi as u32;
+    let user_ptr = tf.rsi;
+    tf.rax = send_user(pid, pml4, cap, user_ptr, tf.rdx as usize, 0);
+    0
+}
+
+fn sys_ipc_recv(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, ptr, max_len) -> bytes_recv or err
+    if user::checked_user_range(tf.rsi, tf.rdx).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let mut switch_to = 0;
+    let cap = tf.rdi as u32;
+    let user_ptr = tf.rsi;
+    let max_len = core::cmp::min(tf.rdx as usize, 1024usize);
+    let mut tmp = [0u8; 256];
+    let n = core::cmp::min(max_len, tmp.len());
+    let got = ipc::ep_recv(pid, cap, &mut tmp[..n]);
+    if got == error::INVALID || got == error::EMPTY {
+        // Empty: block instead of spinning in userspace (idle runs if nothing else can).
+        if got == error::EMPTY {
+            if let Some(ep_id) = crate::sched::cap_lookup(pid, cap) {
+                if ipc::waiter_push(ep_id, pid) {
+                    crate::sched::block_current_on_ep(ep_id);
+                    switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
+                    // Do not update tf.rax here; it will be filled in by the sender's delivery path.
                 } else {
                     tf.rax = got;
                 }
             } else {
-                let got = got as usize;
-                if user_copy_out(user_ptr, &tmp[..got]).is_some() {
-                    tf.rax = got as u64;
-                } else {
-                    tf.rax = u64::MAX;
-                }
+                tf.rax = u64::MAX;
             }
+        } else {
+            tf.rax = got;
+        }
+    } else {
+        let got = got as usize;
+        if user::copy_to(pml4, user_ptr, &tmp[..got]).is_ok() {
+            tf.rax = got as u64;
+        } else {
+            tf.rax = u64::MAX;
         }
-        syscall::IPC_SEND_CAP => {
-            // (cap, ptr, len, xfer_cap) -> bytes_sent or err
-            let cap = tf.rdi as u32;
-            let user_ptr = tf.rsi;
-            let user_len = core::cmp::min(tf.rdx as usize, 1024usize);
-            let xfer_cap = tf.rcx as u32;
+    }
+    switch_to
+}
 
-            let xfer_ep = if xfer_cap == 0 {
-                0
-            } else if let Some(ep) = crate::sched::cap_lookup_current(xfer_cap) {
-                ep
+fn sys_ipc_send_cap(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, ptr, len, xfer_cap) -> bytes_sent or err
+    if user::checked_user_range(tf.rsi, tf.rdx).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let cap = tf.rdi as u32;
+    let user_ptr = tf.rsi;
+    let user_len = tf.rdx as usize;
+    let xfer_cap = tf.rcx as u32;
+
+    let xfer_ep = if xfer_cap == 0 {
+        0
+    } else if let Some(ep) = crate::sched::cap_lookup(pid, xfer_cap) {
+        ep
+    } else {
+        tf.rax = u64::MAX;
+        return 0;
+    };
+
+    tf.rax = send_user(pid, pml4, cap, user_ptr, user_len, xfer_ep);
+    0
+}
+
+fn sys_ipc_send_msg(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, tag, ptr, len) -> payload bytes_sent or err
+    if user::checked_user_range(tf.rdx, tf.rcx).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let cap = tf.rdi as u32;
+    let tag = tf.rsi as u32;
+    let user_ptr = tf.rdx;
+    let user_len = tf.rcx as usize;
+    let mut tmp = [0u8; 256];
+    // Refuse rather than truncate: the header must describe what was sent.
+    if user_len > tmp.len() - MsgHeader::SIZE {
+        tf.rax = error::INVALID;
+    } else {
+        let (hdr, payload) = tmp.split_at_mut(MsgHeader::SIZE);
+        let hdr_bytes = MsgHeader {
+            tag,
+            len: user_len as u32,
+        }
+        .to_bytes();
+        hdr.copy_from_slice(&hdr_bytes);
+        if user::copy_from(pml4, &mut payload[..user_len], user_ptr).is_err() {
+            tf.rax = error::INVALID;
+        } else {
+            let sent = send_ipc(pid, cap, &tmp[..MsgHeader::SIZE + user_len], 0, false);
+            tf.rax = if error::is_err(sent) {
+                sent
             } else {
-                tf.rax = u64::MAX;
-                return 0;
+                sent.saturating_sub(MsgHeader::SIZE as u64)
             };
+        }
+    }
+    0
+}
 
-            let mut tmp = [0u8; 256];
-            let n = core::cmp::min(user_len, tmp.len());
-            if user_copy_in(&mut tmp[..n], user_ptr).is_none() {
-                tf.rax = u64::MAX;
-            } else {
-                if let Some(ep_id) = crate::sched::cap_lookup_current(cap) {
-                    if let Some(pid) = ipc::waiter_pop(ep_id) {
-                        tf.rax = deliver_ipc(pid, &tmp[..n], xfer_ep);
-                    } else {
-                        tf.rax = ipc::ep_send_cap(cap, &tmp[..n], xfer_ep);
-                    }
+fn sys_ipc_ep_destroy(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (cap) -> 0 or err
+    tf.rax = ipc::ep_destroy(pid, tf.rdi as u32);
+    0
+}
+
+fn sys_cap_drop(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (cap) -> 0 or err
+    tf.rax = if crate::sched::cap_drop(pid, tf.rdi as u32) {
+        0
+    } else {
+        error::INVALID
+    };
+    0
+}
+
+fn sys_ipc_call(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, ptr, len, max_reply) -> reply bytes or err; out: rdx=reply cap (0 if none)
+    if user::checked_user_range(tf.rsi, tf.rdx.max(tf.rcx)).is_none() {
+        tf.rax = error::INVALID;
+        tf.rdx = 0;
+        return 0;
+    }
+    let mut switch_to = 0;
+    let cap = tf.rdi as u32;
+    let user_ptr = tf.rsi;
+    let mut tmp = [0u8; 256];
+    let n = core::cmp::min(tf.rdx as usize, tmp.len());
+    if user::copy_from(pml4, &mut tmp[..n], user_ptr).is_err() {
+        tf.rax = error::INVALID;
+    } else if crate::sched::cap_lookup(pid, cap).is_some_and(ipc::is_sysinfo) {
+        // Served by the kernel: answer now instead of queueing for a server.
+        let mut out = [0u8; 1024];
+        tf.rdx = 0;
+        tf.rax = match crate::sysinfo::handle(pid, &tmp[..n], &mut out) {
+            Ok(len) => {
+                let len = core::cmp::min(len, tf.rcx as usize);
+                if user::copy_to(pml4, user_ptr, &out[..len]).is_ok() {
+                    len as u64
                 } else {
-                    tf.rax = u64::MAX;
+                    error::INVALID
                 }
             }
+            Err(e) => e,
+        };
+    } else {
+        let sent = send_ipc(pid, cap, &tmp[..n], 0, true);
+        match crate::sched::cap_lookup(pid, cap) {
+            Some(ep_id) if !error::is_err(sent) => {
+                // The server's IPC_REPLY fills rax/rdx and the reply buffer.
+                crate::sched::block_current_on_ep(ep_id);
+                switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
+            }
+            _ => {
+                tf.rax = sent;
+                tf.rdx = 0;
+            }
         }
-        syscall::IPC_RECV_CAP => {
-            // (cap, ptr, max_len) -> bytes_recv or err; out: rdx=received_cap (0 if none)
-            let cap = tf.rdi as u32;
-            let user_ptr = tf.rsi;
-            let max_len = core::cmp::min(tf.rdx as usize, 1024usize);
-            let mut tmp = [0u8; 256];
-            let n = core::cmp::min(max_len, tmp.len());
-
-            let (got, xfer_ep) = ipc::ep_recv_cap(cap, &mut tmp[..n]);
-            if got == u64::MAX || got == u64::MAX - 2 {
-                if got == u64::MAX - 2 && crate::sched::has_other_runnable() {
-                    if let Some(ep_id) = crate::sched::cap_lookup_current(cap) {
-                        if ipc::waiter_push(ep_id, crate::sched::current_pid()) {
-                            crate::sched::block_current_on_ep(ep_id);
-                            switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
-                            // Sender will fill rax/rdx and user buffer.
-                        } else {
-                            tf.rax = got;
-                            tf.rdx = 0;
-                        }
-                    } else {
-                        tf.rax = u64::MAX;
-                        tf.rdx = 0;
-                    }
+    }
+    switch_to
+}
+
+fn sys_ipc_reply(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (ptr, len, xfer_cap) -> bytes_sent or err
+    if user::checked_user_range(tf.rdi, tf.rsi).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let user_ptr = tf.rdi;
+    let xfer_cap = tf.rdx as u32;
+    let mut tmp = [0u8; 256];
+    let n = core::cmp::min(tf.rsi as usize, tmp.len());
+    let xfer_ep = match xfer_cap {
+        0 => Some(0),
+        c => crate::sched::cap_lookup(pid, c),
+    };
+    tf.rax = match xfer_ep {
+        None => error::INVALID,
+        Some(_) if user::copy_from(pml4, &mut tmp[..n], user_ptr).is_err() => error::INVALID,
+        Some(xfer_ep) => match crate::sched::take_reply_to(pid) {
+            Some(caller) => deliver_reply(caller, &tmp[..n], xfer_ep),
+            None => error::INVALID,
+        },
+    };
+    0
+}
+
+fn sys_ipc_recv_cap(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, ptr, max_len) -> bytes_recv or err; out: rdx=received_cap (0 if none)
+    if user::checked_user_range(tf.rsi, tf.rdx).is_none() {
+        tf.rax = error::INVALID;
+        tf.rdx = 0;
+        return 0;
+    }
+    let mut switch_to = 0;
+    let cap = tf.rdi as u32;
+    let user_ptr = tf.rsi;
+    let max_len = core::cmp::min(tf.rdx as usize, 1024usize);
+    let mut tmp = [0u8; 256];
+    let n = core::cmp::min(max_len, tmp.len());
+
+    let (got, xfer_ep) = ipc::ep_recv_cap(pid, cap, &mut tmp[..n]);
+    if got == error::INVALID || got == error::EMPTY {
+        if got == error::EMPTY {
+            if let Some(ep_id) = crate::sched::cap_lookup(pid, cap) {
+                if ipc::waiter_push(ep_id, pid) {
+                    crate::sched::block_current_on_ep(ep_id);
+                    switch_to = crate::sched::yield_from_syscall(tf as *mut _ as u64);
+                    // Sender will fill rax/rdx and user buffer.
                 } else {
                     tf.rax = got;
                     tf.rdx = 0;
                 }
             } else {
-                let got_usz = got as usize;
-                if user_copy_out(user_ptr, &tmp[..got_usz]).is_some() {
-                    // Install a local cap to the transferred endpoint, if any.
-                    tf.rdx = 0;
-                    if xfer_ep != 0 {
-                        if let Some(new_cap) = crate::sched::cap_alloc_current(xfer_ep) {
-                            tf.rdx = new_cap as u64;
-                        } else {
-                            // No cap slots available: drop the transfer but keep the message.
-                            tf.rdx = 0;
-                        }
-                    }
-                    tf.rax = got;
+                tf.rax = u64::MAX;
+                tf.rdx = 0;
+            }
+        } else {
+            tf.rax = got;
+            tf.rdx = 0;
+        }
+    } else {
+        let got_usz = got as usize;
+        if user::copy_to(pml4, user_ptr, &tmp[..got_usz]).is_ok() {
+            // Install a local cap to the transferred endpoint, if any.
+            tf.rdx = 0;
+            if xfer_ep != 0 {
+                if let Some(new_cap) = crate::sched::cap_alloc_for(pid, xfer_ep) {
+                    tf.rdx = new_cap as u64;
                 } else {
-                    tf.rax = u64::MAX;
+                    // No cap slots available: drop the transfer but keep the message.
                     tf.rdx = 0;
                 }
             }
+            tf.rax = got;
+        } else {
+            tf.rax = u64::MAX;
+            tf.rdx = 0;
         }
-        syscall::PROC_SPAWN => {
-            // (prog_id, role, share_cap) -> pid or err
-            let prog_id = tf.rdi;
-            let role = tf.rsi;
-            let share_cap = tf.rdx as u32;
-            tf.rax = user::spawn_init_from_syscall(prog_id, role, share_cap);
+    }
+    switch_to
+}
+
+// Copy an endpoint name in from the user; None if it is empty, too long or unreadable.
+fn user_ep_name(pml4: u64, ptr: u64, len: u64) -> Option<([u8; mantra_sys::EP_NAME_MAX], usize)> {
+    let mut name = [0u8; mantra_sys::EP_NAME_MAX];
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&n| n != 0 && n <= name.len())?;
+    user::copy_from(pml4, &mut name[..len], ptr).ok()?;
+    Some((name, len))
+}
+
+fn sys_ep_register(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (cap, name_ptr, name_len) -> 0 or err; privileged only
+    if user::checked_user_range(tf.rsi, tf.rdx).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    tf.rax = match user_ep_name(pml4, tf.rsi, tf.rdx) {
+        Some((name, len)) => ipc::ep_register(pid, tf.rdi as u32, &name[..len]),
+        None => error::INVALID,
+    };
+    0
+}
+
+fn sys_ep_lookup(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (name_ptr, name_len) -> new cap or err
+    if user::checked_user_range(tf.rdi, tf.rsi).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    tf.rax = match user_ep_name(pml4, tf.rdi, tf.rsi) {
+        Some((name, len)) => ipc::ep_lookup(pid, &name[..len]),
+        None => error::INVALID,
+    };
+    0
+}
+
+fn sys_cap_list(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (ptr, max_entries) -> entries written; each entry is {cap: u32, ep: u32} (LE).
+    if user::checked_user_range(tf.rdi, tf.rsi.saturating_mul(8)).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let user_ptr = tf.rdi;
+    let max = tf.rsi as usize;
+    let mut written = 0usize;
+    let mut ok = true;
+    crate::sched::for_each_cap(pid, |cap, ep| {
+        if !ok || written >= max {
+            return;
         }
-        _ => {
-            serial::write_str("SYS: unknown int80 n=");
-            serial::write_hex_u64(n);
-            serial::write_str("\n");
-            tf.rax = u64::MAX;
+        let mut e = [0u8; 8];
+        e[..4].copy_from_slice(&cap.to_le_bytes());
+        e[4..].copy_from_slice(&ep.to_le_bytes());
+        let dst = user_ptr.wrapping_add((written * e.len()) as u64);
+        if user::copy_to(pml4, dst, &e).is_ok() {
+            written += 1;
+        } else {
+            ok = false;
         }
+    });
+    tf.rax = if ok { written as u64 } else { u64::MAX };
+    0
+}
+
+fn sys_getrandom(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (ptr, len) -> bytes written or err; filled a chunk at a time so the
+    // buffer may span pages, offering to reschedule after each page.
+    if user::checked_user_range(tf.rdi, tf.rsi).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
     }
+    let user_ptr = tf.rdi;
+    let len = core::cmp::min(tf.rsi as usize, 16 * 1024);
+    let mut tmp = [0u8; 256];
+    let mut done = 0usize;
+    tf.rax = loop {
+        if done == len {
+            break done as u64;
+        }
+        if done != 0 && done.is_multiple_of(PREEMPT_EVERY) {
+            crate::sched::preempt_point();
+        }
+        let n = core::cmp::min(len - done, tmp.len());
+        crate::rng::fill(&mut tmp[..n]);
+        if user::copy_to(pml4, user_ptr.wrapping_add(done as u64), &tmp[..n]).is_err() {
+            break error::INVALID;
+        }
+        done += n;
+    };
+    0
+}
 
-    switch_to
+fn sys_nanosleep(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    // (ns) -> 0; returns once at least `ns` nanoseconds have passed.
+    tf.rax = 0;
+    crate::sched::nanosleep_current(tf.rdi);
+    crate::sched::yield_from_syscall(tf as *mut _ as u64)
+}
+
+fn sys_nice(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (delta) -> new priority (0 = most favoured) or err
+    tf.rax = match crate::sched::nice(pid, tf.rdi as i64) {
+        Ok(prio) => prio as u64,
+        Err(e) => e,
+    };
+    0
+}
+
+fn sys_sched_stats(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (*mut SchedStats) -> 0 or err
+    let size = core::mem::size_of::<mantra_sys::SchedStats>();
+    if user::checked_user_range(tf.rdi, size as u64).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    let s = crate::sched::stats();
+    let out = mantra_sys::SchedStats {
+        switches: s.switches,
+        yields: s.yields,
+        timer_irqs: s.timer_irqs,
+        timer_preemptions: s.timer_preemptions,
+        syscall_preemptions: s.syscall_preemptions,
+        runnable: s.runnable,
+        blocked: s.blocked,
+        sleeping: s.sleeping,
+        zombie: s.zombie,
+        ticks: s.ticks,
+        idle_ticks: s.idle_ticks,
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(&out as *const _ as *const u8, size) };
+    tf.rax = if user::copy_to(pml4, tf.rdi, bytes).is_ok() {
+        0
+    } else {
+        error::INVALID
+    };
+    0
+}
+
+fn sys_proc_spawn(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (prog_id, role, *const SpawnCap, count, flags) -> pid or err
+    if user::checked_user_range(
+        tf.rdx,
+        tf.rcx
+            .saturating_mul(core::mem::size_of::<mantra_sys::SpawnCap>() as u64),
+    )
+    .is_none()
+    {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    use mantra_sys::{spawn_caps, SpawnCap};
+    let count = tf.rcx as usize;
+    let mut caps = [SpawnCap::default(); spawn_caps::MAX];
+    tf.rax = if count > caps.len() {
+        error::INVALID
+    } else {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                caps.as_mut_ptr() as *mut u8,
+                count * core::mem::size_of::<SpawnCap>(),
+            )
+        };
+        match user::copy_from(pml4, bytes, tf.rdx) {
+            Ok(_) => user::spawn_init_from_syscall(pid, tf.rdi, tf.rsi, &caps[..count], tf.r8),
+            Err(_) => error::INVALID,
+        }
+    };
+    0
+}
+
+fn sys_translate(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (pid, va) -> physical address or err; privileged only
+    use crate::sched::ProcState;
+    let target = tf.rdi as usize;
+    tf.rax = if !crate::sched::is_privileged(pid) {
+        error::PERMISSION
+    } else {
+        match crate::sched::proc_info(target) {
+            Some((ProcState::Dead | ProcState::Zombie, _, _)) | None => error::INVALID,
+            Some(_) => crate::sched::proc_cr3(target)
+                .and_then(|cr3| user::user_virt_to_phys(cr3, tf.rsi))
+                .unwrap_or(error::INVALID),
+        }
+    };
+    0
+}
+
+fn sys_memmap(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (*mut MemMapEntry, max_entries, skip) -> entries written or err
+    if user::checked_user_range(
+        tf.rdi,
+        tf.rsi
+            .saturating_mul(core::mem::size_of::<mantra_sys::MemMapEntry>() as u64),
+    )
+    .is_none()
+    {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    tf.rax = copy_memmap(pml4, tf.rdi, tf.rsi as usize, tf.rdx as usize);
+    0
 }
 
-fn virt_to_phys_in(pml4_phys: u64, virt: u64) -> Option<u64> {
-    // Walk 4-level tables. Require U=1 at every level and leaf present.
-    const MASK: u64 = 0x000f_ffff_ffff_f000;
-    const PTE_P: u64 = 1 << 0;
-    const PTE_U: u64 = 1 << 2;
+fn sys_proc_info(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (pid, *mut ProcInfo) -> 0 or err
+    let size = core::mem::size_of::<mantra_sys::ProcInfo>();
+    if user::checked_user_range(tf.rsi, size as u64).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    tf.rax = match crate::sched::proc_info(tf.rdi as usize) {
+        Some((state, mapped_pages, parent)) => {
+            let info = mantra_sys::ProcInfo {
+                state: state.code(),
+                mapped_pages,
+                parent: if parent == crate::sched::NO_PARENT {
+                    u64::MAX
+                } else {
+                    parent as u64
+                },
+                ticks: crate::sched::proc_ticks(tf.rdi as usize),
+            };
+            let bytes =
+                unsafe { core::slice::from_raw_parts(&info as *const _ as *const u8, size) };
+            if user::copy_to(pml4, tf.rsi, bytes).is_ok() {
+                0
+            } else {
+                error::INVALID
+            }
+        }
+        None => error::INVALID,
+    };
+    0
+}
 
-    let pml4 = pml4_phys & MASK;
-    let pml4_i = ((virt >> 39) & 0x1ff) as usize;
-    let pdpt_i = ((virt >> 30) & 0x1ff) as usize;
-    let pd_i = ((virt >> 21) & 0x1ff) as usize;
-    let pt_i = ((virt >> 12) & 0x1ff) as usize;
-    let off = virt & 0xfff;
+fn sys_irq_bind(tf: &mut TrapFrame, pid: usize, _: u64) -> u64 {
+    // (irq, cap) -> 0 or err; privileged only. Cap 0 unbinds the line.
+    let irq = tf.rdi as usize;
+    tf.rax = if !crate::sched::is_privileged(pid) {
+        error::PERMISSION
+    } else {
+        let ep = match tf.rsi {
+            0 => Some(0),
+            cap => crate::sched::cap_lookup(pid, cap as u32),
+        };
+        match ep {
+            Some(ep) if ipc::bind_irq(irq, ep) => 0,
+            _ => error::INVALID,
+        }
+    };
+    0
+}
 
-    unsafe fn rd(table: u64, idx: usize) -> u64 {
-        core::ptr::read_volatile(paging::phys_to_virt_ptr::<u64>(table).add(idx))
+fn sys_park(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    // () -> 0 once unparked, 1 if an unpark was pending. Independent of endpoints:
+    // only UNPARK wakes a parked proc.
+    if crate::sched::park_current() {
+        tf.rax = 0;
+        crate::sched::yield_from_syscall(tf as *mut _ as u64)
+    } else {
+        tf.rax = 1;
+        0
     }
+}
 
-    let pml4e = unsafe { rd(pml4, pml4_i) };
-    if (pml4e & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
+fn sys_unpark(tf: &mut TrapFrame, _: usize, _: u64) -> u64 {
+    // (pid) -> 0 if it was parked, 1 if the unpark waits for its next PARK.
+    tf.rax = match crate::sched::unpark(tf.rdi as usize) {
+        Some(true) => 0,
+        Some(false) => 1,
+        None => error::INVALID,
+    };
+    0
+}
+
+fn sys_proc_regs(tf: &mut TrapFrame, pid: usize, pml4: u64) -> u64 {
+    // (pid, *mut Regs) -> 0 or err; own children only unless privileged. The caller's
+    // own frame is live, not saved, so it can't be read this way.
+    use crate::sched::ProcState;
+    let size = core::mem::size_of::<mantra_sys::Regs>();
+    if user::checked_user_range(tf.rsi, size as u64).is_none() {
+        tf.rax = error::INVALID;
+        return 0;
     }
-    let pdpt = pml4e & MASK;
+    let target = tf.rdi as usize;
+    tf.rax = match crate::sched::proc_info(target) {
+        Some((ProcState::Dead | ProcState::Zombie, _, _)) | None => error::INVALID,
+        Some(_) if target == pid => error::INVALID,
+        Some((_, _, parent)) if parent != pid && !crate::sched::is_privileged(pid) => {
+            error::PERMISSION
+        }
+        // Not `proc_tf_rsp`: that is a ring-0 frame if the target was preempted in a syscall.
+        Some(_) => match crate::sched::proc_user_tf(target) {
+            Some(rsp) if unsafe { &*(rsp as *const TrapFrame) }.is_user() => {
+                let regs = saved_regs(unsafe { &*(rsp as *const TrapFrame) });
+                let bytes =
+                    unsafe { core::slice::from_raw_parts(&regs as *const _ as *const u8, size) };
+                if user::copy_to(pml4, tf.rsi, bytes).is_ok() {
+                    0
+                } else {
+                    error::INVALID
+                }
+            }
+            _ => error::INVALID,
+        },
+    };
+    0
+}
 
-    let pdpte = unsafe { rd(pdpt, pdpt_i) };
-    if (pdpte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
+fn sys_syscall_info(tf: &mut TrapFrame, _: usize, pml4: u64) -> u64 {
+    // (*mut SyscallName, max_entries) -> syscalls implemented or err; entries in number order
+    if user::checked_user_range(
+        tf.rdi,
+        tf.rsi
+            .saturating_mul(core::mem::size_of::<mantra_sys::SyscallName>() as u64),
+    )
+    .is_none()
+    {
+        tf.rax = error::INVALID;
+        return 0;
+    }
+    use mantra_sys::SyscallName;
+    let (dst, max) = (tf.rdi, tf.rsi as usize);
+    let mut count = 0usize;
+    for (nr, call) in SYSCALLS.iter().enumerate() {
+        let Some(call) = call else {
+            continue;
+        };
+        if count < max {
+            let mut entry = SyscallName {
+                nr: nr as u64,
+                ..Default::default()
+            };
+            let len = core::cmp::min(call.name.len(), entry.name.len());
+            entry.name[..len].copy_from_slice(&call.name.as_bytes()[..len]);
+            let bytes = unsafe {
+                core::slice::from_raw_parts(
+                    &entry as *const _ as *const u8,
+                    core::mem::size_of::<SyscallName>(),
+                )
+            };
+            let at = dst.wrapping_add((count * bytes.len()) as u64);
+            if user::copy_to(pml4, at, bytes).is_err() {
+                tf.rax = error::INVALID;
+                return 0;
+            }
+        }
+        count += 1;
     }
-    let pd = pdpte & MASK;
+    tf.rax = count as u64;
+    0
+}
 
-    let pde = unsafe { rd(pd, pd_i) };
-    if (pde & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
+ktest! {
+    fn bad_user_ranges_are_invalid() {
+        // Buffers that wrap around or run past the user half fail with INVALID up front, whatever
+        // the handler would clamp the length to: lengths near u64::MAX, pointers near the top.
+        let top = user::USER_END;
+        // Frames as (syscall, [rdi, rsi, rdx, rcx]).
+        let cases = [
+            (syscall::WRITE, [top - 8, 16, 0, 0]),
+            (syscall::WRITE, [0x1000, u64::MAX, 0, 0]),
+            (syscall::GETRANDOM, [u64::MAX - 7, 8, 0, 0]),
+            (
+                syscall::GETRANDOM,
+                [top - 4096, u64::MAX - top + 4097, 0, 0],
+            ),
+            (syscall::CAP_LIST, [0x1000, u64::MAX / 4, 0, 0]),
+            (syscall::MEMMAP, [top - 24, 2, 0, 0]),
+            (syscall::SYSCALL_INFO, [0x1000, u64::MAX, 0, 0]),
+            (syscall::IPC_SEND_MSG, [0, 0, 0x1000, u64::MAX]),
+            (syscall::IPC_SEND, [0, 0x1000, u64::MAX - 0xfff, 0]),
+            (syscall::IPC_RECV, [0, top - 1, usize::MAX as u64, 0]),
+            (syscall::FB_GET_GAMMA, [top - 8, 0, 0, 0]),
+            (syscall::FB_GET_GAMMA, [u64::MAX - 7, 0, 0, 0]),
+            (syscall::SCHED_STATS, [top - 8, 0, 0, 0]),
+            (syscall::SCHED_STATS, [u64::MAX - 7, 0, 0, 0]),
+            (syscall::PROC_INFO, [0, top - 8, 0, 0]),
+            (syscall::PROC_INFO, [0, u64::MAX - 7, 0, 0]),
+            (syscall::PROC_REGS, [0, top - 8, 0, 0]),
+            (syscall::PROC_REGS, [0, u64::MAX - 7, 0, 0]),
+        ];
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        for (n, [rdi, rsi, rdx, rcx]) in cases {
+            (tf.rax, tf.rdi, tf.rsi, tf.rdx, tf.rcx) = (n, rdi, rsi, rdx, rcx);
+            mantra_syscall80_rust(&mut tf);
+            kassert!(
+                tf.rax == error::INVALID,
+                "syscall {:#x} accepted args {:#x} {:#x} {:#x} {:#x}",
+                n,
+                rdi,
+                rsi,
+                rdx,
+                rcx
+            );
+        }
+        kassert!(
+            user::checked_user_range(top - 4096, 4096) == Some(top)
+                && user::checked_user_range(top, 0) == Some(top)
+                && user::checked_user_range(top, 1).is_none()
+                && user::checked_user_range(u64::MAX, 1).is_none()
+                && user::checked_user_range(1, u64::MAX).is_none(),
+            "checked_user_range bounds wrong"
+        );
     }
-    let pt = pde & MASK;
+}
+
+ktest! {
+    fn con_write_colors_one_write() {
+        // Draw through CON_WRITE from a scratch user buffer onto an off-screen console: "OK" in
+        // red, then "!" in the console's own color. The glyphs must land in those colors, the
+        // console's color must be unchanged afterwards, and bad colors or no console must fail.
+        use crate::fb::{Console, FrameBuffer, Rgb};
+        const W: usize = 64;
+        const H: usize = 32;
+        static mut SCRATCH: [u32; W * H] = [0; W * H];
+        let fb = FrameBuffer {
+            base: core::ptr::addr_of_mut!(SCRATCH) as *mut u8,
+            size: W * H * 4,
+            width: W,
+            height: H,
+            stride: W,
+            format: mantra_bootinfo::PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let Ok(con) = Console::new(fb) else {
+            kassert!(false, "CON_WRITE scratch console rejected");
+            return;
+        };
+        let fg = con.colors().0;
+        let red = Rgb {
+            r: 0xff,
+            g: 0,
+            b: 0,
+        };
+        let screen = crate::fb::swap_console(Some(con));
+
+        // (rdi offset into the buffer, rsi, rdx) per call, then rax.
+        let calls = [(0, 2, 0xff_0000), (2, 1, u64::MAX), (0, 1, 0x100_0000)];
+        let mut rax = [0u64; 4];
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        let mapped = user::with_scratch_bytes(b"OK!", |pml4, va| {
+            for (i, &(off, len, color)) in calls.iter().enumerate() {
+                (tf.rdi, tf.rsi, tf.rdx) = (va + off, len, color);
+                sys_con_write(&mut tf, 0, pml4);
+                rax[i] = tf.rax;
+            }
+        });
+        let con = crate::fb::swap_console(None);
+        (tf.rdi, tf.rsi, tf.rdx) = (0x1000, 1, u64::MAX);
+        sys_con_write(&mut tf, 0, 0);
+        rax[3] = tf.rax;
+        crate::fb::swap_console(screen);
 
-    let pte = unsafe { rd(pt, pt_i) };
-    if (pte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
+        if !mapped {
+            kwarn!("isr: CON_WRITE test skipped, no memory");
+            return;
+        }
+        let Some(con) = con else {
+            kassert!(false, "CON_WRITE lost the console");
+            return;
+        };
+        kassert!(
+            rax == [2, 1, error::INVALID, error::NOT_FOUND],
+            "CON_WRITE returned {:#x?}",
+            rax
+        );
+        kassert!(
+            con.shows(0, 0, b'O', red) && con.shows(1, 0, b'K', red) && con.shows(2, 0, b'!', fg),
+            "CON_WRITE glyphs or colors wrong"
+        );
+        let after = con.colors().0;
+        kassert!(
+            (after.r, after.g, after.b) == (fg.r, fg.g, fg.b),
+            "CON_WRITE left its color on the console"
+        );
     }
+}
+
+ktest! {
+    fn syscall_table_matches_mantra_sys() {
+        // The dispatch table covers exactly the numbers `mantra_sys` defines, each under its own
+        // name, and numbers outside it fail with NOT_FOUND without reaching a handler.
+        let assigned = SYSCALLS.iter().filter(|s| s.is_some()).count();
+        kassert!(
+            assigned == syscall::ALL.len(),
+            "{} syscalls dispatched, mantra_sys defines {}",
+            assigned,
+            syscall::ALL.len()
+        );
+        for &nr in syscall::ALL {
+            kassert!(
+                lookup(nr).is_some(),
+                "syscall {:#x} has no handler",
+                nr
+            );
+        }
+        kassert!(
+            lookup(syscall::YIELD_HINT).is_some_and(|s| s.name == "YIELD_HINT"),
+            "syscall table names out of step"
+        );
 
-    Some((pte & MASK) + off)
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        for n in [syscall::NR_SYSCALLS, 0, u64::MAX] {
+            tf.rax = n;
+            kassert!(
+                mantra_syscall80_rust(&mut tf) == 0 && tf.rax == error::NOT_FOUND,
+                "syscall {:#x} did not fail with NOT_FOUND",
+                n
+            );
+        }
+    }
 }
 
-fn user_copy_out_in(pml4_phys: u64, user_ptr: u64, src: &[u8]) -> Option<()> {
-    for (i, b) in src.iter().enumerate() {
-        let v = user_ptr.wrapping_add(i as u64);
-        let p = virt_to_phys_in(pml4_phys, v)?;
-        unsafe { core::ptr::write_volatile(paging::phys_to_virt_ptr::<u8>(p), *b) };
+// Boot region kinds as userspace sees them (`mantra_sys::mem_kind`).
+fn mem_kind(kind: u32) -> u64 {
+    use mantra_bootinfo::RegionKind;
+    use mantra_sys::mem_kind;
+    match kind {
+        k if k == RegionKind::Usable as u32 => mem_kind::USABLE,
+        k if k == RegionKind::Reserved as u32 => mem_kind::RESERVED,
+        k if k == RegionKind::AcpiReclaim as u32 => mem_kind::ACPI_RECLAIM,
+        k if k == RegionKind::AcpiNvs as u32 => mem_kind::ACPI_NVS,
+        k if k == RegionKind::Mmio as u32 => mem_kind::MMIO,
+        k if k == RegionKind::Kernel as u32 => mem_kind::KERNEL,
+        k if k == RegionKind::Boot as u32 => mem_kind::BOOT,
+        k if k == RegionKind::Framebuffer as u32 => mem_kind::FRAMEBUFFER,
+        _ => mem_kind::UNKNOWN,
     }
-    Some(())
 }
 
-fn deliver_ipc(pid: usize, msg: &[u8], xfer_ep: u32) -> u64 {
-    let Some(cr3) = crate::sched::proc_cr3(pid) else {
-        return u64::MAX;
+// Write entries `skip..skip + max` of the boot map followed by the free ranges to `dst`.
+fn copy_memmap(pml4: u64, dst: u64, max: usize, skip: usize) -> u64 {
+    use mantra_sys::MemMapEntry;
+    const ENTRY: usize = core::mem::size_of::<MemMapEntry>();
+
+    let mut index = 0usize;
+    let mut written = 0usize;
+    let mut fault = false;
+    let mut emit = |e: MemMapEntry| {
+        if index >= skip && written < max && !fault {
+            let bytes = unsafe { core::slice::from_raw_parts(&e as *const _ as *const u8, ENTRY) };
+            let at = dst.wrapping_add((written * ENTRY) as u64);
+            match user::copy_to(pml4, at, bytes) {
+                Ok(_) => written += 1,
+                Err(_) => fault = true,
+            }
+        }
+        index += 1;
     };
-    let Some(tf_rsp) = crate::sched::proc_tf_rsp(pid) else {
+    for r in crate::pmm::boot_regions() {
+        emit(MemMapEntry {
+            base: r.base,
+            len: r.len,
+            kind: mem_kind(r.kind),
+        });
+    }
+    crate::pmm::for_each_free_range(|base, end| {
+        emit(MemMapEntry {
+            base,
+            len: end - base,
+            kind: mantra_sys::mem_kind::FREE,
+        })
+    });
+    if fault {
+        error::INVALID
+    } else {
+        written as u64
+    }
+}
+
+// The registers saved in a stopped proc's trap frame, in `PROC_REGS` layout.
+fn saved_regs(tf: &TrapFrame) -> mantra_sys::Regs {
+    mantra_sys::Regs {
+        rax: tf.rax,
+        rbx: tf.rbx,
+        rcx: tf.rcx,
+        rdx: tf.rdx,
+        rsi: tf.rsi,
+        rdi: tf.rdi,
+        rbp: tf.rbp,
+        r8: tf.r8,
+        r9: tf.r9,
+        r10: tf.r10,
+        r11: tf.r11,
+        r12: tf.r12,
+        r13: tf.r13,
+        r14: tf.r14,
+        r15: tf.r15,
+        rip: tf.rip,
+        rsp: tf.rsp,
+        rflags: tf.rflags,
+    }
+}
+
+// Send `len` bytes at `src` in the caller's space `pml4`. A receiver already blocked on the
+// endpoint gets them copied straight from the sender's pages into its own buffer (up to its
+// size); otherwise they are bounced through the kernel into the endpoint's ring.
+fn send_user(pid: usize, pml4: u64, cap: u32, src: u64, len: usize, xfer_ep: u32) -> u64 {
+    let Some(ep_id) = crate::sched::cap_lookup(pid, cap) else {
         return u64::MAX;
     };
-    let tf = unsafe { &mut *(tf_rsp as *mut SyscallFrame) };
-    let user_ptr = tf.rsi;
-    let max_len = core::cmp::min(tf.rdx as usize, 1024usize);
-    let n = core::cmp::min(core::cmp::min(max_len, 256usize), msg.len());
-
-    if user_copy_out_in(cr3, user_ptr, &msg[..n]).is_none() {
+    if let Some(rx) = ipc::pop_receiver(ep_id) {
+        let t = crate::perf::start();
+        let sent = deliver_direct(rx, ep_id, pml4, src, len, xfer_ep);
+        crate::perf::IPC_DIRECT.record(crate::perf::stop(t));
+        match sent {
+            Ok(n) => {
+                if !error::is_err(n) {
+                    ipc::count_direct(ep_id);
+                }
+                return n;
+            }
+            Err(user::CrossFault::Src) => {
+                // Our buffer is bad; the receiver keeps waiting for someone else.
+                let _ = ipc::waiter_push(ep_id, rx);
+                return error::INVALID;
+            }
+            Err(user::CrossFault::Dst) => {
+                // Its buffer is bad (or it is not waiting here after all): fail its
+                // receive and queue the message instead.
+                if crate::sched::is_blocked_on(rx, ep_id) {
+                    crate::sched::abort_wait(rx, error::INVALID);
+                }
+            }
+        }
+    }
+    let mut tmp = [0u8; 256];
+    let n = core::cmp::min(len, tmp.len());
+    if user::copy_from(pml4, &mut tmp[..n], src).is_err() {
         return u64::MAX;
     }
+    send_ipc(pid, cap, &tmp[..n], xfer_ep, false)
+}
 
+// Only a proc still blocked receiving on `ep_id` has a frame and buffer to write to;
+// anything else is refused like a bad destination buffer.
+fn deliver_direct(
+    pid: usize,
+    ep_id: u32,
+    src_pml4: u64,
+    src: u64,
+    len: usize,
+    xfer_ep: u32,
+) -> Result<u64, user::CrossFault> {
+    if !crate::sched::is_blocked_on(pid, ep_id) {
+        return Err(user::CrossFault::Dst);
+    }
+    let (Some(cr3), Some(tf_rsp)) = (crate::sched::proc_cr3(pid), crate::sched::proc_tf_rsp(pid))
+    else {
+        return Ok(u64::MAX);
+    };
+    let tf = unsafe { &mut *(tf_rsp as *mut TrapFrame) };
+    let n = core::cmp::min(len, tf.rdx as usize);
+    user::copy_between(cr3, tf.rsi, src_pml4, src, n)?;
     tf.rax = n as u64;
     tf.rdx = 0;
     if xfer_ep != 0 {
@@ -345,80 +1313,255 @@ fn deliver_ipc(pid: usize, msg: &[u8], xfer_ep: u32) -> u64 {
         }
     }
     crate::sched::wake(pid);
-    n as u64
+    Ok(n as u64)
 }
 
-fn current_user_pml4() -> u64 {
-    let cr3: u64;
-    unsafe {
-        core::arch::asm!(
-            "mov {}, cr3",
-            out(reg) cr3,
-            options(nomem, nostack, preserves_flags)
-        )
-    };
-    cr3 & 0x000f_ffff_ffff_f000
+ktest! {
+    fn direct_delivery_counts_traffic() {
+        // The receiver is already blocked on the endpoint when each send happens: once handed
+        // a kernel buffer, once copied straight from the sender's pages. Neither message goes
+        // through the ring, but both must count as sent and received.
+        const TX: usize = 5;
+        const RX: usize = 6;
+        if !matches!(crate::sched::proc_info(TX), Some((crate::sched::ProcState::Dead, ..))) {
+            kwarn!("isr: direct delivery test skipped, pid in use");
+            return;
+        }
+        let cap = ipc::ep_create(TX, 4, 64, 0);
+        let Some(ep) = crate::sched::cap_lookup(TX, cap as u32) else {
+            kwarn!("isr: direct delivery test skipped, no endpoint");
+            return;
+        };
+        let cap = cap as u32;
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        let mut got = [[0u8; 5]; 2];
+        let mut sent = [0u64; 2];
+        let mut staged = true;
+        let mapped = user::with_scratch_bytes(b"hello", |tx_pml4, src| {
+            user::with_scratch_bytes(&[0; 5], |rx_pml4, dst| {
+                for (i, got) in got.iter_mut().enumerate() {
+                    (tf.rsi, tf.rdx) = (dst, 5);
+                    staged &= crate::sched::stage_blocked(RX, ep, rx_pml4, &raw mut tf as u64);
+                    if !staged {
+                        return;
+                    }
+                    ipc::waiter_push(ep, RX);
+                    sent[i] = match i {
+                        0 => send_ipc(TX, cap, b"hello", 0, false),
+                        _ => send_user(TX, tx_pml4, cap, src, 5, 0),
+                    };
+                    let _ = user::copy_from(rx_pml4, got, dst);
+                    crate::sched::unstage(RX);
+                }
+            });
+        });
+        let stats = ipc::ep_stats(ep);
+        ipc::ep_destroy(TX, cap);
+        crate::sched::unstage(TX);
+        if !mapped || !staged {
+            kwarn!("isr: direct delivery test skipped, no memory or pid in use");
+            return;
+        }
+        kassert!(sent == [5, 5] && got == [*b"hello"; 2], "direct sends returned {:?}", sent);
+        let counts = stats.map(|s| (s.sent, s.received, s.queued));
+        kassert!(counts == Some((2, 2, 0)), "sent, received, queued: {:?}", counts);
+    }
 }
 
-fn user_virt_to_phys(virt: u64) -> Option<u64> {
-    // Walk 4-level tables. Require U=1 at every level and leaf present.
-    const MASK: u64 = 0x000f_ffff_ffff_f000;
-    const PTE_P: u64 = 1 << 0;
-    const PTE_U: u64 = 1 << 2;
-
-    let pml4 = current_user_pml4();
-    let pml4_i = ((virt >> 39) & 0x1ff) as usize;
-    let pdpt_i = ((virt >> 30) & 0x1ff) as usize;
-    let pd_i = ((virt >> 21) & 0x1ff) as usize;
-    let pt_i = ((virt >> 12) & 0x1ff) as usize;
-    let off = virt & 0xfff;
-
-    unsafe fn rd(table: u64, idx: usize) -> u64 {
-        core::ptr::read_volatile(paging::phys_to_virt_ptr::<u64>(table).add(idx))
+ktest! {
+    fn bad_receive_buffer_queues_the_send() {
+        // A receiver blocked with an unmapped buffer: its receive fails and the message goes
+        // to the ring for the next receive instead of being dropped.
+        const TX: usize = 5;
+        const RX: usize = 6;
+        if !matches!(crate::sched::proc_info(TX), Some((crate::sched::ProcState::Dead, ..))) {
+            kwarn!("isr: bad receive buffer test skipped, pid in use");
+            return;
+        }
+        let cap = ipc::ep_create(TX, 4, 64, 0);
+        let Some(ep) = crate::sched::cap_lookup(TX, cap as u32) else {
+            kwarn!("isr: bad receive buffer test skipped, no endpoint");
+            return;
+        };
+        let cap = cap as u32;
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        let (mut sent, mut blocked, mut staged) = (0, true, false);
+        let mapped = user::with_scratch_bytes(&[0; 5], |rx_pml4, dst| {
+            (tf.rsi, tf.rdx) = (dst + 0x10_0000, 5);
+            staged = crate::sched::stage_blocked(RX, ep, rx_pml4, &raw mut tf as u64);
+            if staged {
+                ipc::waiter_push(ep, RX);
+                sent = send_ipc(TX, cap, b"hello", 0, false);
+                blocked = crate::sched::is_blocked_on(RX, ep);
+                crate::sched::unstage(RX);
+            }
+        });
+        let mut got = [0u8; 8];
+        let queued = ipc::ep_recv(TX, cap, &mut got);
+        ipc::ep_destroy(TX, cap);
+        crate::sched::unstage(TX);
+        if !mapped || !staged {
+            kwarn!("isr: bad receive buffer test skipped, no memory or pid in use");
+            return;
+        }
+        kassert!(sent == 5, "send returned {:#x}", sent);
+        kassert!(!blocked && tf.rax == error::INVALID, "receiver left with rax={:#x}", tf.rax);
+        kassert!(queued == 5 && got[..5] == *b"hello", "ring returned {:#x}", queued);
     }
+}
 
-    let pml4e = unsafe { rd(pml4, pml4_i) };
-    if (pml4e & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
-    }
-    let pdpt = pml4e & MASK;
+ktest! {
+    fn direct_delivery_bench() {
+        // Time a 1 KiB send to a receiver already blocked on the endpoint (copied straight
+        // between the address spaces) against the same send with nobody waiting (bounced
+        // into the ring, then received and copied out), and log the average cycles of each.
+        const TX: usize = 5;
+        const RX: usize = 6;
+        const MSG: usize = 1024;
+        const ROUNDS: usize = 32;
+        if !matches!(crate::sched::proc_info(TX), Some((crate::sched::ProcState::Dead, ..))) {
+            kwarn!("isr: direct delivery bench skipped, pid in use");
+            return;
+        }
+        let cap = ipc::ep_create(TX, 4, 0, 0);
+        let Some(ep) = crate::sched::cap_lookup(TX, cap as u32) else {
+            kwarn!("isr: direct delivery bench skipped, no endpoint");
+            return;
+        };
+        let cap = cap as u32;
+        let mut msg = [0u8; MSG];
+        for (i, v) in msg.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        let direct = crate::perf::Stat::new();
+        let queued = crate::perf::Stat::new();
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        let mut sent = [0u64; 2];
+        let mut got = [0u8; MSG];
+        let mut staged = true;
+        let mapped = user::with_scratch_bytes(&msg, |tx_pml4, src| {
+            user::with_scratch_bytes(&[0; MSG], |rx_pml4, dst| {
+                for _ in 0..ROUNDS {
+                    (tf.rsi, tf.rdx) = (dst, MSG as u64);
+                    staged &= crate::sched::stage_blocked(RX, ep, rx_pml4, &raw mut tf as u64);
+                    if !staged {
+                        return;
+                    }
+                    ipc::waiter_push(ep, RX);
+                    let t = crate::perf::start();
+                    sent[0] = send_user(TX, tx_pml4, cap, src, MSG, 0);
+                    direct.record(crate::perf::stop(t));
+                    crate::sched::unstage(RX);
 
-    let pdpte = unsafe { rd(pdpt, pdpt_i) };
-    if (pdpte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
+                    let t = crate::perf::start();
+                    sent[1] = send_user(TX, tx_pml4, cap, src, MSG, 0);
+                    let mut ring = [0u8; MSG];
+                    let n = ipc::ep_recv(TX, cap, &mut ring);
+                    let n = if error::is_err(n) { 0 } else { core::cmp::min(n as usize, MSG) };
+                    let _ = user::copy_to(rx_pml4, dst, &ring[..n]);
+                    queued.record(crate::perf::stop(t));
+                }
+                let _ = user::copy_from(rx_pml4, &mut got, dst);
+            });
+        });
+        ipc::ep_destroy(TX, cap);
+        crate::sched::unstage(TX);
+        if !mapped || !staged {
+            kwarn!("isr: direct delivery bench skipped, no memory or pid in use");
+            return;
+        }
+        // The ring keeps its bounded slots, so only the direct path moves the whole message.
+        kassert!(sent[0] == MSG as u64 && got == msg, "direct send returned {:#x}", sent[0]);
+        kassert!(sent[1] > 0 && !error::is_err(sent[1]), "queued send returned {:#x}", sent[1]);
+        kinfo!(
+            "isr: 1KiB send avg_cycles direct={} queued={} (queued moves {} bytes)",
+            direct.average().1,
+            queued.average().1,
+            sent[1]
+        );
     }
-    let pd = pdpte & MASK;
+}
 
-    let pde = unsafe { rd(pd, pd_i) };
-    if (pde & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
-    }
-    let pt = pde & MASK;
+// Hand `msg` straight to a receiver blocked on the endpoint, else queue it. For a `call`,
+// the receiver ends up owing `pid` a reply.
+fn send_ipc(pid: usize, cap: u32, msg: &[u8], xfer_ep: u32, call: bool) -> u64 {
+    let Some(ep_id) = crate::sched::cap_lookup(pid, cap) else {
+        return u64::MAX;
+    };
+    let t = crate::perf::start();
+    let direct = ipc::pop_receiver(ep_id).and_then(|rx| {
+        let sent = deliver_ipc(rx, msg, xfer_ep);
+        if error::is_err(sent) {
+            // Its buffer is bad: fail its receive and queue the message instead, as
+            // `send_user` does.
+            if crate::sched::is_blocked_on(rx, ep_id) {
+                crate::sched::abort_wait(rx, error::INVALID);
+            }
+            return None;
+        }
+        ipc::count_direct(ep_id);
+        if call {
+            crate::sched::set_reply_to(rx, pid);
+        }
+        Some(sent)
+    });
+    let sent = match direct {
+        Some(sent) => sent,
+        None if call => ipc::ep_send_call(pid, cap, msg),
+        None => ipc::ep_send_cap(pid, cap, msg, xfer_ep),
+    };
+    crate::perf::IPC_SEND.record(crate::perf::stop(t));
+    sent
+}
 
-    let pte = unsafe { rd(pt, pt_i) };
-    if (pte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
-        return None;
-    }
+fn deliver_ipc(pid: usize, msg: &[u8], xfer_ep: u32) -> u64 {
+    deliver(pid, msg, xfer_ep, |tf| tf.rdx)
+}
 
-    Some((pte & MASK) + off)
+/// Complete the receive `pid` is blocked in with notification message `msg`.
+pub fn deliver_notification(pid: usize, msg: &[u8]) -> u64 {
+    deliver_ipc(pid, msg, 0)
 }
 
-fn user_copy_in(dst: &mut [u8], user_ptr: u64) -> Option<()> {
-    for (i, b) in dst.iter_mut().enumerate() {
-        let v = user_ptr.wrapping_add(i as u64);
-        let p = user_virt_to_phys(v)?;
-        *b = unsafe { core::ptr::read_volatile(paging::phys_to_virt_ptr::<u8>(p)) };
+// Complete the IPC_CALL `pid` is blocked in; its reply buffer size is in rcx. The
+// transferred cap (if any) is installed only in `pid`'s table.
+fn deliver_reply(pid: usize, msg: &[u8], xfer_ep: u32) -> u64 {
+    if !crate::sched::is_blocked(pid) {
+        return error::INVALID;
     }
-    Some(())
+    deliver(pid, msg, xfer_ep, |tf| tf.rcx)
 }
 
-fn user_copy_out(user_ptr: u64, src: &[u8]) -> Option<()> {
-    for (i, b) in src.iter().enumerate() {
-        let v = user_ptr.wrapping_add(i as u64);
-        let p = user_virt_to_phys(v)?;
-        unsafe { core::ptr::write_volatile(paging::phys_to_virt_ptr::<u8>(p), *b) };
+// Copy `msg` into the buffer (rsi, `max_len(tf)` bytes) of a proc blocked in a receive or
+// call, set its return registers and wake it. This never nests: waking only marks the
+// receiver runnable, and it sends again only once scheduled, from its own syscall on its
+// own kernel stack. Syscall and IRQ gates run with interrupts off (only GETRANDOM opens a
+// preempt point), so no delivery starts while another is in progress.
+fn deliver(pid: usize, msg: &[u8], xfer_ep: u32, max_len: impl Fn(&TrapFrame) -> u64) -> u64 {
+    let Some(cr3) = crate::sched::proc_cr3(pid) else {
+        return u64::MAX;
+    };
+    let Some(tf_rsp) = crate::sched::proc_tf_rsp(pid) else {
+        return u64::MAX;
+    };
+    let tf = unsafe { &mut *(tf_rsp as *mut TrapFrame) };
+    let user_ptr = tf.rsi;
+    let max_len = core::cmp::min(max_len(tf) as usize, 1024usize);
+    let n = core::cmp::min(core::cmp::min(max_len, 256usize), msg.len());
+
+    if user::copy_to(cr3, user_ptr, &msg[..n]).is_err() {
+        return u64::MAX;
+    }
+
+    tf.rax = n as u64;
+    tf.rdx = 0;
+    if xfer_ep != 0 {
+        if let Some(new_cap) = crate::sched::cap_alloc_for(pid, xfer_ep) {
+            tf.rdx = new_cap as u64;
+        }
     }
-    Some(())
+    crate::sched::wake(pid);
+    n as u64
 }
 
 global_asm!(
@@ -493,7 +1636,7 @@ global_asm!(
 .global mantra_syscall80_stub
 .type mantra_syscall80_stub, @function
 mantra_syscall80_stub:
-    // Save GPRs. Order matches `SyscallFrame`.
+    // Save GPRs. Order matches `TrapFrame`.
     push rax
     push rbx
     push rcx
@@ -510,7 +1653,7 @@ mantra_syscall80_stub:
     push r14
     push r15
 
-    // Arg0 = &mut SyscallFrame (current RSP)
+    // Arg0 = &mut TrapFrame (current RSP)
     mov rdi, rsp
 
     // Call Rust handler on aligned stack, but keep the frame pointer.
@@ -520,7 +1663,7 @@ mantra_syscall80_stub:
     call mantra_syscall80_rust
     mov rsp, rbx
 
-    // If rax != 0, it is the next task's saved RSP (SyscallFrame/TrapFrame pointer).
+    // If rax != 0, it is the next task's saved RSP (TrapFrame pointer).
     test rax, rax
     jz 1f
     mov rsp, rax
@@ -532,3 +1675,114 @@ mantra_syscall80_stub:
 .att_syntax
 "#
 );
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global mantra_kyield_stub
+.type mantra_kyield_stub, @function
+mantra_kyield_stub:
+    // Same frame as the other stubs; the CPU pushed a ring-0 RIP/CS/RFLAGS/RSP/SS.
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rbp
+    push rdi
+    push rsi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    call mantra_kyield_rust
+    mov rsp, rbx
+
+    // If rax != 0, it is the next task's saved RSP (TrapFrame pointer).
+    test rax, rax
+    jz 1f
+    mov rsp, rax
+    mov rcx, qword ptr [rip + MANTRA_NEXT_CR3]
+    mov cr3, rcx
+1:
+    jmp mantra_trap_return
+.att_syntax
+"#
+);
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global mantra_hrtimer_stub
+.type mantra_hrtimer_stub, @function
+mantra_hrtimer_stub:
+    // Save GPRs. Order matches `TrapFrame`.
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rbp
+    push rdi
+    push rsi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    call mantra_hrtimer_rust
+    mov rsp, rbx
+
+    // If rax != 0, it is the next task's saved RSP (TrapFrame pointer).
+    test rax, rax
+    jz 1f
+    mov rsp, rax
+    mov rcx, qword ptr [rip + MANTRA_NEXT_CR3]
+    mov cr3, rcx
+1:
+    jmp mantra_trap_return
+.att_syntax
+"#
+);
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global mantra_double_fault_stub
+.type mantra_double_fault_stub, @function
+mantra_double_fault_stub:
+    // Runs on IST1. Drop the (always zero) error code so the GPRs below form a `TrapFrame`.
+    add rsp, 8
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rbp
+    push rdi
+    push rsi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    and rsp, -16
+    call mantra_double_fault_rust
+    ud2
+.att_syntax
+"#
+);
@@ -0,0 +1,99 @@
+// Local APIC timer in one-shot mode, for wakeups finer than the PIT tick. The 8259 PIC
+// still delivers the periodic tick; the LAPIC only ever raises `TIMER_VECTOR`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::msr::{rdmsr, wrmsr};
+use super::{cpuid, paging, pit};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR: u64 = 0x000f_ffff_ffff_f000;
+
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INIT: usize = 0x380;
+const REG_TIMER_CUR: usize = 0x390;
+const REG_TIMER_DIV: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_DIV_16: u32 = 0x3;
+
+pub const TIMER_VECTOR: u8 = 0x40;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Virtual address of the register page (0 = no LAPIC) and timer rate (0 = uncalibrated).
+static BASE: AtomicU64 = AtomicU64::new(0);
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read(reg: usize) -> u32 {
+    core::ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, v: u32) {
+    core::ptr::write_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *mut u32, v);
+}
+
+// Needs paging (the register page goes in the KMAP window).
+pub fn init() {
+    if !cpuid::has_apic() {
+        kinfo!("lapic: not present, sleeps use the tick");
+        return;
+    }
+    unsafe {
+        let base = rdmsr(IA32_APIC_BASE);
+        wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        let virt = paging::kmap_mmio(base & APIC_BASE_ADDR, 4096);
+        if virt == 0 {
+            kwarn!("lapic: cannot map registers");
+            return;
+        }
+        BASE.store(virt, Ordering::Relaxed);
+
+        write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        write(REG_TIMER_DIV, TIMER_DIV_16);
+        write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+
+        // Let the counter run down from the top across a PIT-timed 10 ms window.
+        write(REG_TIMER_INIT, u32::MAX);
+        let elapsed = pit::measure_10ms(|| (u32::MAX - read(REG_TIMER_CUR)) as u64);
+        write(REG_TIMER_INIT, 0);
+        if elapsed == 0 {
+            kwarn!("lapic: timer calibration failed");
+            return;
+        }
+        TICKS_PER_MS.store(elapsed / 10, Ordering::Relaxed);
+
+        // One-shot, unmasked; nothing fires until `one_shot_ns` loads a count.
+        write(REG_LVT_TIMER, TIMER_VECTOR as u32);
+    }
+    kinfo!(
+        "lapic: timer {} ticks/ms",
+        TICKS_PER_MS.load(Ordering::Relaxed)
+    );
+}
+
+/// True once the one-shot timer is mapped and calibrated.
+pub fn timer_available() -> bool {
+    TICKS_PER_MS.load(Ordering::Relaxed) != 0
+}
+
+/// Fire `TIMER_VECTOR` once, `ns` from now (rounded up to a timer tick). Re-arming
+/// replaces any pending shot.
+pub fn one_shot_ns(ns: u64) {
+    let per_ms = TICKS_PER_MS.load(Ordering::Relaxed);
+    if per_ms == 0 {
+        return;
+    }
+    let count = ((ns as u128) * (per_ms as u128)).div_ceil(1_000_000);
+    let count = count.clamp(1, u32::MAX as u128) as u32;
+    unsafe { write(REG_TIMER_INIT, count) };
+}
+
+pub fn eoi() {
+    if BASE.load(Ordering::Relaxed) != 0 {
+        unsafe { write(REG_EOI, 0) };
+    }
+}
@@ -1,20 +1,50 @@
+pub mod cpuid;
+pub mod debugcon;
+mod fpu;
 pub mod gdt;
-mod idt;
+pub mod idt;
+pub mod interrupts;
 pub mod isr;
+pub mod lapic;
+pub mod msr;
 pub mod paging;
-mod pic;
-mod pit;
-mod port;
+pub mod pic;
+pub mod pit;
+pub mod port;
+pub mod smap;
 
 pub fn init() {
+    cpuid::log_features();
+    fpu::init();
+    smap::init();
     gdt::init();
     idt::init();
     pic::init();
-    pit::init(100); // 100 Hz
+    let divisor = pit::init(crate::timer::HZ);
+    crate::timer::set_divisor(divisor);
+    crate::serial::write_str("mantracore: pit divisor=");
+    crate::serial::write_dec_u64(divisor as u64);
+    crate::serial::write_str(" hz=");
+    crate::serial::write_dec_u64(crate::timer::hz() as u64);
+    crate::serial::write_str("\n");
+
+    if cpuid::has_tsc() {
+        let khz = pit::calibrate_tsc_khz();
+        crate::timer::set_tsc_khz(khz);
+        crate::serial::write_str("mantracore: tsc khz=");
+        crate::serial::write_dec_u64(khz);
+        crate::serial::write_str("\n");
+    }
 }
 
-pub fn enable_interrupts() {
-    idt::enable_interrupts();
+// Raw time-stamp counter.
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    ((hi as u64) << 32) | lo as u64
 }
 
 pub fn init_paging(max_phys_addr_inclusive: u64) {
@@ -0,0 +1,19 @@
+// Model-specific register access. Callers must check CPUID first: touching an MSR the CPU
+// doesn't implement raises #GP.
+
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, v: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") v as u32,
+        in("edx") (v >> 32) as u32,
+        options(nostack, preserves_flags)
+    );
+}
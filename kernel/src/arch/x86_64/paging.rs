@@ -1,6 +1,8 @@
 use crate::pmm;
 use crate::serial;
+use crate::util::{align_down, align_up_checked};
 use core::sync::atomic::{AtomicU64, Ordering};
+use mantra_bootinfo::KERNEL_VIRT_OFFSET;
 
 const PAGE_SIZE: u64 = 4096;
 const HUGE_2M: u64 = 2 * 1024 * 1024;
@@ -10,10 +12,17 @@ const GIB: u64 = 1024 * 1024 * 1024;
 // PML4 index 256 corresponds to 0xffff_8000_0000_0000..0xffff_ffff_ffff_ffff.
 pub const HHDM_BASE: u64 = 0xffff_8000_0000_0000;
 pub const KMAP_BASE: u64 = 0xffff_ff00_0000_0000;
-const KMAP_PML4_INDEX: usize = 510;
+pub const HHDM_PML4_INDEX: usize = 256;
+pub const KMAP_PML4_INDEX: usize = 510;
+// Kernel image: linked at KERNEL_VIRT_OFFSET + phys (top 2 GiB).
+pub const KERNEL_PML4_INDEX: usize = ((KERNEL_VIRT_OFFSET >> 39) & 0x1ff) as usize;
+// One PML4 entry spans 512 GiB.
+const PML4_SPAN: u64 = 512 * GIB;
 
 const PTE_P: u64 = 1 << 0;
 const PTE_RW: u64 = 1 << 1;
+const PTE_PWT: u64 = 1 << 3;
+const PTE_PCD: u64 = 1 << 4;
 const PTE_PS: u64 = 1 << 7;
 
 #[repr(C, align(4096))]
@@ -23,29 +32,28 @@ struct PageTable {
 
 static PML4_PHYS: AtomicU64 = AtomicU64::new(0);
 static KMAP_NEXT: AtomicU64 = AtomicU64::new(KMAP_BASE);
+// Single KMAP pages released by `kmap_free_4k`, reused before bumping KMAP_NEXT.
+const KMAP_FREE_LEN: usize = 64;
+static mut KMAP_FREE: [u64; KMAP_FREE_LEN] = [0; KMAP_FREE_LEN];
+static mut KMAP_FREE_N: usize = 0;
+// Exclusive end of the physical range covered by the HHDM (0 until paging is up).
+static HHDM_END: AtomicU64 = AtomicU64::new(0);
 
-fn align_up(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    (x + (a - 1)) & !(a - 1)
-}
-
-fn align_down(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    x & !(a - 1)
+unsafe fn zero_page(p: u64) {
+    // Before `init` only the firmware identity map exists; after it, the current CR3 may be
+    // a user address space with no identity map at all.
+    let v = phys_to_virt_checked(p).unwrap_or(p);
+    core::ptr::write_bytes(v as *mut u8, 0, PAGE_SIZE as usize);
 }
 
-unsafe fn zero_page(p: u64) {
-    core::ptr::write_bytes(p as *mut u8, 0, PAGE_SIZE as usize);
+unsafe fn try_alloc_table() -> Option<u64> {
+    let p = pmm::alloc_frame()?;
+    zero_page(p);
+    Some(p)
 }
 
 unsafe fn alloc_table() -> u64 {
-    let p = pmm::alloc_pages(1).expect("paging: alloc_pages failed");
-    zero_page(p);
-    p
+    try_alloc_table().unwrap_or_else(|| bug!("paging: out of frames for page tables"))
 }
 
 unsafe fn load_cr3(pml4_phys: u64) {
@@ -56,11 +64,29 @@ unsafe fn load_cr3(pml4_phys: u64) {
     );
 }
 
+// Unchecked fast path. Debug builds catch addresses past the mapped HHDM here rather
+// than as a page fault on a confusing CR2 later.
 #[inline]
 pub fn phys_to_virt(phys: u64) -> u64 {
+    debug_assert!(
+        {
+            let end = HHDM_END.load(Ordering::Relaxed);
+            end == 0 || phys < end
+        },
+        "phys_to_virt: address beyond HHDM"
+    );
     HHDM_BASE.wrapping_add(phys)
 }
 
+/// HHDM address of `phys`, or None if the HHDM doesn't map it (or paging isn't up yet).
+pub fn phys_to_virt_checked(phys: u64) -> Option<u64> {
+    if phys < HHDM_END.load(Ordering::Acquire) {
+        Some(HHDM_BASE + phys)
+    } else {
+        None
+    }
+}
+
 #[inline]
 pub fn phys_to_virt_ptr<T>(phys: u64) -> *mut T {
     phys_to_virt(phys) as *mut T
@@ -70,6 +96,85 @@ pub fn pml4_phys() -> u64 {
     PML4_PHYS.load(Ordering::Acquire)
 }
 
+/// True if `[phys, phys + len)` is reachable through the HHDM.
+pub fn hhdm_covers(phys: u64, len: u64) -> bool {
+    let (base, end) = hhdm_range();
+    match phys.checked_add(len) {
+        Some(e) => e <= end - base,
+        None => false,
+    }
+}
+
+/// HHDM virtual range `[base, end)` currently backed by mappings (empty before paging init).
+pub fn hhdm_range() -> (u64, u64) {
+    (HHDM_BASE, HHDM_BASE + HHDM_END.load(Ordering::Acquire))
+}
+
+/// KMAP window `[base, end)`: the whole PML4 entry reserved for explicit kernel mappings.
+pub fn kmap_range() -> (u64, u64) {
+    (KMAP_BASE, KMAP_BASE + PML4_SPAN)
+}
+
+pub fn is_hhdm_addr(v: u64) -> bool {
+    let (base, end) = hhdm_range();
+    v >= base && v < end
+}
+
+pub fn is_kmap_addr(v: u64) -> bool {
+    let (base, end) = kmap_range();
+    v >= base && v < end
+}
+
+/// True for any higher-half (kernel) address; user pointers must never be one.
+pub fn is_kernel_addr(v: u64) -> bool {
+    v >= HHDM_BASE
+}
+
+/// True if `virt` is mapped in the current address space. Walks the live tables through
+/// the HHDM, so fault handlers can vet a suspect pointer before dereferencing it.
+pub fn is_mapped(virt: u64) -> bool {
+    const MASK: u64 = 0x000f_ffff_ffff_f000;
+    let cr3: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags))
+    };
+    let mut table = cr3 & MASK;
+    for shift in [39u64, 30, 21, 12] {
+        let Some(t) = phys_to_virt_checked(table) else {
+            return false;
+        };
+        let idx = ((virt >> shift) & 0x1ff) as usize;
+        let e = unsafe { core::ptr::read_volatile((t as *const u64).add(idx)) };
+        if (e & PTE_P) == 0 {
+            return false;
+        }
+        // PS in a PDPTE/PDE marks a 1 GiB/2 MiB leaf.
+        if shift == 12 || (shift != 39 && (e & PTE_PS) != 0) {
+            return true;
+        }
+        table = e & MASK;
+    }
+    false
+}
+
+/// Raw PML4 entry backing the KMAP window, so other address spaces can share it.
+pub fn kmap_pml4_entry() -> u64 {
+    kernel_pml4_entry_at(KMAP_PML4_INDEX)
+}
+
+/// Raw PML4 entry mapping the kernel image, shared by every address space.
+pub fn kernel_pml4_entry() -> u64 {
+    kernel_pml4_entry_at(KERNEL_PML4_INDEX)
+}
+
+fn kernel_pml4_entry_at(idx: usize) -> u64 {
+    let pml4 = pml4_phys();
+    if pml4 == 0 {
+        return 0;
+    }
+    unsafe { core::ptr::read_volatile(table_entry_mut(pml4, idx)) }
+}
+
 unsafe fn invlpg(addr: u64) {
     core::arch::asm!("invlpg [{}]", in(reg) addr, options(nomem, nostack, preserves_flags));
 }
@@ -78,76 +183,250 @@ unsafe fn table_entry_mut(table_phys: u64, idx: usize) -> *mut u64 {
     phys_to_virt_ptr::<u64>(table_phys).add(idx)
 }
 
-unsafe fn get_or_alloc_table(entry: *mut u64) -> u64 {
+unsafe fn get_or_alloc_table(entry: *mut u64) -> Option<u64> {
     let v = core::ptr::read_volatile(entry);
     if (v & PTE_P) != 0 {
-        return v & 0x000f_ffff_ffff_f000;
+        return Some(v & 0x000f_ffff_ffff_f000);
     }
-    let t = alloc_table();
+    let t = try_alloc_table()?;
     core::ptr::write_volatile(entry, t | (PTE_P | PTE_RW));
-    t
+    Some(t)
+}
+
+// Walk (allocating as needed) to the KMAP PTE for `virt`. None if `virt` is outside the
+// window or a table frame couldn't be allocated.
+unsafe fn kmap_pte(virt: u64, alloc: bool) -> Option<*mut u64> {
+    if !is_kmap_addr(virt) {
+        return None;
+    }
+    let pml4 = pml4_phys();
+    if pml4 == 0 {
+        serial::write_str("kmap: paging not initialized\n");
+        return None;
+    }
+
+    let pdpt_i = ((virt >> 30) & 0x1ff) as usize;
+    let pde_i = ((virt >> 21) & 0x1ff) as usize;
+    let pte_i = ((virt >> 12) & 0x1ff) as usize;
+
+    let mut table = pml4;
+    for idx in [KMAP_PML4_INDEX, pdpt_i, pde_i] {
+        let e = table_entry_mut(table, idx);
+        table = if alloc {
+            get_or_alloc_table(e)?
+        } else {
+            let v = core::ptr::read_volatile(e);
+            if (v & PTE_P) == 0 {
+                return None;
+            }
+            v & 0x000f_ffff_ffff_f000
+        };
+    }
+    Some(table_entry_mut(table, pte_i))
 }
 
-// Create a 4 KiB mapping in the dedicated KMAP region.
-pub fn kmap_map_4k(virt: u64, phys: u64, flags: u64) {
+// Create a 4 KiB mapping in the dedicated KMAP region. Returns false if `virt` is outside
+// the window or the PMM can't supply a page-table frame.
+pub fn kmap_map_4k(virt: u64, phys: u64, flags: u64) -> bool {
     let virt = align_down(virt, PAGE_SIZE);
     let phys = align_down(phys, PAGE_SIZE);
 
     unsafe {
-        let pml4 = pml4_phys();
-        if pml4 == 0 {
-            serial::write_str("kmap: paging not initialized\n");
-            return;
+        let Some(pte) = kmap_pte(virt, true) else {
+            return false;
+        };
+        core::ptr::write_volatile(pte, phys | (PTE_P | PTE_RW) | flags);
+        invlpg(virt);
+    }
+    true
+}
+
+// Reserve `span` bytes of fresh KMAP VA, or None once the window is exhausted.
+fn kmap_reserve(span: u64) -> Option<u64> {
+    let (_, kmap_end) = kmap_range();
+    KMAP_NEXT
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            next.checked_add(span).filter(|&e| e <= kmap_end)
+        })
+        .ok()
+}
+
+/// Map one page into KMAP. Returns the virtual address, or 0 if the window or the PMM
+/// is exhausted. Only the ktests map single pages so far.
+#[cfg(feature = "ktest")]
+pub fn kmap_alloc_4k(phys: u64) -> u64 {
+    let reused = super::interrupts::without_interrupts(|| unsafe {
+        if KMAP_FREE_N > 0 {
+            KMAP_FREE_N -= 1;
+            Some(KMAP_FREE[KMAP_FREE_N])
+        } else {
+            None
         }
+    });
+    let Some(virt) = reused.or_else(|| kmap_reserve(PAGE_SIZE)) else {
+        serial::write_str("kmap: window exhausted\n");
+        return 0;
+    };
+    if !kmap_map_4k(virt, phys, 0) {
+        kmap_release_va(virt);
+        return 0;
+    }
+    virt
+}
 
-        let pml4e = table_entry_mut(pml4, KMAP_PML4_INDEX);
-        let pdpt = get_or_alloc_table(pml4e);
+fn kmap_release_va(virt: u64) {
+    super::interrupts::without_interrupts(|| unsafe {
+        // If the free list is full the VA is simply leaked; the window is 512 GiB.
+        if KMAP_FREE_N < KMAP_FREE_LEN {
+            KMAP_FREE[KMAP_FREE_N] = virt;
+            KMAP_FREE_N += 1;
+        }
+    });
+}
 
-        let pdpt_i = ((virt >> 30) & 0x1ff) as usize;
-        let pde_i = ((virt >> 21) & 0x1ff) as usize;
-        let pte_i = ((virt >> 12) & 0x1ff) as usize;
+/// Unmap a page obtained from `kmap_alloc_4k` so its VA can be reused.
+/// The physical frame is not freed; it belongs to the caller.
+pub fn kmap_free_4k(virt: u64) {
+    let virt = align_down(virt, PAGE_SIZE);
+    unsafe {
+        let Some(pte) = kmap_pte(virt, false) else {
+            return;
+        };
+        if (core::ptr::read_volatile(pte) & PTE_P) == 0 {
+            return;
+        }
+        core::ptr::write_volatile(pte, 0);
+        invlpg(virt);
+    }
+    kmap_release_va(virt);
+}
 
-        let pdpte = table_entry_mut(pdpt, pdpt_i);
-        let pd = get_or_alloc_table(pdpte);
+// Map a physical MMIO range (uncached) into the KMAP window. Returns the virtual
+// address corresponding to `phys` (sub-page offset preserved), or 0 on failure.
+pub fn kmap_mmio(phys: u64, size: u64) -> u64 {
+    if size == 0 || pml4_phys() == 0 {
+        return 0;
+    }
+    let Some(end) = phys.checked_add(size) else {
+        return 0;
+    };
+    let p0 = align_down(phys, PAGE_SIZE);
+    let Some(p1) = align_up_checked(end, PAGE_SIZE) else {
+        return 0;
+    };
+    let span = p1 - p0;
 
-        let pde = table_entry_mut(pd, pde_i);
-        let pt = get_or_alloc_table(pde);
+    let Some(virt) = kmap_reserve(span) else {
+        serial::write_str("kmap: window exhausted\n");
+        return 0;
+    };
+    let mut off = 0;
+    while off < span {
+        if !kmap_map_4k(virt + off, p0 + off, PTE_PCD | PTE_PWT) {
+            // Undo the pages mapped so far and hand the whole span back to the free list.
+            for page in (0..span).step_by(PAGE_SIZE as usize) {
+                if page < off {
+                    kmap_free_4k(virt + page);
+                } else {
+                    kmap_release_va(virt + page);
+                }
+            }
+            return 0;
+        }
+        off += PAGE_SIZE;
+    }
+    virt + (phys - p0)
+}
 
-        let pte = table_entry_mut(pt, pte_i);
-        core::ptr::write_volatile(pte, phys | (PTE_P | PTE_RW) | flags);
+/// Kernel address of a framebuffer at physical `phys`: its HHDM alias when the HHDM
+/// covers all of it, else a fresh uncached KMAP mapping (`kmap_mmio`). 0 on failure.
+pub fn map_framebuffer(phys: u64, size: u64) -> u64 {
+    if hhdm_covers(phys, size) {
+        phys_to_virt(phys)
+    } else {
+        kmap_mmio(phys, size)
+    }
+}
 
-        invlpg(virt);
+ktest! {
+    fn high_framebuffer_goes_through_kmap() {
+        // A framebuffer starting just below the end of the HHDM and running past it.
+        let (base, end) = hhdm_range();
+        let high = end - base - PAGE_SIZE;
+        kassert!(map_framebuffer(0x1000, PAGE_SIZE) == phys_to_virt(0x1000));
+        let v = map_framebuffer(high, 2 * PAGE_SIZE);
+        kassert!(is_kmap_addr(v) && is_kmap_addr(v + PAGE_SIZE), "fb mapped at {:#x}", v);
+        kmap_free_4k(v);
+        kmap_free_4k(v + PAGE_SIZE);
+
+        // With a RAM frame standing in for the framebuffer, pixels written through the KMAP
+        // alias must land in the frame, at the same offset into the page.
+        let Some(p) = pmm::alloc_frame() else {
+            kwarn!("paging: no frame for the high framebuffer test");
+            return;
+        };
+        let v = kmap_mmio(p + 0x10, 0x20);
+        kassert!(is_kmap_addr(v) && v % PAGE_SIZE == 0x10, "mmio mapped at {:#x}", v);
+        let seen = unsafe {
+            core::ptr::write_volatile(v as *mut u32, 0x00ff_8040);
+            core::ptr::read_volatile(phys_to_virt_ptr::<u32>(p + 0x10))
+        };
+        kmap_free_4k(v);
+        pmm::free_frame(p);
+        kassert!(seen == 0x00ff_8040, "frame holds {:#x}", seen);
     }
 }
 
-pub fn kmap_alloc_4k(phys: u64) -> u64 {
-    let virt = KMAP_NEXT.fetch_add(PAGE_SIZE, Ordering::Relaxed);
-    kmap_map_4k(virt, phys, 0);
-    virt
+// The HHDM may use every PML4 slot from `HHDM_PML4_INDEX` up to the KMAP window.
+const HHDM_MAX_PML4: usize = KMAP_PML4_INDEX - HHDM_PML4_INDEX;
+
+/// 1 GiB chunks and PML4 entries the HHDM needs to cover physical `[0, max_inclusive]`,
+/// capped at what fits below the KMAP window (~127 TiB).
+pub fn hhdm_geometry(max_phys_addr_inclusive: u64) -> (usize, usize) {
+    let max_end = align_up_checked(max_phys_addr_inclusive.saturating_add(1), GIB)
+        .unwrap_or(align_down(u64::MAX, GIB));
+    let chunks = (max_end / GIB).min((HHDM_MAX_PML4 * 512) as u64) as usize;
+    (chunks, chunks.div_ceil(512))
 }
 
 pub fn init(max_phys_addr_inclusive: u64) {
-    // Identity map [0, max_phys_end) with 2 MiB huge pages.
-    let max_end = align_up(max_phys_addr_inclusive.saturating_add(1), GIB);
-    let pdpt_entries = ((max_end + (GIB - 1)) / GIB).min(512) as usize;
-
-    if pdpt_entries == 0 {
+    // Direct-map [0, max_phys_end) with 2 MiB huge pages, one PDPT per 512 GiB.
+    let (chunks, pml4_entries) = hhdm_geometry(max_phys_addr_inclusive);
+    if chunks == 0 {
         serial::write_str("paging: max_end too small\n");
         return;
     }
+    if (chunks as u64) * GIB <= max_phys_addr_inclusive {
+        kwarn!(
+            "paging: RAM up to {:#x} exceeds the HHDM, mapping the first {} GiB",
+            max_phys_addr_inclusive,
+            chunks
+        );
+    }
 
     unsafe {
         let pml4 = alloc_table();
-        let pdpt = alloc_table();
-
-        // PML4[0] -> PDPT
-        *(pml4 as *mut u64).add(0) = pdpt | (PTE_P | PTE_RW);
-        // PML4[256] -> same PDPT (HHDM)
-        *(pml4 as *mut u64).add(256) = pdpt | (PTE_P | PTE_RW);
-
-        for i in 0..pdpt_entries {
+        // PML4[511] -> kernel image, sharing the PD for physical [0, 1 GiB).
+        let kpdpt = alloc_table();
+        *(pml4 as *mut u64).add(KERNEL_PML4_INDEX) = kpdpt | (PTE_P | PTE_RW);
+
+        let mut pdpt = 0;
+        for i in 0..chunks {
+            if i % 512 == 0 {
+                // PML4[256 + n] -> PDPT for physical [n * 512 GiB, (n + 1) * 512 GiB).
+                pdpt = alloc_table();
+                *(pml4 as *mut u64).add(HHDM_PML4_INDEX + i / 512) = pdpt | (PTE_P | PTE_RW);
+                if i == 0 {
+                    // PML4[0] -> identity map of the first 512 GiB (same PDPT).
+                    *(pml4 as *mut u64).add(0) = pdpt | (PTE_P | PTE_RW);
+                }
+            }
             let pd = alloc_table();
-            *(pdpt as *mut u64).add(i) = pd | (PTE_P | PTE_RW);
+            *(pdpt as *mut u64).add(i % 512) = pd | (PTE_P | PTE_RW);
+            if i == 0 {
+                let kpdpt_i = ((KERNEL_VIRT_OFFSET >> 30) & 0x1ff) as usize;
+                *(kpdpt as *mut u64).add(kpdpt_i) = pd | (PTE_P | PTE_RW);
+            }
 
             // Fill PD with 2MiB entries mapping this 1GiB chunk.
             let chunk_base = (i as u64) * GIB;
@@ -158,34 +437,132 @@ pub fn init(max_phys_addr_inclusive: u64) {
         }
 
         serial::write_str("paging: loading new cr3, identity map up to ");
-        serial::write_dec_u64(max_end / GIB);
-        serial::write_str("GiB (HHDM enabled)\n");
+        serial::write_dec_u64(chunks as u64);
+        serial::write_str("GiB (HHDM enabled, ");
+        serial::write_dec_u64(pml4_entries as u64);
+        serial::write_str(" PML4 entries)\n");
 
         load_cr3(pml4);
         PML4_PHYS.store(pml4, Ordering::Release);
+        HHDM_END.store((chunks as u64) * GIB, Ordering::Release);
         serial::write_str("paging: enabled\n");
     }
 }
 
-pub fn kmap_smoke_test() {
-    let Some(p) = pmm::alloc_frame() else {
-        serial::write_str("kmap: alloc_frame failed\n");
+/// Clear `PML4[0]`, the low identity map `init` keeps for the switch-over, from the kernel
+/// address space. Only call it once nothing touches low addresses: the boot stack, boot
+/// info and memory map all live there. The PDPT behind it is the HHDM's first one, so the
+/// entry goes but no table is freed.
+pub fn drop_identity_map() {
+    let pml4 = pml4_phys();
+    if pml4 == 0 {
         return;
-    };
+    }
+    let cr3: u64;
+    unsafe {
+        core::ptr::write_volatile(table_entry_mut(pml4, 0), 0);
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        // Flush the low translations if the kernel address space is the live one.
+        if (cr3 & 0x000f_ffff_ffff_f000) == pml4 {
+            load_cr3(pml4);
+        }
+    }
+    serial::write_str("paging: identity map dropped\n");
+}
+
+/// After `drop_identity_map`, with the kernel PML4 live: `low`, an address that was identity
+/// mapped, must no longer translate (an access would fault) while its HHDM alias still does.
+pub fn identity_drop_self_test(low: u64) {
+    if !hhdm_covers(low, 1) {
+        kdebug!(
+            "paging: {:#x} not below the HHDM end, identity drop self-test skipped",
+            low
+        );
+        return;
+    }
+    kassert!(
+        kernel_pml4_entry_at(0) == 0 && !is_mapped(low),
+        "paging: {:#x} still mapped after dropping the identity map",
+        low
+    );
+    kassert!(
+        is_mapped(phys_to_virt(low)),
+        "paging: HHDM alias of {:#x} lost with the identity map",
+        low
+    );
+    kdebug!("paging: identity drop self-test ok ({:#x} unmapped)", low);
+}
 
-    let v = kmap_alloc_4k(p);
-    serial::write_str("kmap: mapped p=");
-    serial::write_hex_u64(p);
-    serial::write_str(" v=");
-    serial::write_hex_u64(v);
-    serial::write_str("\n");
+/// Raw PML4 entries `(index, entry)` of the HHDM, for sharing into other address spaces.
+pub fn hhdm_pml4_entries() -> impl Iterator<Item = (usize, u64)> {
+    let n = HHDM_END.load(Ordering::Acquire).div_ceil(PML4_SPAN) as usize;
+    (HHDM_PML4_INDEX..HHDM_PML4_INDEX + n).map(|i| (i, kernel_pml4_entry_at(i)))
+}
 
-    unsafe {
-        let ptr = v as *mut u64;
-        core::ptr::write_volatile(ptr, 0x1122_3344_5566_7788);
-        let r = core::ptr::read_volatile(ptr);
-        serial::write_str("kmap: readback=");
-        serial::write_hex_u64(r);
-        serial::write_str("\n");
+ktest! {
+    fn hhdm_covers_large_and_real_maps() {
+        // Size the HHDM for a simulated machine with RAM up to 700 GiB (past one PML4 entry),
+        // then check the top frame of the real memory map is mapped and readable.
+        use mantra_bootinfo::{MemoryRegion, RegionKind};
+        let region = |base: u64, len: u64, kind: RegionKind| MemoryRegion {
+            base,
+            len,
+            kind: kind as u32,
+            attr: 0,
+        };
+        let high = [
+            region(0x10_0000, 2 * GIB, RegionKind::Usable),
+            region(4 * GIB, 696 * GIB, RegionKind::Usable),
+            region(1024 * GIB, GIB, RegionKind::Mmio),
+        ];
+        let end = pmm::direct_map_end(&high);
+        let (chunks, pml4_entries) = hhdm_geometry(end - 1);
+        kassert!(
+            end == 700 * GIB && (chunks as u64) * GIB >= end && pml4_entries == 2,
+            "700 GiB map sized to {} GiB in {} PML4 entries",
+            chunks,
+            pml4_entries
+        );
+
+        let regions = pmm::boot_regions();
+        if regions.is_empty() {
+            kwarn!("paging: no boot memory map, top frame test skipped");
+            return;
+        }
+        let top = align_down(pmm::direct_map_end(regions), PAGE_SIZE) - PAGE_SIZE;
+        kassert!(
+            hhdm_covers(top, PAGE_SIZE) && is_mapped(phys_to_virt(top)),
+            "top RAM frame {:#x} not in the HHDM",
+            top
+        );
+        unsafe { core::ptr::read_volatile(phys_to_virt_ptr::<u64>(top)) };
+    }
+}
+
+ktest! {
+    fn kmap_reuses_freed_pages() {
+        let Some(p) = pmm::alloc_frame() else {
+            kwarn!("paging: no frame for the kmap test");
+            return;
+        };
+        let v = kmap_alloc_4k(p);
+        kassert!(is_kmap_addr(v), "alloc_4k returned {:#x}", v);
+        let seen = unsafe {
+            core::ptr::write_volatile(v as *mut u64, 0x1122_3344_5566_7788);
+            core::ptr::read_volatile(phys_to_virt_ptr::<u64>(p))
+        };
+        kassert!(seen == 0x1122_3344_5566_7788, "frame holds {:#x}", seen);
+
+        // Freed VAs must be reused: cycling pages shouldn't grow the window.
+        kmap_free_4k(v);
+        let next = KMAP_NEXT.load(Ordering::Relaxed);
+        for _ in 0..2 * KMAP_FREE_LEN {
+            let v = kmap_alloc_4k(p);
+            kassert!(v != 0, "alloc_4k failed");
+            kmap_free_4k(v);
+        }
+        let grown = KMAP_NEXT.load(Ordering::Relaxed) - next;
+        pmm::free_frame(p);
+        kassert!(grown == 0, "window grew by {:#x} across alloc/free", grown);
     }
 }
@@ -1,53 +1,155 @@
-use super::port;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::port::{self, Port};
 
 const PIC1: u16 = 0x20;
 const PIC2: u16 = 0xA0;
-const PIC1_CMD: u16 = PIC1;
-const PIC1_DATA: u16 = PIC1 + 1;
-const PIC2_CMD: u16 = PIC2;
-const PIC2_DATA: u16 = PIC2 + 1;
+const PIC1_CMD: Port<u8> = Port::new(PIC1);
+const PIC1_DATA: Port<u8> = Port::new(PIC1 + 1);
+const PIC2_CMD: Port<u8> = Port::new(PIC2);
+const PIC2_DATA: Port<u8> = Port::new(PIC2 + 1);
 
 const ICW1_INIT: u8 = 0x10;
 const ICW1_ICW4: u8 = 0x01;
 const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+// Spurious IRQ7/IRQ15s seen (and not acknowledged) since boot.
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Chip {
+    Master,
+    Slave,
+}
+
+impl Chip {
+    fn cmd(self) -> Port<u8> {
+        match self {
+            Chip::Master => PIC1_CMD,
+            Chip::Slave => PIC2_CMD,
+        }
+    }
+}
 
 pub fn init() {
     unsafe {
         // Start init sequence.
-        port::outb(PIC1_CMD, ICW1_INIT | ICW1_ICW4);
+        PIC1_CMD.write(ICW1_INIT | ICW1_ICW4);
         port::io_wait();
-        port::outb(PIC2_CMD, ICW1_INIT | ICW1_ICW4);
+        PIC2_CMD.write(ICW1_INIT | ICW1_ICW4);
         port::io_wait();
 
         // Remap offsets: master 0x20, slave 0x28.
-        port::outb(PIC1_DATA, 0x20);
+        PIC1_DATA.write(0x20);
         port::io_wait();
-        port::outb(PIC2_DATA, 0x28);
+        PIC2_DATA.write(0x28);
         port::io_wait();
 
         // Tell Master PIC about Slave at IRQ2, and tell Slave its cascade identity.
-        port::outb(PIC1_DATA, 0x04);
+        PIC1_DATA.write(0x04);
         port::io_wait();
-        port::outb(PIC2_DATA, 0x02);
+        PIC2_DATA.write(0x02);
         port::io_wait();
 
         // 8086 mode.
-        port::outb(PIC1_DATA, ICW4_8086);
+        PIC1_DATA.write(ICW4_8086);
         port::io_wait();
-        port::outb(PIC2_DATA, ICW4_8086);
+        PIC2_DATA.write(ICW4_8086);
         port::io_wait();
 
         // Mask everything except IRQ0 (timer) and IRQ2 (cascade).
-        port::outb(PIC1_DATA, 0b1111_1010);
-        port::outb(PIC2_DATA, 0b1111_1111);
+        PIC1_DATA.write(0b1111_1010);
+        PIC2_DATA.write(0b1111_1111);
     }
 }
 
-pub fn eoi(irq: u8) {
+// In-service register of `chip`: the IRQs it has delivered and not yet seen an EOI for.
+fn read_isr(chip: Chip) -> u8 {
     unsafe {
-        if irq >= 8 {
-            port::outb(PIC2_CMD, 0x20);
+        chip.cmd().write(OCW3_READ_ISR);
+        chip.cmd().read()
+    }
+}
+
+// Acknowledge `irq`, reading in-service registers through `isr` and sending EOIs through
+// `send`. A chip that loses an IRQ between raising INTR and the CPU's acknowledge reports
+// its lowest-priority line (7) with the in-service bit clear; that spurious IRQ must not be
+// EOI'd, or the EOI retires some other, real interrupt. A spurious IRQ15 still reached
+// the master through the cascade line, so the master alone is acknowledged.
+// Returns false if `irq` was spurious.
+fn ack(irq: u8, isr: impl Fn(Chip) -> u8, mut send: impl FnMut(Chip)) -> bool {
+    let (chip, line) = if irq >= 8 {
+        (Chip::Slave, irq - 8)
+    } else {
+        (Chip::Master, irq)
+    };
+    let spurious = line == 7 && isr(chip) & (1 << 7) == 0;
+    if chip == Chip::Slave && !spurious {
+        send(Chip::Slave);
+    }
+    if !(spurious && chip == Chip::Master) {
+        send(Chip::Master);
+    }
+    !spurious
+}
+
+/// Acknowledge `irq` at the end of its handler. Spurious IRQ7/IRQ15s are detected and
+/// dropped silently (only counted).
+pub fn eoi(irq: u8) {
+    if !ack(irq, read_isr, |chip| unsafe { chip.cmd().write(OCW2_EOI) }) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Spurious IRQs dropped so far. Only the ktests read it yet.
+#[cfg(feature = "ktest")]
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+ktest! {
+    fn spurious_irqs_get_the_right_eois() {
+        // Replay IRQ7 and IRQ15 against simulated in-service registers, recording the EOIs instead
+        // of sending them: a spurious IRQ7 gets none, a spurious IRQ15 only the master's, and real
+        // ones (bit set) the usual EOIs. No port is touched.
+        use Chip::{Master, Slave};
+        let sent = |irq: u8, in_service: u8| {
+            let mut sent = [None; 2];
+            let mut n = 0;
+            let real = ack(
+                irq,
+                |_| in_service,
+                |chip| {
+                    if n < sent.len() {
+                        sent[n] = Some(chip);
+                    }
+                    n += 1;
+                },
+            );
+            (real, n, sent)
+        };
+        let seen = spurious_count();
+        let cases = [
+            (7, 0x00, (false, 0, [None, None])),
+            (7, 0x80, (true, 1, [Some(Master), None])),
+            (15, 0x00, (false, 1, [Some(Master), None])),
+            (15, 0x80, (true, 2, [Some(Slave), Some(Master)])),
+            (0, 0x00, (true, 1, [Some(Master), None])),
+        ];
+        for (irq, in_service, want) in cases {
+            let got = sent(irq, in_service);
+            kassert!(
+                got == want,
+                "irq {} with isr {:#x} acked {:?}, want {:?}",
+                irq,
+                in_service,
+                got,
+                want
+            );
         }
-        port::outb(PIC1_CMD, 0x20);
+        // Only `eoi` counts, and it was never called.
+        kassert!(spurious_count() == seen, "simulated spurious IRQs were counted");
     }
 }
@@ -1,13 +1,84 @@
-use super::port;
+use super::port::Port;
 
-pub fn init(hz: u32) {
+const CHANNEL0: Port<u8> = Port::new(0x40);
+const CHANNEL2: Port<u8> = Port::new(0x42);
+const COMMAND: Port<u8> = Port::new(0x43);
+// Keyboard controller port B: channel 2 gate (bit 0), speaker (bit 1), OUT2 (bit 5).
+const PORT_B: Port<u8> = Port::new(0x61);
+
+/// PIT input clock in Hz.
+pub const BASE_HZ: u32 = 1_193_182;
+
+/// Divisor the PIT will actually use for a requested frequency.
+pub fn divisor_for(hz: u32) -> u16 {
     let hz = hz.clamp(18, 2000);
-    let divisor: u16 = (1193182u32 / hz) as u16;
+    (BASE_HZ / hz).min(u16::MAX as u32) as u16
+}
+
+/// Tick frequency (integer Hz) a channel programmed with `divisor` actually runs at.
+pub fn hz_for(divisor: u32) -> u32 {
+    (BASE_HZ / divisor.max(1)).max(1)
+}
+
+ktest! {
+    fn divisor_rounding() {
+        // 1_193_182 / 1900 = 627.99: the divisor truncates to 627, which ticks at 1903 Hz.
+        kassert!(divisor_for(1900) == 627 && hz_for(627) == 1903);
+        kassert!(divisor_for(100) == 11931 && hz_for(11931) == 100);
+        // Requests outside 18..=2000 Hz are clamped before dividing.
+        kassert!(divisor_for(5000) == 596 && hz_for(596) == 2001);
+        kassert!(divisor_for(1) == divisor_for(18) && hz_for(divisor_for(1) as u32) == 18);
+    }
+}
+
+/// Program channel 0 and return the divisor in effect.
+pub fn init(hz: u32) -> u16 {
+    let divisor = divisor_for(hz);
 
     unsafe {
         // Channel 0, lobyte/hibyte, mode 3 (square wave), binary.
-        port::outb(0x43, 0x36);
-        port::outb(0x40, (divisor & 0xff) as u8);
-        port::outb(0x40, (divisor >> 8) as u8);
+        COMMAND.write(0x36);
+        CHANNEL0.write((divisor & 0xff) as u8);
+        CHANNEL0.write((divisor >> 8) as u8);
+    }
+    divisor
+}
+
+/// Measure the TSC frequency in kHz against a 10 ms one-shot on PIT channel 2.
+/// Returns 0 if the channel never reaches terminal count (no usable port 0x61).
+pub fn calibrate_tsc_khz() -> u64 {
+    measure_10ms(super::rdtsc) / 10
+}
+
+/// How far the up-counting `read` advances over a 10 ms one-shot on PIT channel 2
+/// (0 if the channel never reaches terminal count).
+pub fn measure_10ms(read: impl Fn() -> u64) -> u64 {
+    const MS: u32 = 10;
+    let count = (BASE_HZ / 1000 * MS) as u16;
+
+    unsafe {
+        // Speaker off, gate low while programming.
+        let ctl = PORT_B.read() & !0x03;
+        PORT_B.write(ctl);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary.
+        COMMAND.write(0xb0);
+        CHANNEL2.write((count & 0xff) as u8);
+        CHANNEL2.write((count >> 8) as u8);
+        // Raise the gate to start counting.
+        PORT_B.write(ctl | 0x01);
+
+        let t0 = read();
+        let mut spins: u64 = 0;
+        // OUT2 (bit 5) goes high at terminal count.
+        while (PORT_B.read() & 0x20) == 0 {
+            spins += 1;
+            if spins > 10_000_000 {
+                PORT_B.write(ctl);
+                return 0;
+            }
+        }
+        let t1 = read();
+        PORT_B.write(ctl);
+        t1.wrapping_sub(t0)
     }
 }
@@ -0,0 +1,55 @@
+use super::cpuid;
+use crate::serial;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+// `stac`/`clac` are #UD without SMAP, so only emit them once it's enabled.
+static SMAP_ON: AtomicBool = AtomicBool::new(false);
+
+// Forbid the kernel from executing (SMEP) or touching (SMAP) user pages, when supported.
+pub fn init() {
+    let smep = cpuid::has_smep();
+    let smap = cpuid::has_smap();
+    if !smep && !smap {
+        serial::write_str("smap: not supported\n");
+        return;
+    }
+
+    unsafe {
+        let mut cr4: u64;
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        if smep {
+            cr4 |= CR4_SMEP;
+        }
+        if smap {
+            cr4 |= CR4_SMAP;
+        }
+        core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    SMAP_ON.store(smap, Ordering::Relaxed);
+
+    serial::write_str("smap:");
+    if smep {
+        serial::write_str(" smep enabled");
+    }
+    if smap {
+        serial::write_str(" smap enabled");
+    }
+    serial::write_str("\n");
+}
+
+/// Run `f` with supervisor access to user pages allowed (EFLAGS.AC=1). This is the only
+/// place user memory may be touched. The copy helpers go through HHDM aliases today, but
+/// keeping them inside the window means a switch to direct user-VA access stays legal
+/// while any stray access elsewhere faults.
+pub fn user_access<R>(f: impl FnOnce() -> R) -> R {
+    if !SMAP_ON.load(Ordering::Relaxed) {
+        return f();
+    }
+    unsafe { core::arch::asm!("stac", options(nomem, nostack)) };
+    let r = f();
+    unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+    r
+}
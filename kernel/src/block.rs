@@ -0,0 +1,253 @@
+// Block devices and the cache that sits between them and filesystems. No disk driver
+// exists yet, so the module is only built for the ktests, which drive the cache against a
+// RAM disk that counts device accesses.
+
+use alloc::vec::Vec;
+
+pub const BLOCK_SIZE: usize = 512;
+
+// Cache size used when the caller has no better idea.
+pub const DEFAULT_CACHE_BLOCKS: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    OutOfRange, // block number past the end of the device
+    NoMemory,   // no heap left for a cache buffer
+}
+
+/// A device addressed in `BLOCK_SIZE` blocks.
+pub trait BlockDevice {
+    fn block_count(&self) -> u64;
+    fn read_block(&mut self, lba: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error>;
+    fn write_block(&mut self, lba: u64, buf: &[u8; BLOCK_SIZE]) -> Result<(), Error>;
+}
+
+/// When cached writes reach the device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+    Through, // every write goes to the device at once; the cache only serves reads
+    Back,    // writes stay in the cache until `flush` or eviction
+}
+
+struct Slot {
+    lba: u64,
+    data: [u8; BLOCK_SIZE],
+    dirty: bool,
+    last_used: u64,
+}
+
+/// LRU cache of up to `capacity` blocks of `dev`, kept in the heap.
+pub struct Cache<D: BlockDevice> {
+    dev: D,
+    slots: Vec<Slot>,
+    capacity: usize,
+    mode: WriteMode,
+    clock: u64,
+}
+
+impl<D: BlockDevice> Cache<D> {
+    pub fn new(dev: D, capacity: usize, mode: WriteMode) -> Self {
+        Self {
+            dev,
+            slots: Vec::new(),
+            capacity: capacity.max(1),
+            mode,
+            clock: 0,
+        }
+    }
+
+    fn check(&self, lba: u64) -> Result<(), Error> {
+        if lba < self.dev.block_count() {
+            Ok(())
+        } else {
+            Err(Error::OutOfRange)
+        }
+    }
+
+    fn touch(&mut self, i: usize) {
+        self.clock += 1;
+        self.slots[i].last_used = self.clock;
+    }
+
+    fn lookup(&self, lba: u64) -> Option<usize> {
+        self.slots.iter().position(|s| s.lba == lba)
+    }
+
+    // A slot for `lba`, filled from the device when `fill`. Takes a free slot while under
+    // capacity, else evicts the least recently used one (writing it back if dirty).
+    fn slot_for(&mut self, lba: u64, fill: bool) -> Result<usize, Error> {
+        if let Some(i) = self.lookup(lba) {
+            self.touch(i);
+            return Ok(i);
+        }
+        let i = if self.slots.len() < self.capacity {
+            self.slots.try_reserve(1).map_err(|_| Error::NoMemory)?;
+            self.slots.push(Slot {
+                lba,
+                data: [0; BLOCK_SIZE],
+                dirty: false,
+                last_used: 0,
+            });
+            self.slots.len() - 1
+        } else {
+            let (i, _) = self
+                .slots
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, s)| s.last_used)
+                .ok_or(Error::NoMemory)?;
+            let victim = &self.slots[i];
+            if victim.dirty {
+                self.dev.write_block(victim.lba, &victim.data)?;
+            }
+            let slot = &mut self.slots[i];
+            slot.lba = lba;
+            slot.dirty = false;
+            i
+        };
+        if fill {
+            if let Err(e) = self.dev.read_block(lba, &mut self.slots[i].data) {
+                self.slots.swap_remove(i);
+                return Err(e);
+            }
+        }
+        self.touch(i);
+        Ok(i)
+    }
+
+    /// Read block `lba`, from the cache when it holds it.
+    pub fn read(&mut self, lba: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        self.check(lba)?;
+        let i = self.slot_for(lba, true)?;
+        buf.copy_from_slice(&self.slots[i].data);
+        Ok(())
+    }
+
+    /// Write block `lba`. In write-back mode the device sees it on `flush` or eviction;
+    /// repeated writes to the same block before then reach it once.
+    pub fn write(&mut self, lba: u64, buf: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        self.check(lba)?;
+        if self.mode == WriteMode::Through {
+            self.dev.write_block(lba, buf)?;
+        }
+        let i = self.slot_for(lba, false)?;
+        let slot = &mut self.slots[i];
+        slot.data.copy_from_slice(buf);
+        slot.dirty = self.mode == WriteMode::Back;
+        Ok(())
+    }
+
+    /// Write every dirty block to the device, in block order.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.slots.sort_unstable_by_key(|s| s.lba);
+        for slot in self.slots.iter_mut().filter(|s| s.dirty) {
+            self.dev.write_block(slot.lba, &slot.data)?;
+            slot.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Flush and hand back the device.
+    pub fn into_inner(mut self) -> Result<D, Error> {
+        self.flush()?;
+        Ok(self.dev)
+    }
+}
+
+// RAM disk that counts the reads and writes reaching it.
+struct CountingDisk {
+    blocks: Vec<[u8; BLOCK_SIZE]>,
+    reads: u64,
+    writes: u64,
+}
+
+impl BlockDevice for CountingDisk {
+    fn block_count(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    fn read_block(&mut self, lba: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let b = self.blocks.get(lba as usize).ok_or(Error::OutOfRange)?;
+        buf.copy_from_slice(b);
+        self.reads += 1;
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        let b = self.blocks.get_mut(lba as usize).ok_or(Error::OutOfRange)?;
+        b.copy_from_slice(buf);
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+ktest! {
+    fn cache_reads_writes_and_evicts() {
+        // Repeated reads are served from memory, write-back coalesces and defers writes until flush
+        // or eviction, write-through writes at once, and LRU evicts the coldest block.
+        const BLOCKS: usize = 8;
+        let mut blocks = Vec::new();
+        if blocks.try_reserve_exact(BLOCKS).is_err() {
+            kwarn!("block: cache test skipped, no memory");
+            return;
+        }
+        for i in 0..BLOCKS {
+            blocks.push([i as u8; BLOCK_SIZE]);
+        }
+        let disk = CountingDisk {
+            blocks,
+            reads: 0,
+            writes: 0,
+        };
+        let mut buf = [0u8; BLOCK_SIZE];
+
+        // Write-back, two slots.
+        let mut cache = Cache::new(disk, 2, WriteMode::Back);
+        let first = cache.read(3, &mut buf).is_ok() && buf[0] == 3;
+        let second = cache.read(3, &mut buf).is_ok() && buf[0] == 3;
+        kassert!(
+            first && second && cache.dev.reads == 1,
+            "second read reached the device ({} reads)",
+            cache.dev.reads
+        );
+        let _ = cache.write(5, &[0xa5; BLOCK_SIZE]);
+        let _ = cache.write(5, &[0x5a; BLOCK_SIZE]);
+        kassert!(cache.dev.writes == 0, "write-back wrote early");
+        // Slots hold 3 and 5; touching 3 leaves 5 coldest, so reading 6 evicts (and writes) it.
+        let _ = cache.read(3, &mut buf);
+        let _ = cache.read(6, &mut buf);
+        kassert!(
+            cache.dev.writes == 1 && cache.dev.blocks[5][0] == 0x5a && cache.lookup(5).is_none(),
+            "dirty LRU block not written back on eviction"
+        );
+        let reads = cache.dev.reads;
+        let _ = cache.read(3, &mut buf);
+        kassert!(cache.dev.reads == reads, "LRU evicted the hot block");
+        let _ = cache.write(1, &[0x11; BLOCK_SIZE]);
+        kassert!(
+            cache.read(BLOCKS as u64, &mut buf) == Err(Error::OutOfRange),
+            "read past the end accepted"
+        );
+        let Ok(disk) = cache.into_inner() else {
+            kassert!(false, "flush failed");
+            return;
+        };
+        kassert!(
+            disk.writes == 2 && disk.blocks[1][0] == 0x11,
+            "flush lost a dirty block"
+        );
+
+        // Write-through: the device sees the write at once, the read after it is a hit.
+        let mut cache = Cache::new(disk, DEFAULT_CACHE_BLOCKS, WriteMode::Through);
+        let reads = cache.dev.reads;
+        let _ = cache.write(2, &[0x22; BLOCK_SIZE]);
+        let hit = cache.read(2, &mut buf).is_ok() && buf[0] == 0x22;
+        kassert!(
+            cache.dev.writes == 3
+                && cache.dev.blocks[2][0] == 0x22
+                && hit
+                && cache.dev.reads == reads,
+            "write-through misbehaved"
+        );
+    }
+}
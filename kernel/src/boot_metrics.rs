@@ -0,0 +1,82 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::x86_64::{cpuid, rdtsc};
+use crate::sched;
+use crate::serial;
+use crate::timer;
+
+#[derive(Copy, Clone)]
+pub enum Milestone {
+    Serial,
+    Arch, // CPU features, GDT/IDT, PIC/PIT
+    Pmm,
+    Paging,
+    Heap,
+    FirstUser,
+}
+
+const COUNT: usize = 6;
+const NAMES: [&str; COUNT] = ["serial", "arch", "pmm", "paging", "heap", "first_user"];
+
+// Raw TSC at each milestone (0 = not reached). Timer ticks are the fallback when the CPU has
+// no TSC, which in practice means every milestone reads 0 since IRQs are off during boot.
+static STAMPS: [AtomicU64; COUNT] = [const { AtomicU64::new(0) }; COUNT];
+
+fn now() -> u64 {
+    if cpuid::has_tsc() {
+        rdtsc()
+    } else {
+        sched::ticks()
+    }
+}
+
+pub fn mark(m: Milestone) {
+    STAMPS[m as usize].store(now(), Ordering::Relaxed);
+}
+
+// Self-test: reached milestones must be in non-decreasing order.
+fn monotonic() -> bool {
+    let mut prev = 0;
+    for s in &STAMPS {
+        let t = s.load(Ordering::Relaxed);
+        if t == 0 {
+            continue;
+        }
+        if t < prev {
+            return false;
+        }
+        prev = t;
+    }
+    true
+}
+
+/// Log each milestone relative to the first: microseconds when the TSC is calibrated,
+/// otherwise raw counter units.
+pub fn report() {
+    let base = STAMPS[0].load(Ordering::Relaxed);
+    let unit = if timer::tsc_khz().is_some() {
+        "us"
+    } else if cpuid::has_tsc() {
+        "cycles"
+    } else {
+        "ticks"
+    };
+    serial::write_str("boot_metrics: unit=");
+    serial::write_str(unit);
+    serial::write_str("\n");
+    for (name, s) in NAMES.iter().zip(STAMPS.iter()) {
+        let t = s.load(Ordering::Relaxed);
+        if t == 0 {
+            continue;
+        }
+        let delta = t.saturating_sub(base);
+        serial::write_str("boot_metrics: ");
+        serial::write_str(name);
+        serial::write_str("=");
+        serial::write_dec_u64(timer::cycles_to_us(delta).unwrap_or(delta));
+        serial::write_str("\n");
+    }
+    if !monotonic() {
+        serial::write_str("boot_metrics: milestones not monotonic\n");
+    }
+}
@@ -0,0 +1,120 @@
+// Kernel assertions. Both go through `panic!`, whose handler prints the message and
+// source location to serial and dumps registers (see `dump_state`).
+
+/// Unrecoverable kernel bug: report and halt.
+macro_rules! bug {
+    ($($arg:tt)+) => {
+        panic!("BUG: {}", format_args!($($arg)+))
+    };
+}
+
+/// Like `assert!`, but always on (also in release builds) and tagged as a kernel assertion.
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            panic!("kassert failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            panic!("kassert failed: {}: {}", stringify!($cond), format_args!($($arg)+));
+        }
+    };
+}
+
+use core::fmt::Write;
+
+use crate::arch::x86_64::paging;
+use crate::serial;
+use crate::symbols;
+
+const STACK_DUMP_WORDS: usize = 16;
+const BACKTRACE_FRAMES: usize = 16;
+
+/// Control registers, stack pointers and the top of the current stack, for post-mortems.
+pub fn dump_state() {
+    let (rsp, rbp, cr2, cr3): (u64, u64, u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    serial::write_str("  rsp=");
+    serial::write_hex_u64(rsp);
+    serial::write_str(" rbp=");
+    serial::write_hex_u64(rbp);
+    serial::write_str(" cr2=");
+    serial::write_hex_u64(cr2);
+    serial::write_str(" cr3=");
+    serial::write_hex_u64(cr3);
+    serial::write_str(" pid=");
+    serial::write_dec_u64(crate::sched::current_pid() as u64);
+    serial::write_str("\n");
+
+    // Raw words above rsp; return addresses into the kernel image stand out.
+    for i in 0..STACK_DUMP_WORDS {
+        let p = rsp + (i as u64) * 8;
+        let v = unsafe { core::ptr::read_volatile(p as *const u64) };
+        serial::write_str(if i % 4 == 0 { "  " } else { " " });
+        serial::write_hex_u64(v);
+        if i % 4 == 3 {
+            serial::write_str("\n");
+        }
+    }
+}
+
+// A frame record at `rbp` we can read without faulting again.
+fn frame_ok(rbp: u64) -> bool {
+    rbp != 0
+        && rbp.is_multiple_of(8)
+        && paging::is_kernel_addr(rbp)
+        && paging::is_mapped(rbp)
+        && paging::is_mapped(rbp + 8)
+}
+
+/// Walk saved-RBP frame records from `rbp`, printing return addresses. Stops at the first
+/// record that is unmapped, misaligned or not further up the stack than the last one; only
+/// meaningful in kernels built with frame pointers.
+pub fn backtrace(mut rbp: u64) {
+    serial::write_str("  backtrace:");
+    if !frame_ok(rbp) {
+        serial::write_str(" rbp=");
+        serial::write_hex_u64(rbp);
+        serial::write_str(" not walkable\n");
+        return;
+    }
+    serial::write_str("\n");
+    for i in 0..BACKTRACE_FRAMES {
+        let (next, ret) = unsafe {
+            (
+                core::ptr::read_volatile(rbp as *const u64),
+                core::ptr::read_volatile((rbp + 8) as *const u64),
+            )
+        };
+        if ret == 0 {
+            break;
+        }
+        serial::write_str("    #");
+        serial::write_dec_u64(i as u64);
+        serial::write_str(" ");
+        serial::write_hex_u64(ret);
+        // The return address points after the call; look up the call itself.
+        match symbols::resolve(ret - 1) {
+            Some((name, off)) => {
+                let _ = write!(
+                    serial::Writer,
+                    " {}+{:#x}",
+                    symbols::Demangle(name),
+                    off + 1
+                );
+            }
+            None => serial::write_str(" ?"),
+        }
+        serial::write_str("\n");
+        if next <= rbp || !frame_ok(next) {
+            break;
+        }
+        rbp = next;
+    }
+}
@@ -0,0 +1,34 @@
+// Kernel command line: whitespace-separated words the bootloader reads from `\cmdline.txt`
+// on the boot volume (tools/build.sh writes it). Only `ktest` is looked at so far.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mantra_bootinfo::BootInfo;
+
+// Copied out of the boot info page, which is boot memory the kernel may reuse.
+static mut LINE: [u8; BootInfo::CMDLINE_MAX] = [0; BootInfo::CMDLINE_MAX];
+static LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Keep the command line from `bi`. One that is not UTF-8 is ignored.
+pub fn init(bi: &BootInfo) {
+    let len = core::cmp::min(bi.cmdline_len as usize, bi.cmdline.len());
+    let line = &bi.cmdline[..len];
+    if core::str::from_utf8(line).is_err() {
+        kwarn!("cmdline: not UTF-8, ignored");
+        return;
+    }
+    let buf = &raw mut LINE;
+    unsafe { (&mut *buf)[..len].copy_from_slice(line) };
+    LEN.store(len, Ordering::Release);
+}
+
+/// The whole command line, "" if there was none.
+pub fn get() -> &'static str {
+    let len = LEN.load(Ordering::Acquire);
+    let buf = &raw const LINE;
+    core::str::from_utf8(unsafe { &(&*buf)[..len] }).unwrap_or("")
+}
+
+/// True if `word` is one of the command line's words.
+pub fn has(word: &str) -> bool {
+    get().split_ascii_whitespace().any(|w| w == word)
+}
@@ -1,5 +1,9 @@
+use core::cell::UnsafeCell;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use mantra_bootinfo::PixelFormat;
+use mantra_sys::Gamma;
 
 #[derive(Copy, Clone)]
 pub struct Rgb {
@@ -8,6 +12,7 @@ pub struct Rgb {
     pub b: u8,
 }
 
+#[derive(Copy, Clone)]
 pub struct FrameBuffer {
     pub base: *mut u8,
     pub size: usize,
@@ -15,41 +20,318 @@ pub struct FrameBuffer {
     pub height: usize,
     pub stride: usize, // pixels per scanline
     pub format: PixelFormat,
+    pub bpp: usize,      // bytes per pixel
+    pub masks: [u32; 3], // r/g/b channel masks (PixelFormat::Bitmask only)
 }
 
-impl FrameBuffer {
-    pub fn put_pixel(&mut self, x: usize, y: usize, c: Rgb) {
-        if x >= self.width || y >= self.height {
-            return;
-        }
+// Place an 8-bit channel value into the bit range selected by `mask`.
+fn pack_channel(v: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let width = (mask >> shift).count_ones();
+    let v = if width >= 8 {
+        (v as u32) << (width - 8)
+    } else {
+        (v as u32) >> (8 - width)
+    };
+    (v << shift) & mask
+}
 
-        let byte_off = (y * self.stride + x) * 4;
-        if byte_off + 4 > self.size {
-            return;
-        }
+// The color transform every pixel goes through on its way to the screen
+// (`syscall::FB_SET_GAMMA`). Written with interrupts off; skipped while it is the identity.
+struct GammaTable(UnsafeCell<Gamma>);
+
+unsafe impl Sync for GammaTable {}
+
+static GAMMA: GammaTable = GammaTable(UnsafeCell::new(Gamma::IDENTITY));
+static GAMMA_ON: AtomicBool = AtomicBool::new(false);
+
+/// Transform every color drawn from now on through `table`. Pixels already on the screen
+/// keep their values.
+pub fn set_gamma(table: &Gamma) {
+    crate::arch::interrupts::without_interrupts(|| unsafe {
+        *GAMMA.0.get() = *table;
+        GAMMA_ON.store(*table != Gamma::IDENTITY, Ordering::Relaxed);
+    });
+}
+
+/// The color transform in effect.
+pub fn gamma() -> Gamma {
+    crate::arch::interrupts::without_interrupts(|| unsafe { *GAMMA.0.get() })
+}
 
-        let v = match self.format {
+impl FrameBuffer {
+    fn encode(&self, c: Rgb) -> u32 {
+        let c = if GAMMA_ON.load(Ordering::Relaxed) {
+            let g = unsafe { &*GAMMA.0.get() };
+            Rgb {
+                r: g.r[c.r as usize],
+                g: g.g[c.g as usize],
+                b: g.b[c.b as usize],
+            }
+        } else {
+            c
+        };
+        match self.format {
             // UEFI GOP: byte0=R, byte1=G, byte2=B, byte3=reserved
             PixelFormat::Rgb => (c.r as u32) | ((c.g as u32) << 8) | ((c.b as u32) << 16),
             // UEFI GOP: byte0=B, byte1=G, byte2=R, byte3=reserved
             PixelFormat::Bgr => (c.b as u32) | ((c.g as u32) << 8) | ((c.r as u32) << 16),
+            PixelFormat::Bitmask => {
+                pack_channel(c.r, self.masks[0])
+                    | pack_channel(c.g, self.masks[1])
+                    | pack_channel(c.b, self.masks[2])
+            }
             PixelFormat::Unknown => (c.r as u32) | ((c.g as u32) << 8) | ((c.b as u32) << 16),
+        }
+    }
+
+    // Byte offset of pixel (x, y), or None if it is off the screen or past `size`.
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        if x >= self.width || y >= self.height || self.bpp == 0 || self.bpp > 4 {
+            return None;
+        }
+        let byte_off = y
+            .checked_mul(self.stride)?
+            .checked_add(x)?
+            .checked_mul(self.bpp)?;
+        (byte_off + self.bpp <= self.size).then_some(byte_off)
+    }
+
+    // The encoded value of pixel (x, y), as `put_pixel` stored it.
+    #[cfg(feature = "ktest")]
+    fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        let byte_off = self.offset(x, y)?;
+        let mut v = 0;
+        for i in 0..self.bpp {
+            let b = unsafe { core::ptr::read_volatile(self.base.add(byte_off + i)) };
+            v |= (b as u32) << (i * 8);
+        }
+        Some(v)
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, c: Rgb) {
+        let Some(byte_off) = self.offset(x, y) else {
+            return;
         };
+        let bpp = self.bpp;
+        let v = self.encode(c);
 
         unsafe {
-            core::ptr::write_volatile(self.base.add(byte_off) as *mut u32, v);
+            if bpp == 4 {
+                core::ptr::write_volatile(self.base.add(byte_off) as *mut u32, v);
+            } else {
+                // Packed formats (e.g. 24bpp): little-endian, one byte at a time.
+                for i in 0..bpp {
+                    core::ptr::write_volatile(self.base.add(byte_off + i), (v >> (i * 8)) as u8);
+                }
+            }
+        }
+    }
+
+    /// Why this framebuffer can't back a console (headless firmware, bogus mode info).
+    pub fn unusable_reason(&self) -> Option<&'static str> {
+        if self.base.is_null() || self.size == 0 {
+            return Some("no framebuffer memory");
+        }
+        if self.width == 0 || self.height == 0 {
+            return Some("zero width or height");
+        }
+        if self.format == PixelFormat::Unknown {
+            return Some("unknown pixel format");
+        }
+        if self.bpp == 0 || self.bpp > 4 {
+            return Some("unsupported bytes per pixel");
+        }
+        if self.stride < self.width {
+            return Some("stride smaller than width");
+        }
+        let needed = self
+            .stride
+            .checked_mul(self.height)
+            .and_then(|p| p.checked_mul(self.bpp));
+        if needed.is_none_or(|n| n > self.size) {
+            return Some("mode larger than framebuffer");
+        }
+        None
+    }
+
+    // Rows 0..n lie wholly inside the buffer, `pitch` bytes apart: the last byte of each is
+    // below `size`. Truncated mode info (`size` < stride * height * bpp) just loses bottom
+    // rows; a row that doesn't fit whole is never drawn by the bulk paths.
+    fn whole_rows(&self) -> (usize, usize) {
+        if self.base.is_null() || self.bpp == 0 || self.bpp > 4 || self.stride < self.width {
+            return (0, 0);
+        }
+        let (Some(pitch), Some(row)) = (
+            self.stride.checked_mul(self.bpp),
+            self.width.checked_mul(self.bpp),
+        ) else {
+            return (0, 0);
+        };
+        if row == 0 || row > self.size {
+            return (0, pitch);
+        }
+        (self.height.min((self.size - row) / pitch + 1), pitch)
+    }
+
+    // Fill pixel row `y` with the encoded pixel `v`, 32 bits at a time when pixels are.
+    fn fill_row(&mut self, y: usize, pitch: usize, v: u32) {
+        let (bpp, width) = (self.bpp, self.width);
+        let Some(off) = y.checked_mul(pitch) else {
+            return;
+        };
+        if off
+            .checked_add(width * bpp)
+            .is_none_or(|end| end > self.size)
+        {
+            return;
+        }
+        unsafe {
+            let row = self.base.add(off);
+            if bpp == 4 {
+                for x in 0..width {
+                    core::ptr::write_volatile((row as *mut u32).add(x), v);
+                }
+            } else {
+                for i in 0..width * bpp {
+                    core::ptr::write_volatile(row.add(i), (v >> ((i % bpp) * 8)) as u8);
+                }
+            }
         }
     }
 
     pub fn clear(&mut self, c: Rgb) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                self.put_pixel(x, y, c);
+        let (rows, pitch) = self.whole_rows();
+        let v = self.encode(c);
+        for y in 0..rows {
+            self.fill_row(y, pitch, v);
+        }
+    }
+
+    /// Move the picture up by `lines` pixel rows and fill the rows uncovered at the bottom.
+    pub fn scroll_up(&mut self, lines: usize, fill: Rgb) {
+        let (rows, pitch) = self.whole_rows();
+        let lines = lines.min(rows);
+        if lines < rows {
+            // From the start of row `lines` to the last pixel of the last whole row: the
+            // padding after that row may lie past `size`.
+            let len = (rows - lines - 1) * pitch + self.width * self.bpp;
+            let src = lines * pitch;
+            if src.checked_add(len).is_none_or(|end| end > self.size) {
+                return;
             }
+            unsafe { core::ptr::copy(self.base.add(src), self.base, len) };
+        }
+        let v = self.encode(fill);
+        for y in rows - lines..rows {
+            self.fill_row(y, pitch, v);
         }
     }
 }
 
+/// One character cell as it was written, kept so the screen can be redrawn from scrollback.
+#[derive(Copy, Clone)]
+pub struct Cell {
+    ch: u8,
+    fg: Rgb,
+    bg: Rgb,
+}
+
+impl Cell {
+    pub const BLANK: Cell = Cell {
+        ch: b' ',
+        fg: Rgb { r: 0, g: 0, b: 0 },
+        bg: Rgb { r: 0, g: 0, b: 0 },
+    };
+}
+
+/// Ring of the most recent text rows, the ones on screen included; the oldest row is
+/// dropped first. Columns past `cols` are not kept and come back blank.
+pub struct Scrollback {
+    cells: &'static mut [Cell],
+    cols: usize,
+    newest: u64, // row number of the cursor's row, counted from `reset`
+    stored: u64, // rows held, `newest` included
+}
+
+impl Scrollback {
+    pub fn new(cells: &'static mut [Cell], cols: usize) -> Option<Self> {
+        if cols == 0 || cells.len() < cols {
+            return None;
+        }
+        Some(Self {
+            cells,
+            cols,
+            newest: 0,
+            stored: 1,
+        })
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.cells.len() / self.cols) as u64
+    }
+
+    fn oldest(&self) -> u64 {
+        self.newest + 1 - self.stored
+    }
+
+    fn slot(&self, line: u64) -> usize {
+        (line % self.capacity()) as usize * self.cols
+    }
+
+    fn row(&self, line: u64) -> Option<&[Cell]> {
+        if line < self.oldest() || line > self.newest {
+            return None;
+        }
+        let at = self.slot(line);
+        Some(&self.cells[at..at + self.cols])
+    }
+
+    fn set(&mut self, col: usize, cell: Cell) {
+        if col < self.cols {
+            let at = self.slot(self.newest) + col;
+            self.cells[at] = cell;
+        }
+    }
+
+    fn blank_newest(&mut self, blank: Cell) {
+        let at = self.slot(self.newest);
+        self.cells[at..at + self.cols].fill(blank);
+    }
+
+    fn push_line(&mut self, blank: Cell) {
+        self.newest += 1;
+        self.stored = (self.stored + 1).min(self.capacity());
+        self.blank_newest(blank);
+    }
+
+    // Forget everything; the cursor's row becomes row `line`.
+    fn reset(&mut self, line: u64, blank: Cell) {
+        self.newest = line;
+        self.stored = 1;
+        self.blank_newest(blank);
+    }
+}
+
+// History for the boot console: 256 rows of up to 128 columns.
+const BOOT_SCROLLBACK_ROWS: usize = 256;
+const BOOT_SCROLLBACK_COLS: usize = 128;
+static mut BOOT_SCROLLBACK: [Cell; BOOT_SCROLLBACK_ROWS * BOOT_SCROLLBACK_COLS] =
+    [Cell::BLANK; BOOT_SCROLLBACK_ROWS * BOOT_SCROLLBACK_COLS];
+static BOOT_SCROLLBACK_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// The boot console's history buffer; handed out once.
+fn boot_scrollback() -> Option<Scrollback> {
+    if BOOT_SCROLLBACK_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    let cells = &raw mut BOOT_SCROLLBACK;
+    Scrollback::new(unsafe { &mut *cells }, BOOT_SCROLLBACK_COLS)
+}
+
 pub struct Console {
     pub fb: FrameBuffer,
     fg: Rgb,
@@ -58,17 +340,48 @@ pub struct Console {
     cy: usize,
     cols: usize,
     rows: usize,
+    scale: (usize, usize), // each glyph pixel is drawn as an sx-by-sy block
+    history: Option<Scrollback>,
+    view_back: u64,       // rows the view is scrolled back from the live output
+    snap_to_bottom: bool, // new output while scrolled back jumps to the bottom
 }
 
 impl Console {
-    // 8x8 glyph, scaled vertically x2 => 8x16 cell for readability.
-    const CELL_W: usize = 8;
-    const CELL_H: usize = 16;
+    // 8x8 glyph, by default scaled vertically x2 => 8x16 cell for readability.
+    const GLYPH: usize = 8;
+    const DEFAULT_SCALE: (usize, usize) = (1, 2);
+    #[cfg(feature = "ktest")]
+    const CELL_W: usize = Self::GLYPH * Self::DEFAULT_SCALE.0;
+    const CELL_H: usize = Self::GLYPH * Self::DEFAULT_SCALE.1;
 
-    pub fn new(fb: FrameBuffer) -> Self {
-        let cols = fb.width / Self::CELL_W;
-        let rows = fb.height / Self::CELL_H;
-        Self {
+    fn text_geometry(
+        fb: &FrameBuffer,
+        (sx, sy): (usize, usize),
+    ) -> Result<(usize, usize), &'static str> {
+        if let Some(why) = fb.unusable_reason() {
+            return Err(why);
+        }
+        if sx == 0 || sy == 0 {
+            return Err("zero glyph scale");
+        }
+        let cols = fb.width / (Self::GLYPH * sx);
+        let rows = fb.height / (Self::GLYPH * sy);
+        if cols == 0 || rows == 0 {
+            return Err("smaller than one text cell");
+        }
+        Ok((cols, rows))
+    }
+
+    /// Glyph scale that keeps text legible on a screen `height` pixels tall: the default
+    /// 8x16 cell up to 1080 lines, then one more multiple per further 1080 (4K gets 16x32).
+    pub fn scale_for_height(height: usize) -> (usize, usize) {
+        let k = (height / 1080).max(1);
+        (Self::DEFAULT_SCALE.0 * k, Self::DEFAULT_SCALE.1 * k)
+    }
+
+    pub fn new(fb: FrameBuffer) -> Result<Self, &'static str> {
+        let (cols, rows) = Self::text_geometry(&fb, Self::DEFAULT_SCALE)?;
+        Ok(Self {
             fb,
             fg: Rgb {
                 r: 0xff,
@@ -84,6 +397,117 @@ impl Console {
             cy: 0,
             cols,
             rows,
+            scale: Self::DEFAULT_SCALE,
+            history: None,
+            view_back: 0,
+            snap_to_bottom: true,
+        })
+    }
+
+    /// Adopt a new framebuffer after a mode change or remap, keeping colors.
+    /// The cursor is clamped to the new grid and the screen is cleared.
+    pub fn reinit(&mut self, fb: FrameBuffer) -> Result<(), &'static str> {
+        let (cols, rows) = Self::text_geometry(&fb, self.scale)?;
+        self.fb = fb;
+        self.regrid(cols, rows);
+        Ok(())
+    }
+
+    /// Draw each glyph pixel as an `sx`-by-`sy` block, recomputing the text grid. Colors
+    /// are kept, the cursor is clamped to the new grid and the screen is redrawn (from
+    /// scrollback if there is one). On error nothing changes.
+    pub fn set_scale(&mut self, sx: usize, sy: usize) -> Result<(), &'static str> {
+        let (cols, rows) = Self::text_geometry(&self.fb, (sx, sy))?;
+        self.scale = (sx, sy);
+        self.regrid(cols, rows);
+        Ok(())
+    }
+
+    fn regrid(&mut self, cols: usize, rows: usize) {
+        self.cols = cols;
+        self.rows = rows;
+        self.cx = self.cx.min(cols - 1);
+        self.cy = self.cy.min(rows - 1);
+        self.fb.clear(self.bg);
+        self.view_back = 0;
+        self.redraw();
+    }
+
+    /// Keep the rows written from now on in `history` so they can be scrolled back to.
+    pub fn set_scrollback(&mut self, mut history: Scrollback) {
+        history.reset(self.cy as u64, self.blank());
+        self.history = Some(history);
+        self.view_back = 0;
+    }
+
+    /// Whether output arriving while scrolled back jumps to the bottom (the default) or
+    /// leaves the view where it is.
+    #[cfg(feature = "ktest")]
+    pub fn set_snap_to_bottom(&mut self, snap: bool) {
+        self.snap_to_bottom = snap;
+    }
+
+    /// Rows the view is scrolled back from the live output; 0 at the bottom.
+    #[cfg(feature = "ktest")]
+    pub fn scrolled_back(&self) -> u64 {
+        self.view_back
+    }
+
+    fn max_view_back(&self) -> u64 {
+        match &self.history {
+            Some(h) => (h.newest - self.cy as u64).saturating_sub(h.oldest()),
+            None => 0,
+        }
+    }
+
+    /// Show `n` rows further back in the scrollback, stopping at the oldest kept row. No
+    /// key is bound to it yet; only the ktests scroll back.
+    #[cfg(feature = "ktest")]
+    pub fn scroll_up(&mut self, n: usize) {
+        let v = self
+            .view_back
+            .saturating_add(n as u64)
+            .min(self.max_view_back());
+        if v != self.view_back {
+            self.view_back = v;
+            self.redraw();
+        }
+    }
+
+    /// Show `n` rows further forward, stopping at the live output.
+    pub fn scroll_down(&mut self, n: usize) {
+        let v = self.view_back.saturating_sub(n as u64);
+        if v != self.view_back {
+            self.view_back = v;
+            self.redraw();
+        }
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_down(usize::MAX);
+    }
+
+    // Repaint every text row from scrollback at the current view position.
+    fn redraw(&mut self) {
+        let Some(h) = &self.history else {
+            return;
+        };
+        let top = h.newest - self.cy as u64 - self.view_back;
+        let blank = self.blank();
+        for row in 0..self.rows {
+            let line = h.row(top + row as u64);
+            for col in 0..self.cols {
+                let cell = line.and_then(|l| l.get(col)).copied().unwrap_or(blank);
+                Self::draw_cell(&mut self.fb, self.scale, col, row, cell);
+            }
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell {
+            ch: b' ',
+            fg: self.fg,
+            bg: self.bg,
         }
     }
 
@@ -92,18 +516,61 @@ impl Console {
         self.bg = bg;
     }
 
+    /// Current (foreground, background) colors.
+    pub fn colors(&self) -> (Rgb, Rgb) {
+        (self.fg, self.bg)
+    }
+
+    /// Write raw bytes; unlike `write_str` they need not be UTF-8 (each byte is a glyph).
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.put_char(b);
+        }
+    }
+
+    /// True if text cell (col, row) on screen shows the glyph for `ch` in `fg` on the
+    /// current background.
+    #[cfg(feature = "ktest")]
+    pub fn shows(&self, col: usize, row: usize, ch: u8, fg: Rgb) -> bool {
+        let (sx, sy) = self.scale;
+        let (fg, bg) = (self.fb.encode(fg), self.fb.encode(self.bg));
+        Self::glyph(ch).iter().enumerate().all(|(r, bits)| {
+            (0..Self::GLYPH).all(|c| {
+                let want = if bits & (0x80 >> c) != 0 { fg } else { bg };
+                let (x, y) = ((col * Self::GLYPH + c) * sx, (row * Self::GLYPH + r) * sy);
+                self.fb.pixel(x, y) == Some(want)
+            })
+        })
+    }
+
+    /// Blank the screen and drop the scrollback.
     pub fn clear(&mut self, bg: Rgb) {
         self.bg = bg;
         self.fb.clear(bg);
         self.cx = 0;
         self.cy = 0;
+        self.view_back = 0;
+        let blank = self.blank();
+        if let Some(h) = &mut self.history {
+            h.reset(0, blank);
+        }
     }
 
     fn newline(&mut self) {
         self.cx = 0;
-        self.cy += 1;
-        if self.cy >= self.rows {
-            self.cy = self.rows.saturating_sub(1);
+        let blank = self.blank();
+        if let Some(h) = &mut self.history {
+            h.push_line(blank);
+        }
+        if self.cy + 1 < self.rows {
+            self.cy += 1;
+            return;
+        }
+        if self.view_back == 0 {
+            self.fb.scroll_up(Self::GLYPH * self.scale.1, self.bg);
+        } else {
+            // Keep showing the same rows while output continues below them.
+            self.view_back = (self.view_back + 1).min(self.max_view_back());
         }
     }
 
@@ -151,7 +618,34 @@ impl Console {
         }
     }
 
+    fn draw_cell(
+        fb: &mut FrameBuffer,
+        (sx, sy): (usize, usize),
+        col: usize,
+        row: usize,
+        cell: Cell,
+    ) {
+        let glyph = Self::glyph(cell.ch);
+        let px0 = col * Self::GLYPH * sx;
+        let py0 = row * Self::GLYPH * sy;
+
+        for (r, bits) in glyph.iter().copied().enumerate() {
+            for c in 0..Self::GLYPH {
+                let on = (bits & (0x80 >> c)) != 0;
+                let color = if on { cell.fg } else { cell.bg };
+                for y in py0 + r * sy..py0 + (r + 1) * sy {
+                    for x in px0 + c * sx..px0 + (c + 1) * sx {
+                        fb.put_pixel(x, y, color);
+                    }
+                }
+            }
+        }
+    }
+
     fn put_char(&mut self, ch: u8) {
+        if self.view_back != 0 && self.snap_to_bottom {
+            self.scroll_to_bottom();
+        }
         if ch == b'\n' {
             self.newline();
             return;
@@ -165,29 +659,522 @@ impl Console {
             self.newline();
         }
 
-        let glyph = Self::glyph(ch);
-        let px0 = self.cx * Self::CELL_W;
-        let py0 = self.cy * Self::CELL_H;
+        let cell = Cell {
+            ch,
+            fg: self.fg,
+            bg: self.bg,
+        };
+        if let Some(h) = &mut self.history {
+            h.set(self.cx, cell);
+        }
+        if self.view_back == 0 {
+            Self::draw_cell(&mut self.fb, self.scale, self.cx, self.cy, cell);
+        }
+        self.cx += 1;
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+// The screen console once boot hands it over; `syscall::CON_WRITE` draws on it. Only
+// touched with interrupts off, and kernel logging never goes through it.
+struct UserConsole(UnsafeCell<Option<Console>>);
+
+unsafe impl Sync for UserConsole {}
+
+static USER_CONSOLE: UserConsole = UserConsole(UnsafeCell::new(None));
+
+/// Make `con` the console user programs write to, returning the one it replaces.
+pub fn swap_console(con: Option<Console>) -> Option<Console> {
+    crate::arch::interrupts::without_interrupts(|| unsafe {
+        core::mem::replace(&mut *USER_CONSOLE.0.get(), con)
+    })
+}
+
+/// Run `f` on the user console with interrupts off; None if there is none.
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> Option<R> {
+    crate::arch::interrupts::without_interrupts(|| unsafe {
+        (*USER_CONSOLE.0.get()).as_mut().map(f)
+    })
+}
+
+/// Boot-time text output: the framebuffer console, or serial when there is no usable screen.
+pub enum BootConsole {
+    Screen(Console),
+    Serial,
+}
 
-        for (row, bits) in glyph.iter().copied().enumerate() {
-            for col in 0..8 {
-                let on = (bits & (0x80 >> col)) != 0;
-                let color = if on { self.fg } else { self.bg };
-                let y = py0 + row * 2;
-                self.fb.put_pixel(px0 + col, y, color);
-                self.fb.put_pixel(px0 + col, y + 1, color);
+impl BootConsole {
+    pub fn new(fb: FrameBuffer) -> Self {
+        match Console::new(fb) {
+            Ok(mut con) => {
+                let (sx, sy) = Console::scale_for_height(con.fb.height);
+                if con.set_scale(sx, sy).is_err() {
+                    kwarn!("fb: {}x{} glyphs don't fit, keeping the default", sx, sy);
+                }
+                if let Some(history) = boot_scrollback() {
+                    con.set_scrollback(history);
+                }
+                BootConsole::Screen(con)
+            }
+            Err(why) => {
+                kwarn!("fb: {}, using serial-only console", why);
+                BootConsole::Serial
             }
         }
+    }
 
-        self.cx += 1;
+    pub fn screen(&mut self) -> Option<&mut Console> {
+        match self {
+            BootConsole::Screen(con) => Some(con),
+            BootConsole::Serial => None,
+        }
     }
 }
 
-impl fmt::Write for Console {
+impl fmt::Write for BootConsole {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for b in s.bytes() {
-            self.put_char(b);
+        match self {
+            BootConsole::Screen(con) => con.write_str(s),
+            BootConsole::Serial => crate::serial::Writer.write_str(s),
         }
-        Ok(())
+    }
+}
+
+// Copy of the live framebuffer for the panic screen. Plain data and no locks, so the panic
+// path can use it even when the heap or scheduler state is corrupt.
+struct PanicTarget(UnsafeCell<Option<FrameBuffer>>);
+
+unsafe impl Sync for PanicTarget {}
+
+static PANIC_TARGET: PanicTarget = PanicTarget(UnsafeCell::new(None));
+static PANIC_DRAWN: AtomicBool = AtomicBool::new(false);
+
+const PANIC_BANNER_ROWS: usize = 4;
+const PANIC_FG: Rgb = Rgb {
+    r: 0xff,
+    g: 0xff,
+    b: 0xff,
+};
+const PANIC_BG: Rgb = Rgb {
+    r: 0xb0,
+    g: 0x10,
+    b: 0x10,
+};
+
+/// Record where the panic screen should draw. Call again whenever the mapping changes.
+pub fn set_panic_target(fb: &FrameBuffer) {
+    unsafe { *PANIC_TARGET.0.get() = Some(*fb) };
+}
+
+// Red band across the top text rows with `msg` in it. Never allocates.
+fn draw_panic_banner(fb: FrameBuffer, msg: fmt::Arguments) {
+    let Ok(mut con) = Console::new(fb) else {
+        return;
+    };
+    let band = (PANIC_BANNER_ROWS * Console::CELL_H).min(con.fb.height);
+    for y in 0..band {
+        for x in 0..con.fb.width {
+            con.fb.put_pixel(x, y, PANIC_BG);
+        }
+    }
+    con.set_colors(PANIC_FG, PANIC_BG);
+    let _ = fmt::Write::write_fmt(&mut con, format_args!("KERNEL PANIC: {}", msg));
+}
+
+/// Draw the panic banner on the registered framebuffer. A no-op before a console exists,
+/// and only the first (possibly nested) panic draws.
+pub fn panic_screen(msg: fmt::Arguments) {
+    if PANIC_DRAWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(fb) = unsafe { *PANIC_TARGET.0.get() } {
+        draw_panic_banner(fb, msg);
+    }
+}
+
+ktest! {
+    fn panic_banner_is_drawn() {
+        // Render the banner into an off-screen buffer and check the band color and the first glyphs
+        // of the header and message.
+        const W: usize = 160;
+        const H: usize = 32;
+        static mut SCRATCH: [u32; W * H] = [0; W * H];
+
+        let base = core::ptr::addr_of_mut!(SCRATCH) as *mut u8;
+        let fb = FrameBuffer {
+            base,
+            size: W * H * 4,
+            width: W,
+            height: H,
+            stride: W,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        draw_panic_banner(fb, format_args!("TEST"));
+
+        let pixel = |x: usize, y: usize| unsafe {
+            core::ptr::read_volatile((base as *const u32).add(y * W + x))
+        };
+        let (fg, bg) = (fb.encode(PANIC_FG), fb.encode(PANIC_BG));
+        // Glyph rows are doubled; 'K' and 'T' both light column 1 of their first row and
+        // leave column 0 dark.
+        let cell_lit = |col: usize| pixel(col * 8 + 1, 0) == fg && pixel(col * 8, 0) == bg;
+        let header = "KERNEL PANIC: ".len();
+        kassert!(cell_lit(0), "panic header not drawn");
+        kassert!(cell_lit(header), "panic message not drawn");
+        kassert!(pixel(W - 1, H - 1) == bg, "panic band not filled");
+    }
+}
+
+ktest! {
+    fn scrollback_scrolls_snaps_and_holds() {
+        // Write ten lines into a four-row off-screen console, scroll back and check the earlier
+        // lines are redrawn from scrollback, both with snap-to-bottom and with the view held.
+        const W: usize = 160;
+        const H: usize = 64;
+        const COLS: usize = W / Console::CELL_W;
+        static mut SCRATCH: [u32; W * H] = [0; W * H];
+        static mut CELLS: [Cell; 16 * COLS] = [Cell::BLANK; 16 * COLS];
+
+        let base = core::ptr::addr_of_mut!(SCRATCH) as *mut u8;
+        let fb = FrameBuffer {
+            base,
+            size: W * H * 4,
+            width: W,
+            height: H,
+            stride: W,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let Ok(mut con) = Console::new(fb) else {
+            kassert!(false, "scrollback console rejected");
+            return;
+        };
+        let cells = &raw mut CELLS;
+        let Some(history) = Scrollback::new(unsafe { &mut *cells }, COLS) else {
+            kassert!(false, "scrollback rejected");
+            return;
+        };
+        con.set_scrollback(history);
+        let (fg, bg) = (fb.encode(con.fg), fb.encode(con.bg));
+
+        // Does text cell (col, row) on screen hold exactly the glyph for `ch`?
+        let shows = |col: usize, row: usize, ch: u8| {
+            Console::glyph(ch).iter().enumerate().all(|(r, bits)| {
+                (0..8).all(|c| {
+                    let want = if bits & (0x80 >> c) != 0 { fg } else { bg };
+                    let (x, y) = (col * Console::CELL_W + c, row * Console::CELL_H + r * 2);
+                    let px = unsafe { (base as *const u32).add(y * W + x).read_volatile() };
+                    px == want
+                })
+            })
+        };
+
+        // Rows 0..3 end up showing L7, L8, L9 and the empty cursor row.
+        for i in 0..10 {
+            let _ = fmt::Write::write_fmt(&mut con, format_args!("L{}\n", i));
+        }
+        kassert!(
+            shows(1, 0, b'7') && shows(1, 2, b'9'),
+            "console did not scroll"
+        );
+        con.scroll_up(5);
+        kassert!(
+            con.scrolled_back() == 5 && shows(1, 0, b'2') && shows(1, 1, b'3'),
+            "scrollback not redrawn"
+        );
+        con.scroll_up(100);
+        kassert!(
+            con.scrolled_back() == 7 && shows(1, 0, b'0'),
+            "scroll_up went past the oldest row"
+        );
+
+        // Snap: new output brings the live rows back.
+        let _ = fmt::Write::write_str(&mut con, "X");
+        kassert!(
+            con.scrolled_back() == 0 && shows(1, 0, b'7') && shows(0, 3, b'X'),
+            "output did not snap to bottom"
+        );
+
+        // Hold: the view keeps the same rows while a new line arrives below them.
+        con.set_snap_to_bottom(false);
+        con.scroll_up(2);
+        let _ = fmt::Write::write_str(&mut con, "Y\n");
+        kassert!(
+            con.scrolled_back() == 3 && shows(1, 0, b'5') && shows(1, 3, b'8'),
+            "held view moved"
+        );
+        con.scroll_to_bottom();
+        kassert!(
+            shows(1, 0, b'8') && shows(0, 2, b'X') && shows(1, 2, b'Y'),
+            "bottom not redrawn after held output"
+        );
+    }
+}
+
+ktest! {
+    fn writes_stay_inside_the_framebuffer() {
+        // Clear and scroll a padded framebuffer whose `size` stops one pixel short of the last row:
+        // every byte at or past `size` (and the row padding) must stay untouched, the whole rows
+        // must be cleared, and scrolling must move them up by one.
+        const W: usize = 16;
+        const H: usize = 8;
+        const STRIDE: usize = 20;
+        const GUARD: u32 = 0x5a5a_5a5a;
+        static mut SCRATCH: [u32; STRIDE * H] = [0; STRIDE * H];
+
+        let base = core::ptr::addr_of_mut!(SCRATCH) as *mut u32;
+        let size = (STRIDE * (H - 1) + W - 1) * 4;
+        let mut fb = FrameBuffer {
+            base: base as *mut u8,
+            size,
+            width: W,
+            height: H,
+            stride: STRIDE,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let word = |i: usize| unsafe { core::ptr::read_volatile(base.add(i)) };
+        for i in 0..STRIDE * H {
+            unsafe { core::ptr::write_volatile(base.add(i), GUARD) };
+        }
+        let outside = |i: usize| i >= size / 4 || i % STRIDE >= W;
+        let untouched = || {
+            (0..STRIDE * H)
+                .filter(|&i| outside(i))
+                .all(|i| word(i) == GUARD)
+        };
+
+        let red = Rgb {
+            r: 0xff,
+            g: 0,
+            b: 0,
+        };
+        fb.clear(red);
+        let cleared = (0..STRIDE * (H - 1))
+            .filter(|&i| !outside(i))
+            .all(|i| word(i) == fb.encode(red));
+        kassert!(cleared, "clear missed a whole row");
+        kassert!(untouched(), "clear wrote outside the framebuffer");
+
+        // Tag each whole row by the green level of its first pixel.
+        let tag = |y: usize| Rgb {
+            r: 0,
+            g: y as u8,
+            b: 0,
+        };
+        for y in 0..H - 1 {
+            fb.put_pixel(0, y, tag(y));
+        }
+        let blue = Rgb {
+            r: 0,
+            g: 0,
+            b: 0xff,
+        };
+        fb.scroll_up(1, blue);
+        let moved = (0..H - 2).all(|y| word(y * STRIDE) == fb.encode(tag(y + 1)));
+        kassert!(
+            moved && word((H - 2) * STRIDE) == fb.encode(blue),
+            "scroll did not move the whole rows up"
+        );
+        fb.scroll_up(H * 2, blue);
+        kassert!(untouched(), "scroll wrote outside the framebuffer");
+    }
+}
+
+ktest! {
+    fn packed_24bpp_pixel_layout() {
+        // 24bpp with the channel masks of a little-endian BGR layout, 2 pixels of row padding.
+        const W: usize = 4;
+        const STRIDE: usize = 6;
+        let mut buf = [0xeeu8; STRIDE * 2 * 3];
+        let mut fb = FrameBuffer {
+            base: buf.as_mut_ptr(),
+            size: buf.len(),
+            width: W,
+            height: 2,
+            stride: STRIDE,
+            format: PixelFormat::Bitmask,
+            bpp: 3,
+            masks: [0xff_0000, 0xff00, 0xff],
+        };
+        let c = Rgb {
+            r: 0x12,
+            g: 0x34,
+            b: 0x56,
+        };
+        fb.put_pixel(1, 1, c);
+        fb.put_pixel(W, 0, c);
+        let at = (STRIDE + 1) * 3;
+        kassert!(buf[at..at + 3] == [0x56, 0x34, 0x12], "pixel bytes {:x?}", &buf[at..at + 3]);
+        // Neither the neighbours nor the row padding (where x == W would be) may change.
+        let spilled = (0..buf.len()).any(|i| (i < at || i >= at + 3) && buf[i] != 0xee);
+        kassert!(!spilled, "24bpp write spilled");
+    }
+}
+
+ktest! {
+    fn reinit_regrids_the_console() {
+        // 8x16 cells: 80x64 pixels is a 10x4 grid, 128x96 a 16x6 one.
+        const W: usize = 128;
+        const H: usize = 96;
+        static mut SCRATCH: [u32; W * H] = [0; W * H];
+        let fb = |width, height| FrameBuffer {
+            base: (&raw mut SCRATCH).cast(),
+            size: W * H * 4,
+            width,
+            height,
+            stride: W,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let Ok(mut con) = Console::new(fb(80, 64)) else {
+            kassert!(false, "fb: no console on the small screen");
+            return;
+        };
+        let fg = con.colors().0;
+        con.write_bytes(b"0123456789AB");
+        kassert!((con.cx, con.cy) == (2, 1), "small grid cursor {:?}", (con.cx, con.cy));
+
+        // Growing keeps the cursor; text now wraps after 16 columns.
+        kassert!(con.reinit(fb(W, H)).is_ok() && (con.cols, con.rows) == (16, 6));
+        con.write_bytes(&[b'X'; 14]);
+        con.write_bytes(b"Y");
+        kassert!(
+            con.shows(15, 1, b'X', fg) && con.shows(0, 2, b'Y', fg),
+            "text did not wrap at the new column count"
+        );
+
+        // Shrinking clamps a cursor past the new grid.
+        con.write_bytes(b"\n\n\n");
+        con.write_bytes(&[b'X'; 12]);
+        kassert!((con.cx, con.cy) == (12, 5));
+        kassert!(con.reinit(fb(80, 64)).is_ok());
+        kassert!((con.cx, con.cy) == (9, 3), "cursor not clamped: {:?}", (con.cx, con.cy));
+    }
+}
+
+ktest! {
+    fn glyph_scale_draws_bigger_cells() {
+        // Switch an off-screen console to 2x2 glyphs mid-line: the grid shrinks, the cursor column
+        // and colors survive, the next glyph covers 16x16 pixels, and scales that are zero or leave
+        // no whole cell are rejected without changing anything.
+        const W: usize = 64;
+        const H: usize = 32;
+        static mut SCRATCH: [u32; W * H] = [0; W * H];
+
+        let base = core::ptr::addr_of_mut!(SCRATCH) as *mut u8;
+        let fb = FrameBuffer {
+            base,
+            size: W * H * 4,
+            width: W,
+            height: H,
+            stride: W,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let Ok(mut con) = Console::new(fb) else {
+            kassert!(false, "scale console rejected");
+            return;
+        };
+        let (fg, bg) = (
+            Rgb {
+                r: 0xff,
+                g: 0xc0,
+                b: 0x00,
+            },
+            Rgb {
+                r: 0,
+                g: 0,
+                b: 0x40,
+            },
+        );
+        con.set_colors(fg, bg);
+        let _ = fmt::Write::write_str(&mut con, "AB");
+
+        kassert!(
+            con.set_scale(0, 1).is_err() && con.set_scale(8, 8).is_err() && con.scale == (1, 2),
+            "bad glyph scale accepted"
+        );
+        kassert!(
+            con.set_scale(2, 2).is_ok() && (con.cols, con.rows) == (4, 2) && con.cx == 2,
+            "2x2 scale gave {}x{} cursor {}",
+            con.cols,
+            con.rows,
+            con.cx
+        );
+        let _ = fmt::Write::write_str(&mut con, "X");
+
+        let (fg, bg) = (fb.encode(fg), fb.encode(bg));
+        let pixel = |x: usize, y: usize| unsafe {
+            core::ptr::read_volatile((base as *const u32).add(y * W + x))
+        };
+        let glyph = Console::glyph(b'X');
+        let scaled = (0..16).all(|y| {
+            (0..16).all(|x| {
+                let on = glyph[y / 2] & (0x80 >> (x / 2)) != 0;
+                pixel(32 + x, y) == if on { fg } else { bg }
+            })
+        });
+        kassert!(scaled, "2x2 glyph not drawn as 16x16");
+        kassert!(
+            (48..W).all(|x| (0..16).all(|y| pixel(x, y) == bg)) && con.cx == 3,
+            "2x2 glyph spilled into the next cell"
+        );
+    }
+}
+
+ktest! {
+    fn gamma_applies_to_new_pixels() {
+        // Install an inverting table and draw white into an off-screen framebuffer: it must read
+        // back as black, and as white again once the previous table is restored.
+        const W: usize = 4;
+        static mut SCRATCH: [u32; W] = [0; W];
+        let mut fb = FrameBuffer {
+            base: (&raw mut SCRATCH).cast(),
+            size: W * 4,
+            width: W,
+            height: 1,
+            stride: W,
+            format: PixelFormat::Bgr,
+            bpp: 4,
+            masks: [0; 3],
+        };
+        let white = Rgb {
+            r: 0xff,
+            g: 0xff,
+            b: 0xff,
+        };
+        let mut invert = Gamma::IDENTITY;
+        for t in [&mut invert.r, &mut invert.g, &mut invert.b] {
+            t.reverse();
+        }
+        // The table is global; with interrupts off nothing draws on the real screen through it.
+        let inverted = crate::arch::interrupts::without_interrupts(|| {
+            let saved = gamma();
+            set_gamma(&invert);
+            fb.put_pixel(0, 0, white);
+            set_gamma(&saved);
+            fb.pixel(0, 0)
+        });
+        fb.put_pixel(1, 0, white);
+        let plain = fb.pixel(1, 0);
+        kassert!(
+            inverted == Some(0) && plain == Some(0x00ff_ffff),
+            "white drew as {:x?} inverted, {:x?} plain",
+            inverted,
+            plain
+        );
     }
 }
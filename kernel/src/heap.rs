@@ -4,7 +4,7 @@ use core::ptr;
 
 use crate::arch::x86_64::paging;
 use crate::pmm;
-use crate::serial;
+use crate::util::align_up_checked;
 
 struct Bump {
     start: u64,
@@ -41,13 +41,42 @@ static ALLOC: KernelAlloc = KernelAlloc {};
 
 static HEAP: LockedBump = LockedBump::new();
 
-pub fn init() {
-    // Grab a contiguous heap region early. If this fails, keep the heap disabled.
-    // We try larger first then back off.
-    let mut pages: u64 = 4096; // 16 MiB
+// Initial heap: 1/HEAP_FRACTION of free RAM, clamped to [HEAP_MIN, HEAP_MAX].
+const HEAP_FRACTION: u64 = 8;
+const HEAP_MIN: u64 = 512 * 1024;
+const HEAP_MAX: u64 = 256 * 1024 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+// Heap bytes to ask for given `free_bytes` of RAM (page-aligned).
+fn target_size(free_bytes: u64) -> u64 {
+    (free_bytes / HEAP_FRACTION).clamp(HEAP_MIN, HEAP_MAX) & !(PAGE_SIZE - 1)
+}
+
+ktest! {
+    fn size_scales_with_free_ram() {
+        // `target_size` must scale with RAM and bottom out at the floor on small machines.
+        const MIB: u64 = 1024 * 1024;
+        let small = target_size(2 * MIB);
+        let mid = target_size(512 * MIB);
+        let large = target_size(1024 * MIB);
+        kassert!(small == HEAP_MIN, "2MiB free sized {:#x}", small);
+        kassert!(mid == 64 * MIB, "512MiB free sized {:#x}", mid);
+        kassert!(large == 2 * mid, "1GiB free sized {:#x}", large);
+        kassert!(
+            target_size(u64::MAX) == HEAP_MAX,
+            "size not capped at the maximum"
+        );
+    }
+}
+
+pub fn init(free_bytes: u64) {
+    // Grab one contiguous region from the largest free range. If this fails, keep the heap
+    // disabled. Fall back by halves when the largest range is short or fragmented.
+    let target = target_size(free_bytes);
+    let mut pages = core::cmp::min(target, pmm::largest_range_bytes()) / PAGE_SIZE;
     let mut base: Option<u64> = None;
-    while pages >= 128 {
-        if let Some(p) = pmm::alloc_pages(pages) {
+    while pages >= HEAP_MIN / PAGE_SIZE {
+        if let Some(p) = pmm::alloc_largest(pages) {
             base = Some(p);
             break;
         }
@@ -55,11 +84,25 @@ pub fn init() {
     }
 
     let Some(base) = base else {
-        serial::write_str("heap: init failed (no pages)\n");
+        kerror!("heap: init failed (no pages)");
         return;
     };
 
-    let size = pages * 4096;
+    let size = pages * PAGE_SIZE;
+    let base_v = install(base, size);
+    kinfo!(
+        "heap: initialized base(p)={:#x} base(v)={:#x} size={}KiB (1/{} of {}MiB free, target {}KiB)",
+        base,
+        base_v,
+        size / 1024,
+        HEAP_FRACTION,
+        free_bytes / (1024 * 1024),
+        target / 1024
+    );
+}
+
+// Serve allocations from physical [base, base + size). Returns the heap's virtual base.
+fn install(base: u64, size: u64) -> u64 {
     let base_v = paging::phys_to_virt(base);
     unsafe {
         let h = HEAP.bump();
@@ -68,27 +111,170 @@ pub fn init() {
         h.next = base_v;
         h.ready = true;
     }
+    base_v
+}
 
-    serial::write_str("heap: initialized base(p)=");
-    serial::write_hex_u64(base);
-    serial::write_str(" base(v)=");
-    serial::write_hex_u64(base_v);
-    serial::write_str(" size=");
-    serial::write_dec_u64(size / (1024 * 1024));
-    serial::write_str("MiB\n");
+/// Put the heap at physical [base, base + size) instead of where `init` would: below
+/// 4 GiB for DMA-limited devices, say, or later in memory local to a NUMA node. The range
+/// must be page-aligned, free and HHDM-reachable; otherwise returns false and leaves the
+/// heap alone. A heap set up earlier is abandoned, though blocks from it stay valid.
+/// Nothing places the heap like that yet, so only the ktests build it.
+#[cfg(feature = "ktest")]
+pub fn init_from_range(base: u64, size: u64) -> bool {
+    if size == 0
+        || !base.is_multiple_of(PAGE_SIZE)
+        || !size.is_multiple_of(PAGE_SIZE)
+        || !paging::hhdm_covers(base, size)
+        || !pmm::claim_range(base, size / PAGE_SIZE)
+    {
+        return false;
+    }
+    let base_v = install(base, size);
+    kinfo!(
+        "heap: placed base(p)={:#x} base(v)={:#x} size={}KiB",
+        base,
+        base_v,
+        size / 1024
+    );
+    true
 }
 
-pub struct KernelAlloc;
+ktest! {
+    fn vec_and_box_allocate() {
+        use alloc::boxed::Box;
+        use alloc::vec::Vec;
+
+        let mut v: Vec<u64> = Vec::new();
+        for i in 0..16u64 {
+            v.push(i * 3);
+        }
+        let b = Box::new(0xdead_beef_u64);
+        kassert!(v.len() == 16 && v[15] == 45, "vec holds {:?}", v);
+        kassert!(*b == 0xdead_beef, "box holds {:#x}", *b);
+    }
+}
 
-impl KernelAlloc {
-    fn align_up(x: u64, a: u64) -> u64 {
-        if a == 0 {
-            return x;
+ktest! {
+    fn placement_stays_in_its_window() {
+        // Place the heap in a 64 KiB window below 4 GiB with `init_from_range`. Allocations
+        // must land inside it, and the window can't be claimed twice. Afterwards the boot
+        // heap is put back and the window's frames go back to the PMM.
+        use crate::arch::x86_64::interrupts;
+        use alloc::boxed::Box;
+        const PAGES: u64 = 16;
+        const LOW_LIMIT: u64 = 4 << 30;
+        let Some(base) = pmm::find_free_within(0, LOW_LIMIT, PAGES) else {
+            kwarn!("heap: placement test skipped, no free window below 4 GiB");
+            return;
+        };
+        let size = PAGES * PAGE_SIZE;
+        let lo = paging::phys_to_virt(base);
+        let inside = |p: *const u8| (lo..lo + size).contains(&(p as u64));
+        // Check with the boot heap swapped out, assert once it is back.
+        let (placed, word, buf, twice) = interrupts::without_interrupts(|| unsafe {
+            let h = HEAP.bump();
+            let saved = (h.start, h.end, h.next, h.ready);
+            let placed = init_from_range(base, size);
+            let word = Box::new(0u64);
+            let buf = alloc::vec![0u8; 1000];
+            let at = (&*word as *const u64 as *const u8, buf.as_ptr());
+            drop(word);
+            drop(buf);
+            let twice = init_from_range(base, size);
+            let h = HEAP.bump();
+            (h.start, h.end, h.next, h.ready) = saved;
+            (placed, at.0, at.1, twice)
+        });
+        for i in 0..PAGES {
+            pmm::free_frame(base + i * PAGE_SIZE);
         }
-        (x + (a - 1)) & !(a - 1)
+        kassert!(placed, "placing the heap at {:#x} failed", base);
+        kassert!(
+            inside(word) && inside(buf),
+            "allocations {:p} {:p} outside {:#x}..{:#x}",
+            word,
+            buf,
+            lo,
+            lo + size
+        );
+        kassert!(!twice, "the same range was claimed twice");
     }
 }
 
+/// (bytes handed out, heap size); both 0 before `init`.
+pub fn usage() -> (u64, u64) {
+    let h = unsafe { HEAP.bump() };
+    if !h.ready {
+        return (0, 0);
+    }
+    (h.next - h.start, h.end - h.start)
+}
+
+/// Print allocations that are still live, grouped by call site. Needs a kernel built with
+/// `MANTRA_HEAPTRACK=1`; otherwise nothing is tracked.
+pub fn dump_leaks() {
+    #[cfg(mantra_heaptrack)]
+    crate::heap_track::dump_leaks();
+    #[cfg(not(mantra_heaptrack))]
+    kdebug!("heap: leak tracking off (build with MANTRA_HEAPTRACK=1)");
+}
+
+ktest! {
+    fn leaked_blocks_are_tracked() {
+        // With leak tracking on: a leaked box must show up as live and in `dump_leaks`, a
+        // dropped one must not.
+        #[cfg(not(mantra_heaptrack))]
+        kwarn!("heap: leak tracking off, test skipped");
+        #[cfg(mantra_heaptrack)]
+        {
+            use crate::heap_track::{dump_leaks, is_live};
+            use alloc::boxed::Box;
+
+            let before = dump_leaks();
+            let leaked: &mut [u8; 48] = Box::leak(Box::new([0u8; 48]));
+            let dropped = Box::new(7u64);
+            let dropped_ptr = &*dropped as *const u64 as *const u8;
+            drop(dropped);
+            kassert!(is_live(leaked.as_ptr()), "leaked box not tracked");
+            kassert!(!is_live(dropped_ptr), "dropped box still tracked");
+            kassert!(dump_leaks() == before + 1, "leak count off");
+        }
+    }
+}
+
+// With `MANTRA_HEAPPOISON=1`, fresh allocations read as 0xAB and freed ones as 0xDE, so
+// uninitialized reads and use-after-free show up as recognizable bytes.
+#[cfg(mantra_heappoison)]
+const ALLOC_FILL: u8 = 0xab;
+#[cfg(mantra_heappoison)]
+const FREE_FILL: u8 = 0xde;
+
+ktest! {
+    fn blocks_are_poisoned() {
+        // With poisoning on: a new block holds the alloc pattern and, once freed, the free
+        // pattern. Reading the freed block is only sound because the bump heap never reuses
+        // it.
+        #[cfg(not(mantra_heappoison))]
+        kwarn!("heap: poisoning off, test skipped");
+        #[cfg(mantra_heappoison)]
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let p = alloc::alloc::alloc(layout);
+            if p.is_null() {
+                kwarn!("heap: poison test skipped, no heap");
+                return;
+            }
+            let block = core::slice::from_raw_parts(p, layout.size());
+            kassert!(block.iter().all(|&b| b == ALLOC_FILL), "fresh block not alloc-filled");
+            alloc::alloc::dealloc(p, layout);
+            let block = core::slice::from_raw_parts(p, layout.size());
+            kassert!(block.iter().all(|&b| b == FREE_FILL), "freed block not free-filled");
+        }
+    }
+}
+
+pub struct KernelAlloc;
+
 unsafe impl GlobalAlloc for KernelAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let h = HEAP.bump();
@@ -98,17 +284,27 @@ unsafe impl GlobalAlloc for KernelAlloc {
 
         let align = layout.align() as u64;
         let size = layout.size() as u64;
-        let start = Self::align_up(h.next, align);
+        let Some(start) = align_up_checked(h.next, align) else {
+            return ptr::null_mut();
+        };
         let end = start.saturating_add(size);
         if end > h.end {
             return ptr::null_mut();
         }
 
         h.next = end;
+        #[cfg(mantra_heappoison)]
+        ptr::write_bytes(start as *mut u8, ALLOC_FILL, size as usize);
+        #[cfg(mantra_heaptrack)]
+        crate::heap_track::record(start as *mut u8, layout.size());
         start as *mut u8
     }
 
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         // Leak for now. We'll replace with a real allocator once VMM + locking exist.
+        #[cfg(mantra_heappoison)]
+        ptr::write_bytes(_ptr, FREE_FILL, _layout.size());
+        #[cfg(mantra_heaptrack)]
+        crate::heap_track::forget(_ptr);
     }
 }
@@ -0,0 +1,141 @@
+// Live-allocation table for leak hunting. Only built with `MANTRA_HEAPTRACK=1`, which also
+// makes tools/build.sh force frame pointers so the caller walk below is meaningful.
+
+use core::cell::UnsafeCell;
+
+use crate::arch::x86_64::paging;
+
+const SLOTS: usize = 4096; // power of two
+const EMPTY: u64 = 0;
+const TOMBSTONE: u64 = 1;
+
+// Frames between `record`'s caller (`KernelAlloc::alloc`) and the code that asked for
+// memory: skips the `__rust_alloc` shim.
+const CALLER_SKIP: usize = 1;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    ptr: u64,
+    caller: u64,
+    size: u64,
+}
+
+struct Table {
+    slots: UnsafeCell<[Entry; SLOTS]>,
+}
+
+unsafe impl Sync for Table {}
+
+static TABLE: Table = Table {
+    slots: UnsafeCell::new(
+        [Entry {
+            ptr: EMPTY,
+            caller: 0,
+            size: 0,
+        }; SLOTS],
+    ),
+};
+
+fn slots() -> &'static mut [Entry; SLOTS] {
+    unsafe { &mut *TABLE.slots.get() }
+}
+
+fn hash(ptr: u64) -> usize {
+    ((ptr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 52) as usize & (SLOTS - 1)
+}
+
+fn frame_ok(rbp: u64) -> bool {
+    rbp != 0 && rbp % 8 == 0 && paging::is_kernel_addr(rbp)
+}
+
+// Return address `CALLER_SKIP` frames above the function this is inlined into.
+#[inline(always)]
+fn caller() -> u64 {
+    let mut rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags))
+    };
+    for _ in 0..CALLER_SKIP {
+        if !frame_ok(rbp) {
+            return 0;
+        }
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+    if !frame_ok(rbp) {
+        return 0;
+    }
+    unsafe { *((rbp + 8) as *const u64) }
+}
+
+#[inline(always)]
+pub fn record(ptr: *mut u8, size: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let caller = caller();
+    let t = slots();
+    let mut i = hash(ptr as u64);
+    for _ in 0..SLOTS {
+        if t[i].ptr == EMPTY || t[i].ptr == TOMBSTONE {
+            t[i] = Entry {
+                ptr: ptr as u64,
+                caller,
+                size: size as u64,
+            };
+            return;
+        }
+        i = (i + 1) & (SLOTS - 1);
+    }
+    kwarn!("heap: leak table full, {:#x} untracked", ptr as u64);
+}
+
+pub fn forget(ptr: *mut u8) {
+    let t = slots();
+    let mut i = hash(ptr as u64);
+    for _ in 0..SLOTS {
+        match t[i].ptr {
+            EMPTY => return,
+            p if p == ptr as u64 => {
+                t[i].ptr = TOMBSTONE;
+                return;
+            }
+            _ => i = (i + 1) & (SLOTS - 1),
+        }
+    }
+}
+
+pub fn is_live(ptr: *const u8) -> bool {
+    slots().iter().any(|e| e.ptr == ptr as u64)
+}
+
+fn live(e: &Entry) -> bool {
+    e.ptr != EMPTY && e.ptr != TOMBSTONE
+}
+
+/// Print live allocations grouped by call site; returns how many are live.
+pub fn dump_leaks() -> usize {
+    let t = slots();
+    let mut total = 0usize;
+    for (i, e) in t.iter().enumerate() {
+        if !live(e) {
+            continue;
+        }
+        total += 1;
+        // Report each call site once, at its first slot.
+        if t[..i].iter().any(|p| live(p) && p.caller == e.caller) {
+            continue;
+        }
+        let (n, bytes) = t[i..]
+            .iter()
+            .filter(|p| live(p) && p.caller == e.caller)
+            .fold((0u64, 0u64), |(n, b), p| (n + 1, b + p.size));
+        kinfo!(
+            "heap: live caller={:#x} allocs={} bytes={}",
+            e.caller,
+            n,
+            bytes
+        );
+    }
+    kinfo!("heap: {} live allocations", total);
+    total
+}
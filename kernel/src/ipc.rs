@@ -1,67 +1,517 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
+use crate::limits::{self, Resource};
 use crate::sched;
+use alloc::vec::Vec;
+use mantra_sys::sysinfo::EpStats;
+use mantra_sys::{ep_flags, error, MsgHeader, EP_NAME_MAX, NOTIFY_TAG};
 
-const MAX_ENDPOINTS: usize = 32;
+pub const MAX_ENDPOINTS: usize = 32;
+// All entry points take the calling pid explicitly: a handler may switch CURRENT
+// mid-call, so caps are never resolved against whatever happens to be running.
+
+// Per-endpoint limits: `ep_create` picks a depth/message size within these.
 const MAX_MSG: usize = 256;
-const Q_LEN: usize = 32;
+const DEFAULT_Q_LEN: usize = 32;
+const MAX_Q_LEN: usize = 256;
 const MAX_WAITERS: usize = 8;
 
-#[derive(Copy, Clone)]
-struct Msg {
-    len: u16,
-    // Endpoint ID (1-based) transferred with this message, or 0 for none.
-    xfer_ep: u32,
-    data: [u8; MAX_MSG],
+// Ring positions are free-running counters: `tail - head` is the fill level and `pos % len`
+// the slot. Full and empty only ever compare the difference, so neither depends on where
+// the counters happen to be; a u64 will not wrap within the machine's lifetime.
+fn ring_len(head: u64, tail: u64) -> usize {
+    tail.wrapping_sub(head) as usize
 }
 
-const EMPTY_MSG: Msg = Msg {
-    len: 0,
-    xfer_ep: 0,
-    data: [0; MAX_MSG],
-};
+fn ring_slot(pos: u64, len: usize) -> usize {
+    (pos % len as u64) as usize
+}
 
 struct Endpoint {
-    head: AtomicUsize,
-    tail: AtomicUsize,
-    buf: [Msg; Q_LEN],
-    wait_head: AtomicUsize,
-    wait_tail: AtomicUsize,
+    in_use: bool,
+    head: AtomicU64,
+    tail: AtomicU64,
+    // Ring geometry chosen at creation; 0 until the endpoint is created.
+    depth: usize,
+    max_msg: usize,
+    // Per-slot message length, transferred endpoint ID (1-based, 0 for none) and the
+    // IPC_CALL sender awaiting a reply (pid + 1, 0 for a plain send).
+    lens: Vec<u16>,
+    xfer: Vec<u32>,
+    callers: Vec<u8>,
+    // Sending pid + 1 per slot, and the last one dequeued; only fair endpoints use them.
+    senders: Vec<u8>,
+    last_sender: u8,
+    // Round-robin across senders on receive instead of plain FIFO (`ep_flags::FAIR`).
+    fair: bool,
+    // `depth` slots of `max_msg` bytes each.
+    data: Vec<u8>,
+    wait_head: AtomicU64,
+    wait_tail: AtomicU64,
     waiters: [u8; MAX_WAITERS],
+    // Notification bits set by `notify_from_irq`, handed out (and cleared) by the next
+    // receive ahead of any queued message.
+    notify: AtomicU64,
+    // Creating process; only it (or a privileged role) may destroy the endpoint.
+    owner_pid: usize,
+    // Traffic counters for `ep_stats`, reset when the ring is set up. Notifications are
+    // not messages and do not count.
+    sent: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+    high_water: AtomicU64,
 }
 
 static mut ENDPOINTS: [Endpoint; MAX_ENDPOINTS] = [const {
     Endpoint {
-        head: AtomicUsize::new(0),
-        tail: AtomicUsize::new(0),
-        buf: [EMPTY_MSG; Q_LEN],
-        wait_head: AtomicUsize::new(0),
-        wait_tail: AtomicUsize::new(0),
+        in_use: false,
+        head: AtomicU64::new(0),
+        tail: AtomicU64::new(0),
+        depth: 0,
+        max_msg: 0,
+        lens: Vec::new(),
+        xfer: Vec::new(),
+        callers: Vec::new(),
+        senders: Vec::new(),
+        last_sender: 0,
+        fair: false,
+        data: Vec::new(),
+        wait_head: AtomicU64::new(0),
+        wait_tail: AtomicU64::new(0),
         waiters: [0; MAX_WAITERS],
+        notify: AtomicU64::new(0),
+        owner_pid: 0,
+        sent: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        high_water: AtomicU64::new(0),
     }
 }; MAX_ENDPOINTS];
 
-static NEXT_EP: AtomicUsize = AtomicUsize::new(0);
+// Endpoint answered by the kernel itself (`crate::sysinfo`); 0 until `init_sysinfo`.
+// It has no ring, so plain sends and receives on it fail; IPC_CALL is served inline.
+static SYSINFO_EP: AtomicU32 = AtomicU32::new(0);
+const KERNEL_OWNER: usize = usize::MAX;
+
+// Legacy IRQ lines that can be bound to an endpoint; line `n` notifies with bit `n`.
+pub const IRQ_LINES: usize = 16;
+static IRQ_EPS: [AtomicU32; IRQ_LINES] = [const { AtomicU32::new(0) }; IRQ_LINES];
+
+/// Size of the message a receive returns for pending notification bits.
+pub const NOTIFY_LEN: usize = MsgHeader::SIZE + 8;
+
+// Endpoint names published with EP_REGISTER; a slot is free while `ep` is 0.
+const MAX_NAMES: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Name {
+    ep: u32,
+    len: u8,
+    bytes: [u8; EP_NAME_MAX],
+}
+
+static mut NAMES: [Name; MAX_NAMES] = [Name {
+    ep: 0,
+    len: 0,
+    bytes: [0; EP_NAME_MAX],
+}; MAX_NAMES];
+
+unsafe fn names() -> &'static mut [Name; MAX_NAMES] {
+    let names = &raw mut NAMES;
+    &mut *names
+}
+
+unsafe fn endpoint_mut(epi: usize) -> &'static mut Endpoint {
+    let ep = &raw mut ENDPOINTS[epi];
+    &mut *ep
+}
 
 pub fn endpoint_alloc() -> Option<u32> {
-    let i = NEXT_EP.fetch_add(1, Ordering::Relaxed);
-    if i >= MAX_ENDPOINTS {
-        return None;
+    unsafe {
+        for i in 0..MAX_ENDPOINTS {
+            let ep = endpoint_mut(i);
+            if !ep.in_use {
+                ep.in_use = true;
+                // Endpoint IDs are 1-based so 0 can be used as "empty" in cap tables.
+                return Some((i as u32) + 1);
+            }
+        }
+    }
+    None
+}
+
+// Return an endpoint (and its ring) to the free pool.
+fn endpoint_free(endpoint_id: u32) {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return;
+    }
+    unsafe {
+        let ep = endpoint_mut(epi);
+        ep.in_use = false;
+        ep.owner_pid = 0;
+        for n in names().iter_mut().filter(|n| n.ep == endpoint_id) {
+            n.ep = 0;
+        }
+        ep.depth = 0;
+        ep.max_msg = 0;
+        ep.lens = Vec::new();
+        ep.xfer = Vec::new();
+        ep.callers = Vec::new();
+        ep.senders = Vec::new();
+        ep.last_sender = 0;
+        ep.fair = false;
+        ep.data = Vec::new();
+        ep.head.store(0, Ordering::Relaxed);
+        ep.tail.store(0, Ordering::Relaxed);
+        ep.wait_head.store(0, Ordering::Relaxed);
+        ep.wait_tail.store(0, Ordering::Relaxed);
+        ep.notify.store(0, Ordering::Relaxed);
     }
-    // Endpoint IDs are 1-based so 0 can be used as "empty" in cap tables.
-    Some((i as u32) + 1)
 }
 
-pub fn ep_create() -> u64 {
+// Allocate the message ring for a freshly allocated endpoint.
+fn endpoint_init(endpoint_id: u32, depth: usize, max_msg: usize, fair: bool) -> bool {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return false;
+    }
+    let mut lens = Vec::new();
+    let mut xfer = Vec::new();
+    let mut callers = Vec::new();
+    let mut senders = Vec::new();
+    let mut data = Vec::new();
+    if lens.try_reserve_exact(depth).is_err()
+        || xfer.try_reserve_exact(depth).is_err()
+        || callers.try_reserve_exact(depth).is_err()
+        || senders.try_reserve_exact(depth).is_err()
+        || data.try_reserve_exact(depth * max_msg).is_err()
+    {
+        return false;
+    }
+    lens.resize(depth, 0);
+    xfer.resize(depth, 0);
+    callers.resize(depth, 0);
+    senders.resize(depth, 0);
+    data.resize(depth * max_msg, 0);
+
+    unsafe {
+        let ep = endpoint_mut(epi);
+        ep.depth = depth;
+        ep.max_msg = max_msg;
+        ep.lens = lens;
+        ep.xfer = xfer;
+        ep.callers = callers;
+        ep.senders = senders;
+        ep.last_sender = 0;
+        ep.fair = fair;
+        ep.data = data;
+        for c in [&ep.sent, &ep.received, &ep.dropped, &ep.high_water] {
+            c.store(0, Ordering::Relaxed);
+        }
+        ep.head.store(0, Ordering::Relaxed);
+        ep.tail.store(0, Ordering::Release);
+    }
+    true
+}
+
+/// Create an endpoint holding up to `depth` messages of at most `max_msg` bytes each
+/// (0 selects the default; values are clamped to the kernel maximums). `flags` are
+/// `ep_flags::*`.
+pub fn ep_create(pid: usize, depth: usize, max_msg: usize, flags: u64) -> u64 {
+    if (flags & !ep_flags::ALL) != 0 {
+        return error::INVALID;
+    }
+    let depth = match depth {
+        0 => DEFAULT_Q_LEN,
+        d => d.min(MAX_Q_LEN),
+    };
+    let max_msg = match max_msg {
+        0 => MAX_MSG,
+        m => m.min(MAX_MSG),
+    };
+    if let Err(e) = limits::check(pid, Resource::Endpoints, owned_by(pid) + 1)
+        .and_then(|()| limits::check(pid, Resource::Caps, sched::cap_count(pid) + 1))
+    {
+        return e;
+    }
     let Some(ep) = endpoint_alloc() else {
-        return u64::MAX;
+        return error::NO_ENDPOINTS;
     };
-    let Some(cap) = sched::cap_alloc_current(ep) else {
-        return u64::MAX;
+    if !endpoint_init(ep, depth, max_msg, (flags & ep_flags::FAIR) != 0) {
+        endpoint_free(ep);
+        return error::NO_MEMORY;
+    }
+    unsafe { endpoint_mut(ep as usize - 1).owner_pid = pid };
+    let Some(cap) = sched::cap_alloc_for(pid, ep) else {
+        // Don't burn the endpoint if the caller has no cap slot to hold it.
+        endpoint_free(ep);
+        return error::NO_CAP_SLOTS;
     };
     cap as u64
 }
 
+// Endpoints created by `pid` that still exist.
+fn owned_by(pid: usize) -> u64 {
+    (0..MAX_ENDPOINTS)
+        .filter(|&epi| {
+            let ep = unsafe { endpoint_mut(epi) };
+            ep.in_use && ep.owner_pid == pid
+        })
+        .count() as u64
+}
+
+/// Reserve the kernel introspection endpoint. Call once, before the first proc starts.
+pub fn init_sysinfo() {
+    let Some(ep) = endpoint_alloc() else {
+        kerror!("ipc: no endpoint left for sysinfo");
+        return;
+    };
+    unsafe { endpoint_mut(ep as usize - 1).owner_pid = KERNEL_OWNER };
+    SYSINFO_EP.store(ep, Ordering::Relaxed);
+}
+
+pub fn sysinfo_ep() -> Option<u32> {
+    match SYSINFO_EP.load(Ordering::Relaxed) {
+        0 => None,
+        ep => Some(ep),
+    }
+}
+
+pub fn is_sysinfo(endpoint_id: u32) -> bool {
+    endpoint_id != 0 && sysinfo_ep() == Some(endpoint_id)
+}
+
+/// True while `endpoint_id` names an endpoint that exists (not yet destroyed).
+#[cfg(feature = "ktest")]
+pub fn endpoint_live(endpoint_id: u32) -> bool {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    epi < MAX_ENDPOINTS && unsafe { endpoint_mut(epi).in_use }
+}
+
+fn find_name(name: &[u8]) -> Option<usize> {
+    unsafe { names() }
+        .iter()
+        .position(|n| n.ep != 0 && &n.bytes[..n.len as usize] == name)
+}
+
+/// Publish the endpoint behind `cap` as `name` (1 to `EP_NAME_MAX` bytes). Privileged procs
+/// only, so nobody squats on a well-known name; it stays taken until the endpoint is
+/// destroyed.
+pub fn ep_register(pid: usize, cap: u32, name: &[u8]) -> u64 {
+    if !sched::is_privileged(pid) {
+        return error::PERMISSION;
+    }
+    let Some(ep) = sched::cap_lookup(pid, cap) else {
+        return error::INVALID;
+    };
+    if name.is_empty() || name.len() > EP_NAME_MAX || is_sysinfo(ep) || find_name(name).is_some() {
+        return error::INVALID;
+    }
+    let Some(slot) = unsafe { names() }.iter_mut().find(|n| n.ep == 0) else {
+        return error::FULL;
+    };
+    slot.ep = ep;
+    slot.len = name.len() as u8;
+    slot.bytes[..name.len()].copy_from_slice(name);
+    0
+}
+
+/// A new cap for `pid` to the endpoint published as `name`. Caps carry no rights yet, so it
+/// is a plain endpoint cap; the name itself is the only thing being looked up.
+pub fn ep_lookup(pid: usize, name: &[u8]) -> u64 {
+    let Some(i) = find_name(name) else {
+        return error::NOT_FOUND;
+    };
+    if let Err(e) = limits::check(pid, Resource::Caps, sched::cap_count(pid) + 1) {
+        return e;
+    }
+    match sched::cap_alloc_for(pid, unsafe { names()[i].ep }) {
+        Some(cap) => cap as u64,
+        None => error::NO_CAP_SLOTS,
+    }
+}
+
+/// (endpoints in use, endpoint table size, messages queued across all endpoints).
+pub fn stats() -> (usize, usize, usize) {
+    let mut in_use = 0;
+    let mut queued = 0;
+    for epi in 0..MAX_ENDPOINTS {
+        let ep = unsafe { endpoint_mut(epi) };
+        if ep.in_use {
+            in_use += 1;
+            queued += ring_len(
+                ep.head.load(Ordering::Relaxed),
+                ep.tail.load(Ordering::Relaxed),
+            );
+        }
+    }
+    (in_use, MAX_ENDPOINTS, queued)
+}
+
+/// Traffic counters of `endpoint_id`; None unless it is live and has a queue.
+pub fn ep_stats(endpoint_id: u32) -> Option<EpStats> {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return None;
+    }
+    let ep = unsafe { endpoint_mut(epi) };
+    if !ep.in_use || ep.depth == 0 {
+        return None;
+    }
+    Some(EpStats {
+        endpoint: endpoint_id as u64,
+        depth: ep.depth as u64,
+        queued: ring_len(
+            ep.head.load(Ordering::Relaxed),
+            ep.tail.load(Ordering::Relaxed),
+        ) as u64,
+        sent: ep.sent.load(Ordering::Relaxed),
+        received: ep.received.load(Ordering::Relaxed),
+        dropped: ep.dropped.load(Ordering::Relaxed),
+        high_water: ep.high_water.load(Ordering::Relaxed),
+    })
+}
+
+/// Count a message handed straight to a receiver blocked on `endpoint_id`: it never passes
+/// through the ring, so it is sent and received at once.
+pub fn count_direct(endpoint_id: u32) {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return;
+    }
+    let ep = unsafe { endpoint_mut(epi) };
+    ep.sent.fetch_add(1, Ordering::Relaxed);
+    ep.received.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Messages queued on `endpoint_id` (0 for an invalid id).
+pub fn queued(endpoint_id: u32) -> usize {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return 0;
+    }
+    let ep = unsafe { endpoint_mut(epi) };
+    let notified = ep.notify.load(Ordering::Acquire) != 0;
+    ring_len(
+        ep.head.load(Ordering::Acquire),
+        ep.tail.load(Ordering::Relaxed),
+    ) + notified as usize
+}
+
+/// Next proc blocked receiving on `endpoint_id`. Procs waiting there in YIELD_HINT are
+/// woken on the way and skipped: whatever arrives is queued for them to receive themselves.
+pub fn pop_receiver(endpoint_id: u32) -> Option<usize> {
+    while let Some(rx) = waiter_pop(endpoint_id) {
+        // An entry for a proc no longer blocked here (killed, maybe with its slot reused)
+        // names nobody waiting for this message.
+        if !sched::is_blocked_on(rx, endpoint_id) {
+            continue;
+        }
+        if !sched::hint_waiting(rx) {
+            return Some(rx);
+        }
+        sched::wake(rx);
+    }
+    None
+}
+
+// The message a receive returns for notification `bits`.
+fn notification(bits: u64) -> [u8; NOTIFY_LEN] {
+    let mut msg = [0u8; NOTIFY_LEN];
+    let hdr = MsgHeader {
+        tag: NOTIFY_TAG,
+        len: 8,
+    };
+    msg[..MsgHeader::SIZE].copy_from_slice(&hdr.to_bytes());
+    msg[MsgHeader::SIZE..].copy_from_slice(&bits.to_le_bytes());
+    msg
+}
+
+/// Set `badge` bits on the endpoint and, if a proc is blocked receiving there, hand it the
+/// accumulated bits at once. Callable from interrupt handlers: it only touches atomics and
+/// the waiter ring, which non-IRQ paths update with interrupts off, and never allocates.
+/// Bits coalesce, so a burst of interrupts costs one message. False for a dead endpoint.
+pub fn notify_from_irq(endpoint_id: u32, badge: u64) -> bool {
+    let epi = (endpoint_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS || badge == 0 {
+        return false;
+    }
+    let ep = unsafe { endpoint_mut(epi) };
+    if !ep.in_use || ep.depth == 0 {
+        return false;
+    }
+    ep.notify.fetch_or(badge, Ordering::AcqRel);
+    if let Some(rx) = pop_receiver(endpoint_id) {
+        let bits = ep.notify.swap(0, Ordering::AcqRel);
+        crate::arch::x86_64::isr::deliver_notification(rx, &notification(bits));
+    }
+    true
+}
+
+/// Route IRQ line `irq` to `endpoint_id` (0 unbinds). False for a bad line or endpoint.
+pub fn bind_irq(irq: usize, endpoint_id: u32) -> bool {
+    if irq >= IRQ_LINES || (endpoint_id != 0 && is_sysinfo(endpoint_id)) {
+        return false;
+    }
+    IRQ_EPS[irq].store(endpoint_id, Ordering::Release);
+    true
+}
+
+/// Called by the handler for IRQ line `irq`: notify the bound endpoint, if any.
+pub fn irq_fired(irq: usize) {
+    if let Some(ep) = IRQ_EPS.get(irq) {
+        let ep = ep.load(Ordering::Acquire);
+        if ep != 0 {
+            notify_from_irq(ep, 1 << irq);
+        }
+    }
+}
+
+/// Destroy the endpoint behind `cap`. Only its creator or a privileged proc may do this;
+/// everyone else can just drop their own cap. All caps to it are revoked and receivers
+/// blocked on it wake with `error::INVALID`.
+pub fn ep_destroy(pid: usize, cap: u32) -> u64 {
+    let Some(ep_id) = sched::cap_lookup(pid, cap) else {
+        return error::INVALID;
+    };
+    let epi = (ep_id as usize).wrapping_sub(1);
+    if epi >= MAX_ENDPOINTS {
+        return error::INVALID;
+    }
+    let owner = unsafe { endpoint_mut(epi).owner_pid };
+    if owner == KERNEL_OWNER {
+        return error::PERMISSION;
+    }
+    if owner != pid && !sched::is_privileged(pid) {
+        return error::PERMISSION;
+    }
+    for line in &IRQ_EPS {
+        let _ = line.compare_exchange(ep_id, 0, Ordering::AcqRel, Ordering::Relaxed);
+    }
+    while let Some(rx) = waiter_pop(ep_id) {
+        if sched::is_blocked_on(rx, ep_id) {
+            sched::abort_wait(rx, error::INVALID);
+        }
+    }
+    // Callers whose request is still queued would otherwise wait for a reply forever.
+    unsafe {
+        let ep = endpoint_mut(epi);
+        let tail = ep.tail.load(Ordering::Relaxed);
+        let mut i = ep.head.load(Ordering::Relaxed);
+        while i != tail {
+            let caller = ep.callers[ring_slot(i, ep.depth)];
+            if caller != 0 {
+                sched::abort_wait(caller as usize - 1, error::INVALID);
+            }
+            i += 1;
+        }
+    }
+    sched::revoke_endpoint(ep_id);
+    endpoint_free(ep_id);
+    0
+}
+
 pub fn waiter_push(endpoint_id: u32, pid: usize) -> bool {
     if endpoint_id == 0 || pid > u8::MAX as usize {
         return false;
@@ -71,15 +521,14 @@ pub fn waiter_push(endpoint_id: u32, pid: usize) -> bool {
         return false;
     }
     unsafe {
-        let ep = &mut ENDPOINTS[epi];
+        let ep = endpoint_mut(epi);
         let head = ep.wait_head.load(Ordering::Acquire);
         let tail = ep.wait_tail.load(Ordering::Relaxed);
-        if (tail.wrapping_add(1) % MAX_WAITERS) == head {
+        if ring_len(head, tail) >= MAX_WAITERS {
             return false; // full
         }
-        let slot = tail % MAX_WAITERS;
-        ep.waiters[slot] = pid as u8;
-        ep.wait_tail.store(tail.wrapping_add(1), Ordering::Release);
+        ep.waiters[ring_slot(tail, MAX_WAITERS)] = pid as u8;
+        ep.wait_tail.store(tail + 1, Ordering::Release);
         true
     }
 }
@@ -93,76 +542,357 @@ pub fn waiter_pop(endpoint_id: u32) -> Option<usize> {
         return None;
     }
     unsafe {
-        let ep = &mut ENDPOINTS[epi];
+        let ep = endpoint_mut(epi);
         let head = ep.wait_head.load(Ordering::Acquire);
         let tail = ep.wait_tail.load(Ordering::Relaxed);
-        if head == tail {
+        if ring_len(head, tail) == 0 {
             return None;
         }
-        let slot = head % MAX_WAITERS;
-        let pid = ep.waiters[slot] as usize;
-        ep.wait_head.store(head.wrapping_add(1), Ordering::Release);
+        let pid = ep.waiters[ring_slot(head, MAX_WAITERS)] as usize;
+        ep.wait_head.store(head + 1, Ordering::Release);
         Some(pid)
     }
 }
 
-pub fn ep_send(cap: u32, msg: &[u8]) -> u64 {
-    ep_send_cap(cap, msg, 0)
+/// Take `pid` out of every waiter ring, keeping the others in order. For a proc being
+/// killed, which must never be handed a message. Interrupts must be disabled.
+pub fn forget_waiter(pid: usize) {
+    for epi in 0..MAX_ENDPOINTS {
+        let ep = unsafe { endpoint_mut(epi) };
+        if !ep.in_use {
+            continue;
+        }
+        let head = ep.wait_head.load(Ordering::Acquire);
+        let tail = ep.wait_tail.load(Ordering::Relaxed);
+        let mut kept = head;
+        for i in head..tail {
+            let w = ep.waiters[ring_slot(i, MAX_WAITERS)];
+            if w as usize != pid {
+                ep.waiters[ring_slot(kept, MAX_WAITERS)] = w;
+                kept += 1;
+            }
+        }
+        ep.wait_tail.store(kept, Ordering::Release);
+    }
 }
 
-pub fn ep_send_cap(cap: u32, msg: &[u8], xfer_ep: u32) -> u64 {
-    let Some(epi) = sched::cap_lookup_current(cap) else {
+ktest! {
+    fn dead_waiters_are_skipped() {
+        // Pids of slots nobody runs in: never blocked, so never handed a message.
+        const A: usize = 5;
+        const B: usize = 6;
+        let Some(ep) = endpoint_alloc() else {
+            kwarn!("ipc: no endpoint for the dead waiter test");
+            return;
+        };
+        kassert!(endpoint_init(ep, 4, 8, false));
+        for pid in [A, B, A] {
+            waiter_push(ep, pid);
+        }
+        forget_waiter(A);
+        let left = (waiter_pop(ep), waiter_pop(ep));
+        waiter_push(ep, B);
+        let rx = pop_receiver(ep);
+        let drained = waiter_pop(ep).is_none();
+        endpoint_free(ep);
+        kassert!(left == (Some(B), None), "waiters left {:?}", left);
+        kassert!(rx.is_none() && drained, "pop_receiver returned {:?}", rx);
+    }
+}
+
+pub fn ep_send(pid: usize, cap: u32, msg: &[u8]) -> u64 {
+    ep_send_cap(pid, cap, msg, 0)
+}
+
+pub fn ep_send_cap(pid: usize, cap: u32, msg: &[u8], xfer_ep: u32) -> u64 {
+    enqueue(pid, cap, msg, xfer_ep, 0)
+}
+
+/// Queue the request of an IPC_CALL; whoever receives it owes `pid` a reply.
+pub fn ep_send_call(pid: usize, cap: u32, msg: &[u8]) -> u64 {
+    if pid >= u8::MAX as usize {
+        return error::INVALID;
+    }
+    enqueue(pid, cap, msg, 0, pid as u8 + 1)
+}
+
+fn enqueue(pid: usize, cap: u32, msg: &[u8], xfer_ep: u32, caller: u8) -> u64 {
+    let Some(epi) = sched::cap_lookup(pid, cap) else {
         return u64::MAX;
     };
     let epi = (epi as usize).wrapping_sub(1);
-    if epi >= MAX_ENDPOINTS {
+    if epi >= MAX_ENDPOINTS || pid >= u8::MAX as usize {
         return u64::MAX;
     }
+    unsafe { push(epi, pid as u8 + 1, msg, xfer_ep, caller) }
+}
 
-    let n = core::cmp::min(msg.len(), MAX_MSG);
-    unsafe {
-        let ep = &mut ENDPOINTS[epi];
-        let head = ep.head.load(Ordering::Relaxed);
-        let tail = ep.tail.load(Ordering::Relaxed);
-        if (tail.wrapping_add(1) % Q_LEN) == head {
-            return u64::MAX - 1; // full
-        }
-        let slot = tail % Q_LEN;
-        ep.buf[slot].len = n as u16;
-        ep.buf[slot].xfer_ep = xfer_ep;
-        ep.buf[slot].data[..n].copy_from_slice(&msg[..n]);
-        ep.tail.store(tail.wrapping_add(1), Ordering::Release);
+unsafe fn push(epi: usize, sender: u8, msg: &[u8], xfer_ep: u32, caller: u8) -> u64 {
+    let ep = endpoint_mut(epi);
+    if ep.depth == 0 {
+        return u64::MAX;
+    }
+    let n = core::cmp::min(msg.len(), ep.max_msg);
+    let head = ep.head.load(Ordering::Relaxed);
+    let tail = ep.tail.load(Ordering::Relaxed);
+    if ring_len(head, tail) >= ep.depth {
+        ep.dropped.fetch_add(1, Ordering::Relaxed);
+        return error::FULL;
     }
+    let slot = ring_slot(tail, ep.depth);
+    let off = slot * ep.max_msg;
+    ep.lens[slot] = n as u16;
+    ep.xfer[slot] = xfer_ep;
+    ep.callers[slot] = caller;
+    ep.senders[slot] = sender;
+    ep.data[off..off + n].copy_from_slice(&msg[..n]);
+    ep.tail.store(tail + 1, Ordering::Release);
+    ep.sent.fetch_add(1, Ordering::Relaxed);
+    ep.high_water
+        .fetch_max(ring_len(head, tail + 1) as u64, Ordering::Relaxed);
     n as u64
 }
 
-pub fn ep_recv(cap: u32, out: &mut [u8]) -> u64 {
-    let (n, _cap) = ep_recv_cap(cap, out);
+pub fn ep_recv(pid: usize, cap: u32, out: &mut [u8]) -> u64 {
+    let (n, _cap) = ep_recv_cap(pid, cap, out);
     n
 }
 
-pub fn ep_recv_cap(cap: u32, out: &mut [u8]) -> (u64, u32) {
-    let Some(epi) = sched::cap_lookup_current(cap) else {
+pub fn ep_recv_cap(pid: usize, cap: u32, out: &mut [u8]) -> (u64, u32) {
+    let Some(epi) = sched::cap_lookup(pid, cap) else {
         return (u64::MAX, 0);
     };
     let epi = (epi as usize).wrapping_sub(1);
     if epi >= MAX_ENDPOINTS {
         return (u64::MAX, 0);
     }
+    let ep = unsafe { endpoint_mut(epi) };
+    if out.len() >= NOTIFY_LEN && ep.depth != 0 {
+        let bits = ep.notify.swap(0, Ordering::AcqRel);
+        if bits != 0 {
+            out[..NOTIFY_LEN].copy_from_slice(&notification(bits));
+            return (NOTIFY_LEN as u64, 0);
+        }
+    }
+    match unsafe { pop(epi, out) } {
+        Ok((n, xfer_ep, caller)) => {
+            if caller != 0 {
+                sched::set_reply_to(pid, caller as usize - 1);
+            }
+            (n as u64, xfer_ep)
+        }
+        Err(e) => (e, 0),
+    }
+}
 
-    unsafe {
-        let ep = &mut ENDPOINTS[epi];
-        let head = ep.head.load(Ordering::Acquire);
-        let tail = ep.tail.load(Ordering::Relaxed);
-        if head == tail {
-            return (u64::MAX - 2, 0); // empty
-        }
-        let slot = head % Q_LEN;
-        let len = ep.buf[slot].len as usize;
-        let n = core::cmp::min(len, out.len());
-        let xfer_ep = ep.buf[slot].xfer_ep;
-        out[..n].copy_from_slice(&ep.buf[slot].data[..n]);
-        ep.head.store(head.wrapping_add(1), Ordering::Release);
-        (n as u64, xfer_ep)
+// Queue position (offset from head) of the next message a fair endpoint hands out: the
+// oldest one from the first sender after the last one served, in cyclic pid order.
+fn fair_pick(ep: &Endpoint, head: u64, tail: u64) -> usize {
+    let queued = ring_len(head, tail);
+    let rank = |s: u8| s.wrapping_sub(ep.last_sender).wrapping_sub(1);
+    let sender = |i: usize| ep.senders[ring_slot(head + i as u64, ep.depth)];
+    let mut best = 0;
+    for i in 1..queued {
+        if rank(sender(i)) < rank(sender(best)) {
+            best = i;
+        }
+    }
+    best
+}
+
+// Dequeue one message into `out`: (bytes, transferred endpoint, caller). Plain endpoints
+// are FIFO. Fair ones may take a message from mid-queue; the older messages in front of it
+// then shift back one slot, which costs up to a full ring copy.
+unsafe fn pop(epi: usize, out: &mut [u8]) -> Result<(usize, u32, u8), u64> {
+    let ep = endpoint_mut(epi);
+    if ep.depth == 0 {
+        return Err(u64::MAX);
+    }
+    let head = ep.head.load(Ordering::Acquire);
+    let tail = ep.tail.load(Ordering::Relaxed);
+    if ring_len(head, tail) == 0 {
+        return Err(error::EMPTY);
+    }
+    let pos = if ep.fair {
+        fair_pick(ep, head, tail)
+    } else {
+        0
+    };
+    let slot = ring_slot(head + pos as u64, ep.depth);
+    let off = slot * ep.max_msg;
+    let len = ep.lens[slot] as usize;
+    let n = core::cmp::min(len, out.len());
+    let xfer_ep = ep.xfer[slot];
+    let caller = ep.callers[slot];
+    ep.last_sender = ep.senders[slot];
+    out[..n].copy_from_slice(&ep.data[off..off + n]);
+
+    for i in (0..pos).rev() {
+        let from = ring_slot(head + i as u64, ep.depth);
+        let to = ring_slot(head + i as u64 + 1, ep.depth);
+        ep.lens[to] = ep.lens[from];
+        ep.xfer[to] = ep.xfer[from];
+        ep.callers[to] = ep.callers[from];
+        ep.senders[to] = ep.senders[from];
+        ep.data
+            .copy_within(from * ep.max_msg..(from + 1) * ep.max_msg, to * ep.max_msg);
+    }
+    ep.head.store(head + 1, Ordering::Release);
+    ep.received.fetch_add(1, Ordering::Relaxed);
+    Ok((n, xfer_ep, caller))
+}
+
+ktest! {
+    fn fair_dequeue_alternates() {
+        // Two senders share a fair endpoint: pid 1 queues six messages before pid 2 queues two.
+        // Plain FIFO would hand out all of pid 1's first; fair dequeue must alternate while both
+        // have messages waiting.
+        let Some(ep) = endpoint_alloc() else {
+            kwarn!("ipc: no endpoint, fairness test skipped");
+            return;
+        };
+        let epi = ep as usize - 1;
+        kassert!(
+            endpoint_init(ep, 8, 8, true),
+            "endpoint init failed"
+        );
+        let mut order = [0u8; 8];
+        unsafe {
+            for (sender, count) in [(1u8, 6), (2, 2)] {
+                for _ in 0..count {
+                    push(epi, sender + 1, &[sender], 0, 0);
+                }
+            }
+            for o in order.iter_mut() {
+                let mut b = [0u8; 8];
+                if let Ok((1, _, _)) = pop(epi, &mut b) {
+                    *o = b[0];
+                }
+            }
+        }
+        endpoint_free(ep);
+        kassert!(
+            order == [1, 2, 1, 2, 1, 1, 1, 1],
+            "fair dequeue order {:?}",
+            order
+        );
+    }
+}
+
+ktest! {
+    fn rings_survive_counter_wrap() {
+        // Jump the message and waiter ring counters to where billions of operations would have left
+        // them (around the 32-bit boundary and far past it) and cycle the rings from there: each
+        // round must fill to exactly capacity, drain in order and then report empty.
+        const DEPTH: usize = 3;
+        let Some(ep) = endpoint_alloc() else {
+            kwarn!("ipc: no endpoint, ring test skipped");
+            return;
+        };
+        let epi = ep as usize - 1;
+        kassert!(
+            endpoint_init(ep, DEPTH, 8, false),
+            "endpoint init failed"
+        );
+        let mut bad = None;
+        for start in [
+            0,
+            u32::MAX as u64 - 1,
+            5_000_000_000,
+            1 << 40,
+            (1 << 62) + 1,
+        ] {
+            unsafe {
+                let e = endpoint_mut(epi);
+                for c in [&e.head, &e.tail, &e.wait_head, &e.wait_tail] {
+                    c.store(start, Ordering::Relaxed);
+                }
+            }
+            for round in 0..2 * DEPTH * MAX_WAITERS {
+                let mut pushed = 0;
+                while unsafe { push(epi, 1, &[pushed as u8], 0, 0) } == 1 {
+                    pushed += 1;
+                }
+                let mut popped = 0;
+                let mut b = [0u8; 8];
+                while let Ok((1, _, _)) = unsafe { pop(epi, &mut b) } {
+                    if b[0] != popped as u8 {
+                        bad = Some((start, round, "message order"));
+                    }
+                    popped += 1;
+                }
+                let mut waiters = 0;
+                while waiter_push(ep, waiters) {
+                    waiters += 1;
+                }
+                let mut woken = 0;
+                while let Some(pid) = waiter_pop(ep) {
+                    if pid != woken {
+                        bad = Some((start, round, "waiter order"));
+                    }
+                    woken += 1;
+                }
+                if (pushed, popped) != (DEPTH, DEPTH)
+                    || (waiters, woken) != (MAX_WAITERS, MAX_WAITERS)
+                {
+                    bad = Some((start, round, "false full/empty"));
+                }
+                // Step both rings one slot so the next round fills from a different offset.
+                unsafe {
+                    push(epi, 1, &[0], 0, 0);
+                    let _ = pop(epi, &mut b);
+                }
+                waiter_push(ep, 0);
+                waiter_pop(ep);
+            }
+        }
+        endpoint_free(ep);
+        kassert!(bad.is_none(), "rings failed at {:?}", bad);
+    }
+}
+
+ktest! {
+    fn ring_counts_traffic() {
+        // Overflow a depth-4 endpoint with six sends, drain two and send once more: the counters
+        // must show five sent, two dropped, two received and a high-water mark of four.
+        const DEPTH: usize = 4;
+        let Some(ep) = endpoint_alloc() else {
+            kwarn!("ipc: no endpoint for the stats test");
+            return;
+        };
+        let epi = ep as usize - 1;
+        kassert!(endpoint_init(ep, DEPTH, 8, false), "stats test init");
+        let mut full = 0;
+        let mut b = [0u8; 8];
+        unsafe {
+            for i in 0..DEPTH + 2 {
+                if push(epi, 1, &[i as u8], 0, 0) == error::FULL {
+                    full += 1;
+                }
+            }
+            for _ in 0..2 {
+                let _ = pop(epi, &mut b);
+            }
+            push(epi, 1, &[0], 0, 0);
+        }
+        let stats = ep_stats(ep);
+        endpoint_free(ep);
+        let want = EpStats {
+            endpoint: ep as u64,
+            depth: DEPTH as u64,
+            queued: DEPTH as u64 - 1,
+            sent: DEPTH as u64 + 1,
+            received: 2,
+            dropped: 2,
+            high_water: DEPTH as u64,
+        };
+        kassert!(
+            full == 2 && stats == Some(want),
+            "endpoint stats {:?} after {} refused sends, want {:?}",
+            stats,
+            full,
+            want
+        );
+        kassert!(ep_stats(ep).is_none(), "stats for a freed endpoint");
     }
 }
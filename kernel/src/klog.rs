@@ -0,0 +1,83 @@
+// Leveled kernel logging to serial. Messages above the current level are dropped before
+// any formatting happens.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::serial;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    /// Parse a `loglevel=` value ("error", "warn", "info", "debug", "trace").
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    (level as u8) <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn emit(level: Level, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let prefix = match level {
+        Level::Error => "error: ",
+        Level::Warn => "warn: ",
+        _ => "",
+    };
+    let mut w = serial::Writer;
+    let _ = w.write_str(prefix);
+    let _ = w.write_fmt(args);
+    let _ = w.write_str("\n");
+}
+
+macro_rules! klog {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::klog::enabled($level) {
+            $crate::klog::emit($level, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! kerror {
+    ($($arg:tt)+) => { klog!($crate::klog::Level::Error, $($arg)+) };
+}
+
+macro_rules! kwarn {
+    ($($arg:tt)+) => { klog!($crate::klog::Level::Warn, $($arg)+) };
+}
+
+macro_rules! kinfo {
+    ($($arg:tt)+) => { klog!($crate::klog::Level::Info, $($arg)+) };
+}
+
+macro_rules! kdebug {
+    ($($arg:tt)+) => { klog!($crate::klog::Level::Debug, $($arg)+) };
+}
+
+#[allow(unused_macros)]
+macro_rules! ktrace {
+    ($($arg:tt)+) => { klog!($crate::klog::Level::Trace, $($arg)+) };
+}
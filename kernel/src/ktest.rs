@@ -0,0 +1,208 @@
+// In-kernel test harness, built in with the `ktest` feature (MANTRA_KTEST=1 in
+// tools/build.sh). `ktest!` puts each test in the `.ktests` linker section, and `run`
+// calls them in link order. It prints `ok` or `FAILED` per test and exits QEMU with the
+// aggregate status through the debug-exit port. `_start` calls `run` instead of starting
+// the boot programs when the kernel command line has the word `ktest` (tools/build.sh
+// adds it along with the feature); without it a ktest kernel boots as usual.
+//
+// A failing test panics. The panic handler passes the panic to `on_panic`, which jumps
+// back into `run` setjmp/longjmp style (callee-saved registers, stack pointer and resume
+// address are saved before each test), and the next test still runs. Whatever the failed
+// test held (heap blocks, locks) is abandoned, so tests should not hold shared locks.
+
+/// Define a test function and register it with the harness. `#[should_panic]` inverts
+/// the verdict. Without the `ktest` feature the test is compiled out.
+macro_rules! ktest {
+    (@register $should_panic:expr, $name:ident, $body:block) => {
+        #[cfg(feature = "ktest")]
+        fn $name() $body
+
+        #[cfg(feature = "ktest")]
+        const _: () = {
+            #[used]
+            #[link_section = ".ktests"]
+            static TEST: $crate::ktest::Test = $crate::ktest::Test {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                run: $name,
+                should_panic: $should_panic,
+            };
+        };
+    };
+    (#[should_panic] fn $name:ident() $body:block) => {
+        ktest!(@register true, $name, $body);
+    };
+    (fn $name:ident() $body:block) => {
+        ktest!(@register false, $name, $body);
+    };
+}
+
+#[cfg(feature = "ktest")]
+pub use harness::*;
+
+#[cfg(feature = "ktest")]
+mod harness {
+    use core::arch::global_asm;
+    use core::fmt::{self, Write};
+    use core::panic::PanicInfo;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::arch::x86_64::debugcon;
+    use crate::arch::x86_64::interrupts;
+    use crate::serial;
+
+    /// One registered test; built by `ktest!`.
+    pub struct Test {
+        pub name: &'static str,
+        pub run: fn(),
+        pub should_panic: bool,
+    }
+
+    extern "C" {
+        // Linker-script symbols bounding `.ktests`, an array of `Test`.
+        static __ktests_start: u8;
+        static __ktests_end: u8;
+
+        // Save rbx, rbp, r12-r15, the stack pointer and the return address in `buf`, then
+        // call `f(arg)`. Returns 0 when `f` returns, 1 when `mantra_ktest_unwind` comes back.
+        fn mantra_ktest_call(buf: *mut [u64; 8], f: extern "C" fn(u64), arg: u64) -> u64;
+        // Return from the `mantra_ktest_call` that filled `buf`, with 1.
+        fn mantra_ktest_unwind(buf: *const [u64; 8]) -> !;
+    }
+
+    global_asm!(
+        r#"
+.intel_syntax noprefix
+.global mantra_ktest_call
+.type mantra_ktest_call, @function
+mantra_ktest_call:
+    mov [rdi + 0], rbx
+    mov [rdi + 8], rbp
+    mov [rdi + 16], r12
+    mov [rdi + 24], r13
+    mov [rdi + 32], r14
+    mov [rdi + 40], r15
+    // Stack pointer and resume address as our own `ret` would leave them.
+    lea rax, [rsp + 8]
+    mov [rdi + 48], rax
+    mov rax, [rsp]
+    mov [rdi + 56], rax
+    mov rdi, rdx
+    sub rsp, 8
+    call rsi
+    add rsp, 8
+    xor eax, eax
+    ret
+
+.global mantra_ktest_unwind
+.type mantra_ktest_unwind, @function
+mantra_ktest_unwind:
+    mov rbx, [rdi + 0]
+    mov rbp, [rdi + 8]
+    mov r12, [rdi + 16]
+    mov r13, [rdi + 24]
+    mov r14, [rdi + 32]
+    mov r15, [rdi + 40]
+    mov rsp, [rdi + 48]
+    mov eax, 1
+    jmp qword ptr [rdi + 56]
+.att_syntax
+"#
+    );
+
+    // Resume point of the test running now; only valid while RUNNING.
+    static mut JUMP: [u64; 8] = [0; 8];
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    fn tests() -> &'static [Test] {
+        unsafe {
+            let start = (&raw const __ktests_start).cast::<Test>();
+            let end = (&raw const __ktests_end).cast::<Test>();
+            core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+        }
+    }
+
+    // Test output goes to serial and, under QEMU, to the debug console as well.
+    fn out(args: fmt::Arguments) {
+        let _ = serial::Writer.write_fmt(args);
+        let _ = debugcon::Writer.write_fmt(args);
+    }
+
+    extern "C" fn run_one(test: u64) {
+        let test = unsafe { &*(test as *const Test) };
+        (test.run)();
+    }
+
+    /// Run every registered test, report, and exit QEMU with the aggregate status. Never
+    /// returns: halts if there is no exit device.
+    pub fn run() {
+        let tests = tests();
+        let irqs = interrupts::are_enabled();
+        out(format_args!("ktest: running {} tests\n", tests.len()));
+        let mut failed = 0;
+        for test in tests {
+            RUNNING.store(true, Ordering::Relaxed);
+            let panicked =
+                unsafe { mantra_ktest_call(&raw mut JUMP, run_one, test as *const Test as u64) }
+                    != 0;
+            RUNNING.store(false, Ordering::Relaxed);
+            if panicked && irqs {
+                // The panic handler disabled interrupts.
+                unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+            }
+            let ok = panicked == test.should_panic;
+            if !ok {
+                failed += 1;
+            }
+            out(format_args!(
+                "ktest: {} ... {}\n",
+                test.name,
+                if ok { "ok" } else { "FAILED" }
+            ));
+        }
+        let status = if failed == 0 {
+            debugcon::PASS
+        } else {
+            debugcon::FAIL
+        };
+        debugcon::finish(
+            status,
+            format_args!("ktest passed={} failed={}", tests.len() - failed, failed),
+        );
+        kwarn!("ktest: no exit device, halting");
+        loop {
+            unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)) };
+        }
+    }
+
+    /// Called first thing by the panic handler: a panic inside a test is reported and
+    /// resumes `run` at the next test. Returns (and the panic proceeds) otherwise.
+    pub fn on_panic(info: &PanicInfo) {
+        if !RUNNING.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        match info.location() {
+            Some(loc) => out(format_args!(
+                "ktest: panicked: {} at {}:{}\n",
+                info.message(),
+                loc.file(),
+                loc.line()
+            )),
+            None => out(format_args!("ktest: panicked: {}\n", info.message())),
+        }
+        unsafe { mantra_ktest_unwind(&raw const JUMP) }
+    }
+}
+
+ktest! {
+    fn passing_assertion_is_ok() {
+        let sum: u64 = (1..=10).sum();
+        kassert!(sum == 55, "1..=10 summed to {}", sum);
+    }
+}
+
+ktest! {
+    #[should_panic]
+    fn failed_assertion_is_caught() {
+        kassert!(1 + 1 == 3, "this test fails on purpose");
+    }
+}
@@ -0,0 +1,103 @@
+// Boot manifest: which programs to start, and with what role, once the kernel is up.
+// There is no initrd, and the command line doesn't carry it, so the manifest comes from
+// MANTRA_MANIFEST at build time. Entries are `<program> <role>`, separated by newlines or `;`; `#` starts a
+// comment. Programs are `init` (the embedded image) and `yield` (see `user::program_id`).
+// The first valid entry becomes pid 0. Without any valid entry the embedded init is
+// started as ROLE_INIT.
+
+use crate::sched;
+use crate::user;
+
+pub const MAX_ENTRIES: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub prog_id: u64,
+    pub role: u64,
+}
+
+const DEFAULT: Entry = Entry {
+    prog_id: user::INIT_PROG_ID,
+    role: sched::ROLE_INIT,
+};
+
+fn parse_entry(text: &str) -> Result<Entry, &'static str> {
+    let mut words = text.split_whitespace();
+    let name = words.next().ok_or("empty entry")?;
+    let prog_id = user::program_id(name).ok_or("unknown program")?;
+    let role = words
+        .next()
+        .ok_or("missing role")?
+        .parse::<u64>()
+        .map_err(|_| "bad role")?;
+    if words.next().is_some() {
+        return Err("trailing fields");
+    }
+    Ok(Entry { prog_id, role })
+}
+
+/// Fill `out` from `manifest`, skipping malformed entries. Returns the number kept.
+pub fn parse(manifest: &str, out: &mut [Entry; MAX_ENTRIES]) -> usize {
+    let mut n = 0;
+    for raw in manifest.split(['\n', ';']) {
+        let text = raw.split('#').next().unwrap_or("").trim();
+        if text.is_empty() {
+            continue;
+        }
+        if n == MAX_ENTRIES {
+            kwarn!(
+                "launcher: more than {} entries, ignoring the rest",
+                MAX_ENTRIES
+            );
+            break;
+        }
+        match parse_entry(text) {
+            Ok(e) => {
+                out[n] = e;
+                n += 1;
+            }
+            Err(why) => kwarn!("launcher: skipping \"{}\": {}", text, why),
+        }
+    }
+    n
+}
+
+/// Start the boot programs; does not return (enters the first one in ring 3).
+pub fn run() -> ! {
+    let mut entries = [DEFAULT; MAX_ENTRIES];
+    let n = match option_env!("MANTRA_MANIFEST") {
+        Some(m) => parse(m, &mut entries),
+        None => 0,
+    };
+    let n = if n == 0 {
+        kinfo!("launcher: no manifest entries, starting embedded init");
+        entries[0] = DEFAULT;
+        1
+    } else {
+        kinfo!("launcher: {} manifest entries", n);
+        n
+    };
+    user::enter_first_user(&entries[..n])
+}
+
+ktest! {
+    fn parse_keeps_valid_entries() {
+        // Both separators and comments; an unknown program, a missing or bad role and a
+        // trailing field are each skipped.
+        let mut out = [DEFAULT; MAX_ENTRIES];
+        let manifest = "# boot set\ninit 0 ; yield 1 # spinner\n\n;;bogus 1;init;yield x;init 0 2";
+        let n = parse(manifest, &mut out);
+        let want = [
+            Entry { prog_id: user::INIT_PROG_ID, role: 0 },
+            Entry { prog_id: user::YIELD_PROG_ID, role: 1 },
+        ];
+        kassert!(out[..n] == want, "parsed {:?}", &out[..n]);
+
+        // Entries past MAX_ENTRIES are dropped, malformed ones don't count towards it.
+        let manifest = "init 0;bad;yield 1;yield 1;yield 1;yield 1;yield 1;yield 1;yield 1;init 0";
+        let n = parse(manifest, &mut out);
+        kassert!(n == MAX_ENTRIES, "kept {} of 9 entries", n);
+        kassert!(out[MAX_ENTRIES - 1].prog_id == user::YIELD_PROG_ID, "kept the 9th entry");
+        kassert!(parse("# nothing\n;\n", &mut out) == 0, "empty manifest gave entries");
+    }
+}
@@ -0,0 +1,130 @@
+// Resource budgets for unprivileged procs, so a runaway program cannot spawn or allocate
+// its way through the proc, endpoint and frame tables. The command line doesn't carry
+// them, so overrides come from MANTRA_LIMITS at build time: comma-separated `<key>=<n>` with keys
+// `children`, `pages`, `caps`, `endpoints` and `total_pages`; unset keys keep the
+// defaults. Privileged procs (ROLE_INIT) are never limited.
+
+use mantra_sys::error;
+
+use crate::sched;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Resource {
+    // Live children of one proc.
+    Children,
+    // User pages mapped by one proc.
+    Pages,
+    // Occupied cap slots of one proc.
+    Caps,
+    // Endpoints created (and not yet destroyed) by one proc.
+    Endpoints,
+    // User pages mapped by all procs together.
+    TotalPages,
+}
+
+const KEYS: [(&str, Resource); 5] = [
+    ("children", Resource::Children),
+    ("pages", Resource::Pages),
+    ("caps", Resource::Caps),
+    ("endpoints", Resource::Endpoints),
+    ("total_pages", Resource::TotalPages),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub children: u64,
+    pub pages: u64,
+    pub caps: u64,
+    pub endpoints: u64,
+    pub total_pages: u64,
+}
+
+impl Limits {
+    fn slot(&mut self, what: Resource) -> &mut u64 {
+        match what {
+            Resource::Children => &mut self.children,
+            Resource::Pages => &mut self.pages,
+            Resource::Caps => &mut self.caps,
+            Resource::Endpoints => &mut self.endpoints,
+            Resource::TotalPages => &mut self.total_pages,
+        }
+    }
+
+    pub fn of(mut self, what: Resource) -> u64 {
+        *self.slot(what)
+    }
+}
+
+pub const DEFAULT: Limits = Limits {
+    children: 2,
+    pages: 1024,
+    caps: 16,
+    endpoints: 8,
+    total_pages: 16 * 1024,
+};
+
+static mut LIMITS: Limits = DEFAULT;
+
+/// Apply `spec` on top of `base`; the first bad entry fails the whole spec.
+pub fn parse(spec: &str, base: Limits) -> Result<Limits, &'static str> {
+    let mut out = base;
+    for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = item.split_once('=').ok_or("entry without '='")?;
+        let what = KEYS
+            .iter()
+            .find(|(k, _)| *k == key.trim())
+            .map(|&(_, what)| what)
+            .ok_or("unknown key")?;
+        *out.slot(what) = value.trim().parse().map_err(|_| "bad number")?;
+    }
+    Ok(out)
+}
+
+/// Load MANTRA_LIMITS over the defaults. Call once, before the first proc starts.
+pub fn init() {
+    let limits = match option_env!("MANTRA_LIMITS").map(|s| parse(s, DEFAULT)) {
+        Some(Ok(l)) => l,
+        Some(Err(why)) => {
+            kwarn!("limits: ignoring MANTRA_LIMITS: {}", why);
+            DEFAULT
+        }
+        None => DEFAULT,
+    };
+    unsafe { LIMITS = limits };
+    kinfo!(
+        "limits: children={} pages={} caps={} endpoints={} total_pages={}",
+        limits.children,
+        limits.pages,
+        limits.caps,
+        limits.endpoints,
+        limits.total_pages
+    );
+}
+
+pub fn get() -> Limits {
+    unsafe { LIMITS }
+}
+
+fn within(privileged: bool, what: Resource, wanted: u64) -> Result<(), u64> {
+    let limit = get().of(what);
+    if privileged || wanted <= limit {
+        return Ok(());
+    }
+    kdebug!(
+        "limits: over the {:?} budget ({} > {})",
+        what,
+        wanted,
+        limit
+    );
+    Err(error::LIMIT)
+}
+
+/// Ok if `pid` may hold `wanted` of `what` in total, else `error::LIMIT`.
+pub fn check(pid: usize, what: Resource, wanted: u64) -> Result<(), u64> {
+    within(sched::is_privileged(pid), what, wanted)
+}
+
+/// `check` for a proc that does not exist yet and will run with `role`.
+pub fn check_role(role: u64, what: Resource, wanted: u64) -> Result<(), u64> {
+    within(role == sched::ROLE_INIT, what, wanted)
+}
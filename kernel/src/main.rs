@@ -9,25 +9,62 @@ use core::fmt::Write;
 use core::panic::PanicInfo;
 use mantra_bootinfo::{BootInfo, MemoryRegion, PixelFormat, RegionKind};
 
+// Macros first: textual order matters for `macro_rules!`.
+#[macro_use]
+mod bug;
+#[macro_use]
+mod klog;
+#[macro_use]
+mod ktest;
+
 mod arch;
+#[cfg(feature = "ktest")]
+mod block;
+mod boot_metrics;
+mod cmdline;
 mod fb;
 mod heap;
+#[cfg(mantra_heaptrack)]
+mod heap_track;
 mod init_elf;
 mod ipc;
+mod launcher;
+mod limits;
+mod oom;
+mod perf;
 mod pmm;
+mod rng;
 mod sched;
 mod serial;
+mod swtrace;
+mod symbols;
+mod sysinfo;
+mod timer;
 mod user;
+mod util;
 
 #[no_mangle]
 pub extern "sysv64" fn _start(boot_info: *const BootInfo) -> ! {
     serial::init();
+    boot_metrics::mark(boot_metrics::Milestone::Serial);
+    // Set before the boot info (and so the command line) is read: `loglevel=` is taken from
+    // MANTRA_LOGLEVEL at build time.
+    if let Some(level) = option_env!("MANTRA_LOGLEVEL").and_then(klog::Level::parse) {
+        klog::set_level(level);
+    }
     serial::write_str("mantracore: entered kernel\n");
 
     // Firmware may leave IF=1. Keep interrupts off until IDT/PIC/PIT/scheduler are ready.
     unsafe { core::arch::asm!("cli", options(nomem, nostack, preserves_flags)) };
 
     arch::init();
+    rng::init();
+    // Likewise `sched=det` is MANTRA_SCHED=det at build time.
+    if option_env!("MANTRA_SCHED") == Some("det") {
+        sched::set_deterministic(sched::DET_SEED);
+    }
+    perf::init();
+    boot_metrics::mark(boot_metrics::Milestone::Arch);
 
     let bi = unsafe { boot_info.as_ref() };
     if bi.is_none() {
@@ -48,6 +85,8 @@ pub extern "sysv64" fn _start(boot_info: *const BootInfo) -> ! {
             }
         }
     }
+    cmdline::init(bi);
+    kinfo!("mantracore: cmdline \"{}\"", cmdline::get());
 
     let regions: &[MemoryRegion] = if bi.regions_ptr != 0 && bi.regions_len != 0 {
         unsafe {
@@ -74,43 +113,50 @@ pub extern "sysv64" fn _start(boot_info: *const BootInfo) -> ! {
     let format = match bi.fb_format {
         x if x == PixelFormat::Rgb as u32 => PixelFormat::Rgb,
         x if x == PixelFormat::Bgr as u32 => PixelFormat::Bgr,
+        x if x == PixelFormat::Bitmask as u32 => PixelFormat::Bitmask,
         _ => PixelFormat::Unknown,
     };
 
-    let mut con = fb::Console::new(fb::FrameBuffer {
+    let mut con = fb::BootConsole::new(fb::FrameBuffer {
         base: bi.fb_base as *mut u8,
         size: bi.fb_size as usize,
         width: bi.fb_width as usize,
         height: bi.fb_height as usize,
         stride: bi.fb_stride as usize,
         format,
+        bpp: bi.fb_bpp as usize,
+        masks: [bi.fb_red_mask, bi.fb_green_mask, bi.fb_blue_mask],
     });
 
-    con.clear(fb::Rgb {
-        r: 0x08,
-        g: 0x0b,
-        b: 0x10,
-    });
-    con.set_colors(
-        fb::Rgb {
-            r: 0xe8,
-            g: 0xef,
-            b: 0xff,
-        },
-        fb::Rgb {
+    if let Some(screen) = con.screen() {
+        fb::set_panic_target(&screen.fb);
+        screen.clear(fb::Rgb {
             r: 0x08,
             g: 0x0b,
             b: 0x10,
-        },
-    );
+        });
+        screen.set_colors(
+            fb::Rgb {
+                r: 0xe8,
+                g: 0xef,
+                b: 0xff,
+            },
+            fb::Rgb {
+                r: 0x08,
+                g: 0x0b,
+                b: 0x10,
+            },
+        );
+        serial::write_str("mantracore: framebuffer initialized\n");
+    }
 
     writeln!(&mut con, "MantraOS").ok();
     writeln!(&mut con, "BootInfo v{} OK", bi.version).ok();
     writeln!(&mut con, "Regions: {}", regions.len()).ok();
     writeln!(
         &mut con,
-        "FB {}x{} stride={} fmt={:?}",
-        bi.fb_width, bi.fb_height, bi.fb_stride, format
+        "FB {}x{} stride={} fmt={:?} bpp={}",
+        bi.fb_width, bi.fb_height, bi.fb_stride, format, bi.fb_bpp
     )
     .ok();
     writeln!(&mut con, "FB base={:#x} size={:#x}", bi.fb_base, bi.fb_size).ok();
@@ -121,10 +167,21 @@ pub extern "sysv64" fn _start(boot_info: *const BootInfo) -> ! {
     )
     .ok();
 
-    serial::write_str("mantracore: framebuffer initialized\n");
-
-    match pmm::init(regions) {
+    // Firmware may keep the framebuffer in memory it types as something other than video
+    // memory; keep the allocator off it so no frame aliases the screen.
+    if let Some(r) = pmm::framebuffer_conflict(regions, bi.fb_base, bi.fb_size) {
+        kwarn!(
+            "mantracore: framebuffer {:#x}+{:#x} overlaps region {:#x}+{:#x} kind={}, reserving it",
+            bi.fb_base,
+            bi.fb_size,
+            r.base,
+            r.len,
+            r.kind
+        );
+    }
+    match pmm::init(regions, &[(bi.fb_base, bi.fb_size)]) {
         Ok(stats) => {
+            boot_metrics::mark(boot_metrics::Milestone::Pmm);
             serial::write_str("mantracore: pmm initialized\n");
             let _ = writeln!(
                 &mut con,
@@ -136,92 +193,129 @@ pub extern "sysv64" fn _start(boot_info: *const BootInfo) -> ! {
 
             for n in 0..3 {
                 if let Some(p) = pmm::alloc_frame() {
-                    serial::write_str("mantracore: alloc_frame ok ");
-                    serial::write_hex_u64(p);
-                    serial::write_str("\n");
+                    kdebug!("mantracore: alloc_frame ok {:#x}", p);
                     let _ = writeln!(&mut con, "Frame{} {:#x}", n, p);
                 } else {
-                    serial::write_str("mantracore: alloc_frame failed\n");
+                    kwarn!("mantracore: alloc_frame failed");
                     let _ = writeln!(&mut con, "Frame{} FAIL", n);
                 }
             }
 
-            // Take ownership of paging (identity map enough RAM for kernel+fb).
-            let mut max_phys = bi.kernel_phys_end;
-            let fb_end = bi.fb_base.saturating_add(bi.fb_size);
-            if fb_end > max_phys {
-                max_phys = fb_end;
-            }
-            // Keep some headroom for page tables and early allocations.
-            max_phys = max_phys.saturating_add(512 * 1024 * 1024);
+            // Take ownership of paging: the HHDM covers all RAM in the memory map, so every
+            // frame the PMM hands out is reachable. A framebuffer above it goes via KMAP.
+            let max_phys = pmm::direct_map_end(regions).max(bi.kernel_phys_end) - 1;
             arch::init_paging(max_phys);
+            boot_metrics::mark(boot_metrics::Milestone::Paging);
+            symbols::init(bi.kernel_file_ptr, bi.kernel_file_len);
 
-            // Switch framebuffer pointer to the higher-half direct map.
-            con.fb.base = crate::arch::x86_64::paging::phys_to_virt_ptr(bi.fb_base);
+            // Switch framebuffer pointer to the higher-half direct map. Framebuffers at very
+            // high physical addresses (discrete GPUs) can sit beyond the HHDM; map those
+            // explicitly into the KMAP window instead.
+            if let Some(screen) = con.screen() {
+                let v = crate::arch::x86_64::paging::map_framebuffer(bi.fb_base, bi.fb_size);
+                if !crate::arch::x86_64::paging::is_hhdm_addr(v) {
+                    serial::write_str("mantracore: fb beyond HHDM, kmap v=");
+                    serial::write_hex_u64(v);
+                    serial::write_str("\n");
+                }
+                let mut fb = screen.fb;
+                fb.base = v as *mut u8;
+                if v == 0 {
+                    fb.size = 0;
+                }
+                // Same mode at a new address: redraw what was shown from the scrollback.
+                if screen.reinit(fb).is_err() {
+                    // Unusable (size 0): nothing is drawn, but never at the old address.
+                    screen.fb = fb;
+                }
+                fb::set_panic_target(&screen.fb);
+            }
 
-            heap::init();
-            crate::arch::x86_64::paging::kmap_smoke_test();
+            // Optional RAM test before the heap claims its region. The command line only
+            // carries `ktest` so far, so it's selected at build time via MANTRA_MEMTEST.
+            let memtest = match option_env!("MANTRA_MEMTEST") {
+                Some("full") => pmm::Memtest::Full,
+                Some("1") | Some("sample") => pmm::Memtest::Sample,
+                _ => pmm::Memtest::Off,
+            };
+            pmm::memtest(memtest);
 
-            // Heap smoke test (forces `alloc` to work).
-            {
-                use alloc::boxed::Box;
-                use alloc::vec::Vec;
+            heap::init(stats.free_bytes);
+            ipc::init_sysinfo();
+            limits::init();
+            boot_metrics::mark(boot_metrics::Milestone::Heap);
+            crate::arch::x86_64::lapic::init();
 
-                let mut v: Vec<u64> = Vec::new();
-                for i in 0..16u64 {
-                    v.push(i * 3);
-                }
-                let b = Box::new(0xdead_beef_u64);
+            heap::dump_leaks();
 
-                serial::write_str("heap: vec_len=");
-                serial::write_dec_u64(v.len() as u64);
-                serial::write_str(" box=");
-                serial::write_hex_u64(*b);
-                serial::write_str("\n");
+            // Deliberately kill the machine to exercise the double-fault dump.
+            if option_env!("MANTRA_DFTEST") == Some("1") {
+                crate::arch::x86_64::idt::provoke_double_fault();
+            }
+            // `ktest` on the command line runs the `ktest!` tests and exits here.
+            if cmdline::has("ktest") {
+                #[cfg(feature = "ktest")]
+                ktest::run();
+                #[cfg(not(feature = "ktest"))]
+                kwarn!("mantracore: ktest requested, but built without the ktest feature");
             }
 
-            // First ring3 smoke test (int 0x80 back into kernel).
-            user::enter_first_user(bi.kernel_phys_base, bi.kernel_phys_end, max_phys);
-        }
-        Err(_) => {
-            serial::write_str("mantracore: pmm init failed\n");
-            let _ = writeln!(&mut con, "PMM init failed");
-        }
-    }
-
-    // Enable IRQ delivery once the console + PMM are up (so we can debug easily).
-    arch::enable_interrupts();
+            boot_metrics::mark(boot_metrics::Milestone::FirstUser);
+            boot_metrics::report();
 
-    // Prove the IDT works (exception path) before we move to scheduler/VM work.
-    unsafe {
-        core::arch::asm!("int3");
-    }
-
-    // Visible "alive" marker (diagonal line).
-    for i in 0..core::cmp::min(con.fb.width, con.fb.height) {
-        con.fb.put_pixel(
-            i,
-            i,
-            fb::Rgb {
-                r: 0x5a,
-                g: 0xff,
-                b: 0x86,
-            },
-        );
-    }
+            // From here the screen belongs to user programs (`syscall::CON_WRITE`); kernel
+            // logs keep going to serial.
+            if let fb::BootConsole::Screen(screen) = con {
+                fb::swap_console(Some(screen));
+            }
 
-    loop {
-        unsafe {
-            core::arch::asm!("hlt");
+            // Start the boot programs (ring 3; int 0x80 back into the kernel).
+            launcher::run();
+        }
+        Err(e) => {
+            // Nothing past this point works without a frame allocator: report and stop.
+            kerror!("mantracore: pmm init failed: {}", e.reason());
+            let _ = writeln!(&mut con, "PMM init failed: {}", e.reason());
+            let _ = writeln!(&mut con, "System halted.");
+            loop {
+                unsafe {
+                    core::arch::asm!("cli; hlt", options(nomem, nostack));
+                }
+            }
         }
     }
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+    #[cfg(feature = "ktest")]
+    ktest::on_panic(info);
+    let mut w = serial::Writer;
+    let _ = write!(&mut w, "\nKERNEL PANIC: {}", info.message());
+    if let Some(loc) = info.location() {
+        let _ = write!(&mut w, " at {}:{}:{}", loc.file(), loc.line(), loc.column());
+    }
+    let _ = writeln!(&mut w);
+    crate::arch::x86_64::debugcon::finish(
+        crate::arch::x86_64::debugcon::FAIL,
+        format_args!("{}", info.message()),
+    );
+    match info.location() {
+        Some(loc) => fb::panic_screen(format_args!(
+            "{} at {}:{}:{}",
+            info.message(),
+            loc.file(),
+            loc.line(),
+            loc.column()
+        )),
+        None => fb::panic_screen(format_args!("{}", info.message())),
+    }
+    bug::dump_state();
+    swtrace::dump();
     loop {
         unsafe {
-            core::arch::asm!("hlt");
+            core::arch::asm!("cli; hlt", options(nomem, nostack));
         }
     }
 }
@@ -0,0 +1,23 @@
+use crate::pmm;
+use crate::sched;
+
+/// Out-of-memory policy for user-triggered allocations: kill the process with the most mapped
+/// pages (never init or `caller`) so its frames return to the PMM. Returns false if there was
+/// no victim; the caller should then fail with `error::NO_MEMORY`.
+pub fn reclaim(caller: usize) -> bool {
+    let Some(victim) = sched::oom_select(caller) else {
+        kwarn!("oom: no victim, failing allocation");
+        return false;
+    };
+    let before = pmm::free_frames();
+    let Some(pages) = sched::kill(victim) else {
+        return false;
+    };
+    kwarn!(
+        "oom: killed pid={} mapped_pages={} reclaimed_frames={}",
+        victim,
+        pages,
+        pmm::free_frames().saturating_sub(before)
+    );
+    true
+}
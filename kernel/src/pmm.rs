@@ -1,4 +1,8 @@
 use core::cmp;
+
+use crate::arch::x86_64::paging;
+use crate::serial;
+use crate::util::{align_down, align_up_checked};
 use mantra_bootinfo::{MemoryRegion, RegionKind};
 
 const PAGE_SIZE: u64 = 4096;
@@ -10,6 +14,30 @@ struct Range {
     end: u64, // exclusive
 }
 
+/// Why `init` could not build an allocator from the memory map.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InitError {
+    /// The map has no page-sized usable region at all.
+    NoUsableMemory,
+    /// More disjoint usable regions than the range table holds.
+    TooManyRanges,
+    /// Carving out a reserved region would split a range past the table's capacity.
+    SplitOverflow,
+    /// Usable regions exist, but reservations (and the low 1 MiB) cover all of them.
+    AllReserved,
+}
+
+impl InitError {
+    pub fn reason(self) -> &'static str {
+        match self {
+            InitError::NoUsableMemory => "no usable memory in the firmware memory map",
+            InitError::TooManyRanges => "too many usable memory ranges",
+            InitError::SplitOverflow => "too many ranges after subtracting reserved memory",
+            InitError::AllReserved => "all usable memory is reserved",
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct PmmStats {
     pub usable_bytes: u64,
@@ -39,24 +67,17 @@ struct Pmm {
     ranges: [Range; MAX_RANGES],
     len: usize,
     cursor: usize,
+    // Singly-linked list of freed frames; the next pointer lives in the frame (via HHDM).
+    free_head: u64,
+    free_count: u64,
+    usable_bytes: u64,
+    // The bootloader's region array (physical; firmware identity map at `init` time).
+    regions_phys: u64,
+    regions_len: usize,
 }
 
 static PMM: StaticCell<Option<Pmm>> = StaticCell::new(None);
 
-fn align_up(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    (x + (a - 1)) & !(a - 1)
-}
-
-fn align_down(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    x & !(a - 1)
-}
-
 fn overlaps(a0: u64, a1: u64, b0: u64, b1: u64) -> bool {
     a0 < b1 && b0 < a1
 }
@@ -157,58 +178,96 @@ fn subtract_reserved(ranges: &mut [Range], len: &mut usize, res_base: u64, res_e
     true
 }
 
-pub fn init(regions: &[MemoryRegion]) -> Result<PmmStats, ()> {
+// Usable RAM the PMM may hand out. Runtime-services memory is excluded even if a
+// firmware types it as conventional.
+fn is_free(r: &MemoryRegion) -> bool {
+    r.kind == RegionKind::Usable as u32 && !r.is_runtime()
+}
+
+/// Exclusive end of the RAM in `regions` the kernel has to reach through the HHDM: free
+/// memory plus the kernel image, boot data and ACPI tables. MMIO and holes don't count.
+pub fn direct_map_end(regions: &[MemoryRegion]) -> u64 {
+    const RAM: [RegionKind; 5] = [
+        RegionKind::Usable,
+        RegionKind::Kernel,
+        RegionKind::Boot,
+        RegionKind::AcpiReclaim,
+        RegionKind::AcpiNvs,
+    ];
+    regions
+        .iter()
+        .filter(|r| RAM.iter().any(|&k| r.kind == k as u32))
+        .map(|r| r.base.saturating_add(r.len))
+        .max()
+        .unwrap_or(0)
+}
+
+// Free physical ranges derived from a memory map. Pure (no globals), so the
+// merge/subtract logic can be exercised on any `regions` input.
+struct FreeRanges {
+    ranges: [Range; MAX_RANGES],
+    len: usize,
+    usable_bytes: u64,
+}
+
+// `reserve` holds extra `(base, len)` ranges to keep out on top of the map's own.
+fn free_ranges(regions: &[MemoryRegion], reserve: &[(u64, u64)]) -> Result<FreeRanges, InitError> {
     let mut ranges = [Range::default(); MAX_RANGES];
     let mut len: usize = 0;
     let mut usable_bytes: u64 = 0;
 
     // Collect usable ranges.
     for r in regions {
-        if r.kind != RegionKind::Usable as u32 {
+        if !is_free(r) {
             continue;
         }
-        let base = align_up(r.base, PAGE_SIZE);
+        let Some(base) = align_up_checked(r.base, PAGE_SIZE) else {
+            continue;
+        };
         let end = align_down(r.base.saturating_add(r.len), PAGE_SIZE);
         if end <= base {
             continue;
         }
         usable_bytes = usable_bytes.saturating_add(end - base);
         if len >= ranges.len() {
-            return Err(());
+            return Err(InitError::TooManyRanges);
         }
         ranges[len] = Range { base, end };
         len += 1;
     }
 
     if len == 0 {
-        return Err(());
+        return Err(InitError::NoUsableMemory);
     }
 
     sort_by_base(&mut ranges, len);
     merge_adjacent(&mut ranges, &mut len);
 
-    // Subtract all non-usable ranges (including kernel/boot/framebuffer).
-    for r in regions {
-        if r.kind == RegionKind::Usable as u32 {
+    // Subtract all non-usable ranges (including kernel/boot/framebuffer), then the extras.
+    let map_reserved = regions
+        .iter()
+        .filter(|r| !is_free(r))
+        .map(|r| (r.base, r.len));
+    for (base, size) in map_reserved.chain(reserve.iter().copied()) {
+        if size == 0 {
             continue;
         }
-        if r.len == 0 {
-            continue;
-        }
-        let res_base = align_down(r.base, PAGE_SIZE);
-        let res_end = align_up(r.base.saturating_add(r.len), PAGE_SIZE);
+        let res_base = align_down(base, PAGE_SIZE);
+        // A reservation running into the last page covers it rather than wrapping to 0.
+        let res_end = align_up_checked(base.saturating_add(size), PAGE_SIZE)
+            .unwrap_or(align_down(u64::MAX, PAGE_SIZE));
         if res_end <= res_base {
             continue;
         }
         if !subtract_reserved(&mut ranges, &mut len, res_base, res_end) {
-            return Err(());
+            return Err(InitError::SplitOverflow);
         }
     }
 
     // Hard-reserve the first 1 MiB. Even if firmware marks parts as usable,
     // this avoids allocating over low-memory real-mode/firmware structures.
     if !subtract_reserved(&mut ranges, &mut len, 0, 0x10_0000) {
-        return Err(());
+        return Err(InitError::SplitOverflow);
     }
 
     // Drop empty ranges.
@@ -221,8 +280,142 @@ pub fn init(regions: &[MemoryRegion]) -> Result<PmmStats, ()> {
     }
     len = out;
     if len == 0 {
-        return Err(());
+        return Err(InitError::AllReserved);
+    }
+
+    Ok(FreeRanges {
+        ranges,
+        len,
+        usable_bytes,
+    })
+}
+
+ktest! {
+    fn bad_memory_maps_are_rejected() {
+        // Feed `free_ranges` synthetic memory maps that hit each `InitError` cause. Pure, so it
+        // leaves no allocator state behind.
+        const MIB: u64 = 0x10_0000;
+        fn region(base: u64, len: u64, kind: RegionKind) -> MemoryRegion {
+            MemoryRegion {
+                base,
+                len,
+                kind: kind as u32,
+                attr: 0,
+            }
+        }
+        fn check(name: &str, regions: &[MemoryRegion], want: InitError) {
+            let got = free_ranges(regions, &[]).err();
+            kassert!(got == Some(want), "{} map gave {:?}", name, got);
+        }
+
+        check("empty", &[], InitError::NoUsableMemory);
+        check(
+            "reserved-only",
+            &[region(MIB, 16 * MIB, RegionKind::Reserved)],
+            InitError::NoUsableMemory,
+        );
+        check(
+            "low-only",
+            &[region(0x1000, 0x9e000, RegionKind::Usable)],
+            InitError::AllReserved,
+        );
+
+        // One more disjoint (gap-separated, so unmerged) usable page than the table holds.
+        let mut many = [region(0, 0, RegionKind::Usable); MAX_RANGES + 1];
+        for (i, r) in many.iter_mut().enumerate() {
+            *r = region(
+                MIB + (i as u64) * 2 * PAGE_SIZE,
+                PAGE_SIZE,
+                RegionKind::Usable,
+            );
+        }
+        check("fragmented", &many, InitError::TooManyRanges);
+
+        // A full table, then a reservation in the middle of the first range.
+        let mut full = [region(0, 0, RegionKind::Usable); MAX_RANGES + 1];
+        for (i, r) in full.iter_mut().take(MAX_RANGES).enumerate() {
+            *r = region(
+                MIB + (i as u64) * 4 * PAGE_SIZE,
+                3 * PAGE_SIZE,
+                RegionKind::Usable,
+            );
+        }
+        full[MAX_RANGES] = region(MIB + PAGE_SIZE, PAGE_SIZE, RegionKind::Reserved);
+        check("split", &full, InitError::SplitOverflow);
     }
+}
+
+/// The first region of `regions` that the physical range `[base, base + len)` of the
+/// framebuffer overlaps and that is not typed as video memory (`Framebuffer`, `Reserved` or
+/// `Mmio`): typically `Usable` RAM the firmware still draws to.
+pub fn framebuffer_conflict(
+    regions: &[MemoryRegion],
+    base: u64,
+    len: u64,
+) -> Option<&MemoryRegion> {
+    let end = base.saturating_add(len);
+    regions.iter().find(|r| {
+        r.len != 0
+            && r.kind != RegionKind::Framebuffer as u32
+            && r.kind != RegionKind::Reserved as u32
+            && r.kind != RegionKind::Mmio as u32
+            && overlaps(r.base, r.base.saturating_add(r.len), base, end)
+    })
+}
+
+ktest! {
+    fn framebuffer_frames_are_reserved() {
+        // A framebuffer the map types as `Usable` is a conflict, and passing its range to
+        // `free_ranges` keeps every frame of it out of the allocator; one the map already types as
+        // `Framebuffer` is not.
+        const MIB: u64 = 0x10_0000;
+        let usable = MemoryRegion {
+            base: MIB,
+            len: 63 * MIB,
+            kind: RegionKind::Usable as u32,
+            attr: 0,
+        };
+        let (fb_base, fb_len) = (16 * MIB + 0x800, 8 * MIB);
+        kassert!(
+            framebuffer_conflict(&[usable], fb_base, fb_len).is_some(),
+            "framebuffer over usable RAM not flagged"
+        );
+        let Ok(free) = free_ranges(&[usable], &[(fb_base, fb_len)]) else {
+            kassert!(false, "map with a framebuffer reservation rejected");
+            return;
+        };
+        let (fb_first, fb_end) = (
+            align_down(fb_base, PAGE_SIZE),
+            align_up_checked(fb_base + fb_len, PAGE_SIZE).unwrap_or(u64::MAX),
+        );
+        kassert!(
+            free.ranges[..free.len]
+                .iter()
+                .all(|r| !overlaps(r.base, r.end, fb_first, fb_end)),
+            "framebuffer frames left allocatable"
+        );
+
+        let fb = MemoryRegion {
+            base: fb_base,
+            len: fb_len,
+            kind: RegionKind::Framebuffer as u32,
+            attr: 0,
+        };
+        kassert!(
+            framebuffer_conflict(&[fb], fb_base, fb_len).is_none(),
+            "framebuffer region flagged as a conflict with itself"
+        );
+    }
+}
+
+/// Build the allocator from `regions`, also keeping the `(base, len)` ranges in `reserve`
+/// out of it.
+pub fn init(regions: &[MemoryRegion], reserve: &[(u64, u64)]) -> Result<PmmStats, InitError> {
+    let FreeRanges {
+        ranges,
+        len,
+        usable_bytes,
+    } = free_ranges(regions, reserve)?;
 
     let mut free_bytes: u64 = 0;
     for i in 0..len {
@@ -234,6 +427,11 @@ pub fn init(regions: &[MemoryRegion]) -> Result<PmmStats, ()> {
             ranges,
             len,
             cursor: 0,
+            free_head: 0,
+            free_count: 0,
+            usable_bytes,
+            regions_phys: regions.as_ptr() as u64,
+            regions_len: regions.len(),
         });
     }
 
@@ -245,9 +443,100 @@ pub fn init(regions: &[MemoryRegion]) -> Result<PmmStats, ()> {
 }
 
 pub fn alloc_frame() -> Option<u64> {
+    unsafe {
+        let slot = &mut *PMM.get();
+        let pmm = slot.as_mut()?;
+        if pmm.free_head != 0 {
+            let p = pmm.free_head;
+            pmm.free_head = *paging::phys_to_virt_ptr::<u64>(p);
+            pmm.free_count -= 1;
+            return Some(p);
+        }
+    }
     alloc_pages(1)
 }
 
+/// Number of frames currently on the free list.
+pub fn free_frames() -> u64 {
+    unsafe {
+        match &*PMM.get() {
+            Some(pmm) => pmm.free_count,
+            None => 0,
+        }
+    }
+}
+
+/// Current totals: usable RAM from the memory map, and what is still unallocated (range
+/// tails plus the free list).
+pub fn stats() -> PmmStats {
+    unsafe {
+        match &*PMM.get() {
+            Some(pmm) => PmmStats {
+                usable_bytes: pmm.usable_bytes,
+                free_bytes: pmm.ranges[..pmm.len]
+                    .iter()
+                    .map(|r| r.end.saturating_sub(r.base))
+                    .sum::<u64>()
+                    + pmm.free_count * PAGE_SIZE,
+                range_count: pmm.len,
+            },
+            None => PmmStats {
+                usable_bytes: 0,
+                free_bytes: 0,
+                range_count: 0,
+            },
+        }
+    }
+}
+
+/// The memory map `init` was given, read back through the HHDM. Empty before paging is up.
+pub fn boot_regions() -> &'static [MemoryRegion] {
+    let (phys, len) = match unsafe { &*PMM.get() } {
+        Some(pmm) => (pmm.regions_phys, pmm.regions_len),
+        None => return &[],
+    };
+    let bytes = (len * core::mem::size_of::<MemoryRegion>()) as u64;
+    if len == 0 || !paging::hhdm_covers(phys, bytes) {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(paging::phys_to_virt_ptr(phys), len) }
+}
+
+/// Call `f(base, end)` for each range the bump allocator has not handed out yet. Frames on
+/// the free list are not included.
+pub fn for_each_free_range(mut f: impl FnMut(u64, u64)) {
+    if let Some(pmm) = unsafe { &*PMM.get() } {
+        for r in &pmm.ranges[..pmm.len] {
+            if r.base < r.end {
+                f(r.base, r.end);
+            }
+        }
+    }
+}
+
+/// Return a single frame obtained from `alloc_frame`/`alloc_pages(1)`.
+pub fn free_frame(phys: u64) {
+    if phys == 0 || !phys.is_multiple_of(PAGE_SIZE) {
+        return;
+    }
+    // The free list links through the frames themselves, so they must be HHDM-reachable.
+    let Some(v) = paging::phys_to_virt_checked(phys) else {
+        serial::write_str("pmm: free_frame beyond HHDM, leaking ");
+        serial::write_hex_u64(phys);
+        serial::write_str("\n");
+        return;
+    };
+    unsafe {
+        let slot = &mut *PMM.get();
+        let Some(pmm) = slot.as_mut() else {
+            return;
+        };
+        *(v as *mut u64) = pmm.free_head;
+        pmm.free_head = phys;
+        pmm.free_count += 1;
+    }
+}
+
 pub fn alloc_pages(pages: u64) -> Option<u64> {
     if pages == 0 {
         return None;
@@ -280,3 +569,255 @@ pub fn alloc_pages(pages: u64) -> Option<u64> {
         None
     }
 }
+
+/// `pages` contiguous frames whose base is aligned to `align_pages` pages (a power of two),
+/// for page-table pools and DMA buffers with hardware alignment rules. Frames skipped to
+/// reach the alignment go on the free list rather than being lost. No such pool or driver
+/// exists yet, so only the ktests build it.
+#[cfg(feature = "ktest")]
+pub fn alloc_aligned(pages: u64, align_pages: u64) -> Option<u64> {
+    if pages == 0 || !align_pages.is_power_of_two() {
+        return None;
+    }
+    let need = pages.checked_mul(PAGE_SIZE)?;
+    let align = align_pages.checked_mul(PAGE_SIZE)?;
+    let (base, skipped) = unsafe {
+        let slot = &mut *PMM.get();
+        let pmm = slot.as_mut()?;
+        let cursor = pmm.cursor;
+        let r = pmm.ranges[cursor..pmm.len].iter_mut().find(|r| {
+            align_up_checked(r.base, align)
+                .and_then(|base| base.checked_add(need))
+                .is_some_and(|end| end <= r.end)
+        })?;
+        let skipped = r.base;
+        let base = align_up_checked(r.base, align)?;
+        r.base = base + need;
+        (base, skipped)
+    };
+    let mut f = skipped;
+    while f < base {
+        free_frame(f);
+        f += PAGE_SIZE;
+    }
+    Some(base)
+}
+
+ktest! {
+    fn alloc_aligned_takes_aligned_frames() {
+        // Ask for 4 frames on a 16 KiB boundary: the base must be aligned, and none of the frames
+        // may still be free (in a range or on the free list). They are freed again afterwards.
+        const PAGES: u64 = 4;
+        let Some(base) = alloc_aligned(PAGES, PAGES) else {
+            kwarn!("pmm: aligned alloc test skipped, no memory");
+            return;
+        };
+        let end = base + PAGES * PAGE_SIZE;
+        kassert!(
+            base % (PAGES * PAGE_SIZE) == 0,
+            "alloc_aligned returned {:#x}, not 16 KiB aligned",
+            base
+        );
+        let mut in_range = false;
+        for_each_free_range(|lo, hi| in_range |= overlaps(lo, hi, base, end));
+        let mut listed = false;
+        unsafe {
+            if let Some(pmm) = &*PMM.get() {
+                let mut p = pmm.free_head;
+                while p != 0 {
+                    listed |= p >= base && p < end;
+                    p = *paging::phys_to_virt_ptr::<u64>(p);
+                }
+            }
+        }
+        kassert!(
+            !in_range && !listed,
+            "aligned frames {:#x}..{:#x} still free",
+            base,
+            end
+        );
+        kassert!(
+            alloc_aligned(1, 3).is_none() && alloc_aligned(0, 1).is_none(),
+            "alloc_aligned accepted a bad request"
+        );
+        for i in 0..PAGES {
+            free_frame(base + i * PAGE_SIZE);
+        }
+    }
+}
+
+// Bytes of `r` the kernel can reach through the HHDM.
+fn hhdm_bytes(r: &Range) -> u64 {
+    let (lo, hi) = paging::hhdm_range();
+    cmp::min(r.end, hi - lo).saturating_sub(r.base)
+}
+
+/// Bytes in the largest HHDM-reachable free range (what one `alloc_largest` can return).
+pub fn largest_range_bytes() -> u64 {
+    unsafe {
+        match &*PMM.get() {
+            Some(pmm) => pmm.ranges[..pmm.len]
+                .iter()
+                .map(hhdm_bytes)
+                .max()
+                .unwrap_or(0),
+            None => 0,
+        }
+    }
+}
+
+/// Like `alloc_pages`, but carve from the largest HHDM-reachable free range so a big
+/// request does not fail just because the cursor's range is small.
+pub fn alloc_largest(pages: u64) -> Option<u64> {
+    if pages == 0 {
+        return None;
+    }
+    let need = pages.checked_mul(PAGE_SIZE)?;
+    unsafe {
+        let slot = &mut *PMM.get();
+        let pmm = slot.as_mut()?;
+        let r = pmm.ranges[..pmm.len]
+            .iter_mut()
+            .max_by_key(|r| hhdm_bytes(r))?;
+        if hhdm_bytes(r) < need {
+            return None;
+        }
+        let p = r.base;
+        r.base += need;
+        Some(p)
+    }
+}
+
+/// Lowest base of `pages` contiguous free frames inside [lo, hi) that the HHDM reaches.
+/// Nothing is taken; hand the result to `claim_range`. Only the ktests look for one so far.
+#[cfg(feature = "ktest")]
+pub fn find_free_within(lo: u64, hi: u64, pages: u64) -> Option<u64> {
+    let need = pages.checked_mul(PAGE_SIZE)?;
+    let (hhdm_lo, hhdm_hi) = paging::hhdm_range();
+    let pmm = unsafe { &*PMM.get() }.as_ref()?;
+    pmm.ranges[..pmm.len].iter().find_map(|r| {
+        let base = align_up_checked(cmp::max(r.base, lo), PAGE_SIZE)?;
+        let end = cmp::min(cmp::min(r.end, hi), hhdm_hi - hhdm_lo);
+        (pages != 0 && base.checked_add(need)? <= end).then_some(base)
+    })
+}
+
+/// Take the `pages` frames at `base` out of the free ranges. False, with nothing taken,
+/// unless all of them lie in one free range.
+#[cfg(feature = "ktest")]
+pub fn claim_range(base: u64, pages: u64) -> bool {
+    let Some(end) = pages
+        .checked_mul(PAGE_SIZE)
+        .and_then(|n| base.checked_add(n))
+    else {
+        return false;
+    };
+    if pages == 0 || !base.is_multiple_of(PAGE_SIZE) {
+        return false;
+    }
+    unsafe {
+        let slot = &mut *PMM.get();
+        let Some(pmm) = slot.as_mut() else {
+            return false;
+        };
+        let free = pmm.ranges[..pmm.len]
+            .iter()
+            .any(|r| r.base <= base && end <= r.end);
+        free && subtract_reserved(&mut pmm.ranges, &mut pmm.len, base, end)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Memtest {
+    Off,
+    // Test one page out of every `MEMTEST_SAMPLE_STRIDE`.
+    Sample,
+    Full,
+}
+
+const MEMTEST_SAMPLE_STRIDE: u64 = 256; // 1 MiB
+const MEMTEST_MAX_BAD: usize = 32;
+
+// Fill one page with `pattern` (or its own address when `None`) and verify it.
+// Returns the first mismatching physical address.
+unsafe fn memtest_page(phys: u64, pattern: Option<u64>) -> Option<u64> {
+    let p = paging::phys_to_virt_ptr::<u64>(phys);
+    let words = (PAGE_SIZE / 8) as usize;
+    for i in 0..words {
+        let v = pattern.unwrap_or(phys + (i as u64) * 8);
+        core::ptr::write_volatile(p.add(i), v);
+    }
+    for i in 0..words {
+        let v = pattern.unwrap_or(phys + (i as u64) * 8);
+        if core::ptr::read_volatile(p.add(i)) != v {
+            return Some(phys + (i as u64) * 8);
+        }
+    }
+    None
+}
+
+/// Pattern-test free memory through the HHDM and drop any failing pages from the
+/// allocator. Must run after paging is up and before the heap takes its region.
+/// Returns the number of pages excluded.
+pub fn memtest(mode: Memtest) -> usize {
+    if mode == Memtest::Off {
+        return 0;
+    }
+    const PATTERNS: [Option<u64>; 5] = [
+        Some(0),
+        Some(u64::MAX),
+        Some(0xaaaa_aaaa_aaaa_aaaa),
+        Some(0x5555_5555_5555_5555),
+        None, // address-as-data
+    ];
+    let stride = match mode {
+        Memtest::Full => 1,
+        _ => MEMTEST_SAMPLE_STRIDE,
+    };
+
+    unsafe {
+        let slot = &mut *PMM.get();
+        let Some(pmm) = slot.as_mut() else {
+            return 0;
+        };
+
+        let mut bad = [0u64; MEMTEST_MAX_BAD];
+        let mut nbad = 0usize;
+        let mut tested: u64 = 0;
+        for i in pmm.cursor..pmm.len {
+            let r = pmm.ranges[i];
+            let mut page = r.base;
+            while page < r.end && nbad < MEMTEST_MAX_BAD {
+                if !paging::hhdm_covers(page, PAGE_SIZE) {
+                    break;
+                }
+                tested += 1;
+                for pat in PATTERNS {
+                    if let Some(addr) = memtest_page(page, pat) {
+                        serial::write_str("memtest: mismatch at ");
+                        serial::write_hex_u64(addr);
+                        serial::write_str("\n");
+                        bad[nbad] = page;
+                        nbad += 1;
+                        break;
+                    }
+                }
+                page = page.saturating_add(stride * PAGE_SIZE);
+            }
+        }
+
+        let mut excluded = 0usize;
+        for &page in &bad[..nbad] {
+            if subtract_reserved(&mut pmm.ranges, &mut pmm.len, page, page + PAGE_SIZE) {
+                excluded += 1;
+            }
+        }
+
+        serial::write_str("memtest: tested ");
+        serial::write_dec_u64(tested);
+        serial::write_str(" pages, excluded ");
+        serial::write_dec_u64(excluded as u64);
+        serial::write_str("\n");
+        excluded
+    }
+}
@@ -0,0 +1,120 @@
+// Kernel randomness: RDRAND output (when the CPU has it) folded into a xorshift64*
+// pool that is seeded from RDSEED/RDRAND or the TSC and stirred with timer-tick jitter.
+// Good enough for canaries and ASLR slides; not a vetted CSPRNG.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::arch::x86_64::{cpuid, rdtsc};
+
+// xorshift64* state; never 0 once seeded.
+static STATE: AtomicU64 = AtomicU64::new(0x9e37_79b9_7f4a_7c15);
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static HAS_TSC: AtomicBool = AtomicBool::new(false);
+// Set by `fix_seed`: no hardware or jitter input, so the stream depends only on the seed.
+static FIXED: AtomicBool = AtomicBool::new(false);
+
+// Ticks between jitter reseeds from the timer IRQ.
+const RESEED_TICKS: u64 = 16;
+
+const HW_RETRIES: usize = 10;
+
+fn rdrand64() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let v: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!("rdrand {}", "setc {}", out(reg) v, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn rdseed64() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let v: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!("rdseed {}", "setc {}", out(reg) v, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn jitter() -> u64 {
+    let tsc = if HAS_TSC.load(Ordering::Relaxed) {
+        rdtsc()
+    } else {
+        0
+    };
+    tsc ^ crate::sched::ticks().rotate_left(32)
+}
+
+// Stir `v` into the pool (splitmix64 finalizer so nearby inputs diverge).
+fn mix(v: u64) {
+    let _ = STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| {
+        let mut z = s ^ v;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Some(if z == 0 { 0x9e37_79b9_7f4a_7c15 } else { z })
+    });
+}
+
+pub fn init() {
+    HAS_TSC.store(cpuid::has_tsc(), Ordering::Relaxed);
+    let rdrand = cpuid::has_rdrand() && rdrand64().is_some();
+    HAS_RDRAND.store(rdrand, Ordering::Relaxed);
+
+    let (seed, source) = match (cpuid::has_rdseed().then(rdseed64).flatten(), rdrand) {
+        (Some(s), _) => (s, "rdseed"),
+        (None, true) => (rdrand64().unwrap_or(0), "rdrand"),
+        (None, false) => (0, "tsc"),
+    };
+    mix(seed ^ jitter());
+    kinfo!("rng: seeded from {}", source);
+}
+
+/// Restart the pool from `seed` and stop stirring in RDRAND and jitter, so every later
+/// value (canaries, ASLR slides, RANDOM) repeats from boot to boot. For reproducible tests.
+pub fn fix_seed(seed: u64) {
+    FIXED.store(true, Ordering::Relaxed);
+    STATE.store(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+    mix(seed);
+}
+
+/// Called from the timer IRQ; folds in TSC jitter every few ticks.
+pub fn on_tick(t: u64) {
+    if t.is_multiple_of(RESEED_TICKS) && !FIXED.load(Ordering::Relaxed) {
+        mix(jitter());
+    }
+}
+
+pub fn next_u64() -> u64 {
+    let mut out = 0;
+    let _ = STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mut s| {
+        s ^= s >> 12;
+        s ^= s << 25;
+        s ^= s >> 27;
+        out = s.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        Some(s)
+    });
+    if HAS_RDRAND.load(Ordering::Relaxed) && !FIXED.load(Ordering::Relaxed) {
+        if let Some(hw) = rdrand64() {
+            out ^= hw;
+        }
+    }
+    out
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let v = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&v[..chunk.len()]);
+    }
+}
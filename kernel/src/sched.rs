@@ -1,185 +1,1286 @@
+use crate::arch::interrupts::without_interrupts;
 use crate::arch::x86_64::gdt;
-use crate::arch::x86_64::isr::TrapFrame;
+use crate::arch::x86_64::isr::{self, TrapFrame};
+use crate::arch::x86_64::lapic;
+use crate::limits::Resource;
 use crate::serial;
+use crate::swtrace;
+use crate::user;
 use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 const MAX_PROCS: usize = 8;
+// Pseudo-pid of the idle task (runs when nothing in PROCS is runnable).
+pub const IDLE_PID: usize = MAX_PROCS;
+
+pub const ROLE_INIT: u64 = 0;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ProcState {
+    Runnable,
+    // Blocked on an endpoint receive (1-based endpoint id), or PARKED in PARK.
+    Blocked(u32),
+    // Sleeping until the given tick count.
+    Sleeping(u64),
+    // Exited; its kernel stack may still be in use until we switch away, so the slot
+    // is reaped (stack freed, state -> Dead) on a later switch.
+    Zombie,
+    // Slot is free (never used or exited).
+    Dead,
+}
+
+impl ProcState {
+    /// The `mantra_sys::proc_state` value userspace sees for this state.
+    pub fn code(self) -> u64 {
+        use mantra_sys::proc_state;
+        match self {
+            ProcState::Runnable => proc_state::RUNNABLE,
+            ProcState::Blocked(_) => proc_state::BLOCKED,
+            ProcState::Sleeping(_) => proc_state::SLEEPING,
+            ProcState::Zombie => proc_state::ZOMBIE,
+            ProcState::Dead => proc_state::DEAD,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 struct Proc {
-    tf_rsp: u64,      // saved TrapFrame pointer (kernel RSP)
-    kstack_top: u64,  // TSS.rsp0 to use for this task
-    cr3: u64,         // address space root
-    caps: [u32; 32],  // cap -> endpoint id (0 = empty)
-    alive: bool,
-    runnable: bool,
-    // Bring-up blocking model: a proc can block on an endpoint receive.
-    blocked_ep: u32, // endpoint id (1-based) or 0
+    tf_rsp: u64,       // saved TrapFrame pointer (kernel RSP)
+    kstack_top: u64,   // TSS.rsp0 to use for this task
+    kstack_base: u64,  // kernel stack allocation base (freed when reaped)
+    cr3: u64,          // address space root
+    caps: [u32; 32],   // cap -> endpoint id (0 = empty)
+    state: ProcState,  // only changed with interrupts disabled
+    mapped_pages: u64, // user pages mapped into `cr3`
+    role: u64,         // spawn role; ROLE_INIT is never chosen by the OOM killer
+    canary: u64,       // expected value of the word at `kstack_base`
+    parent: usize,     // spawning proc (NO_PARENT for pid 0); orphans move to pid 0
+    die_with_parent: bool,
+    reply_to: usize, // caller blocked in IPC_CALL awaiting our reply (NO_CALLER if none)
+    priority: u8,    // 0 (most favoured) ..= PRIO_MAX; PRIO_DEFAULT at spawn
+    skipped: u8,     // picks passed over since last run (see `pick_next`)
+    hint_wait: bool, // blocked in YIELD_HINT: wake on the next send, don't deliver to us
+    ticks: u64,      // timer ticks that landed while this proc was running
+    unpark_pending: bool, // UNPARK arrived while not parked; the next PARK returns at once
 }
 
+// `Blocked` id of a proc in PARK: endpoint ids are 1-based, so it is never a real one.
+const PARKED: u32 = 0;
+
+pub const NO_PARENT: usize = usize::MAX;
+const NO_CALLER: usize = usize::MAX;
+
+pub const PRIO_MAX: u8 = 15;
+pub const PRIO_DEFAULT: u8 = 8;
+
+const DEAD_PROC: Proc = Proc {
+    tf_rsp: 0,
+    kstack_top: 0,
+    kstack_base: 0,
+    cr3: 0,
+    caps: [0; 32],
+    state: ProcState::Dead,
+    mapped_pages: 0,
+    role: 0,
+    canary: 0,
+    parent: NO_PARENT,
+    die_with_parent: false,
+    reply_to: NO_CALLER,
+    priority: PRIO_DEFAULT,
+    skipped: 0,
+    hint_wait: false,
+    ticks: 0,
+    unpark_pending: false,
+};
+
 static INITED: AtomicBool = AtomicBool::new(false);
 static CURRENT: AtomicUsize = AtomicUsize::new(0);
 static TICKS: AtomicU64 = AtomicU64::new(0);
+// Set by the timer when it lands in a syscall; consumed at the next `preempt_point`.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static SYSCALL_PREEMPTIONS: AtomicU64 = AtomicU64::new(0);
+// Scheduler activity, reported by `stats`. Atomics so counts stay exact once more than
+// one CPU updates them.
+static SWITCHES: AtomicU64 = AtomicU64::new(0);
+static YIELDS: AtomicU64 = AtomicU64::new(0);
+static TIMER_IRQS: AtomicU64 = AtomicU64::new(0);
+static TIMER_PREEMPTIONS: AtomicU64 = AtomicU64::new(0);
+// Deterministic mode (`set_deterministic`): interrupts never switch a proc away.
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+// Ticks that landed while the idle task ran. One CPU so far, so one counter; busy time is
+// `ticks() - idle_ticks()`.
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
 
 #[no_mangle]
 pub static mut MANTRA_NEXT_CR3: u64 = 0;
 
-static mut PROCS: [Proc; MAX_PROCS] = [const {
-    Proc {
-        tf_rsp: 0,
-        kstack_top: 0,
-        cr3: 0,
-        caps: [0; 32],
-        alive: false,
-        runnable: false,
-        blocked_ep: 0,
+static mut PROCS: [Proc; MAX_PROCS] = [DEAD_PROC; MAX_PROCS];
+
+unsafe fn procs() -> &'static mut [Proc; MAX_PROCS] {
+    let procs = &raw mut PROCS;
+    &mut *procs
+}
+
+// Idle task: a ring0 context halting with interrupts on. It lives outside PROCS so it can
+// never block, exit or hold caps.
+static mut IDLE_STACK: [u8; 8 * 1024] = [0; 8 * 1024];
+static mut IDLE_TF_RSP: u64 = 0;
+
+extern "C" fn idle_loop() -> ! {
+    loop {
+        unsafe { core::arch::asm!("sti; hlt", options(nomem, nostack)) };
     }
-}; MAX_PROCS];
+}
 
-pub fn install_first(tf_rsp: u64, kstack_top: u64, cr3: u64) {
-    unsafe {
-        PROCS[0] = Proc {
+unsafe fn build_idle_tf() -> u64 {
+    let top = (&raw mut IDLE_STACK as *mut u8).add(core::mem::size_of::<[u8; 8 * 1024]>()) as u64;
+    let tf = (top - core::mem::size_of::<TrapFrame>() as u64) as *mut TrapFrame;
+    core::ptr::write_bytes(tf as *mut u8, 0, core::mem::size_of::<TrapFrame>());
+    (*tf).rip = idle_loop as *const () as u64;
+    (*tf).cs = gdt::KCODE_SEL as u64;
+    (*tf).rflags = 0x202;
+    (*tf).rsp = top;
+    (*tf).ss = gdt::KDATA_SEL as u64;
+    tf as u64
+}
+
+pub fn install_first(tf_rsp: u64, kstack_top: u64, cr3: u64, mapped_pages: u64, role: u64) {
+    without_interrupts(|| unsafe {
+        let procs = procs();
+        procs[0] = Proc {
             tf_rsp,
             kstack_top,
+            kstack_base: kstack_top - user::KSTACK_SIZE as u64,
             cr3,
             caps: [0; 32],
-            alive: true,
-            runnable: true,
-            blocked_ep: 0,
+            state: ProcState::Runnable,
+            mapped_pages,
+            role,
+            canary: plant_canary(kstack_top - user::KSTACK_SIZE as u64),
+            parent: NO_PARENT,
+            die_with_parent: false,
+            reply_to: NO_CALLER,
+            priority: PRIO_DEFAULT,
+            skipped: 0,
+            hint_wait: false,
+            ticks: 0,
+            unpark_pending: false,
         };
-        for p in PROCS.iter_mut().skip(1) {
-            *p = Proc {
-                tf_rsp: 0,
-                kstack_top: 0,
-                cr3: 0,
-                caps: [0; 32],
-                alive: false,
-                runnable: false,
-                blocked_ep: 0,
-            };
+        for p in procs.iter_mut().skip(1) {
+            *p = DEAD_PROC;
         }
         MANTRA_NEXT_CR3 = cr3;
-    }
+        IDLE_TF_RSP = build_idle_tf();
+    });
     CURRENT.store(0, Ordering::Release);
     INITED.store(true, Ordering::Release);
     serial::write_str("sched: installed proc0\n");
 }
 
+// Kernel stacks grow down towards `kstack_base`; a random word planted there is the last
+// thing an overflow clobbers before running off the allocation, so a changed value at
+// switch time means the stack overflowed (or something scribbled on it).
+unsafe fn plant_canary(kstack_base: u64) -> u64 {
+    let canary = crate::rng::next_u64();
+    core::ptr::write_volatile(kstack_base as *mut u64, canary);
+    canary
+}
+
+unsafe fn canary_intact(kstack_base: u64, canary: u64) -> bool {
+    core::ptr::read_volatile(kstack_base as *const u64) == canary
+}
+
+ktest! {
+    fn canary_catches_overflow() {
+        // The canary must catch a write past a stack's logical end.
+        let mut fake_stack = [0u64; 8];
+        let base = fake_stack.as_mut_ptr() as u64;
+        unsafe {
+            let canary = plant_canary(base);
+            kassert!(
+                canary_intact(base, canary),
+                "fresh canary reads as clobbered"
+            );
+            // Simulated overflow: the deepest frame spills onto the base word.
+            core::ptr::write_volatile(base as *mut u64, !canary);
+            kassert!(
+                !canary_intact(base, canary),
+                "overflow not detected"
+            );
+        }
+    }
+}
+
+/// Timer ticks since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Of `ticks()`, those spent in the idle task.
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
 pub fn current_pid() -> usize {
     CURRENT.load(Ordering::Relaxed)
 }
 
-pub fn spawn_proc(tf_rsp: u64, kstack_top: u64, cr3: u64) -> Option<usize> {
-    unsafe {
-        for (pid, p) in PROCS.iter_mut().enumerate() {
-            if !p.alive {
+pub fn spawn_proc(
+    tf_rsp: u64,
+    kstack_top: u64,
+    cr3: u64,
+    mapped_pages: u64,
+    role: u64,
+    parent: usize,
+    die_with_parent: bool,
+) -> Option<usize> {
+    without_interrupts(|| unsafe {
+        for (pid, p) in procs().iter_mut().enumerate() {
+            if p.state == ProcState::Dead {
                 *p = Proc {
                     tf_rsp,
                     kstack_top,
+                    kstack_base: kstack_top - user::KSTACK_SIZE as u64,
                     cr3,
                     caps: [0; 32],
-                    alive: true,
-                    runnable: true,
-                    blocked_ep: 0,
+                    state: ProcState::Runnable,
+                    mapped_pages,
+                    role,
+                    canary: plant_canary(kstack_top - user::KSTACK_SIZE as u64),
+                    parent,
+                    die_with_parent,
+                    reply_to: NO_CALLER,
+                    priority: PRIO_DEFAULT,
+                    skipped: 0,
+                    hint_wait: false,
+                    ticks: 0,
+                    unpark_pending: false,
                 };
                 return Some(pid);
             }
         }
-    }
-    None
+        None
+    })
 }
 
 pub fn proc_cr3(pid: usize) -> Option<u64> {
     if pid >= MAX_PROCS {
         return None;
     }
-    unsafe { Some(PROCS[pid].cr3) }
+    unsafe { Some(procs()[pid].cr3) }
 }
 
 pub fn proc_tf_rsp(pid: usize) -> Option<u64> {
     if pid >= MAX_PROCS {
         return None;
     }
-    unsafe { Some(PROCS[pid].tf_rsp) }
+    unsafe { Some(procs()[pid].tf_rsp) }
+}
+
+/// Test fixture: make the free slot `pid` a proc blocked receiving on `ep_id`, with its
+/// saved frame at `tf_rsp` in address space `cr3`. False if the slot is in use.
+#[cfg(feature = "ktest")]
+pub fn stage_blocked(pid: usize, ep_id: u32, cr3: u64, tf_rsp: u64) -> bool {
+    if pid >= MAX_PROCS || pid == current_pid() {
+        return false;
+    }
+    let p = unsafe { &mut procs()[pid] };
+    if p.state != ProcState::Dead {
+        return false;
+    }
+    (p.state, p.cr3, p.tf_rsp) = (ProcState::Blocked(ep_id), cr3, tf_rsp);
+    true
+}
+
+/// Test fixture: return `pid`'s slot to unused, caps included.
+#[cfg(feature = "ktest")]
+pub fn unstage(pid: usize) {
+    if pid < MAX_PROCS && pid != current_pid() {
+        unsafe { procs()[pid] = DEAD_PROC };
+    }
+}
+
+/// Test fixture: free a proc a test spawned, kernel stack and address space included. The
+/// caller must not be running on its stack or in its address space.
+#[cfg(feature = "ktest")]
+pub fn release(pid: usize) {
+    if pid < MAX_PROCS {
+        without_interrupts(|| unsafe {
+            let p = &mut procs()[pid];
+            if p.state != ProcState::Dead {
+                reap(p);
+            }
+        });
+    }
+}
+
+/// The frame `pid` last entered the kernel with from ring 3: always the top of its kernel
+/// stack. `proc_tf_rsp` is a ring-0 frame instead while it is preempted at a `preempt_point`.
+pub fn proc_user_tf(pid: usize) -> Option<u64> {
+    if pid >= MAX_PROCS {
+        return None;
+    }
+    let top = unsafe { procs()[pid].kstack_top };
+    (top != 0).then(|| top - core::mem::size_of::<TrapFrame>() as u64)
+}
+
+/// Snapshot of a proc's state, page count and parent, or None for an invalid pid.
+pub fn proc_info(pid: usize) -> Option<(ProcState, u64, usize)> {
+    if pid >= MAX_PROCS {
+        return None;
+    }
+    unsafe {
+        let p = &procs()[pid];
+        Some((p.state, p.mapped_pages, p.parent))
+    }
+}
+
+/// Timer ticks charged to `pid` so far (0 for an invalid pid).
+pub fn proc_ticks(pid: usize) -> u64 {
+    if pid >= MAX_PROCS {
+        return 0;
+    }
+    unsafe { procs()[pid].ticks }
+}
+
+/// User pages mapped by all live procs together.
+pub fn total_mapped_pages() -> u64 {
+    unsafe { procs() }
+        .iter()
+        .filter(|p| p.state != ProcState::Dead)
+        .map(|p| p.mapped_pages)
+        .sum()
+}
+
+/// Call `f(child_pid)` for each live proc spawned by `pid` (or re-parented to it).
+pub fn children(pid: usize, mut f: impl FnMut(usize)) {
+    for (child, p) in unsafe { procs() }.iter().enumerate() {
+        if p.parent == pid && p.state != ProcState::Dead {
+            f(child);
+        }
+    }
+}
+
+// `pid` is going away: kill children that asked to die with it, hand the rest to pid 0 so
+// init can supervise (and eventually reap) them. Interrupts must be disabled.
+unsafe fn release_children(pid: usize) {
+    let mut kids = [false; MAX_PROCS];
+    children(pid, |c| kids[c] = true);
+    for child in (0..MAX_PROCS).filter(|&c| kids[c]) {
+        let p = &mut procs()[child];
+        if p.die_with_parent {
+            kdebug!("sched: killing pid={} with parent {}", child, pid);
+            let _ = kill(child);
+        } else if pid != 0 {
+            p.parent = 0;
+            kdebug!("sched: pid={} re-parented to 0", child);
+        }
+    }
+}
+
+// `pid` is going away: fail the call it owed a reply to, and forget any reply owed to it
+// so a later proc reusing the slot can't receive it. Interrupts must be disabled.
+unsafe fn drop_calls(pid: usize) {
+    let owed = core::mem::replace(&mut procs()[pid].reply_to, NO_CALLER);
+    if owed != NO_CALLER {
+        abort_wait(owed, mantra_sys::error::INVALID);
+    }
+    for p in procs().iter_mut().filter(|p| p.reply_to == pid) {
+        p.reply_to = NO_CALLER;
+    }
+}
+
+/// `server` received a message sent with IPC_CALL by `caller` and now owes it a reply.
+/// A server answers one call at a time: an unanswered earlier call fails.
+pub fn set_reply_to(server: usize, caller: usize) {
+    if server >= MAX_PROCS || caller >= MAX_PROCS {
+        return;
+    }
+    without_interrupts(|| unsafe {
+        let prev = core::mem::replace(&mut procs()[server].reply_to, caller);
+        if prev != NO_CALLER && prev != caller {
+            abort_wait(prev, mantra_sys::error::INVALID);
+        }
+    });
+}
+
+/// The caller `server` owes a reply to, clearing it. None if there is no pending call.
+pub fn take_reply_to(server: usize) -> Option<usize> {
+    if server >= MAX_PROCS {
+        return None;
+    }
+    without_interrupts(|| unsafe {
+        let caller = core::mem::replace(&mut procs()[server].reply_to, NO_CALLER);
+        (caller != NO_CALLER).then_some(caller)
+    })
+}
+
+pub fn is_blocked(pid: usize) -> bool {
+    pid < MAX_PROCS && matches!(unsafe { procs()[pid].state }, ProcState::Blocked(_))
+}
+
+/// True if `pid` is blocked receiving on (or in YIELD_HINT at) endpoint `ep_id`.
+pub fn is_blocked_on(pid: usize, ep_id: u32) -> bool {
+    pid < MAX_PROCS && unsafe { procs()[pid].state } == ProcState::Blocked(ep_id)
 }
 
 pub fn wake(pid: usize) {
     if pid >= MAX_PROCS {
         return;
     }
-    unsafe {
-        if PROCS[pid].alive {
-            PROCS[pid].runnable = true;
-            PROCS[pid].blocked_ep = 0;
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        match p.state {
+            ProcState::Blocked(_) => {
+                p.state = ProcState::Runnable;
+                p.hint_wait = false;
+            }
+            ProcState::Sleeping(_) => {
+                crate::timer::cancel(wake_sleeper, pid as u64);
+                p.state = ProcState::Runnable;
+            }
+            ProcState::Runnable | ProcState::Zombie | ProcState::Dead => {}
         }
+    });
+}
+
+/// Block the current proc on `ep_id` until the next send there, which queues the message
+/// for it to receive itself instead of delivering it (YIELD_HINT).
+pub fn block_current_for_hint(ep_id: u32) {
+    let pid = current_pid();
+    if pid >= MAX_PROCS {
+        return;
     }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        if p.state == ProcState::Runnable {
+            p.state = ProcState::Blocked(ep_id);
+            p.hint_wait = true;
+        }
+    });
+}
+
+/// True if `pid` is blocked in YIELD_HINT rather than in a receive.
+pub fn hint_waiting(pid: usize) -> bool {
+    pid < MAX_PROCS && unsafe { procs()[pid].hint_wait }
+}
+
+/// Park the current proc until another one unparks it (PARK). If an unpark is already
+/// pending it is consumed instead and false is returned without blocking.
+pub fn park_current() -> bool {
+    let pid = current_pid();
+    if pid >= MAX_PROCS {
+        return false;
+    }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        if core::mem::take(&mut p.unpark_pending) || p.state != ProcState::Runnable {
+            return false;
+        }
+        p.state = ProcState::Blocked(PARKED);
+        true
+    })
+}
+
+/// Unpark `pid` (UNPARK): make it runnable if it is parked (Some(true)), otherwise leave
+/// one unpark pending for its next park (Some(false); pending unparks don't stack). None
+/// if `pid` is not a live proc.
+pub fn unpark(pid: usize) -> Option<bool> {
+    if pid >= MAX_PROCS {
+        return None;
+    }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        match p.state {
+            ProcState::Zombie | ProcState::Dead => None,
+            ProcState::Blocked(PARKED) => {
+                p.state = ProcState::Runnable;
+                Some(true)
+            }
+            _ => {
+                p.unpark_pending = true;
+                Some(false)
+            }
+        }
+    })
 }
 
 pub fn block_current_on_ep(ep_id: u32) {
+    block_on_ep(current_pid(), ep_id);
+}
+
+fn block_on_ep(pid: usize, ep_id: u32) {
+    if pid >= MAX_PROCS {
+        return;
+    }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        if p.state == ProcState::Runnable {
+            p.state = ProcState::Blocked(ep_id);
+        }
+    });
+}
+
+// Mark `p` asleep until `tick` and queue its wakeup. If the deadline table is full the
+// proc stays runnable: an early return beats sleeping forever.
+unsafe fn sleep_until(p: &mut Proc, pid: usize, tick: u64) {
+    if crate::timer::add(tick, wake_sleeper, pid as u64) {
+        p.state = ProcState::Sleeping(tick);
+    } else {
+        kwarn!("sched: deadline table full, pid={} not put to sleep", pid);
+    }
+}
+
+// Deadline callback for sleeps. The proc may have been woken early (and maybe gone back to
+// sleep for longer) since this was queued; only wake it if its own deadline has passed.
+fn wake_sleeper(pid: u64, now: u64) {
+    let p = unsafe { &mut procs()[pid as usize] };
+    if let ProcState::Sleeping(until) = p.state {
+        if now >= until {
+            p.state = ProcState::Runnable;
+        }
+    }
+}
+
+// Fine-grained sleepers, sorted by deadline (`timer::now_ns` clock). The LAPIC one-shot is
+// always armed for the head; a proc here is also `Sleeping` until a backstop tick in case
+// the shot is lost. Entries for procs that woke another way are dropped when reached.
+static mut HR_QUEUE: [(u64, usize); MAX_PROCS] = [(0, 0); MAX_PROCS];
+static mut HR_LEN: usize = 0;
+
+unsafe fn hr_queue() -> &'static mut [(u64, usize); MAX_PROCS] {
+    let queue = &raw mut HR_QUEUE;
+    &mut *queue
+}
+
+// Insert (or move) `pid`'s deadline, keeping the queue ordered.
+unsafe fn hr_insert(deadline: u64, pid: usize) {
+    let q = hr_queue();
+    if let Some(i) = q[..HR_LEN].iter().position(|&(_, p)| p == pid) {
+        q.copy_within(i + 1..HR_LEN, i);
+        HR_LEN -= 1;
+    }
+    let at = q[..HR_LEN].partition_point(|&(d, _)| d <= deadline);
+    q.copy_within(at..HR_LEN, at + 1);
+    q[at] = (deadline, pid);
+    HR_LEN += 1;
+}
+
+unsafe fn hr_arm(now: u64) {
+    if HR_LEN > 0 {
+        lapic::one_shot_ns(hr_queue()[0].0.saturating_sub(now));
+    }
+}
+
+/// Put the current proc to sleep for at least `ns` nanoseconds. The caller must switch
+/// away afterwards. Uses the LAPIC one-shot when available, else rounds up to ticks.
+pub fn nanosleep_current(ns: u64) {
     let pid = current_pid();
+    if pid >= MAX_PROCS || ns == 0 {
+        return;
+    }
+    let backstop = TICKS.load(Ordering::Relaxed) + crate::timer::ns_to_ticks_ceil(ns) + 1;
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        if p.state != ProcState::Runnable {
+            return;
+        }
+        sleep_until(p, pid, backstop);
+        let now = crate::timer::now_ns();
+        if let (true, Some(now)) = (lapic::timer_available(), now) {
+            hr_insert(now + ns, pid);
+            hr_arm(now);
+        }
+    });
+}
+
+/// LAPIC one-shot: wake every sleeper whose deadline passed and re-arm for the next.
+pub fn on_hrtimer(current_tf: *mut TrapFrame) -> u64 {
+    if !INITED.load(Ordering::Acquire) {
+        return 0;
+    }
+    let Some(now) = crate::timer::now_ns() else {
+        return 0;
+    };
+    let mut woke = false;
     unsafe {
-        PROCS[pid].runnable = false;
-        PROCS[pid].blocked_ep = ep_id;
+        let q = hr_queue();
+        let due = q[..HR_LEN].partition_point(|&(d, _)| d <= now);
+        for &(_, pid) in &q[..due] {
+            if matches!(procs()[pid].state, ProcState::Sleeping(_)) {
+                crate::timer::cancel(wake_sleeper, pid as u64);
+                procs()[pid].state = ProcState::Runnable;
+                woke = true;
+            }
+        }
+        q.copy_within(due..HR_LEN, 0);
+        HR_LEN -= due;
+        hr_arm(now);
+    }
+    // Same rule as the tick, except a proc in the kernel is not asked to yield: the wakeup
+    // only needs to beat the next tick, which will do that.
+    let cur = CURRENT.load(Ordering::Relaxed);
+    let from_user = unsafe { &*current_tf }.is_user();
+    if !woke || irq_switch(deterministic(), cur, from_user) != IrqSwitch::Now {
+        return 0;
+    }
+    timed_switch_from(current_tf as u64, swtrace::Reason::Wake)
+}
+
+ktest! {
+    fn block_wake_against_ticks() {
+        // A spare slot driven through a random mix of blocks, wakes, sleeps and timer
+        // ticks, checked against a model after every step: a wake is never lost, a sleeper
+        // wakes at its own deadline and not before, and only a runnable proc is picked.
+        const PID: usize = MAX_PROCS - 1;
+        const STEPS: usize = 4000;
+        if unsafe { procs()[PID].state } != ProcState::Dead || current_pid() == PID {
+            kwarn!("sched: block/wake stress skipped, pid {} in use", PID);
+            return;
+        }
+        unsafe { procs()[PID].state = ProcState::Runnable };
+        let mut model = ProcState::Runnable;
+        let mut now = TICKS.load(Ordering::Relaxed);
+        let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+        for step in 0..STEPS {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            match x % 4 {
+                0 => {
+                    block_on_ep(PID, 1);
+                    if model == ProcState::Runnable {
+                        model = ProcState::Blocked(1);
+                    }
+                }
+                1 => {
+                    wake(PID);
+                    model = ProcState::Runnable;
+                }
+                2 => {
+                    let until = now + 1 + (x >> 8) % 3;
+                    without_interrupts(|| unsafe {
+                        let p = &mut procs()[PID];
+                        if p.state == ProcState::Runnable {
+                            sleep_until(p, PID, until);
+                        }
+                    });
+                    if model == ProcState::Runnable {
+                        model = ProcState::Sleeping(until);
+                    }
+                }
+                _ => {
+                    now += 1;
+                    without_interrupts(|| crate::timer::expire(now));
+                    if matches!(model, ProcState::Sleeping(until) if until <= now) {
+                        model = ProcState::Runnable;
+                    }
+                }
+            }
+            let state = unsafe { procs()[PID].state };
+            let picked = pick_next_runnable(PID);
+            kassert!(
+                state == model && (picked == Some(PID)) == (state == ProcState::Runnable),
+                "sched: step {}: state {} (want {}), picked {:?}",
+                step,
+                state.code(),
+                model.code(),
+                picked
+            );
+        }
+        // A proc that is not asleep must not leave a wakeup behind.
+        let stale = model == ProcState::Runnable && crate::timer::cancel(wake_sleeper, PID as u64);
+        crate::timer::cancel(wake_sleeper, PID as u64);
+        unsafe { procs()[PID] = DEAD_PROC };
+        kassert!(!stale, "sched: woken proc left its sleep deadline queued");
+    }
+}
+
+/// Mark the current proc as exited. The caller must switch away before returning to it.
+pub fn exit_current() {
+    let pid = current_pid();
+    if pid >= MAX_PROCS {
+        return;
     }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        p.state = ProcState::Zombie;
+        p.caps = [0; 32];
+        release_children(pid);
+        drop_calls(pid);
+    });
 }
 
-pub fn has_other_runnable() -> bool {
-    let cur = current_pid();
+// Free a zombie's kernel stack and address space. Caller guarantees it is off-CPU and
+// its CR3 is not loaded.
+unsafe fn reap(p: &mut Proc) {
+    user::kstack_free(p.kstack_base);
+    user::free_address_space(p.cr3);
+    *p = DEAD_PROC;
+}
+
+// Reap exited procs other than `cur` (whose stack we're running on). A zombie whose CR3 is
+// still loaded (idle keeps the last one) waits until another proc is switched in.
+fn reap_zombies(cur: usize) {
     unsafe {
-        for (pid, p) in PROCS.iter().enumerate() {
-            if pid != cur && p.alive && p.runnable {
-                return true;
+        let loaded_cr3 = MANTRA_NEXT_CR3;
+        for (pid, p) in procs().iter_mut().enumerate() {
+            if pid != cur && p.state == ProcState::Zombie && p.cr3 != loaded_cr3 {
+                reap(p);
             }
         }
     }
-    false
 }
 
-fn pick_next_runnable(cur: usize) -> usize {
-    let mut next = cur;
-    for _ in 0..MAX_PROCS {
-        next = (next + 1) % MAX_PROCS;
+/// OOM victim: the proc with the most mapped pages, excluding `caller` and ROLE_INIT procs.
+pub fn oom_select(caller: usize) -> Option<usize> {
+    without_interrupts(|| unsafe {
+        let mut best: Option<usize> = None;
+        for (pid, p) in procs().iter().enumerate() {
+            if pid == caller || p.role == ROLE_INIT || p.mapped_pages == 0 {
+                continue;
+            }
+            if !matches!(
+                p.state,
+                ProcState::Runnable | ProcState::Blocked(_) | ProcState::Sleeping(_)
+            ) {
+                continue;
+            }
+            if best.is_none_or(|b| p.mapped_pages > procs()[b].mapped_pages) {
+                best = Some(pid);
+            }
+        }
+        best
+    })
+}
+
+/// Kill `pid` and reclaim its memory immediately. `pid` must not be the current proc.
+/// Returns the number of user pages it had mapped.
+pub fn kill(pid: usize) -> Option<u64> {
+    if pid >= MAX_PROCS || pid == current_pid() {
+        return None;
+    }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        if matches!(p.state, ProcState::Zombie | ProcState::Dead) {
+            return None;
+        }
+        let pages = p.mapped_pages;
+        p.state = ProcState::Zombie;
+        p.caps = [0; 32];
+        // A receive it was blocked in must not be completed into a freed or reused slot.
+        crate::ipc::forget_waiter(pid);
+        // Before any reap resets the slot, which forgets who is waiting on a reply from it.
+        release_children(pid);
+        drop_calls(pid);
+        let p = &mut procs()[pid];
+        if p.cr3 != MANTRA_NEXT_CR3 {
+            reap(p);
+        }
+        Some(pages)
+    })
+}
+
+ktest! {
+    fn killed_server_fails_the_call() {
+        // The server took the client's IPC_CALL and owes it a reply when it is killed.
+        // Its address space (an empty PML4) is not the loaded one, so it is reaped at once.
+        const SERVER: usize = MAX_PROCS - 2;
+        const CLIENT: usize = MAX_PROCS - 1;
+        let free = |pid: usize| unsafe { procs()[pid].state } == ProcState::Dead;
+        if !free(SERVER) || !free(CLIENT) || current_pid() >= SERVER {
+            kwarn!("sched: killed server test skipped, pids in use");
+            return;
+        }
+        let Some(pml4) = crate::pmm::alloc_frame() else {
+            kwarn!("sched: killed server test skipped, no frame");
+            return;
+        };
+        let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+        let tf = &raw mut frame;
         unsafe {
-            if PROCS[next].alive && PROCS[next].runnable {
-                return next;
+            let table = crate::arch::x86_64::paging::phys_to_virt_ptr::<u8>(pml4);
+            core::ptr::write_bytes(table, 0, 4096);
+            let ps = procs();
+            ps[CLIENT].state = ProcState::Blocked(1);
+            ps[CLIENT].tf_rsp = tf as u64;
+            ps[SERVER].state = ProcState::Runnable;
+            ps[SERVER].cr3 = pml4;
+        }
+        set_reply_to(SERVER, CLIENT);
+        let killed = kill(SERVER).is_some();
+        let (server, client) = unsafe { (procs()[SERVER].state, procs()[CLIENT].state) };
+        unsafe { procs()[CLIENT] = DEAD_PROC };
+        kassert!(killed && server == ProcState::Dead, "server not reaped");
+        kassert!(
+            client == ProcState::Runnable && unsafe { (*tf).rax } == mantra_sys::error::INVALID,
+            "client still waiting for a reply (state {})",
+            client.code()
+        );
+    }
+}
+
+ktest! {
+    fn user_tf_ignores_kernel_frame() {
+        // A proc preempted at a `preempt_point` has its ring-0 KYIELD frame saved in `tf_rsp`;
+        // its user registers are still in the frame at the top of its kernel stack.
+        const PID: usize = MAX_PROCS - 1;
+        if unsafe { procs()[PID].state } != ProcState::Dead || current_pid() == PID {
+            kwarn!("sched: user frame test skipped, pid in use");
+            return;
+        }
+        let mut stack = [0u64; 64];
+        let top = stack.as_mut_ptr() as u64 + core::mem::size_of_val(&stack) as u64;
+        let mut kframe: TrapFrame = unsafe { core::mem::zeroed() };
+        unsafe {
+            let ps = procs();
+            ps[PID].kstack_top = top;
+            ps[PID].tf_rsp = &raw mut kframe as u64;
+        }
+        let user = proc_user_tf(PID);
+        unsafe { procs()[PID] = DEAD_PROC };
+        kassert!(
+            user == Some(top - core::mem::size_of::<TrapFrame>() as u64),
+            "user frame at {:?}, stack top {:#x}",
+            user,
+            top
+        );
+        kassert!(proc_user_tf(PID).is_none(), "dead proc has a user frame");
+    }
+}
+
+// Round-robin starting after `cur` (and considering `cur` last), weighted by priority: a
+// proc `d` levels below the best runnable one is passed over `d` times per run, so it
+// gets roughly 1/(d+1) of the turns instead of starving.
+fn pick_next(procs: &mut [Proc], cur: usize) -> Option<usize> {
+    let best = procs
+        .iter()
+        .filter(|p| p.state == ProcState::Runnable)
+        .map(|p| p.priority)
+        .min()?;
+    let n = procs.len();
+    let mut next = if cur >= n { n - 1 } else { cur };
+    for _ in 0..n {
+        next = (next + 1) % n;
+        let p = &mut procs[next];
+        if p.state != ProcState::Runnable {
+            continue;
+        }
+        if p.skipped < p.priority - best {
+            p.skipped += 1;
+            continue;
+        }
+        p.skipped = 0;
+        return Some(next);
+    }
+    // Every runnable proc was passed over this round; the best one still runs.
+    let next = procs
+        .iter()
+        .position(|p| p.state == ProcState::Runnable && p.priority == best)?;
+    procs[next].skipped = 0;
+    Some(next)
+}
+
+fn pick_next_runnable(cur: usize) -> Option<usize> {
+    pick_next(unsafe { procs() }, cur)
+}
+
+/// Lower (`delta` > 0) or raise `pid`'s scheduling priority; raising needs privilege.
+/// Returns the new priority number, clamped to 0..=PRIO_MAX.
+pub fn nice(pid: usize, delta: i64) -> Result<u8, u64> {
+    if pid >= MAX_PROCS {
+        return Err(mantra_sys::error::INVALID);
+    }
+    if delta < 0 && !is_privileged(pid) {
+        return Err(mantra_sys::error::PERMISSION);
+    }
+    without_interrupts(|| unsafe {
+        let p = &mut procs()[pid];
+        let prio = (p.priority as i64)
+            .saturating_add(delta)
+            .clamp(0, PRIO_MAX as i64) as u8;
+        p.priority = prio;
+        p.skipped = 0;
+        Ok(prio)
+    })
+}
+
+ktest! {
+    fn niced_proc_runs_less() {
+        // Two CPU-bound procs over a fixed window of picks: the one niced by 2 must run about a
+        // third as often as its default-priority peer, and never be starved.
+        const WINDOW: usize = 60;
+        let mut table = [DEAD_PROC; 3];
+        table[0].state = ProcState::Runnable;
+        table[2].state = ProcState::Runnable;
+        table[2].priority = PRIO_DEFAULT + 2;
+        let mut runs = [0usize; 3];
+        let mut cur = 0;
+        for _ in 0..WINDOW {
+            cur = pick_next(&mut table, cur).unwrap_or(cur);
+            runs[cur] += 1;
+        }
+        kassert!(
+            runs[2] > 0 && runs[2] * 2 < runs[0],
+            "niced proc ran {} of {} picks",
+            runs[2],
+            WINDOW
+        );
+        kdebug!(
+            "sched: nice default={} niced={}",
+            runs[0],
+            runs[2]
+        );
+    }
+}
+
+/// Seed for boots built with MANTRA_SCHED=det.
+pub const DET_SEED: u64 = 0x6d61_6e74_7261;
+
+/// Stop timer preemption: procs only switch where they yield, block, sleep or exit, so a
+/// fixed set of programs always runs in the same order. `seed` fixes the kernel RNG too
+/// (see `rng::fix_seed`). There is no way back; production boots stay preemptive.
+pub fn set_deterministic(seed: u64) {
+    DETERMINISTIC.store(true, Ordering::Relaxed);
+    crate::rng::fix_seed(seed);
+    kinfo!("sched: deterministic mode, seed {:#x}", seed);
+}
+
+pub fn deterministic() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+// What a timer or hrtimer interrupt landing in `cur` may do about switching.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum IrqSwitch {
+    Now,         // switch from the interrupt
+    AtSafePoint, // ask the proc to yield at its next `preempt_point`
+    Never,
+}
+
+// Kernel code is only preempted at explicit safe points: a proc interrupted in ring 0
+// (inside `preempt_point`'s IRQ window) is asked to yield there instead of being switched
+// away from an arbitrary spot. Idle holds no state and is always switched directly; in
+// deterministic mode nothing else is.
+fn irq_switch(det: bool, cur: usize, from_user: bool) -> IrqSwitch {
+    if cur == IDLE_PID {
+        IrqSwitch::Now
+    } else if det {
+        IrqSwitch::Never
+    } else if from_user {
+        IrqSwitch::Now
+    } else {
+        IrqSwitch::AtSafePoint
+    }
+}
+
+#[cfg(feature = "ktest")]
+#[derive(Copy, Clone)]
+enum IpcOp {
+    Send(u32), // endpoint index
+    Recv(u32),
+}
+
+// Play a two-proc call/reply exchange through `pick_next` and `irq_switch` on a private
+// table, recording each switch in `out` (with the op count as its tick). A timer tick
+// lands between ops wherever `tick_seed` says; in deterministic mode none may matter.
+#[cfg(feature = "ktest")]
+fn det_exchange(tick_seed: u64, out: &swtrace::Trace) {
+    use IpcOp::{Recv, Send};
+    const CLIENT: [IpcOp; 4] = [Send(0), Recv(1), Send(0), Recv(1)];
+    const SERVER: [IpcOp; 4] = [Recv(0), Send(1), Recv(0), Send(1)];
+    let progs: [&[IpcOp]; 2] = [&CLIENT, &SERVER];
+    let mut table = [DEAD_PROC; 2];
+    for p in table.iter_mut() {
+        p.state = ProcState::Runnable;
+    }
+    let mut pc = [0usize; 2];
+    let mut queued = [0u32; 2];
+    let mut irq = tick_seed | 1;
+    let mut step = 0;
+    let mut cur = 0;
+    let switch = |table: &mut [Proc], cur: &mut usize, reason, step| {
+        let next = pick_next(table, *cur).unwrap_or(IDLE_PID);
+        if next != *cur {
+            out.record(swtrace::Record {
+                from: *cur as u8,
+                to: next as u8,
+                reason,
+                tick: step,
+            });
+            *cur = next;
+        }
+    };
+    while cur != IDLE_PID {
+        step += 1;
+        irq ^= irq << 13;
+        irq ^= irq >> 7;
+        irq ^= irq << 17;
+        if irq & 1 != 0 && irq_switch(true, cur, true) == IrqSwitch::Now {
+            switch(&mut table, &mut cur, swtrace::Reason::Timer, step);
+            continue;
+        }
+        let blocks = match progs[cur].get(pc[cur]) {
+            None => {
+                table[cur].state = ProcState::Dead;
+                true
+            }
+            Some(&Send(ep)) => {
+                let peer = &mut table[1 - cur];
+                if peer.state == ProcState::Blocked(ep + 1) {
+                    peer.state = ProcState::Runnable;
+                } else {
+                    queued[ep as usize] += 1;
+                }
+                false
             }
+            Some(&Recv(ep)) => {
+                if queued[ep as usize] > 0 {
+                    queued[ep as usize] -= 1;
+                    false
+                } else {
+                    table[cur].state = ProcState::Blocked(ep + 1);
+                    true
+                }
+            }
+        };
+        pc[cur] += 1;
+        if blocks {
+            switch(&mut table, &mut cur, swtrace::Reason::Block, step);
+        }
+    }
+}
+
+ktest! {
+    fn deterministic_exchange_repeats() {
+        // A call/reply exchange run twice under deterministic mode, with ticks landing in different
+        // places each time, must switch in exactly the same (known) sequence.
+        use swtrace::Reason::Block;
+        const WANT: [(u8, u8, swtrace::Reason); 5] = [
+            (0, 1, Block),
+            (1, 0, Block),
+            (0, 1, Block),
+            (1, 0, Block),
+            (0, IDLE_PID as u8, Block),
+        ];
+        kassert!(
+            irq_switch(false, 0, true) == IrqSwitch::Now,
+            "a tick in user mode would not preempt"
+        );
+        for seed in [0x2545_f491, 0x9e37_79b9] {
+            let trace = swtrace::Trace::new();
+            det_exchange(seed, &trace);
+            let mut seen = 0;
+            let mut ok = true;
+            trace.for_each(|r| {
+                ok &= WANT.get(seen) == Some(&(r.from, r.to, r.reason));
+                seen += 1;
+            });
+            kassert!(
+                ok && seen == WANT.len(),
+                "deterministic exchange switched differently (seed {:#x}, {} switches)",
+                seed,
+                seen
+            );
         }
     }
-    cur
 }
 
+// Caller must have interrupts disabled (trap/IRQ entry).
+// Returns the TrapFrame pointer to resume, or 0 to keep running the current context.
 fn switch_from(cur_tf: u64) -> u64 {
     let cur = CURRENT.load(Ordering::Relaxed);
     unsafe {
-        PROCS[cur].tf_rsp = cur_tf;
+        if cur == IDLE_PID {
+            IDLE_TF_RSP = cur_tf;
+        } else {
+            let p = &mut procs()[cur];
+            if !canary_intact(p.kstack_base, p.canary) {
+                bug!(
+                    "sched: kernel stack overflow pid={} kstack_base={:#x}",
+                    cur,
+                    p.kstack_base
+                );
+            }
+            p.tf_rsp = cur_tf;
+        }
     }
+    reap_zombies(cur);
 
-    let next = pick_next_runnable(cur);
+    let Some(next) = pick_next_runnable(cur) else {
+        // Nothing runnable. Never resume a proc that just blocked or exited: run idle.
+        if cur == IDLE_PID {
+            return 0;
+        }
+        CURRENT.store(IDLE_PID, Ordering::Relaxed);
+        // Keep the current CR3; every address space maps the kernel image.
+        return unsafe { IDLE_TF_RSP };
+    };
     if next == cur {
         return 0;
     }
 
     unsafe {
-        gdt::set_rsp0(PROCS[next].kstack_top);
-        MANTRA_NEXT_CR3 = PROCS[next].cr3;
+        let p = &procs()[next];
+        kassert!(
+            p.state == ProcState::Runnable,
+            "sched: picked non-runnable proc {}",
+            next
+        );
+        gdt::set_rsp0(p.kstack_top);
+        MANTRA_NEXT_CR3 = p.cr3;
     }
     CURRENT.store(next, Ordering::Relaxed);
-    unsafe { PROCS[next].tf_rsp }
+    unsafe { procs()[next].tf_rsp }
 }
 
 pub fn yield_from_syscall(current_tf: u64) -> u64 {
+    YIELDS.fetch_add(1, Ordering::Relaxed);
     if !INITED.load(Ordering::Acquire) {
         return 0;
     }
-    switch_from(current_tf)
+    let cur = CURRENT.load(Ordering::Relaxed);
+    let still_runnable = cur < MAX_PROCS && unsafe { procs()[cur].state } == ProcState::Runnable;
+    let reason = if still_runnable {
+        swtrace::Reason::Yield
+    } else {
+        swtrace::Reason::Block
+    };
+    timed_switch_from(current_tf, reason)
+}
+
+/// Safe point for long-running syscalls (call with no locks held and no user access
+/// window open). Briefly lets a pending timer IRQ in; if it asked for a reschedule, the
+/// current proc yields here and resumes at this call when it next runs.
+pub fn preempt_point() {
+    if !INITED.load(Ordering::Acquire) {
+        return;
+    }
+    unsafe { core::arch::asm!("sti", "nop", "cli", options(nomem, nostack)) };
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        SYSCALL_PREEMPTIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { core::arch::asm!("int {}", const isr::KYIELD_VECTOR, options(nomem)) };
+    }
+}
+
+/// Times a syscall yielded at a `preempt_point`.
+pub fn syscall_preemptions() -> u64 {
+    SYSCALL_PREEMPTIONS.load(Ordering::Relaxed)
+}
+
+// `switch_from`, with its cost and a trace record kept when it actually picks another task.
+fn timed_switch_from(cur_tf: u64, reason: swtrace::Reason) -> u64 {
+    let t = crate::perf::start();
+    let cur = CURRENT.load(Ordering::Relaxed);
+    let next_tf = switch_from(cur_tf);
+    if next_tf != 0 {
+        SWITCHES.fetch_add(1, Ordering::Relaxed);
+        crate::perf::SWITCH.record(crate::perf::stop(t));
+        swtrace::record(cur, CURRENT.load(Ordering::Relaxed), reason, ticks());
+    }
+    next_tf
 }
 
+/// Scheduler counters since boot plus a snapshot of how many procs are in each state.
+#[derive(Copy, Clone, Default)]
+pub struct Stats {
+    pub switches: u64,          // context switches to a different task (idle included)
+    pub yields: u64,            // voluntary: yield, block, sleep and exit syscalls
+    pub timer_irqs: u64,        // timer interrupts seen by the scheduler
+    pub timer_preemptions: u64, // timer interrupts that switched tasks
+    pub syscall_preemptions: u64, // yields taken at a `preempt_point`
+    pub ticks: u64,             // scheduler ticks (see `ticks`)
+    pub idle_ticks: u64,        // of those, ticks the idle task was running
+    pub runnable: u64,
+    pub blocked: u64,
+    pub sleeping: u64,
+    pub zombie: u64,
+}
+
+pub fn stats() -> Stats {
+    let mut s = Stats {
+        switches: SWITCHES.load(Ordering::Relaxed),
+        yields: YIELDS.load(Ordering::Relaxed),
+        timer_irqs: TIMER_IRQS.load(Ordering::Relaxed),
+        timer_preemptions: TIMER_PREEMPTIONS.load(Ordering::Relaxed),
+        syscall_preemptions: syscall_preemptions(),
+        ticks: ticks(),
+        idle_ticks: idle_ticks(),
+        ..Stats::default()
+    };
+    without_interrupts(|| {
+        for p in unsafe { procs() }.iter() {
+            match p.state {
+                ProcState::Runnable => s.runnable += 1,
+                ProcState::Blocked(_) => s.blocked += 1,
+                ProcState::Sleeping(_) => s.sleeping += 1,
+                ProcState::Zombie => s.zombie += 1,
+                ProcState::Dead => {}
+            }
+        }
+    });
+    s
+}
+
+ktest! {
+    fn counters_move_before_init() {
+        // Drive the yield and timer entry points a known number of times before any proc exists:
+        // the counters must move by exactly that much, and nothing may be switched to.
+        const YIELDS_N: u64 = 5;
+        const TICKS_N: u64 = 3;
+        if INITED.load(Ordering::Acquire) {
+            kwarn!("sched: procs already installed, stats test skipped");
+            return;
+        }
+        let before = stats();
+        for _ in 0..YIELDS_N {
+            kassert!(
+                yield_from_syscall(0) == 0,
+                "yield switched before init"
+            );
+        }
+        let mut tf: TrapFrame = unsafe { core::mem::zeroed() };
+        for _ in 0..TICKS_N {
+            kassert!(
+                on_timer_irq(&mut tf) == 0,
+                "tick switched before init"
+            );
+        }
+        let after = stats();
+        kassert!(
+            after.yields - before.yields == YIELDS_N,
+            "yields counted {}",
+            after.yields - before.yields
+        );
+        kassert!(
+            after.timer_irqs - before.timer_irqs == TICKS_N,
+            "timer irqs counted {}",
+            after.timer_irqs - before.timer_irqs
+        );
+        kassert!(
+            after.switches == before.switches
+                && after.timer_preemptions == before.timer_preemptions,
+            "switches counted before init"
+        );
+        kdebug!(
+            "stats switches={} yields={} timer_irqs={} timer_preemptions={} syscall_preemptions={}",
+            after.switches,
+            after.yields,
+            after.timer_irqs,
+            after.timer_preemptions,
+            after.syscall_preemptions
+        );
+    }
+}
+
+/// Install `endpoint_id` in `pid`'s first free cap slot. None if there is none, or if the
+/// proc's cap budget (`limits`) is used up.
 pub fn cap_alloc_for(pid: usize, endpoint_id: u32) -> Option<u32> {
     if pid >= MAX_PROCS || endpoint_id == 0 {
         return None;
     }
+    crate::limits::check(pid, Resource::Caps, cap_count(pid) + 1).ok()?;
     unsafe {
-        for (i, slot) in PROCS[pid].caps.iter_mut().enumerate() {
+        for (i, slot) in procs()[pid].caps.iter_mut().enumerate() {
             if *slot == 0 {
                 *slot = endpoint_id;
                 return Some((i as u32) + 1);
@@ -189,47 +1290,301 @@ pub fn cap_alloc_for(pid: usize, endpoint_id: u32) -> Option<u32> {
     None
 }
 
-pub fn cap_alloc_current(endpoint_id: u32) -> Option<u32> {
-    cap_alloc_for(current_pid(), endpoint_id)
+/// Put `endpoint_id` in `pid`'s cap slot `cap`; false if the slot is taken or out of range.
+pub fn cap_install(pid: usize, cap: u32, endpoint_id: u32) -> bool {
+    let idx = (cap as usize).wrapping_sub(1);
+    if pid >= MAX_PROCS || idx >= 32 || endpoint_id == 0 {
+        return false;
+    }
+    unsafe {
+        let slot = &mut procs()[pid].caps[idx];
+        if *slot != 0 {
+            return false;
+        }
+        *slot = endpoint_id;
+    }
+    true
 }
 
-pub fn cap_lookup_current(cap: u32) -> Option<u32> {
+pub fn cap_lookup(pid: usize, cap: u32) -> Option<u32> {
     if cap == 0 {
         return None;
     }
     let idx = (cap as usize).wrapping_sub(1);
-    let pid = current_pid();
     if pid >= MAX_PROCS || idx >= 32 {
         return None;
     }
     unsafe {
-        let ep = PROCS[pid].caps[idx];
+        let ep = procs()[pid].caps[idx];
         if ep == 0 { None } else { Some(ep) }
     }
 }
 
+/// Clear one of `pid`'s cap slots. The endpoint itself is untouched.
+pub fn cap_drop(pid: usize, cap: u32) -> bool {
+    if cap_lookup(pid, cap).is_none() {
+        return false;
+    }
+    unsafe { procs()[pid].caps[cap as usize - 1] = 0 };
+    true
+}
+
+/// Remove every cap to `endpoint_id` from every proc (the endpoint is going away).
+pub fn revoke_endpoint(endpoint_id: u32) {
+    without_interrupts(|| unsafe {
+        for p in procs().iter_mut() {
+            for slot in p.caps.iter_mut().filter(|c| **c == endpoint_id) {
+                *slot = 0;
+            }
+        }
+    });
+}
+
+/// Procs allowed to manage resources they didn't create.
+pub fn is_privileged(pid: usize) -> bool {
+    pid < MAX_PROCS && unsafe { procs()[pid].role } == ROLE_INIT
+}
+
+/// Fail a receive or call `pid` is blocked in: its syscall returns `err` once it runs again.
+pub fn abort_wait(pid: usize, err: u64) {
+    if pid >= MAX_PROCS {
+        return;
+    }
+    unsafe {
+        let p = &procs()[pid];
+        if !matches!(p.state, ProcState::Blocked(_)) {
+            return;
+        }
+        let tf = &mut *(p.tf_rsp as *mut TrapFrame);
+        tf.rax = err;
+        tf.rdx = 0;
+    }
+    wake(pid);
+}
+
+/// Call `f(cap, endpoint_id)` for each non-empty cap slot of `pid` (caps are 1-based).
+pub fn cap_count(pid: usize) -> u64 {
+    let mut n = 0;
+    for_each_cap(pid, |_, _| n += 1);
+    n
+}
+
+pub fn for_each_cap(pid: usize, mut f: impl FnMut(u32, u32)) {
+    if pid >= MAX_PROCS {
+        return;
+    }
+    let caps = unsafe { procs()[pid].caps };
+    for (i, ep) in caps.iter().copied().enumerate() {
+        if ep != 0 {
+            f((i as u32) + 1, ep);
+        }
+    }
+}
+
+// Cap table snapshot: magic, count, then one (cap, endpoint id) pair per filled slot, all
+// little-endian u32s. Caps carry no rights or badges, so the endpoint id is the whole cap.
+// Nothing checkpoints or migrates procs yet, so only the ktests build the snapshot code.
+#[cfg(feature = "ktest")]
+const CAPS_MAGIC: u32 = u32::from_le_bytes(*b"MCAP");
+
+/// Bytes a snapshot of `caps` caps takes.
+#[cfg(feature = "ktest")]
+pub const fn caps_snapshot_len(caps: usize) -> usize {
+    8 + 8 * caps
+}
+
+/// Write a snapshot of `pid`'s cap table to `buf` for `import_caps`. Returns the bytes
+/// written, or None if `pid` is out of range or `buf` is too small.
+#[cfg(feature = "ktest")]
+pub fn export_caps(pid: usize, buf: &mut [u8]) -> Option<usize> {
+    if pid >= MAX_PROCS {
+        return None;
+    }
+    let caps = unsafe { procs()[pid].caps };
+    let n = caps.iter().filter(|&&ep| ep != 0).count();
+    let out = buf.get_mut(..caps_snapshot_len(n))?;
+    let mut words = out.chunks_exact_mut(4);
+    let mut put = |w: u32| {
+        if let Some(b) = words.next() {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+    };
+    put(CAPS_MAGIC);
+    put(n as u32);
+    for (i, &ep) in caps.iter().enumerate().filter(|&(_, &ep)| ep != 0) {
+        put(i as u32 + 1);
+        put(ep);
+    }
+    Some(out.len())
+}
+
+/// Install the caps of snapshot `buf` in the same slots of `pid`'s table. All or nothing:
+/// a malformed snapshot (`INVALID`), an endpoint destroyed since the export (`NOT_FOUND`),
+/// a slot `pid` already uses (`NO_CAP_SLOTS`) or a cap limit overrun (`LIMIT`) leaves the
+/// table as it was. Returns the number of caps installed.
+#[cfg(feature = "ktest")]
+pub fn import_caps(pid: usize, buf: &[u8]) -> Result<usize, u64> {
+    use mantra_sys::error;
+    if pid >= MAX_PROCS {
+        return Err(error::INVALID);
+    }
+    let word = |i: usize| {
+        let b = buf.get(i * 4..i * 4 + 4)?;
+        Some(u32::from_le_bytes(b.try_into().ok()?))
+    };
+    if word(0) != Some(CAPS_MAGIC) {
+        return Err(error::INVALID);
+    }
+    let n = word(1).ok_or(error::INVALID)? as usize;
+    if n > 32 || buf.len() != caps_snapshot_len(n) {
+        return Err(error::INVALID);
+    }
+    // Stage the whole table first so a bad entry anywhere fails before anything changes.
+    let mut staged = [0u32; 32];
+    for k in 0..n {
+        let (cap, ep) = (word(2 + 2 * k), word(3 + 2 * k));
+        let (Some(cap), Some(ep)) = (cap, ep) else {
+            return Err(error::INVALID);
+        };
+        let idx = (cap as usize).wrapping_sub(1);
+        if idx >= 32 || ep == 0 || staged[idx] != 0 {
+            return Err(error::INVALID);
+        }
+        staged[idx] = ep;
+    }
+    crate::limits::check(pid, Resource::Caps, cap_count(pid) + n as u64)?;
+    without_interrupts(|| unsafe {
+        let caps = &mut procs()[pid].caps;
+        for (&have, &ep) in caps.iter().zip(&staged).filter(|&(_, &ep)| ep != 0) {
+            if !crate::ipc::endpoint_live(ep) {
+                return Err(error::NOT_FOUND);
+            }
+            if have != 0 {
+                return Err(error::NO_CAP_SLOTS);
+            }
+        }
+        for (slot, &ep) in caps.iter_mut().zip(&staged).filter(|&(_, &ep)| ep != 0) {
+            *slot = ep;
+        }
+        Ok(n)
+    })
+}
+
+ktest! {
+    fn cap_snapshot_round_trips() {
+        // Export three caps from one unused proc slot and import them into another: the imported
+        // caps must reach the same endpoints, a snapshot naming a destroyed endpoint or a truncated
+        // one must be refused, and a refused import must leave the table untouched.
+        use crate::ipc;
+        use mantra_sys::error;
+        const SRC: usize = MAX_PROCS - 2;
+        const DST: usize = MAX_PROCS - 1;
+        if cap_count(SRC) != 0 || cap_count(DST) != 0 {
+            kdebug!("sched: cap snapshot test skipped, procs in use");
+            return;
+        }
+        let mut caps = [0u32; 3];
+        for i in 0..caps.len() {
+            let r = ipc::ep_create(SRC, 4, 16, 0);
+            if r > 32 {
+                kwarn!("sched: cap snapshot test skipped, ep_create: {:#x}", r);
+                for &cap in &caps[..i] {
+                    ipc::ep_destroy(SRC, cap);
+                }
+                return;
+            }
+            caps[i] = r as u32;
+        }
+        let mut buf = [0u8; caps_snapshot_len(3)];
+        let len = export_caps(SRC, &mut buf);
+        let imported = import_caps(DST, &buf);
+        // Send through the imported caps and receive through the originals.
+        let mut works = true;
+        for &cap in &caps {
+            let mut b = [0u8; 16];
+            works &= ipc::ep_send(DST, cap, &[cap as u8, 0x5a]) == 2
+                && ipc::ep_recv(SRC, cap, &mut b) == 2
+                && b[..2] == [cap as u8, 0x5a];
+        }
+        let again = import_caps(DST, &buf);
+        for &cap in &caps {
+            cap_drop(DST, cap);
+        }
+        ipc::ep_destroy(SRC, caps[2]);
+        let stale = import_caps(DST, &buf);
+        let untouched = cap_count(DST) == 0;
+        let short = import_caps(DST, &buf[..buf.len() - 1]);
+        for &cap in &caps[..2] {
+            ipc::ep_destroy(SRC, cap);
+        }
+        kassert!(
+            len == Some(buf.len()) && imported == Ok(3) && works,
+            "cap export {:?}, import {:?}, imported caps work: {}",
+            len,
+            imported,
+            works
+        );
+        kassert!(
+            again == Err(error::NO_CAP_SLOTS),
+            "import over filled slots gave {:?}",
+            again
+        );
+        kassert!(
+            stale == Err(error::NOT_FOUND) && untouched,
+            "import naming a destroyed endpoint gave {:?} (table untouched: {})",
+            stale,
+            untouched
+        );
+        kassert!(
+            short == Err(error::INVALID),
+            "truncated snapshot gave {:?}",
+            short
+        );
+    }
+}
+
 pub fn on_timer_irq(current_tf: *mut TrapFrame) -> u64 {
+    TIMER_IRQS.fetch_add(1, Ordering::Relaxed);
     if !INITED.load(Ordering::Acquire) {
         return 0;
     }
 
     let t = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::rng::on_tick(t);
+    user::publish_time(t);
+    crate::timer::expire(t);
     let cur = CURRENT.load(Ordering::Relaxed);
+    if cur < MAX_PROCS {
+        unsafe { procs()[cur].ticks += 1 };
+    } else {
+        IDLE_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    match irq_switch(deterministic(), cur, unsafe { &*current_tf }.is_user()) {
+        IrqSwitch::Now => {}
+        IrqSwitch::AtSafePoint => {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+            return 0;
+        }
+        IrqSwitch::Never => return 0,
+    }
     // Save and potentially switch. If all other tasks are blocked, this returns 0 and we keep running cur.
-    let next_tf = switch_from(current_tf as u64);
+    let next_tf = timed_switch_from(current_tf as u64, swtrace::Reason::Timer);
     if next_tf == 0 {
         return 0;
     }
+    TIMER_PREEMPTIONS.fetch_add(1, Ordering::Relaxed);
     let next = CURRENT.load(Ordering::Relaxed);
 
-    if (t % 100) == 0 {
-        serial::write_str("sched: tick=");
-        serial::write_dec_u64(t);
-        serial::write_str(" switch ");
-        serial::write_dec_u64(cur as u64);
-        serial::write_str("->");
-        serial::write_dec_u64(next as u64);
-        serial::write_str("\n");
+    if t.is_multiple_of(crate::timer::hz() as u64) {
+        kdebug!(
+            "sched: tick={} ms={} switch {}->{}",
+            t,
+            crate::timer::ticks_to_ms(t),
+            cur,
+            next
+        );
+        kdebug!("sched: syscall preemptions={}", syscall_preemptions());
+        crate::perf::report();
     }
     next_tf
 }
@@ -1,24 +1,56 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::interrupts;
+use crate::arch::x86_64::port::Port;
+
 pub fn init() {
     unsafe {
         // Disable interrupts
-        outb(COM1 + 1, 0x00);
+        INT_ENABLE.write(0x00);
         // Enable DLAB
-        outb(COM1 + 3, 0x80);
+        LINE_CTRL.write(0x80);
         // Divisor (lo/hi) for 115200 baud on 1.8432 MHz clock => 1
-        outb(COM1 + 0, 0x01);
-        outb(COM1 + 1, 0x00);
+        DATA.write(0x01);
+        INT_ENABLE.write(0x00);
         // 8 bits, no parity, one stop bit
-        outb(COM1 + 3, 0x03);
+        LINE_CTRL.write(0x03);
         // Enable FIFO, clear, 14-byte threshold
-        outb(COM1 + 2, 0xC7);
+        FIFO_CTRL.write(0xC7);
         // IRQs enabled, RTS/DSR set
-        outb(COM1 + 4, 0x0B);
+        MODEM_CTRL.write(0x0B);
+    }
+}
+
+// Serializes output so a write from IRQ context can't land in the middle of another.
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Run `f` with interrupts off and the serial lock held. If the lock is already held with
+// interrupts off, we are nested inside the holder (e.g. a fault handler logging mid-write
+// on this single CPU); spinning would deadlock, so write through without the lock.
+fn locked(f: impl FnOnce()) {
+    let _irq = interrupts::disable();
+    let nested = LOCK.swap(true, Ordering::Acquire);
+    f();
+    if !nested {
+        LOCK.store(false, Ordering::Release);
     }
 }
 
 pub fn write_str(s: &str) {
-    for b in s.bytes() {
-        write_byte(b);
+    locked(|| {
+        for b in s.bytes() {
+            put(b);
+        }
+    });
+}
+
+/// `core::fmt` adapter, for formatted output such as panic messages.
+pub struct Writer;
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_str(s);
+        Ok(())
     }
 }
 
@@ -34,40 +66,46 @@ pub fn write_dec_u64(mut v: u64) {
         v /= 10;
         i += 1;
     }
-    while i > 0 {
-        i -= 1;
-        write_byte(buf[i]);
-    }
+    locked(|| {
+        while i > 0 {
+            i -= 1;
+            put(buf[i]);
+        }
+    });
 }
 
 pub fn write_hex_u64(v: u64) {
-    write_str("0x");
-    for i in (0..16).rev() {
-        let shift = i * 4;
-        let d = ((v >> shift) & 0xf) as u8;
-        let c = match d {
-            0..=9 => b'0' + d,
-            _ => b'a' + (d - 10),
-        };
-        write_byte(c);
-    }
+    locked(|| {
+        put(b'0');
+        put(b'x');
+        for i in (0..16).rev() {
+            let shift = i * 4;
+            let d = ((v >> shift) & 0xf) as u8;
+            let c = match d {
+                0..=9 => b'0' + d,
+                _ => b'a' + (d - 10),
+            };
+            put(c);
+        }
+    });
 }
 
 const COM1: u16 = 0x3F8;
+// 16550 registers; with DLAB set, DATA and INT_ENABLE hold the divisor (lo/hi).
+const DATA: Port<u8> = Port::new(COM1);
+const INT_ENABLE: Port<u8> = Port::new(COM1 + 1);
+const FIFO_CTRL: Port<u8> = Port::new(COM1 + 2);
+const LINE_CTRL: Port<u8> = Port::new(COM1 + 3);
+const MODEM_CTRL: Port<u8> = Port::new(COM1 + 4);
+const LINE_STATUS: Port<u8> = Port::new(COM1 + 5);
 
 pub fn write_byte(b: u8) {
-    unsafe {
-        while (inb(COM1 + 5) & 0x20) == 0 {}
-        outb(COM1, b);
-    }
+    locked(|| put(b));
 }
 
-unsafe fn outb(port: u16, val: u8) {
-    core::arch::asm!("out dx, al", in("dx") port, in("al") val, options(nomem, nostack, preserves_flags));
-}
-
-unsafe fn inb(port: u16) -> u8 {
-    let mut val: u8;
-    core::arch::asm!("in al, dx", in("dx") port, out("al") val, options(nomem, nostack, preserves_flags));
-    val
+fn put(b: u8) {
+    unsafe {
+        while (LINE_STATUS.read() & 0x20) == 0 {}
+        DATA.write(b);
+    }
 }
@@ -0,0 +1,160 @@
+// Trace of the most recent context switches, dumped on panic to see what ran before a hang
+// or a crash. A record packs into one word of a lock-free ring, so taking it costs an
+// atomic add and a store and does no I/O.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::serial;
+
+const LEN: usize = 64;
+
+/// Why the scheduler switched.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reason {
+    Timer = 1, // the tick preempted the running task
+    Yield = 2, // a syscall gave up the CPU and is still runnable
+    Block = 3, // a syscall blocked, slept or exited
+    Wake = 4,  // a sleeper woke before the next tick (hrtimer)
+}
+
+impl Reason {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Reason::Timer),
+            2 => Some(Reason::Yield),
+            3 => Some(Reason::Block),
+            4 => Some(Reason::Wake),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Timer => "timer",
+            Reason::Yield => "yield",
+            Reason::Block => "block",
+            Reason::Wake => "wake",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub from: u8, // pids; the idle task is `sched::IDLE_PID`
+    pub to: u8,
+    pub reason: Reason,
+    pub tick: u64, // low 40 bits of the scheduler tick
+}
+
+impl Record {
+    // tick in bits 63..24, from in 23..16, to in 15..8, reason in 7..0. Reasons start at 1,
+    // so a zero word is a slot never written.
+    fn pack(self) -> u64 {
+        (self.tick << 24)
+            | ((self.from as u64) << 16)
+            | ((self.to as u64) << 8)
+            | self.reason as u64
+    }
+
+    fn unpack(w: u64) -> Option<Self> {
+        Some(Self {
+            from: (w >> 16) as u8,
+            to: (w >> 8) as u8,
+            reason: Reason::from_u8(w as u8)?,
+            tick: w >> 24,
+        })
+    }
+}
+
+/// Ring of the last `LEN` records. Writers never wait: a slot overwritten while it is
+/// read just shows the newer record.
+pub struct Trace {
+    ring: [AtomicU64; LEN],
+    next: AtomicU64,
+}
+
+impl Trace {
+    pub const fn new() -> Self {
+        Self {
+            ring: [const { AtomicU64::new(0) }; LEN],
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, r: Record) {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.ring[(i % LEN as u64) as usize].store(r.pack(), Ordering::Relaxed);
+    }
+
+    /// Call `f` for each record still in the ring, oldest first.
+    pub fn for_each(&self, mut f: impl FnMut(Record)) {
+        let end = self.next.load(Ordering::Relaxed);
+        for i in end.saturating_sub(LEN as u64)..end {
+            let w = self.ring[(i % LEN as u64) as usize].load(Ordering::Relaxed);
+            if let Some(r) = Record::unpack(w) {
+                f(r);
+            }
+        }
+    }
+}
+
+pub static SWITCHES: Trace = Trace::new();
+
+/// Record a switch in the global trace.
+pub fn record(from: usize, to: usize, reason: Reason, tick: u64) {
+    SWITCHES.record(Record {
+        from: from as u8,
+        to: to as u8,
+        reason,
+        tick: tick & ((1 << 40) - 1),
+    });
+}
+
+/// Print the global trace to serial, oldest first. Never allocates (panic path).
+pub fn dump() {
+    serial::write_str("  last switches (tick from->to reason):\n");
+    SWITCHES.for_each(|r| {
+        serial::write_str("    ");
+        serial::write_dec_u64(r.tick);
+        serial::write_str(" ");
+        serial::write_dec_u64(r.from as u64);
+        serial::write_str("->");
+        serial::write_dec_u64(r.to as u64);
+        serial::write_str(" ");
+        serial::write_str(r.reason.name());
+        serial::write_str("\n");
+    });
+}
+
+ktest! {
+    fn ring_keeps_the_last_records() {
+        // Replay a known switch sequence into a private trace, long enough to wrap the ring: the
+        // trace must hold exactly its last `LEN` records, in order.
+        const REASONS: [Reason; 4] = [Reason::Timer, Reason::Yield, Reason::Block, Reason::Wake];
+        const N: usize = LEN + 5;
+        let switch = |i: usize| Record {
+            from: (i % 3) as u8,
+            to: ((i + 1) % 3) as u8,
+            reason: REASONS[i % REASONS.len()],
+            tick: 1000 + i as u64,
+        };
+        let trace = Trace::new();
+        for i in 0..N {
+            trace.record(switch(i));
+        }
+        let mut seen = 0;
+        let mut ok = true;
+        trace.for_each(|r| {
+            ok &= r == switch(N - LEN + seen);
+            seen += 1;
+        });
+        kassert!(
+            ok && seen == LEN,
+            "replayed {} switches, trace holds {} (in order: {})",
+            N,
+            seen,
+            ok
+        );
+    }
+}
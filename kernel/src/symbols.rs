@@ -0,0 +1,215 @@
+// Kernel symbol lookup for backtraces. The bootloader hands over the kernel ELF file as it
+// was read from disk; `resolve` scans its `.symtab` in place, so it never allocates and is
+// safe to call from fault handlers.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::x86_64::paging;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+struct Elf64Shdr {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+#[repr(C)]
+struct Elf64Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+// Virtual addresses and lengths of the symbol and string tables; 0 until `init` finds them.
+static SYMTAB: AtomicU64 = AtomicU64::new(0);
+static SYMTAB_LEN: AtomicU64 = AtomicU64::new(0);
+static STRTAB: AtomicU64 = AtomicU64::new(0);
+static STRTAB_LEN: AtomicU64 = AtomicU64::new(0);
+
+fn u16_at(b: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(b.get(off..off + 8)?.try_into().ok()?))
+}
+
+// Locate `.symtab` and its string table in `elf`: ((offset, len), (offset, len)).
+fn find_tables(elf: &[u8]) -> Option<((usize, usize), (usize, usize))> {
+    if elf.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let shoff = u64_at(elf, 0x28)? as usize;
+    let shentsize = u16_at(elf, 0x3a)? as usize;
+    let shnum = u16_at(elf, 0x3c)? as usize;
+    if shentsize != core::mem::size_of::<Elf64Shdr>() {
+        return None;
+    }
+    let shdrs = elf.get(shoff..shoff.checked_add(shnum * shentsize)?)?;
+    let shdr = |i: usize| -> Option<&Elf64Shdr> {
+        let b = shdrs.get(i * shentsize..(i + 1) * shentsize)?;
+        Some(unsafe { &*(b.as_ptr() as *const Elf64Shdr) })
+    };
+    let in_file = |off: u64, len: u64| {
+        let (off, len) = (off as usize, len as usize);
+        (off.checked_add(len)? <= elf.len()).then_some((off, len))
+    };
+    for i in 0..shnum {
+        let sh = shdr(i)?;
+        if sh.sh_type != SHT_SYMTAB || sh.sh_entsize as usize != core::mem::size_of::<Elf64Sym>() {
+            continue;
+        }
+        let strtab = shdr(sh.sh_link as usize)?;
+        return Some((
+            in_file(sh.sh_offset, sh.sh_size)?,
+            in_file(strtab.sh_offset, strtab.sh_size)?,
+        ));
+    }
+    None
+}
+
+/// Record the kernel ELF file at physical `file_phys`. Needs the HHDM (after paging init);
+/// without a usable `.symtab`, backtraces just stay unsymbolized.
+pub fn init(file_phys: u64, len: u64) {
+    if file_phys == 0 || !paging::hhdm_covers(file_phys, len) {
+        kwarn!("symbols: kernel file not reachable, backtraces unsymbolized");
+        return;
+    }
+    let base = paging::phys_to_virt(file_phys);
+    let elf = unsafe { core::slice::from_raw_parts(base as *const u8, len as usize) };
+    let Some(((sym_off, sym_len), (str_off, str_len))) = find_tables(elf) else {
+        kwarn!("symbols: no .symtab in the kernel file, backtraces unsymbolized");
+        return;
+    };
+    SYMTAB.store(base + sym_off as u64, Ordering::Relaxed);
+    SYMTAB_LEN.store(sym_len as u64, Ordering::Relaxed);
+    STRTAB.store(base + str_off as u64, Ordering::Relaxed);
+    STRTAB_LEN.store(str_len as u64, Ordering::Relaxed);
+    kinfo!(
+        "symbols: {} symbol table entries",
+        sym_len / core::mem::size_of::<Elf64Sym>()
+    );
+}
+
+fn tables() -> Option<(&'static [Elf64Sym], &'static [u8])> {
+    let sym = SYMTAB.load(Ordering::Relaxed);
+    if sym == 0 {
+        return None;
+    }
+    let n = SYMTAB_LEN.load(Ordering::Relaxed) as usize / core::mem::size_of::<Elf64Sym>();
+    let strtab = STRTAB.load(Ordering::Relaxed);
+    let str_len = STRTAB_LEN.load(Ordering::Relaxed) as usize;
+    unsafe {
+        Some((
+            core::slice::from_raw_parts(sym as *const Elf64Sym, n),
+            core::slice::from_raw_parts(strtab as *const u8, str_len),
+        ))
+    }
+}
+
+fn name_at(strtab: &'static [u8], off: u32) -> Option<&'static str> {
+    let s = strtab.get(off as usize..)?;
+    let end = s.iter().position(|&c| c == 0)?;
+    core::str::from_utf8(&s[..end]).ok()
+}
+
+/// The function containing `addr` and the offset into it, or None if no symbol covers it.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let (syms, strtab) = tables()?;
+    let sym = syms.iter().find(|s| {
+        (s.st_info & 0xf) == STT_FUNC
+            && s.st_value != 0
+            && addr >= s.st_value
+            && addr - s.st_value < s.st_size.max(1)
+    })?;
+    Some((name_at(strtab, sym.st_name)?, addr - sym.st_value))
+}
+
+// Split a `<len><ident>` run off the front of a mangled body.
+fn next_segment(rest: &str) -> Option<(&str, &str)> {
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = rest[..digits].parse().ok()?;
+    let seg = rest.get(digits..digits + len)?;
+    Some((seg, &rest[digits + len..]))
+}
+
+// Path segments of a legacy-mangled Rust name (`_ZN<len><ident>...E`), minus the trailing
+// `h<hash>`. None for anything else (v0 or C names), which are shown as-is.
+fn legacy_segments(name: &str) -> Option<impl Iterator<Item = &str>> {
+    let body = name.strip_prefix("_ZN")?.strip_suffix('E')?;
+    let mut check = body;
+    while !check.is_empty() {
+        check = next_segment(check)?.1;
+    }
+    let mut rest = body;
+    Some(core::iter::from_fn(move || {
+        let (seg, tail) = next_segment(rest)?;
+        rest = tail;
+        let is_hash = rest.is_empty()
+            && seg.len() == 17
+            && seg.starts_with('h')
+            && seg[1..].bytes().all(|c| c.is_ascii_hexdigit());
+        (!is_hash).then_some(seg)
+    }))
+}
+
+/// Displays a symbol name with legacy Rust mangling undone (`a::b::c`).
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(segs) = legacy_segments(self.0) else {
+            return f.write_str(self.0);
+        };
+        for (i, seg) in segs.enumerate() {
+            if i != 0 {
+                f.write_str("::")?;
+            }
+            f.write_str(seg)?;
+        }
+        Ok(())
+    }
+}
+
+ktest! {
+    fn resolves_own_address() {
+        // Resolve this function's own address: it must map back to
+        // `symbols::resolves_own_address` at offset 0, one byte further to offset 1, and
+        // address 0 to nothing.
+        if tables().is_none() {
+            kwarn!("symbols: no table, test skipped");
+            return;
+        }
+        let addr = resolves_own_address as *const () as u64;
+        let hit = resolve(addr);
+        let ok = hit.is_some_and(|(name, off)| {
+            let mut tail = [""; 2];
+            if let Some(segs) = legacy_segments(name) {
+                for seg in segs {
+                    tail = [tail[1], seg];
+                }
+            }
+            off == 0 && tail == ["symbols", "resolves_own_address"]
+        });
+        kassert!(ok, "{:#x} resolved to {:?}", addr, hit);
+        kassert!(
+            resolve(addr + 1).map(|(_, off)| off) == Some(1),
+            "offset into the function wrong"
+        );
+        kassert!(resolve(0).is_none(), "address 0 resolved");
+    }
+}
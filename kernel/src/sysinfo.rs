@@ -0,0 +1,105 @@
+// Kernel state served over IPC on the introspection endpoint (`mantra_sys::sysinfo`).
+// Requests are answered inline from the IPC_CALL syscall; nothing ever queues on it.
+
+use crate::arch::x86_64::debugcon;
+use crate::{heap, ipc, pmm, sched};
+use mantra_sys::sysinfo::{self, IpcStats, MemInfo, ProcEntry, TestReport};
+use mantra_sys::{error, MsgHeader};
+
+/// Answer request `req` from `pid` into `out` (header included); returns the reply length.
+pub fn handle(pid: usize, req: &[u8], out: &mut [u8]) -> Result<usize, u64> {
+    if !sched::is_privileged(pid) {
+        return Err(error::PERMISSION);
+    }
+    let Some((hdr, payload)) = MsgHeader::parse(req) else {
+        return Err(error::INVALID);
+    };
+    let Some((head, body)) = out.split_at_mut_checked(MsgHeader::SIZE) else {
+        return Err(error::INVALID);
+    };
+
+    let len = match hdr.tag {
+        sysinfo::MEMINFO => {
+            let mem = pmm::stats();
+            let (heap_used, heap_bytes) = heap::usage();
+            let info = MemInfo {
+                usable_bytes: mem.usable_bytes,
+                free_bytes: mem.free_bytes,
+                heap_bytes,
+                heap_used,
+            };
+            put(body, &info.to_bytes())?
+        }
+        sysinfo::LIST_PROCS => {
+            let mut len = 0;
+            let live = (0..)
+                .map_while(|pid| sched::proc_info(pid).map(|info| (pid, info)))
+                .filter(|(_, (state, _, _))| *state != sched::ProcState::Dead);
+            for (pid, (state, mapped_pages, parent)) in live {
+                let entry = ProcEntry {
+                    pid: pid as u64,
+                    state: state.code(),
+                    mapped_pages,
+                    parent: if parent == sched::NO_PARENT {
+                        u64::MAX
+                    } else {
+                        parent as u64
+                    },
+                };
+                match put(&mut body[len..], &entry.to_bytes()) {
+                    Ok(n) => len += n,
+                    Err(_) => break,
+                }
+            }
+            len
+        }
+        sysinfo::IPC_STATS => {
+            let (endpoints, max_endpoints, queued) = ipc::stats();
+            let stats = IpcStats {
+                endpoints: endpoints as u64,
+                max_endpoints: max_endpoints as u64,
+                queued: queued as u64,
+            };
+            put(body, &stats.to_bytes())?
+        }
+        sysinfo::EP_STATS => {
+            let mut len = 0;
+            let live = (1..=ipc::MAX_ENDPOINTS as u32).filter_map(ipc::ep_stats);
+            for stats in live {
+                match put(&mut body[len..], &stats.to_bytes()) {
+                    Ok(n) => len += n,
+                    Err(_) => break,
+                }
+            }
+            len
+        }
+        sysinfo::TEST_REPORT => {
+            let report = TestReport::parse(payload).ok_or(error::INVALID)?;
+            kinfo!(
+                "sysinfo: pid {} ran {} checks, {} failed",
+                pid,
+                report.checks,
+                report.failed
+            );
+            // Under QEMU this is the end of the run.
+            debugcon::checks_done(report.failed);
+            0
+        }
+        _ => return Err(error::INVALID),
+    };
+
+    head.copy_from_slice(
+        &MsgHeader {
+            tag: hdr.tag,
+            len: len as u32,
+        }
+        .to_bytes(),
+    );
+    Ok(MsgHeader::SIZE + len)
+}
+
+fn put(out: &mut [u8], bytes: &[u8]) -> Result<usize, u64> {
+    let dst = out.get_mut(..bytes.len()).ok_or(error::INVALID)?;
+    dst.copy_from_slice(bytes);
+    Ok(bytes.len())
+}
@@ -0,0 +1,188 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::x86_64::{pit, rdtsc};
+
+/// Requested timer/scheduler tick frequency. The kernel command line doesn't carry it,
+/// so this is the single place to change it.
+pub const HZ: u32 = 100;
+
+// PIT divisor actually programmed (0 until the timer is initialized).
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+// Calibrated TSC frequency (0 = not calibrated / no TSC).
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_divisor(divisor: u16) {
+    DIVISOR.store(divisor as u32, Ordering::Relaxed);
+}
+
+fn divisor() -> u32 {
+    match DIVISOR.load(Ordering::Relaxed) {
+        0 => pit::divisor_for(HZ) as u32,
+        d => d,
+    }
+}
+
+/// Achieved tick frequency after divisor rounding (integer Hz, for logging/periods).
+pub fn hz() -> u32 {
+    pit::hz_for(divisor())
+}
+
+/// Convert timer ticks to milliseconds using the real divisor.
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    let ms = (ticks as u128) * (divisor() as u128) * 1000 / (pit::BASE_HZ as u128);
+    ms as u64
+}
+
+/// Ticks needed to cover at least `ns` nanoseconds.
+pub fn ns_to_ticks_ceil(ns: u64) -> u64 {
+    let ticks =
+        ((ns as u128) * (pit::BASE_HZ as u128)).div_ceil((divisor() as u128) * 1_000_000_000);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+pub fn set_tsc_khz(khz: u64) {
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+}
+
+/// TSC frequency in kHz, if calibrated.
+pub fn tsc_khz() -> Option<u64> {
+    match TSC_KHZ.load(Ordering::Relaxed) {
+        0 => None,
+        k => Some(k),
+    }
+}
+
+/// Convert a TSC cycle delta to microseconds (None if uncalibrated).
+pub fn cycles_to_us(cycles: u64) -> Option<u64> {
+    let khz = tsc_khz()?;
+    Some(((cycles as u128) * 1000 / (khz as u128)) as u64)
+}
+
+/// Nanoseconds on the TSC clock (None if uncalibrated). Only differences are meaningful.
+pub fn now_ns() -> Option<u64> {
+    let khz = tsc_khz()?;
+    Some(((rdtsc() as u128) * 1_000_000 / (khz as u128)) as u64)
+}
+
+/// Run when a deadline passes, in timer IRQ context: `(arg, now)` with `now` in ticks.
+pub type Callback = fn(u64, u64);
+
+#[derive(Copy, Clone)]
+struct Deadline {
+    tick: u64,
+    cb: Callback,
+    arg: u64,
+}
+
+fn nop(_: u64, _: u64) {}
+
+// Pending deadlines sorted latest-first, so everything due sits at the end and `expire`
+// pops it without touching the rest.
+const MAX_DEADLINES: usize = 32;
+static mut DEADLINES: [Deadline; MAX_DEADLINES] = [Deadline {
+    tick: 0,
+    cb: nop,
+    arg: 0,
+}; MAX_DEADLINES];
+static mut DEADLINES_LEN: usize = 0;
+
+unsafe fn deadlines() -> &'static mut [Deadline; MAX_DEADLINES] {
+    let deadlines = &raw mut DEADLINES;
+    &mut *deadlines
+}
+
+fn same(a: &Deadline, cb: Callback, arg: u64) -> bool {
+    core::ptr::fn_addr_eq(a.cb, cb) && a.arg == arg
+}
+
+// Drop the entry for (cb, arg), if any. Interrupts must be disabled.
+unsafe fn remove(cb: Callback, arg: u64) -> bool {
+    let q = deadlines();
+    let Some(i) = q[..DEADLINES_LEN].iter().position(|d| same(d, cb, arg)) else {
+        return false;
+    };
+    q.copy_within(i + 1..DEADLINES_LEN, i);
+    DEADLINES_LEN -= 1;
+    true
+}
+
+/// Call `cb(arg, now)` once the tick count reaches `tick`. A pending deadline for the same
+/// (cb, arg) is moved rather than duplicated. False if the table is full.
+pub fn add(tick: u64, cb: Callback, arg: u64) -> bool {
+    without_interrupts(|| unsafe {
+        remove(cb, arg);
+        if DEADLINES_LEN == MAX_DEADLINES {
+            return false;
+        }
+        let q = deadlines();
+        let at = q[..DEADLINES_LEN].partition_point(|d| d.tick > tick);
+        q.copy_within(at..DEADLINES_LEN, at + 1);
+        q[at] = Deadline { tick, cb, arg };
+        DEADLINES_LEN += 1;
+        true
+    })
+}
+
+/// Forget the pending deadline for (cb, arg); false if there was none.
+pub fn cancel(cb: Callback, arg: u64) -> bool {
+    without_interrupts(|| unsafe { remove(cb, arg) })
+}
+
+/// Run every callback whose deadline is at or before `now`, earliest first. Timer IRQ
+/// context (interrupts disabled); callbacks may add new deadlines.
+pub fn expire(now: u64) {
+    loop {
+        let due = unsafe {
+            match DEADLINES_LEN.checked_sub(1) {
+                Some(last) if deadlines()[last].tick <= now => {
+                    DEADLINES_LEN = last;
+                    deadlines()[last]
+                }
+                _ => return,
+            }
+        };
+        (due.cb)(due.arg, now);
+    }
+}
+
+ktest! {
+    fn deadlines_fire_in_order() {
+        // Queue deadlines out of order (one of them moved), expire tick by tick and check each
+        // callback ran once, at its own tick, in deadline order. Needs the table to itself.
+        if unsafe { DEADLINES_LEN } != 0 {
+            kwarn!("timer: deadlines already queued, test skipped");
+            return;
+        }
+        static mut FIRED: [(u64, u64); 4] = [(0, 0); 4];
+        static mut FIRED_N: usize = 0;
+        fn record(arg: u64, now: u64) {
+            unsafe {
+                if FIRED_N < 4 {
+                    FIRED[FIRED_N] = (arg, now);
+                    FIRED_N += 1;
+                }
+            }
+        }
+
+        for (tick, arg) in [(30, 3), (10, 1), (50, 9), (20, 2)] {
+            kassert!(add(tick, record, arg), "deadline table full");
+        }
+        // Re-adding (record, 9) moves it instead of queueing it twice.
+        add(40, record, 9);
+        for now in 0..=60 {
+            expire(now);
+        }
+        let fired = unsafe { FIRED };
+        kassert!(
+            fired == [(1, 10), (2, 20), (3, 30), (9, 40)] && unsafe { FIRED_N } == 4,
+            "deadlines fired as {:?}",
+            fired
+        );
+        kassert!(
+            unsafe { DEADLINES_LEN } == 0,
+            "deadlines left behind"
+        );
+    }
+}
@@ -1,51 +1,56 @@
 use crate::arch::x86_64::gdt;
 use crate::arch::x86_64::isr;
+use crate::arch::x86_64::isr::TrapFrame;
 use crate::arch::x86_64::paging;
+use crate::arch::x86_64::smap;
 use crate::init_elf;
 use crate::ipc;
+use crate::launcher;
+use crate::limits::{self, Resource};
+use crate::oom;
 use crate::pmm;
+use crate::rng;
 use crate::sched;
 use crate::serial;
+use crate::util::{align_down, align_up_checked};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mantra_sys::{error, spawn_caps, spawn_flags, SpawnCap};
 
 const PAGE_SIZE: u64 = 4096;
 
 const PTE_P: u64 = 1 << 0;
 const PTE_RW: u64 = 1 << 1;
 const PTE_U: u64 = 1 << 2;
+const PTE_PS: u64 = 1 << 7;
+// Software-available bit: the frame belongs to a shared cache (text, program templates), not
+// to this address space, so `free_user_space` leaves it alone.
+const PTE_SHARED: u64 = 1 << 9;
+// Software-available bit: a read-only view of a shared frame (the zero frame, or a page of a
+// program template) that gets a private copy on its first write (`fault_in_cow`). Always
+// set together with `PTE_SHARED`.
+const PTE_COW: u64 = 1 << 10;
+
+// The frame behind every zero-fill page, allocated on first use and never freed.
+static ZERO_FRAME: AtomicU64 = AtomicU64::new(0);
+// The frame behind `mantra_sys::TIME_PAGE` in every address space; same lifetime.
+static TIME_FRAME: AtomicU64 = AtomicU64::new(0);
 
 // Transition stack used while switching CR3 and building the iretq frame.
 // The kernel's current stack may still be in boot/firmware memory, which won't be
 // mapped in the user CR3 (we only map the kernel image + HHDM + user pages).
 static mut USER_SWITCH_STACK: [u8; 16 * 1024] = [0; 16 * 1024];
 
-static BOOT_KB: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
-static BOOT_KE: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
-static BOOT_MAX: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
-
-fn align_down(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    x & !(a - 1)
-}
-
-fn align_up(x: u64, a: u64) -> u64 {
-    if a == 0 {
-        return x;
-    }
-    (x + (a - 1)) & !(a - 1)
-}
-
 unsafe fn zero_page(p: u64) {
     core::ptr::write_bytes(paging::phys_to_virt_ptr::<u8>(p), 0, PAGE_SIZE as usize);
 }
 
-unsafe fn alloc_table() -> u64 {
-    let p = pmm::alloc_pages(1).expect("user: alloc_pages failed");
+unsafe fn alloc_table() -> Option<u64> {
+    let p = pmm::alloc_frame()?;
     zero_page(p);
-    p
+    Some(p)
 }
 
 unsafe fn invlpg(addr: u64) {
@@ -56,7 +61,7 @@ unsafe fn table_entry_mut(table_phys: u64, idx: usize) -> *mut u64 {
     paging::phys_to_virt_ptr::<u64>(table_phys).add(idx)
 }
 
-unsafe fn get_or_alloc_table(entry: *mut u64, flags: u64) -> u64 {
+unsafe fn get_or_alloc_table(entry: *mut u64, flags: u64) -> Option<u64> {
     let mut v = core::ptr::read_volatile(entry);
     if (v & PTE_P) != 0 {
         // For user mappings, every level must have the U bit set.
@@ -64,18 +69,18 @@ unsafe fn get_or_alloc_table(entry: *mut u64, flags: u64) -> u64 {
             v |= PTE_U;
             core::ptr::write_volatile(entry, v);
         }
-        return v & 0x000f_ffff_ffff_f000;
+        return Some(v & 0x000f_ffff_ffff_f000);
     }
-    let t = alloc_table();
+    let t = alloc_table()?;
     let mut e = t | (PTE_P | PTE_RW);
     if (flags & PTE_U) != 0 {
         e |= PTE_U;
     }
     core::ptr::write_volatile(entry, e);
-    t
+    Some(t)
 }
 
-unsafe fn map_4k(pml4: u64, virt: u64, phys: u64, flags: u64) {
+unsafe fn map_4k(pml4: u64, virt: u64, phys: u64, flags: u64) -> Option<()> {
     let virt = align_down(virt, PAGE_SIZE);
     let phys = align_down(phys, PAGE_SIZE);
 
@@ -85,37 +90,237 @@ unsafe fn map_4k(pml4: u64, virt: u64, phys: u64, flags: u64) {
     let pt_i = ((virt >> 12) & 0x1ff) as usize;
 
     let pml4e = table_entry_mut(pml4, pml4_i);
-    let pdpt = get_or_alloc_table(pml4e, flags);
+    let pdpt = get_or_alloc_table(pml4e, flags)?;
 
     let pdpte = table_entry_mut(pdpt, pdpt_i);
-    let pd = get_or_alloc_table(pdpte, flags);
+    let pd = get_or_alloc_table(pdpte, flags)?;
 
     let pde = table_entry_mut(pd, pd_i);
-    let pt = get_or_alloc_table(pde, flags);
+    let pt = get_or_alloc_table(pde, flags)?;
 
     let pte = table_entry_mut(pt, pt_i);
     core::ptr::write_volatile(pte, phys | (PTE_P | flags));
     invlpg(virt);
+    Some(())
+}
+
+// Allocate a zeroed user frame and map it at `virt`.
+unsafe fn map_new_user_page(pml4: u64, virt: u64, flags: u64) -> Option<u64> {
+    let p = pmm::alloc_frame()?;
+    zero_page(p);
+    if map_4k(pml4, virt, p, flags).is_none() {
+        pmm::free_frame(p);
+        return None;
+    }
+    Some(p)
+}
+
+// The zeroed frame in `slot`, allocated the first time it is asked for.
+fn shared_frame(slot: &AtomicU64) -> Option<u64> {
+    let f = slot.load(Ordering::Acquire);
+    if f != 0 {
+        return Some(f);
+    }
+    let f = pmm::alloc_frame()?;
+    unsafe { zero_page(f) };
+    match slot.compare_exchange(0, f, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Some(f),
+        Err(won) => {
+            pmm::free_frame(f);
+            Some(won)
+        }
+    }
+}
+
+fn zero_frame() -> Option<u64> {
+    shared_frame(&ZERO_FRAME)
+}
+
+// Map the time page read-only at `TIME_PAGE`. It stays shared: no proc is charged for it.
+unsafe fn map_time_page(pml4: u64) -> Option<()> {
+    let f = shared_frame(&TIME_FRAME)?;
+    let page = &*paging::phys_to_virt_ptr::<mantra_sys::TimePage>(f);
+    page.hz.store(crate::timer::hz() as u64, Ordering::Relaxed);
+    map_4k(pml4, mantra_sys::TIME_PAGE, f, PTE_U | PTE_SHARED)
+}
+
+/// Publish tick `ticks` on the time page, from the timer IRQ. A no-op until the first
+/// address space has mapped it.
+pub fn publish_time(ticks: u64) {
+    let f = TIME_FRAME.load(Ordering::Acquire);
+    if f == 0 {
+        return;
+    }
+    let page = unsafe { &*paging::phys_to_virt_ptr::<mantra_sys::TimePage>(f) };
+    page.write(ticks, crate::timer::now_ns().unwrap_or(0));
+}
+
+// Map `virt` to the zero frame, read-only until the first write. Only page tables are
+// allocated here; the page itself costs no frame until then.
+unsafe fn map_zero_page(pml4: u64, virt: u64) -> Option<()> {
+    map_4k(pml4, virt, zero_frame()?, PTE_U | PTE_SHARED | PTE_COW)
+}
+
+// The 4 KiB leaf entry for `virt`, or None if a level is missing or a huge page maps it.
+unsafe fn leaf_pte(pml4: u64, virt: u64) -> Option<*mut u64> {
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+    let mut table = pml4;
+    for shift in [39u64, 30, 21] {
+        let e =
+            core::ptr::read_volatile(table_entry_mut(table, ((virt >> shift) & 0x1ff) as usize));
+        if (e & PTE_P) == 0 || (e & PTE_PS) != 0 {
+            return None;
+        }
+        table = e & ADDR;
+    }
+    Some(table_entry_mut(table, ((virt >> 12) & 0x1ff) as usize))
+}
+
+// Nothing unmaps single user pages outside the ktests yet (there is no munmap), so the
+// unmap path below is only built with them.
+
+/// When an unmap drops the old translation from the TLB.
+#[cfg(feature = "ktest")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Flush {
+    Page,     // invlpg each page as it is unmapped
+    Deferred, // one CR3 reload after the batch, none at all if the space is not loaded
+}
+
+// True if `pml4` is the address space loaded in CR3.
+#[cfg(feature = "ktest")]
+fn is_live(pml4: u64) -> bool {
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    (cr3 & 0x000f_ffff_ffff_f000) == pml4
 }
 
-unsafe fn map_hhdm_huge(pml4: u64, max_phys_inclusive: u64) {
-    // Map HHDM using 2 MiB huge pages (supervisor-only).
-    let max_end = align_up(max_phys_inclusive.saturating_add(1), 1024 * 1024 * 1024);
-    let pdpt_entries =
-        ((max_end + (1024 * 1024 * 1024 - 1)) / (1024 * 1024 * 1024)).min(512) as usize;
+// Drop every non-global translation by reloading CR3.
+#[cfg(feature = "ktest")]
+unsafe fn flush_all() {
+    asm!(
+        "mov {tmp}, cr3",
+        "mov cr3, {tmp}",
+        tmp = out(reg) _,
+        options(nostack, preserves_flags)
+    );
+}
+
+// Clear the 4 KiB mapping of `virt` and return the entry it held, or None if nothing maps
+// it there. With `Flush::Deferred` the old translation may stay cached until the caller
+// flushes; page tables are kept either way.
+#[cfg(feature = "ktest")]
+unsafe fn unmap_4k(pml4: u64, virt: u64, flush: Flush) -> Option<u64> {
+    let virt = align_down(virt, PAGE_SIZE);
+    let pte = leaf_pte(pml4, virt)?;
+    let e = core::ptr::read_volatile(pte);
+    if (e & PTE_P) == 0 {
+        return None;
+    }
+    core::ptr::write_volatile(pte, 0);
+    if flush == Flush::Page {
+        invlpg(virt);
+    }
+    Some(e)
+}
+
+// Unmap `pages` pages from `start`, freeing the frames this space owns (shared ones stay),
+// and return how many were mapped. A deferred batch that unmapped anything from the live
+// space ends with one full flush; a space not in CR3 has nothing cached to flush.
+#[cfg(feature = "ktest")]
+unsafe fn unmap_range(pml4: u64, start: u64, pages: u64, flush: Flush) -> u64 {
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+    let mut n = 0;
+    for i in 0..pages {
+        let Some(e) = unmap_4k(pml4, start + i * PAGE_SIZE, flush) else {
+            continue;
+        };
+        if (e & PTE_U) != 0 && (e & PTE_SHARED) == 0 {
+            pmm::free_frame(e & ADDR);
+        }
+        n += 1;
+    }
+    if flush == Flush::Deferred && n != 0 && is_live(pml4) {
+        flush_all();
+    }
+    n
+}
 
-    let pdpt = alloc_table();
-    *table_entry_mut(pml4, 256) = pdpt | (PTE_P | PTE_RW);
+/// Give the copy-on-write page at user address `va` in `pml4` a private, writable copy of the
+/// frame it shares (zeros for a zero-fill page). False if `va` is not copy-on-write or no
+/// frame is free.
+pub fn fault_in_cow(pml4: u64, va: u64) -> bool {
+    if paging::is_kernel_addr(va) {
+        return false;
+    }
+    unsafe {
+        let Some(pte) = leaf_pte(pml4, va) else {
+            return false;
+        };
+        let e = core::ptr::read_volatile(pte);
+        if (e & (PTE_P | PTE_COW)) != (PTE_P | PTE_COW) {
+            return false;
+        }
+        let Some(f) = pmm::alloc_frame() else {
+            return false;
+        };
+        core::ptr::copy_nonoverlapping(
+            paging::phys_to_virt_ptr::<u8>(e & 0x000f_ffff_ffff_f000),
+            paging::phys_to_virt_ptr::<u8>(f),
+            PAGE_SIZE as usize,
+        );
+        core::ptr::write_volatile(pte, f | PTE_P | PTE_U | PTE_RW);
+        invlpg(align_down(va, PAGE_SIZE));
+    }
+    true
+}
 
-    for i in 0..pdpt_entries {
-        let pd = alloc_table();
-        *table_entry_mut(pdpt, i) = pd | (PTE_P | PTE_RW);
-        let chunk_base = (i as u64) * (1024 * 1024 * 1024);
+// Free every frame owned by a user address space: user-accessible leaf pages and all
+// per-process tables below PML4 index 256, and the PML4 itself. Shared kernel mappings
+// (kernel image frames, HHDM, KMAP window) are left alone.
+// The address space must not be loaded in CR3.
+unsafe fn free_user_space(pml4: u64) {
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+    for i in 0..paging::HHDM_PML4_INDEX {
+        let e4 = *table_entry_mut(pml4, i);
+        if (e4 & PTE_P) == 0 {
+            continue;
+        }
+        let pdpt = e4 & ADDR;
         for j in 0..512usize {
-            let phys = chunk_base + (j as u64) * (2 * 1024 * 1024);
-            *table_entry_mut(pd, j) = phys | (PTE_P | PTE_RW | (1 << 7));
+            let e3 = *table_entry_mut(pdpt, j);
+            if (e3 & PTE_P) == 0 || (e3 & PTE_PS) != 0 {
+                continue;
+            }
+            let pd = e3 & ADDR;
+            for k in 0..512usize {
+                let e2 = *table_entry_mut(pd, k);
+                if (e2 & PTE_P) == 0 || (e2 & PTE_PS) != 0 {
+                    continue;
+                }
+                let pt = e2 & ADDR;
+                for l in 0..512usize {
+                    let e1 = *table_entry_mut(pt, l);
+                    if (e1 & PTE_P) != 0 && (e1 & PTE_U) != 0 && (e1 & PTE_SHARED) == 0 {
+                        pmm::free_frame(e1 & ADDR);
+                    }
+                }
+                pmm::free_frame(pt);
+            }
+            pmm::free_frame(pd);
         }
+        pmm::free_frame(pdpt);
+    }
+
+    pmm::free_frame(pml4);
+}
+
+/// Release the address space of a reaped proc.
+pub fn free_address_space(cr3: u64) {
+    if cr3 == 0 {
+        return;
     }
+    unsafe { free_user_space(cr3) }
 }
 
 #[repr(C)]
@@ -148,59 +353,85 @@ struct Elf64Phdr {
     p_align: u64,
 }
 
+#[repr(C)]
+struct Elf64Dyn {
+    d_tag: u64,
+    d_val: u64,
+}
+
+#[repr(C)]
+struct Elf64Rela {
+    r_offset: u64,
+    r_info: u64,
+    r_addend: i64,
+}
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
 const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
 const PF_X: u32 = 1;
 const PF_W: u32 = 2;
 const PF_R: u32 = 4;
 
-#[repr(C)]
-struct TaskTrapFrame {
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    r11: u64,
-    r10: u64,
-    r9: u64,
-    r8: u64,
-    rsi: u64,
-    rdi: u64,
-    rbp: u64,
-    rdx: u64,
-    rcx: u64,
-    rbx: u64,
-    rax: u64,
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
-}
-
 unsafe fn build_initial_tf(
     kstack_top: u64,
     entry: u64,
     user_rsp: u64,
     role: u64,
-    init_ep_cap: u64,
-) -> u64 {
-    let tf_ptr = (kstack_top - core::mem::size_of::<TaskTrapFrame>() as u64) as *mut TaskTrapFrame;
-    core::ptr::write_bytes(tf_ptr as *mut u8, 0, core::mem::size_of::<TaskTrapFrame>());
+) -> *mut TrapFrame {
+    let tf_ptr = (kstack_top - core::mem::size_of::<TrapFrame>() as u64) as *mut TrapFrame;
+    core::ptr::write_bytes(tf_ptr as *mut u8, 0, core::mem::size_of::<TrapFrame>());
     (*tf_ptr).rdi = role;
-    (*tf_ptr).rsi = init_ep_cap;
     (*tf_ptr).rip = entry;
     (*tf_ptr).cs = (gdt::UCODE_SEL as u64) | 3;
     (*tf_ptr).rflags = 0x202;
     (*tf_ptr).rsp = user_rsp;
     (*tf_ptr).ss = (gdt::UDATA_SEL as u64) | 3;
-    tf_ptr as u64
+    tf_ptr
 }
 
+pub const KSTACK_SIZE: usize = 16 * 1024;
+
+// Kernel stacks released by the reaper, reused before allocating new ones.
+// (The heap is still a bump allocator, so returning them to it would not reclaim anything.)
+const KSTACK_POOL_LEN: usize = 16;
+static mut KSTACK_POOL: [u64; KSTACK_POOL_LEN] = [0; KSTACK_POOL_LEN];
+static mut KSTACK_POOL_N: usize = 0;
+
 fn kstack_alloc_top() -> u64 {
-    // Leak a kernel stack; it's mapped via HHDM in every user CR3.
-    let b: Box<[u8; 16 * 1024]> = Box::new([0; 16 * 1024]);
+    // Kernel stacks live on the heap, so they're mapped via HHDM in every user CR3.
+    unsafe {
+        if KSTACK_POOL_N > 0 {
+            KSTACK_POOL_N -= 1;
+            let base = KSTACK_POOL[KSTACK_POOL_N];
+            return base + KSTACK_SIZE as u64;
+        }
+    }
+    let b: Box<[u8; KSTACK_SIZE]> = Box::new([0; KSTACK_SIZE]);
     let base = Box::into_raw(b) as *mut u8 as u64;
-    base + (16 * 1024) as u64
+    base + KSTACK_SIZE as u64
+}
+
+/// Release a kernel stack by its base address. The owning proc must be fully off-CPU.
+pub fn kstack_free(base: u64) {
+    if base == 0 {
+        return;
+    }
+    unsafe {
+        if KSTACK_POOL_N < KSTACK_POOL_LEN {
+            KSTACK_POOL[KSTACK_POOL_N] = base;
+            KSTACK_POOL_N += 1;
+        } else {
+            drop(Box::from_raw(base as *mut [u8; KSTACK_SIZE]));
+        }
+    }
 }
 
 unsafe fn translate_4k(pml4: u64, virt: u64) -> Option<u64> {
@@ -221,12 +452,20 @@ unsafe fn translate_4k(pml4: u64, virt: u64) -> Option<u64> {
     if (pdpte & PTE_P) == 0 {
         return None;
     }
+    // PS=1: 1 GiB leaf.
+    if (pdpte & PTE_PS) != 0 {
+        return Some((pdpte & 0x000f_ffff_c000_0000) + (virt & 0x3fff_ffff));
+    }
     let pd = pdpte & 0x000f_ffff_ffff_f000;
 
     let pde = core::ptr::read_volatile(table_entry_mut(pd, pd_i));
     if (pde & PTE_P) == 0 {
         return None;
     }
+    // PS=1: 2 MiB leaf.
+    if (pde & PTE_PS) != 0 {
+        return Some((pde & 0x000f_ffff_ffe0_0000) + (virt & 0x1f_ffff));
+    }
     let pt = pde & 0x000f_ffff_ffff_f000;
 
     let pte = core::ptr::read_volatile(table_entry_mut(pt, pt_i));
@@ -237,7 +476,843 @@ unsafe fn translate_4k(pml4: u64, virt: u64) -> Option<u64> {
     Some(phys)
 }
 
-unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
+// Translate a user virtual address in `pml4_phys`; None unless mapped user-accessible.
+pub fn user_virt_to_phys(pml4_phys: u64, virt: u64) -> Option<u64> {
+    // Walk 4-level tables. Require U=1 at every level and leaf present.
+    // A PDPTE/PDE with PS=1 is a 1 GiB/2 MiB leaf, not a pointer to the next table.
+    const MASK: u64 = 0x000f_ffff_ffff_f000;
+    const MASK_1G: u64 = 0x000f_ffff_c000_0000;
+    const MASK_2M: u64 = 0x000f_ffff_ffe0_0000;
+
+    // Kernel mappings are supervisor-only anyway, but never walk for a higher-half pointer.
+    if paging::is_kernel_addr(virt) {
+        return None;
+    }
+
+    let pml4 = pml4_phys & MASK;
+    let pml4_i = ((virt >> 39) & 0x1ff) as usize;
+    let pdpt_i = ((virt >> 30) & 0x1ff) as usize;
+    let pd_i = ((virt >> 21) & 0x1ff) as usize;
+    let pt_i = ((virt >> 12) & 0x1ff) as usize;
+    let off = virt & 0xfff;
+
+    unsafe fn rd(table: u64, idx: usize) -> u64 {
+        core::ptr::read_volatile(paging::phys_to_virt_ptr::<u64>(table).add(idx))
+    }
+
+    let pml4e = unsafe { rd(pml4, pml4_i) };
+    if (pml4e & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
+        return None;
+    }
+    let pdpt = pml4e & MASK;
+
+    let pdpte = unsafe { rd(pdpt, pdpt_i) };
+    if (pdpte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
+        return None;
+    }
+    if (pdpte & PTE_PS) != 0 {
+        return Some((pdpte & MASK_1G) + (virt & 0x3fff_ffff));
+    }
+    let pd = pdpte & MASK;
+
+    let pde = unsafe { rd(pd, pd_i) };
+    if (pde & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
+        return None;
+    }
+    if (pde & PTE_PS) != 0 {
+        return Some((pde & MASK_2M) + (virt & 0x1f_ffff));
+    }
+    let pt = pde & MASK;
+
+    let pte = unsafe { rd(pt, pt_i) };
+    if (pte & (PTE_P | PTE_U)) != (PTE_P | PTE_U) {
+        return None;
+    }
+
+    Some((pte & MASK) + off)
+}
+
+/// Physical address space of the running proc (CR3 without flag bits).
+pub fn current_pml4() -> u64 {
+    let cr3: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, cr3",
+            out(reg) cr3,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    cr3 & 0x000f_ffff_ffff_f000
+}
+
+// End of the canonical lower half; every user range lies below it.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// The end of `len` bytes at user address `uva`, or None if the range wraps or runs past
+/// the user half. Every user (ptr, len) is checked with this before anything is copied.
+pub fn checked_user_range(uva: u64, len: u64) -> Option<u64> {
+    uva.checked_add(len).filter(|&end| end <= USER_END)
+}
+
+// True if `va` is mapped user-writable in `pml4`. Kernel writes go through the HHDM, so
+// read-only user pages (shared text, the time page) must be refused here.
+fn user_writable(pml4: u64, va: u64) -> bool {
+    const WANT: u64 = PTE_P | PTE_U | PTE_RW;
+    !paging::is_kernel_addr(va)
+        && unsafe { leaf_pte(pml4, va) }
+            .is_some_and(|pte| unsafe { core::ptr::read_volatile(pte) } & WANT == WANT)
+}
+
+/// A user copy stopped at an unmapped, read-only, supervisor or kernel address; `done`
+/// bytes made it.
+#[derive(Copy, Clone)]
+pub struct CopyFault {
+    pub done: usize,
+}
+
+// Walk `len` bytes of user memory at `uva` in `pml4` a page at a time, calling
+// `f(kernel_ptr, offset, n)` for each contiguous in-page chunk (reached through the HHDM).
+// With `write`, copy-on-write pages get their private frame first (the HHDM path never faults)
+// and read-only pages stop the copy.
+fn for_each_user_chunk(
+    pml4: u64,
+    uva: u64,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) -> Result<usize, CopyFault> {
+    if checked_user_range(uva, len as u64).is_none() {
+        return Err(CopyFault { done: 0 });
+    }
+    let mut done = 0usize;
+    smap::user_access(|| {
+        while done < len {
+            let va = uva + done as u64;
+            if write {
+                fault_in_cow(pml4, va);
+                if !user_writable(pml4, va) {
+                    return Err(CopyFault { done });
+                }
+            }
+            let Some(pa) = user_virt_to_phys(pml4, va) else {
+                return Err(CopyFault { done });
+            };
+            let in_page = (PAGE_SIZE - (va & (PAGE_SIZE - 1))) as usize;
+            let n = core::cmp::min(len - done, in_page);
+            f(paging::phys_to_virt_ptr::<u8>(pa), done, n);
+            done += n;
+        }
+        Ok(done)
+    })
+}
+
+/// Copy `src` to user address `dst_uva` in address space `pml4` (need not be current).
+pub fn copy_to(pml4: u64, dst_uva: u64, src: &[u8]) -> Result<usize, CopyFault> {
+    for_each_user_chunk(pml4, dst_uva, src.len(), true, |p, off, n| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr().add(off), p, n);
+    })
+}
+
+/// Fill `dst` from user address `src_uva` in address space `pml4` (need not be current).
+pub fn copy_from(pml4: u64, dst: &mut [u8], src_uva: u64) -> Result<usize, CopyFault> {
+    let len = dst.len();
+    let out = dst.as_mut_ptr();
+    for_each_user_chunk(pml4, src_uva, len, false, |p, off, n| unsafe {
+        core::ptr::copy_nonoverlapping(p, out.add(off), n);
+    })
+}
+
+/// The side of a user-to-user copy that hit an unmapped, supervisor or kernel address (or,
+/// for the destination, a read-only page).
+pub enum CrossFault {
+    Src,
+    Dst,
+}
+
+/// Copy `len` bytes between two user address spaces (neither need be current), page chunk
+/// by page chunk through the HHDM, with no kernel bounce buffer.
+pub fn copy_between(
+    dst_pml4: u64,
+    dst_uva: u64,
+    src_pml4: u64,
+    src_uva: u64,
+    len: usize,
+) -> Result<usize, CrossFault> {
+    if checked_user_range(src_uva, len as u64).is_none() {
+        return Err(CrossFault::Src);
+    }
+    if checked_user_range(dst_uva, len as u64).is_none() {
+        return Err(CrossFault::Dst);
+    }
+    let mut done = 0usize;
+    smap::user_access(|| {
+        while done < len {
+            let sva = src_uva + done as u64;
+            let dva = dst_uva + done as u64;
+            fault_in_cow(dst_pml4, dva);
+            if !user_writable(dst_pml4, dva) {
+                return Err(CrossFault::Dst);
+            }
+            let Some(spa) = user_virt_to_phys(src_pml4, sva) else {
+                return Err(CrossFault::Src);
+            };
+            let Some(dpa) = user_virt_to_phys(dst_pml4, dva) else {
+                return Err(CrossFault::Dst);
+            };
+            let s_left = (PAGE_SIZE - (sva & (PAGE_SIZE - 1))) as usize;
+            let d_left = (PAGE_SIZE - (dva & (PAGE_SIZE - 1))) as usize;
+            let n = (len - done).min(s_left).min(d_left);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    paging::phys_to_virt_ptr::<u8>(spa),
+                    paging::phys_to_virt_ptr::<u8>(dpa),
+                    n,
+                );
+            }
+            done += n;
+        }
+        Ok(done)
+    })
+}
+
+ktest! {
+    fn ipc_copy_bench() {
+        // Compare a 1 KiB message moved directly between two address spaces with the
+        // queued path's copy-in, ring copy and copy-out, and log the average cycles of each.
+        const SRC: u64 = 0x0000_0000_4000_0000;
+        const DST: u64 = 0x0000_0000_5000_0000;
+        const MSG: usize = 1024;
+        const ROUNDS: u64 = 64;
+        unsafe {
+            let (Some(a), Some(b)) = (alloc_table(), alloc_table()) else {
+                kwarn!("user: ipc copy bench skipped, no memory");
+                return;
+            };
+            // Misaligned so the direct copy has to split at page boundaries on both sides.
+            let (src, dst) = (SRC + PAGE_SIZE - 300, DST + PAGE_SIZE - 700);
+            let mapped = [
+                (a, SRC),
+                (a, SRC + PAGE_SIZE),
+                (b, DST),
+                (b, DST + PAGE_SIZE),
+            ]
+            .iter()
+            .all(|&(pml4, va)| map_new_user_page(pml4, va, PTE_U | PTE_RW).is_some());
+            if !mapped {
+                free_user_space(a);
+                free_user_space(b);
+                kwarn!("user: ipc copy bench skipped, no memory");
+                return;
+            }
+            let mut msg = [0u8; MSG];
+            for (i, v) in msg.iter_mut().enumerate() {
+                *v = i as u8;
+            }
+            let _ = copy_to(a, src, &msg);
+
+            let direct = crate::perf::Stat::new();
+            let queued = crate::perf::Stat::new();
+            let mut bounce = [0u8; MSG];
+            let mut ring = [0u8; MSG];
+            for _ in 0..ROUNDS {
+                let t = crate::perf::start();
+                let ok = copy_between(b, dst, a, src, MSG).is_ok();
+                direct.record(crate::perf::stop(t));
+                kassert!(ok, "user: direct copy faulted");
+
+                let t = crate::perf::start();
+                let _ = copy_from(a, &mut bounce, src);
+                ring.copy_from_slice(core::hint::black_box(&bounce));
+                let _ = copy_to(b, dst, &ring);
+                queued.record(crate::perf::stop(t));
+            }
+            let mut back = [0u8; MSG];
+            let _ = copy_from(b, &mut back, dst);
+            kassert!(back == msg, "user: direct copy corrupted the message");
+            free_user_space(a);
+            free_user_space(b);
+
+            kinfo!(
+                "user: 1KiB ipc copy avg_cycles direct={} queued={}",
+                direct.average().1,
+                queued.average().1
+            );
+        }
+    }
+}
+
+ktest! {
+    fn copies_stop_at_faults() {
+        // The copy primitives against a scratch (non-current) address space: a
+        // copy spanning two mapped pages, one that runs into an unmapped page, and a read back.
+        // Writes to read-only pages and to ranges past the user half must be refused.
+        const BASE: u64 = 0x0000_0000_4000_0000;
+        unsafe {
+            let Some(pml4) = alloc_table() else {
+                kwarn!("user: copy test skipped, no memory");
+                return;
+            };
+            if map_new_user_page(pml4, BASE, PTE_U | PTE_RW).is_none()
+                || map_new_user_page(pml4, BASE + PAGE_SIZE, PTE_U | PTE_RW).is_none()
+            {
+                free_user_space(pml4);
+                kwarn!("user: copy test skipped, no memory");
+                return;
+            }
+
+            let mut src = [0u8; 3 * PAGE_SIZE as usize];
+            for (i, b) in src.iter_mut().enumerate() {
+                *b = (i % 251) as u8;
+            }
+            // Straddle the two mapped pages.
+            let mid = BASE + PAGE_SIZE - 100;
+            let two = &src[..PAGE_SIZE as usize];
+            kassert!(
+                copy_to(pml4, mid, two).ok() == Some(two.len()),
+                "copy_to across pages"
+            );
+            let mut back = [0u8; PAGE_SIZE as usize];
+            kassert!(
+                copy_from(pml4, &mut back, mid).ok() == Some(back.len()) && back[..] == *two,
+                "copy_from across pages"
+            );
+            // The third page is unmapped: stop exactly at its start.
+            let fault = copy_to(pml4, BASE, &src).err().map(|f| f.done);
+            kassert!(
+                fault == Some(2 * PAGE_SIZE as usize),
+                "copy_to did not stop at the unmapped page"
+            );
+            // The time page reads fine but refuses writes.
+            let mut seq = [0u8; 8];
+            kassert!(
+                map_time_page(pml4).is_some()
+                    && copy_from(pml4, &mut seq, mantra_sys::TIME_PAGE).is_ok()
+                    && copy_to(pml4, mantra_sys::TIME_PAGE, &seq)
+                        .err()
+                        .map(|f| f.done)
+                        == Some(0),
+                "time page not mapped read-only"
+            );
+            // Ranges that wrap or leave the user half fail before touching anything.
+            let near_top = [USER_END - 8, u64::MAX - 7];
+            kassert!(
+                near_top
+                    .iter()
+                    .all(|&va| copy_to(pml4, va, &src[..16]).err().map(|f| f.done) == Some(0)),
+                "copy_to past the user half"
+            );
+            kassert!(
+                matches!(
+                    copy_between(pml4, BASE, pml4, USER_END - 8, 16),
+                    Err(CrossFault::Src)
+                ) && matches!(
+                    copy_between(pml4, u64::MAX - 7, pml4, BASE, 16),
+                    Err(CrossFault::Dst)
+                ),
+                "copy_between past the user half"
+            );
+            free_user_space(pml4);
+        }
+    }
+}
+
+/// Run `f(pml4, va)` on a scratch address space (never loaded) that holds `bytes` at `va`,
+/// so a test can hand a syscall handler a user buffer. False if frames ran out.
+#[cfg(feature = "ktest")]
+pub fn with_scratch_bytes(bytes: &[u8], f: impl FnOnce(u64, u64)) -> bool {
+    const BASE: u64 = 0x0000_0000_4000_0000;
+    unsafe {
+        let Some(pml4) = alloc_table() else {
+            return false;
+        };
+        let pages = (bytes.len() as u64).div_ceil(PAGE_SIZE);
+        let mapped = (0..pages)
+            .all(|i| map_new_user_page(pml4, BASE + i * PAGE_SIZE, PTE_U | PTE_RW).is_some());
+        if mapped && copy_to(pml4, BASE, bytes).is_ok() {
+            f(pml4, BASE);
+        }
+        free_user_space(pml4);
+        mapped
+    }
+}
+
+ktest! {
+    fn zero_fill_pages_share_a_frame() {
+        // Zero-fill pages: map a 4 MiB region of them in a scratch address
+        // space, read all of it back as zeros without using a frame, then write into one page and
+        // check that exactly one frame was taken and only that page stopped mapping the zero frame.
+        const BASE: u64 = 0x0000_0000_4000_0000;
+        const PAGES: u64 = 1024;
+        let start = pmm::free_frames();
+        unsafe {
+            let Some(pml4) = alloc_table() else {
+                kwarn!("user: zero-fill test skipped, no memory");
+                return;
+            };
+            if !(0..PAGES).all(|i| map_zero_page(pml4, BASE + i * PAGE_SIZE).is_some()) {
+                free_user_space(pml4);
+                kwarn!("user: zero-fill test skipped, no memory");
+                return;
+            }
+            let zero = zero_frame().unwrap_or(0);
+
+            let before = pmm::free_frames();
+            let mut buf = [0xffu8; PAGE_SIZE as usize];
+            let mut all_zero = true;
+            for i in 0..PAGES {
+                let ok = copy_from(pml4, &mut buf, BASE + i * PAGE_SIZE).is_ok();
+                all_zero &= ok && buf.iter().all(|&b| b == 0);
+            }
+            let after_read = pmm::free_frames();
+
+            let target = BASE + 7 * PAGE_SIZE;
+            let wrote = copy_to(pml4, target + 100, b"mantra").ok();
+            let after_write = pmm::free_frames();
+            let mut back = [0u8; 6];
+            let _ = copy_from(pml4, &mut back, target + 100);
+            let frame_of =
+                |va: u64| user_virt_to_phys(pml4, va).map(|pa| align_down(pa, PAGE_SIZE));
+
+            kassert!(all_zero, "zero-fill pages did not read as zeros");
+            kassert!(
+                after_read == before,
+                "reading zero-fill pages took {} frames",
+                before.saturating_sub(after_read)
+            );
+            kassert!(
+                wrote == Some(6) && before - after_write == 1,
+                "first write took {} frames",
+                before.saturating_sub(after_write)
+            );
+            kassert!(back == *b"mantra", "zero-fill write lost");
+            kassert!(
+                frame_of(target).is_some_and(|f| f != zero)
+                    && frame_of(target - PAGE_SIZE) == Some(zero)
+                    && frame_of(target + PAGE_SIZE) == Some(zero),
+                "write broke the wrong zero-fill page"
+            );
+            free_user_space(pml4);
+        }
+        // The zero frame stays allocated for good; everything else must come back.
+        let leaked = start.saturating_sub(pmm::free_frames());
+        kassert!(
+            leaked <= 1,
+            "leaked {} frames",
+            leaked
+        );
+    }
+}
+
+ktest! {
+    fn unmap_flushes_stale_translations() {
+        // Unmap the same 256 pages of an unloaded space once flushing each page and once deferring,
+        // and log what each batch cost. Then check the deferred path on the live kernel space:
+        // after unmapping a page it has read, repointing the PTE without an invlpg must show the
+        // new frame, so the batch's closing flush really dropped the old translation.
+        const ADDR: u64 = 0x000f_ffff_ffff_f000;
+        const BASE: u64 = 0x4000_0000;
+        const PAGES: u64 = 256;
+        // PML4 index 1: nothing maps there in the kernel space.
+        const LIVE_VA: u64 = 0x0000_0080_0000_0000;
+        let start = pmm::free_frames();
+        let mut cycles = [0u64; 2];
+        let mut unmapped = [0u64; 2];
+        unsafe {
+            let Some(pml4) = alloc_table() else {
+                kwarn!("user: unmap test skipped, no memory");
+                return;
+            };
+            for (i, flush) in [Flush::Page, Flush::Deferred].into_iter().enumerate() {
+                for p in 0..PAGES {
+                    if map_new_user_page(pml4, BASE + p * PAGE_SIZE, PTE_U | PTE_RW).is_none() {
+                        free_user_space(pml4);
+                        kwarn!("user: unmap test skipped, no memory");
+                        return;
+                    }
+                }
+                let t = crate::perf::start();
+                unmapped[i] = unmap_range(pml4, BASE, PAGES, flush);
+                cycles[i] = crate::perf::stop(t).cycles;
+            }
+            free_user_space(pml4);
+        }
+        let leaked = start.saturating_sub(pmm::free_frames());
+        kassert!(
+            unmapped == [PAGES; 2] && leaked == 0,
+            "unmapped {:?} of {} pages, leaked {} frames",
+            unmapped,
+            PAGES,
+            leaked
+        );
+        kinfo!(
+            "unmapping {} pages took {} cycles flushing each, {} deferred",
+            PAGES,
+            cycles[0],
+            cycles[1]
+        );
+
+        let kernel = paging::pml4_phys();
+        unsafe {
+            if !is_live(kernel) || (*table_entry_mut(kernel, 1) & PTE_P) != 0 {
+                kdebug!("user: kernel space not live, deferred flush check skipped");
+                return;
+            }
+            let (Some(a), Some(b)) = (pmm::alloc_frame(), pmm::alloc_frame()) else {
+                kwarn!("user: deferred flush check skipped, no memory");
+                return;
+            };
+            *paging::phys_to_virt_ptr::<u64>(a) = 0xa;
+            *paging::phys_to_virt_ptr::<u64>(b) = 0xb;
+            let mut seen = [0u64; 2];
+            if map_4k(kernel, LIVE_VA, a, PTE_RW).is_some() {
+                seen[0] = core::ptr::read_volatile(LIVE_VA as *const u64);
+                unmap_range(kernel, LIVE_VA, 1, Flush::Deferred);
+                if let Some(pte) = leaf_pte(kernel, LIVE_VA) {
+                    core::ptr::write_volatile(pte, b | PTE_P | PTE_RW);
+                    seen[1] = core::ptr::read_volatile(LIVE_VA as *const u64);
+                    core::ptr::write_volatile(pte, 0);
+                    invlpg(LIVE_VA);
+                }
+            }
+            // Take PML4[1] back out and free the tables `map_4k` built under it.
+            let mut e = core::ptr::read_volatile(table_entry_mut(kernel, 1));
+            core::ptr::write_volatile(table_entry_mut(kernel, 1), 0);
+            flush_all();
+            for _ in 0..3 {
+                if (e & PTE_P) == 0 {
+                    break;
+                }
+                let table = e & ADDR;
+                e = *table_entry_mut(table, 0);
+                pmm::free_frame(table);
+            }
+            pmm::free_frame(a);
+            pmm::free_frame(b);
+            kassert!(
+                seen == [0xa, 0xb],
+                "read {:#x?} through a deferred unmap, stale translation kept",
+                seen
+            );
+        }
+    }
+}
+
+ktest! {
+    fn template_clones_copy_on_write() {
+        // Build ten address spaces for the init program from its template and ten straight from the
+        // ELF, and log what building the tenth cost each way. A clone must read the template's
+        // data, and its first write there must land in a private copy, not in the template.
+        const INSTANCES: usize = 10;
+        const ADDR: u64 = 0x000f_ffff_ffff_f000;
+        if init_elf::INIT_ELF.is_empty() {
+            kwarn!("user: no init image, template test skipped");
+            return;
+        }
+        let layout = UserLayout {
+            stack_top: USER_STACK_TOP,
+            mmap_base: USER_MMAP_BASE,
+        };
+        let mut tenth = [0u64; 2];
+        for (i, use_template) in [true, false].into_iter().enumerate() {
+            for n in 0..INSTANCES {
+                let Some(pml4) = (unsafe { alloc_table() }) else {
+                    kwarn!("user: template test skipped, no memory");
+                    return;
+                };
+                let mut pages = 0;
+                let t = crate::perf::start();
+                let built = unsafe {
+                    build_user_space(pml4, INIT_PROG_ID, layout, use_template, &mut pages)
+                };
+                let c = crate::perf::stop(t);
+                unsafe { free_user_space(pml4) };
+                kassert!(built.is_some(), "spawn {} failed building", n);
+                tenth[i] = c.cycles;
+            }
+        }
+        kinfo!(
+            "10th spawn cycles template={} elf={}",
+            tenth[0],
+            tenth[1]
+        );
+
+        unsafe {
+            let Some(t) = program_template(INIT_PROG_ID, init_elf::INIT_ELF) else {
+                kwarn!("user: no template for init, copy-on-write check skipped");
+                return;
+            };
+            // The first page the template owns and the program may write (its .data).
+            let mut data = None;
+            let _ = for_each_user_leaf(t.pml4, |va, e| {
+                if (e & (PTE_RW | PTE_SHARED)) == PTE_RW {
+                    data = Some((va, e & ADDR));
+                    return None;
+                }
+                Some(())
+            });
+            let Some((va, frame)) = data else {
+                kdebug!("user: init has no data page, copy-on-write check skipped");
+                return;
+            };
+            let Some(pml4) = alloc_table() else {
+                kwarn!("user: template test skipped, no memory");
+                return;
+            };
+            let mut pages = 0;
+            let mut want = [0u8; 64];
+            want.copy_from_slice(core::slice::from_raw_parts(
+                paging::phys_to_virt_ptr::<u8>(frame),
+                64,
+            ));
+            let mut got = [0u8; 64];
+            let read = clone_template(t, pml4, &mut pages).is_some()
+                && copy_from(pml4, &mut got, va).is_ok()
+                && user_virt_to_phys(pml4, va) == Some(frame);
+            let flipped = [!want[0]];
+            let wrote = copy_to(pml4, va, &flipped).is_ok();
+            let private =
+                user_virt_to_phys(pml4, va).is_some_and(|pa| align_down(pa, PAGE_SIZE) != frame);
+            let kept = *paging::phys_to_virt_ptr::<u8>(frame) == want[0];
+            free_user_space(pml4);
+            kassert!(
+                read && got == want,
+                "clone does not map the template's data"
+            );
+            kassert!(
+                wrote && private && kept,
+                "write to a cloned data page reached the template"
+            );
+        }
+    }
+}
+
+ktest! {
+    fn pie_relocations_apply_at_the_bias() {
+        // Load a minimal static PIE at a non-zero bias: one writable PT_LOAD holding a pointer, its
+        // dynamic section and one R_X86_64_RELATIVE entry for the pointer. The loaded pointer must
+        // resolve to the biased address of its target, and the entry point must move too.
+        const BIAS: u64 = 0x40_0000_0000;
+        const IMAGE: usize = 512;
+        const DYN: usize = 176; // 3 Elf64Dyn entries + DT_NULL
+        const RELA: usize = 240;
+        const PTR: usize = 264; // the relocated global
+        const TARGET: usize = 272; // what it points at
+        const ENTRY: u64 = 0x100;
+        let mut elf = [0u8; IMAGE];
+        let mut put = |off: usize, v: &[u8]| elf[off..off + v.len()].copy_from_slice(v);
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(16, &ET_DYN.to_le_bytes());
+        put(18, &0x3eu16.to_le_bytes());
+        put(24, &ENTRY.to_le_bytes());
+        put(32, &64u64.to_le_bytes()); // e_phoff
+        put(54, &56u16.to_le_bytes()); // e_phentsize
+        put(56, &2u16.to_le_bytes()); // e_phnum
+        let phdr = |p_type: u32, flags: u32, off: u64, size: u64| {
+            let mut b = [0u8; 56];
+            b[0..4].copy_from_slice(&p_type.to_le_bytes());
+            b[4..8].copy_from_slice(&flags.to_le_bytes());
+            for (i, v) in [off, off, off, size, size, 8].into_iter().enumerate() {
+                b[8 + i * 8..16 + i * 8].copy_from_slice(&v.to_le_bytes());
+            }
+            b
+        };
+        put(64, &phdr(PT_LOAD, PF_R | PF_W, 0, IMAGE as u64));
+        put(120, &phdr(PT_DYNAMIC, PF_R | PF_W, DYN as u64, 64));
+        let dynamic = [
+            (DT_RELA, RELA as u64),
+            (DT_RELASZ, 24),
+            (DT_RELAENT, 24),
+            (DT_NULL, 0),
+        ];
+        for (i, (tag, val)) in dynamic.into_iter().enumerate() {
+            put(DYN + i * 16, &tag.to_le_bytes());
+            put(DYN + i * 16 + 8, &val.to_le_bytes());
+        }
+        put(RELA, &(PTR as u64).to_le_bytes());
+        put(RELA + 8, &(R_X86_64_RELATIVE as u64).to_le_bytes());
+        put(RELA + 16, &(TARGET as u64).to_le_bytes());
+        put(TARGET, b"relocated");
+
+        let Some(pml4) = (unsafe { alloc_table() }) else {
+            kwarn!("user: PIE test skipped, no memory");
+            return;
+        };
+        let mut pages = 0;
+        // The segment is writable, so no shared frames are cached under this program ID.
+        let entry = unsafe { load_elf_into_user(pml4, 0, &elf, BIAS, &mut pages) };
+        let mut ptr = [0u8; 8];
+        let mut target = [0u8; 9];
+        let read = copy_from(pml4, &mut ptr, BIAS + PTR as u64).is_ok();
+        let global = u64::from_le_bytes(ptr);
+        let deref = read && copy_from(pml4, &mut target, global).is_ok();
+        unsafe { free_user_space(pml4) };
+        kassert!(
+            entry == Some(BIAS + ENTRY),
+            "PIE entry {:?}, want {:#x}",
+            entry,
+            BIAS + ENTRY
+        );
+        kassert!(
+            global == BIAS + TARGET as u64 && deref && target == *b"relocated",
+            "relocated pointer {:#x}, want {:#x}",
+            global,
+            BIAS + TARGET as u64
+        );
+        let mut fixed = elf;
+        fixed[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        let Some(pml4) = (unsafe { alloc_table() }) else {
+            return;
+        };
+        let moved = unsafe { load_elf_into_user(pml4, 0, &fixed, BIAS, &mut pages) };
+        unsafe { free_user_space(pml4) };
+        kassert!(moved.is_none(), "fixed executable loaded at a bias");
+    }
+}
+
+// Frames backing a read-only PT_LOAD segment, filled once and then mapped into every
+// instance of the program. Programs are embedded in the kernel image, so entries (and
+// their frames) live for the rest of the boot.
+struct SharedSeg {
+    prog_id: u64,
+    vaddr: u64,
+    frames: Vec<u64>,
+}
+
+const SHARED_SEGS: usize = 8;
+static mut SHARED_TEXT: [Option<SharedSeg>; SHARED_SEGS] = [const { None }; SHARED_SEGS];
+
+// Cached frames for segment `ph` of `prog_id`, loading them on first use. None if the cache
+// is full or frames ran out; the caller then gives this instance a private copy.
+unsafe fn shared_frames(prog_id: u64, elf: &[u8], ph: &Elf64Phdr) -> Option<&'static [u64]> {
+    let cache = &raw mut SHARED_TEXT;
+    let cache = &mut *cache;
+    let hit = cache
+        .iter()
+        .position(|s| matches!(s, Some(s) if s.prog_id == prog_id && s.vaddr == ph.p_vaddr));
+    if let Some(i) = hit {
+        return cache[i].as_ref().map(|s| &s.frames[..]);
+    }
+    let slot = cache.iter_mut().find(|s| s.is_none())?;
+
+    let seg_start = align_down(ph.p_vaddr, PAGE_SIZE);
+    let seg_end = align_up_checked(ph.p_vaddr.checked_add(ph.p_memsz)?, PAGE_SIZE)?;
+    let file_end = ph.p_vaddr + ph.p_filesz;
+    let mut frames = Vec::new();
+    if frames
+        .try_reserve_exact(((seg_end - seg_start) / PAGE_SIZE) as usize)
+        .is_err()
+    {
+        return None;
+    }
+    let mut v = seg_start;
+    while v < seg_end {
+        let Some(f) = pmm::alloc_frame() else {
+            frames.iter().for_each(|&f| pmm::free_frame(f));
+            return None;
+        };
+        zero_page(f);
+        // File bytes landing in this page; the rest stays zero (BSS).
+        let lo = v.max(ph.p_vaddr);
+        let hi = (v + PAGE_SIZE).min(file_end);
+        if lo < hi {
+            let src = (ph.p_offset + (lo - ph.p_vaddr)) as usize;
+            core::ptr::copy_nonoverlapping(
+                elf[src..].as_ptr(),
+                paging::phys_to_virt_ptr::<u8>(f).add((lo - v) as usize),
+                (hi - lo) as usize,
+            );
+        }
+        frames.push(f);
+        v += PAGE_SIZE;
+    }
+    kdebug!(
+        "user: caching prog={} segment {:#x} ({} pages) for sharing",
+        prog_id,
+        ph.p_vaddr,
+        frames.len()
+    );
+    Some(
+        &slot
+            .insert(SharedSeg {
+                prog_id,
+                vaddr: ph.p_vaddr,
+                frames,
+            })
+            .frames,
+    )
+}
+
+// File offset of the `len` bytes at link-time address `vaddr`, if one PT_LOAD holds them.
+fn file_offset(phdrs: &[Elf64Phdr], vaddr: u64, len: u64) -> Option<usize> {
+    let end = vaddr.checked_add(len)?;
+    let ph = phdrs
+        .iter()
+        .find(|p| p.p_type == PT_LOAD && p.p_vaddr <= vaddr && end <= p.p_vaddr + p.p_filesz)?;
+    Some((ph.p_offset + (vaddr - ph.p_vaddr)) as usize)
+}
+
+// Apply the relocations a PIE lists in its dynamic section (a static PIE's `.rela.dyn`).
+// Only R_X86_64_RELATIVE is supported: it stores `bias + addend` at `bias + offset`. Any
+// other type fails the load, as does a target outside the image's private writable pages
+// (shared read-only frames are the same for every load bias).
+unsafe fn apply_relocations(pml4: u64, elf: &[u8], phdrs: &[Elf64Phdr], bias: u64) -> Option<()> {
+    let Some(dynamic) = phdrs.iter().find(|p| p.p_type == PT_DYNAMIC) else {
+        return Some(());
+    };
+    let dyn_sz = core::mem::size_of::<Elf64Dyn>();
+    let dyn_off = dynamic.p_offset as usize;
+    let dyn_n = dynamic.p_filesz as usize / dyn_sz;
+    if dyn_off.checked_add(dyn_n * dyn_sz)? > elf.len() {
+        return None;
+    }
+    let (mut rela, mut rela_sz, mut rela_ent) = (None, 0, 0);
+    for i in 0..dyn_n {
+        let d =
+            core::ptr::read_unaligned(elf.as_ptr().add(dyn_off + i * dyn_sz) as *const Elf64Dyn);
+        match d.d_tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(d.d_val),
+            DT_RELASZ => rela_sz = d.d_val,
+            DT_RELAENT => rela_ent = d.d_val,
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Some(());
+    };
+    let ent = core::mem::size_of::<Elf64Rela>();
+    if rela_ent != ent as u64 {
+        return None;
+    }
+    let off = file_offset(phdrs, rela, rela_sz)?;
+    for i in 0..rela_sz as usize / ent {
+        let r = core::ptr::read_unaligned(elf.as_ptr().add(off + i * ent) as *const Elf64Rela);
+        match r.r_info as u32 {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => {}
+            _ => return None,
+        }
+        let va = bias.checked_add(r.r_offset)?;
+        if va % 8 != 0 || (*leaf_pte(pml4, va)? & PTE_RW) == 0 {
+            return None;
+        }
+        let pa = translate_4k(pml4, va)?;
+        *paging::phys_to_virt_ptr::<u64>(pa) = bias.wrapping_add(r.r_addend as u64);
+    }
+    Some(())
+}
+
+// Maps and fills the PT_LOAD segments, adding the number of user pages mapped to `pages`.
+// Read-only segments map the frames cached for `prog_id`; writable ones get private copies.
+// A PIE (ET_DYN) is placed `bias` bytes above its link addresses and relocated; a fixed
+// executable (ET_EXEC) only loads with a bias of 0.
+unsafe fn load_elf_into_user(
+    pml4: u64,
+    prog_id: u64,
+    elf: &[u8],
+    bias: u64,
+    pages: &mut u64,
+) -> Option<u64> {
     if elf.len() < core::mem::size_of::<Elf64Ehdr>() {
         return None;
     }
@@ -256,6 +1331,14 @@ unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
     if eh.e_machine != 0x3e {
         return None;
     }
+    let relocatable = match eh.e_type {
+        ET_DYN => true,
+        ET_EXEC => false,
+        _ => return None,
+    };
+    if !bias.is_multiple_of(PAGE_SIZE) || (bias != 0 && !relocatable) {
+        return None;
+    }
     if eh.e_phentsize as usize != core::mem::size_of::<Elf64Phdr>() {
         return None;
     }
@@ -267,15 +1350,25 @@ unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
         return None;
     }
 
-    for i in 0..phnum {
-        let ph = &*(elf.as_ptr().add(phoff + i * phsz) as *const Elf64Phdr);
+    let phdrs = core::slice::from_raw_parts(elf.as_ptr().add(phoff) as *const Elf64Phdr, phnum);
+    for ph in phdrs {
         if ph.p_type != PT_LOAD || ph.p_memsz == 0 {
             continue;
         }
 
+        let foff = ph.p_offset as usize;
+        let fsz = ph.p_filesz as usize;
+        if ph.p_filesz > ph.p_memsz || foff.checked_add(fsz).unwrap_or(usize::MAX) > elf.len() {
+            return None;
+        }
+        let vaddr = ph.p_vaddr.checked_add(bias)?;
+        if checked_user_range(vaddr, ph.p_memsz).is_none() {
+            return None;
+        }
+
         // Map segment pages.
-        let seg_start = align_down(ph.p_vaddr, PAGE_SIZE);
-        let seg_end = align_up(ph.p_vaddr.saturating_add(ph.p_memsz), PAGE_SIZE);
+        let seg_start = align_down(vaddr, PAGE_SIZE);
+        let seg_end = align_up_checked(vaddr.checked_add(ph.p_memsz)?, PAGE_SIZE)?;
 
         let mut flags = PTE_U;
         if (ph.p_flags & PF_W) != 0 {
@@ -284,22 +1377,43 @@ unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
         // NX is not enabled yet; ignore PF_X/PF_R.
         let _ = ph.p_flags & (PF_X | PF_R);
 
+        if (ph.p_flags & PF_W) == 0 {
+            if let Some(frames) = shared_frames(prog_id, elf, ph) {
+                for (i, &f) in frames.iter().enumerate() {
+                    map_4k(
+                        pml4,
+                        seg_start + i as u64 * PAGE_SIZE,
+                        f,
+                        flags | PTE_SHARED,
+                    )?;
+                    *pages += 1;
+                }
+                continue;
+            }
+        }
+
+        // Whole pages past the file bytes of a writable segment are pure BSS: map them to
+        // the zero frame and let the first write fault in a private copy.
+        let lazy_from = if (ph.p_flags & PF_W) != 0 {
+            align_up_checked(vaddr + ph.p_filesz, PAGE_SIZE)?
+        } else {
+            seg_end
+        };
         let mut v = seg_start;
         while v < seg_end {
-            let p = pmm::alloc_frame().expect("user: alloc_frame segment");
-            map_4k(pml4, v, p, flags);
+            if v >= lazy_from {
+                map_zero_page(pml4, v)?;
+            } else {
+                map_new_user_page(pml4, v, flags)?;
+            }
+            *pages += 1;
             v += PAGE_SIZE;
         }
 
         // Copy file bytes -> mapped pages using the built page tables to translate.
         if ph.p_filesz != 0 {
-            let foff = ph.p_offset as usize;
-            let fsz = ph.p_filesz as usize;
-            if foff.checked_add(fsz).unwrap_or(usize::MAX) > elf.len() {
-                return None;
-            }
             for off in 0..fsz {
-                let va = ph.p_vaddr + off as u64;
+                let va = vaddr + off as u64;
                 let Some(pa) = translate_4k(pml4, va) else {
                     return None;
                 };
@@ -308,11 +1422,12 @@ unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
             }
         }
 
-        // Zero BSS.
-        if ph.p_memsz > ph.p_filesz {
-            let z = (ph.p_memsz - ph.p_filesz) as usize;
+        // Zero the BSS that shares a page with file bytes; lazy pages are zero already.
+        let bss_end = (vaddr + ph.p_memsz).min(lazy_from);
+        if bss_end > vaddr + ph.p_filesz {
+            let z = (bss_end - vaddr - ph.p_filesz) as usize;
             for off in 0..z {
-                let va = ph.p_vaddr + ph.p_filesz + off as u64;
+                let va = vaddr + ph.p_filesz + off as u64;
                 let Some(pa) = translate_4k(pml4, va) else {
                     return None;
                 };
@@ -321,129 +1436,558 @@ unsafe fn load_elf_into_user(pml4: u64, elf: &[u8]) -> Option<u64> {
         }
     }
 
-    Some(eh.e_entry)
+    if relocatable {
+        apply_relocations(pml4, elf, phdrs, bias)?;
+    }
+    eh.e_entry.checked_add(bias)
+}
+
+// A program's loaded image in an address space of its own, built from the ELF on the first
+// spawn and cloned into every later instance: read-only pages map the same frames, written
+// ones copy-on-write. Like the shared text, templates and their frames live for the rest of
+// the boot, and the template address space itself never runs.
+struct Template {
+    prog_id: u64,
+    pml4: u64,
+    entry: u64,
 }
 
-unsafe fn build_proc_from_init(role: u64, init_ep_cap: u64) -> (u64, u64, u64, u64) {
-    let kb = BOOT_KB.load(core::sync::atomic::Ordering::Relaxed);
-    let ke = BOOT_KE.load(core::sync::atomic::Ordering::Relaxed);
-    let maxp = BOOT_MAX.load(core::sync::atomic::Ordering::Relaxed);
-    if kb == 0 || ke == 0 || maxp == 0 {
-        panic!("user: boot params not set");
+const TEMPLATES: usize = 4;
+static mut PROG_TEMPLATES: [Option<Template>; TEMPLATES] = [const { None }; TEMPLATES];
+
+// Call `f(va, pte)` for every present 4 KiB user leaf of `pml4`.
+unsafe fn for_each_user_leaf(pml4: u64, mut f: impl FnMut(u64, u64) -> Option<()>) -> Option<()> {
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+    let present = |table: u64, i: usize| {
+        let e = *table_entry_mut(table, i);
+        ((e & PTE_P) != 0 && (e & PTE_PS) == 0).then_some(e)
+    };
+    for i in 0..paging::HHDM_PML4_INDEX {
+        let Some(e4) = present(pml4, i) else { continue };
+        for j in 0..512usize {
+            let Some(e3) = present(e4 & ADDR, j) else {
+                continue;
+            };
+            for k in 0..512usize {
+                let Some(e2) = present(e3 & ADDR, k) else {
+                    continue;
+                };
+                for l in 0..512usize {
+                    let Some(e1) = present(e2 & ADDR, l) else {
+                        continue;
+                    };
+                    let va = ((i as u64) << 39)
+                        | ((j as u64) << 30)
+                        | ((k as u64) << 21)
+                        | ((l as u64) << 12);
+                    f(va, e1)?;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+// The template for `prog_id`, loading `elf` into it on first use. None if the cache is full
+// or the image doesn't load; the caller then loads this instance from the ELF itself.
+unsafe fn program_template(prog_id: u64, elf: &[u8]) -> Option<&'static Template> {
+    let cache = &raw mut PROG_TEMPLATES;
+    let cache = &mut *cache;
+    let hit = cache
+        .iter()
+        .position(|t| matches!(t, Some(t) if t.prog_id == prog_id));
+    if let Some(i) = hit {
+        return cache[i].as_ref();
     }
+    let slot = cache.iter_mut().find(|t| t.is_none())?;
+    let pml4 = alloc_table()?;
+    let mut pages = 0;
+    let Some(entry) = load_elf_into_user(pml4, prog_id, elf, load_bias(elf), &mut pages) else {
+        free_user_space(pml4);
+        return None;
+    };
+    kdebug!(
+        "user: built template for prog={} ({} pages)",
+        prog_id,
+        pages
+    );
+    Some(slot.insert(Template {
+        prog_id,
+        pml4,
+        entry,
+    }))
+}
 
-    let pml4 = alloc_table();
+// Map the image of `t` into `pml4`, adding the pages mapped to `pages`. Nothing is copied:
+// shared pages keep their flags, the template's own pages are mapped read-only and, where
+// the program may write them, copy-on-write.
+unsafe fn clone_template(t: &Template, pml4: u64, pages: &mut u64) -> Option<u64> {
+    const ADDR: u64 = 0x000f_ffff_ffff_f000;
+    for_each_user_leaf(t.pml4, |va, e| {
+        let flags = if (e & PTE_SHARED) != 0 {
+            e & !ADDR & !PTE_P
+        } else if (e & PTE_RW) != 0 {
+            PTE_U | PTE_SHARED | PTE_COW
+        } else {
+            PTE_U | PTE_SHARED
+        };
+        map_4k(pml4, va, e & ADDR, flags)?;
+        *pages += 1;
+        Some(())
+    })?;
+    Some(t.entry)
+}
 
-    // Map kernel identity (supervisor).
-    let kb = align_down(kb, PAGE_SIZE);
-    let ke = align_up(ke, PAGE_SIZE);
-    let mut p = kb;
-    while p < ke {
-        map_4k(pml4, p, p, PTE_RW);
-        p += PAGE_SIZE;
+// Load program `prog_id` (image `elf`) into `pml4`: cloned from its template when
+// `use_template` and one can be had, else straight from the ELF.
+unsafe fn load_program(
+    pml4: u64,
+    prog_id: u64,
+    elf: &[u8],
+    use_template: bool,
+    pages: &mut u64,
+) -> Option<u64> {
+    let template = if use_template {
+        program_template(prog_id, elf)
+    } else {
+        None
+    };
+    match template {
+        Some(t) => clone_template(t, pml4, pages),
+        None => load_elf_into_user(pml4, prog_id, elf, load_bias(elf), pages),
     }
-    map_hhdm_huge(pml4, maxp);
+}
 
-    // User stack (fixed VA).
-    let user_stack_top: u64 = 0x0000_0000_2000_0000;
-    let stack_pages = 4u64;
-    let stack_base = user_stack_top - stack_pages * PAGE_SIZE;
-    for i in 0..stack_pages {
-        let sp = pmm::alloc_frame().expect("user: alloc_frame stack");
-        map_4k(pml4, stack_base + i * PAGE_SIZE, sp, PTE_U | PTE_RW);
+// A freshly built (not yet scheduled) process.
+struct NewProc {
+    tf: *mut TrapFrame,
+    kstack_top: u64,
+    cr3: u64,
+    entry: u64,
+    user_pages: u64, // user-accessible pages mapped into `cr3`
+    layout: UserLayout,
+}
+
+// Per-process placement of the user stack and the (future) mmap region.
+#[derive(Copy, Clone)]
+struct UserLayout {
+    stack_top: u64,
+    mmap_base: u64,
+}
+
+// Deterministic layout, used when ASLR is off.
+const USER_STACK_TOP: u64 = 0x0000_0000_2000_0000;
+const USER_MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+// Randomized stack tops slide down from here by up to `ASLR_STACK_PAGES` pages (16 GiB),
+// mmap bases slide up from `USER_MMAP_BASE` by up to `ASLR_MMAP_PAGES` (1 TiB). Both stay
+// well clear of the program image and of the top of the lower canonical half.
+const ASLR_STACK_TOP_MAX: u64 = 0x0000_7fff_0000_0000;
+const ASLR_STACK_PAGES: u64 = 1 << 22;
+const ASLR_MMAP_PAGES: u64 = 1 << 28;
+
+// Not on the kernel command line: build with MANTRA_NOASLR=1 for reproducible layouts.
+fn aslr_enabled() -> bool {
+    !matches!(option_env!("MANTRA_NOASLR"), Some("1"))
+}
+
+fn choose_layout() -> UserLayout {
+    if !aslr_enabled() {
+        return UserLayout {
+            stack_top: USER_STACK_TOP,
+            mmap_base: USER_MMAP_BASE,
+        };
+    }
+    let stack_slide = (rng::next_u64() % ASLR_STACK_PAGES) * PAGE_SIZE;
+    let mmap_slide = (rng::next_u64() % ASLR_MMAP_PAGES) * PAGE_SIZE;
+    UserLayout {
+        stack_top: ASLR_STACK_TOP_MAX - stack_slide,
+        mmap_base: USER_MMAP_BASE + mmap_slide,
+    }
+}
+
+// PIE images load at `PIE_BASE`, slid up by up to `ASLR_PIE_PAGES` pages (64 GiB) with
+// ASLR on: clear of both the fixed and the randomized stack and mmap areas.
+const PIE_BASE: u64 = 0x0000_5555_0000_0000;
+const ASLR_PIE_PAGES: u64 = 1 << 24;
+
+// Load bias for `elf`: 0 for a fixed executable, else where its PIE image goes. Instances
+// cloned from a template share the bias the template was loaded with.
+fn load_bias(elf: &[u8]) -> u64 {
+    let is_pie = elf
+        .get(16..18)
+        .is_some_and(|t| u16::from_le_bytes([t[0], t[1]]) == ET_DYN);
+    if !is_pie {
+        0
+    } else if !aslr_enabled() {
+        PIE_BASE
+    } else {
+        PIE_BASE + (rng::next_u64() % ASLR_PIE_PAGES) * PAGE_SIZE
     }
+}
+
+// Returns None if frames ran out; everything allocated so far is released.
+unsafe fn build_proc(prog_id: u64, role: u64) -> Option<NewProc> {
+    let pml4 = alloc_table()?;
+    let layout = choose_layout();
+    let mut user_pages = 0;
+    let Some(entry) = build_user_space(pml4, prog_id, layout, true, &mut user_pages) else {
+        free_user_space(pml4);
+        return None;
+    };
     // SysV ABI: at function entry, compilers generally assume RSP % 16 == 8.
     // Since we enter userspace via `iretq` (not a `call`), we emulate the post-call alignment.
-    let user_rsp = user_stack_top - 8;
+    let user_rsp = layout.stack_top - 8;
+
+    let kstack_top = kstack_alloc_top();
+    let tf = build_initial_tf(kstack_top, entry, user_rsp, role);
+    Some(NewProc {
+        tf,
+        kstack_top,
+        cr3: pml4,
+        entry,
+        user_pages,
+        layout,
+    })
+}
+
+// Populate `pml4` with the kernel mappings, user stack and program image (see `load_program`).
+// Returns the entry point.
+// Everything below PML4 index 256 belongs to the program; the kernel is only reachable through
+// the higher half (image, HHDM, KMAP), all supervisor-only.
+unsafe fn build_user_space(
+    pml4: u64,
+    prog_id: u64,
+    layout: UserLayout,
+    use_template: bool,
+    user_pages: &mut u64,
+) -> Option<u64> {
+    // Share the kernel image mapping (trap entry, statics such as USER_SWITCH_STACK).
+    let kernel_e = paging::kernel_pml4_entry();
+    if kernel_e == 0 {
+        return None;
+    }
+    *table_entry_mut(pml4, paging::KERNEL_PML4_INDEX) = kernel_e;
+    // Share the kernel's HHDM (supervisor-only), however many PML4 entries it spans.
+    for (i, e) in paging::hhdm_pml4_entries() {
+        *table_entry_mut(pml4, i) = e;
+    }
+    // Share the kernel's KMAP window (explicit MMIO mappings such as a high framebuffer).
+    let kmap_e = paging::kmap_pml4_entry();
+    if kmap_e != 0 {
+        *table_entry_mut(pml4, paging::KMAP_PML4_INDEX) = kmap_e;
+    }
+
+    map_time_page(pml4)?;
+
+    // User stack.
+    let stack_pages = 4u64;
+    let stack_base = layout.stack_top - stack_pages * PAGE_SIZE;
+    for i in 0..stack_pages {
+        map_new_user_page(pml4, stack_base + i * PAGE_SIZE, PTE_U | PTE_RW)?;
+        *user_pages += 1;
+    }
 
     // Code.
-    let entry = if !init_elf::INIT_ELF.is_empty() {
-        load_elf_into_user(pml4, init_elf::INIT_ELF).expect("user: init ELF load failed")
+    if prog_id == INIT_PROG_ID && !init_elf::INIT_ELF.is_empty() {
+        load_program(
+            pml4,
+            INIT_PROG_ID,
+            init_elf::INIT_ELF,
+            use_template,
+            user_pages,
+        )
     } else {
-        let user_code_v: u64 = 0x0000_0000_1000_0000;
-        let code_p = pmm::alloc_frame().expect("user: alloc_frame code");
-        map_4k(pml4, user_code_v, code_p, PTE_U);
-        let code = [0xCDu8, 0x80, 0xEBu8, 0xFE]; // int 0x80; jmp $
+        let code_p = map_new_user_page(pml4, YIELD_CODE_VA, PTE_U)?;
+        *user_pages += 1;
         let code_ptr = paging::phys_to_virt_ptr::<u8>(code_p);
-        core::ptr::copy_nonoverlapping(code.as_ptr(), code_ptr, code.len());
-        user_code_v
-    };
+        core::ptr::copy_nonoverlapping(YIELD_CODE.as_ptr(), code_ptr, YIELD_CODE.len());
+        Some(YIELD_CODE_VA)
+    }
+}
 
-    let kstack_top = kstack_alloc_top();
-    let tf_rsp = build_initial_tf(kstack_top, entry, user_rsp, role, init_ep_cap);
-    (tf_rsp, kstack_top, pml4, entry)
+/// Program ID of the embedded init image (for `PROC_SPAWN` and the boot manifest).
+pub const INIT_PROG_ID: u64 = 1;
+/// Program ID of the built-in yielder, a few bytes of code that call `YIELD_` forever. It
+/// also stands in for init when no init image was built in.
+pub const YIELD_PROG_ID: u64 = 2;
+
+// mov eax, YIELD_; xor edi, edi; int 0x80; jmp back to the mov
+const YIELD_CODE: [u8; 11] = [0xb8, 0x02, 0, 0, 0, 0x31, 0xff, 0xcd, 0x80, 0xeb, 0xf5];
+const YIELD_CODE_VA: u64 = 0x0000_0000_1000_0000;
+
+/// Look up a program by name.
+pub fn program_id(name: &str) -> Option<u64> {
+    match name {
+        "init" => Some(INIT_PROG_ID),
+        "yield" => Some(YIELD_PROG_ID),
+        _ => None,
+    }
+}
+
+// Privileged procs start with the kernel introspection endpoint in `sysinfo::CAP`; this
+// must run before any other cap is handed to `pid`.
+fn grant_sysinfo(pid: usize, role: u64) {
+    if role != sched::ROLE_INIT {
+        return;
+    }
+    let Some(ep) = ipc::sysinfo_ep() else {
+        return;
+    };
+    let cap = sched::cap_alloc_for(pid, ep);
+    kassert!(
+        cap.map(u64::from) == Some(mantra_sys::sysinfo::CAP),
+        "user: sysinfo cap for pid={} landed in {:?}",
+        pid,
+        cap
+    );
 }
 
-pub fn spawn_init_from_syscall(prog_id: u64, role: u64, share_cap: u32) -> u64 {
-    // Only one program exists right now.
-    if prog_id != 1 {
+// `caps` are resolved against the parent up front, so a bad entry fails the spawn before
+// anything is built. Failure to get frames triggers the OOM killer once before giving up
+// with NO_MEMORY.
+pub fn spawn_init_from_syscall(
+    parent: usize,
+    prog_id: u64,
+    role: u64,
+    caps: &[SpawnCap],
+    flags: u64,
+) -> u64 {
+    if prog_id != INIT_PROG_ID && prog_id != YIELD_PROG_ID {
         return u64::MAX;
     }
 
-    let ep_id = if share_cap != 0 {
-        sched::cap_lookup_current(share_cap).unwrap_or(0)
-    } else {
-        0
-    };
+    // Unprivileged procs may only start unprivileged children, within their budgets.
+    if role == sched::ROLE_INIT && !sched::is_privileged(parent) {
+        return error::PERMISSION;
+    }
+    let mut children = 0;
+    sched::children(parent, |_| children += 1);
+    if let Err(e) = limits::check(parent, Resource::Children, children + 1)
+        .and_then(|()| limits::check_role(role, Resource::Caps, caps.len() as u64))
+    {
+        return e;
+    }
+
+    let mut ep_ids = [0u32; spawn_caps::MAX];
+    if caps.len() > ep_ids.len() {
+        return error::INVALID;
+    }
+    for (c, ep_id) in caps.iter().zip(ep_ids.iter_mut()) {
+        match sched::cap_lookup(parent, c.cap) {
+            Some(ep) if c.rights == 0 && c.badge == 0 => *ep_id = ep,
+            _ => return error::INVALID,
+        }
+    }
 
     unsafe {
-        // Build the process with placeholder cap.
-        let (tf_rsp, kstack_top, cr3, _entry) = build_proc_from_init(role, 0);
-        let Some(pid) = sched::spawn_proc(tf_rsp, kstack_top, cr3) else {
+        let np = match build_proc(prog_id, role) {
+            Some(np) => np,
+            None if oom::reclaim(parent) => match build_proc(prog_id, role) {
+                Some(np) => np,
+                None => return error::NO_MEMORY,
+            },
+            None => return error::NO_MEMORY,
+        };
+        let pages = np.user_pages;
+        if let Err(e) = limits::check_role(role, Resource::Pages, pages).and_then(|()| {
+            limits::check_role(
+                role,
+                Resource::TotalPages,
+                sched::total_mapped_pages() + pages,
+            )
+        }) {
+            kstack_free(np.kstack_top - KSTACK_SIZE as u64);
+            free_user_space(np.cr3);
+            return e;
+        }
+        let die_with_parent = (flags & spawn_flags::DIE_WITH_PARENT) != 0;
+        let Some(pid) = sched::spawn_proc(
+            np.tf as u64,
+            np.kstack_top,
+            np.cr3,
+            np.user_pages,
+            role,
+            parent,
+            die_with_parent,
+        ) else {
+            kstack_free(np.kstack_top - KSTACK_SIZE as u64);
+            free_user_space(np.cr3);
             return u64::MAX;
         };
 
-        // Derive a child-local cap to the shared endpoint and patch the trap frame.
-        let mut child_cap: u64 = 0;
-        if ep_id != 0 {
-            let c = sched::cap_alloc_for(pid, ep_id).unwrap_or(0);
-            child_cap = c as u64;
+        kdebug!(
+            "user: pid={} stack_top={:#x} mmap_base={:#x}",
+            pid,
+            np.layout.stack_top,
+            np.layout.mmap_base
+        );
+
+        grant_sysinfo(pid, role);
+
+        // The child has not run yet; its table is empty from `spawn_caps::FIRST` on.
+        for (i, &ep_id) in ep_ids[..caps.len()].iter().enumerate() {
+            let cap = spawn_caps::FIRST as u32 + i as u32;
+            kassert!(
+                sched::cap_install(pid, cap, ep_id),
+                "user: spawn cap {} for pid={} taken",
+                cap,
+                pid
+            );
         }
-        let tf_ptr = tf_rsp as *mut TaskTrapFrame;
-        (*tf_ptr).rsi = child_cap;
 
         pid as u64
     }
 }
 
-pub fn enter_first_user(kernel_phys_base: u64, kernel_phys_end: u64, max_phys_hint: u64) -> ! {
-    serial::write_str("user: setting up address space\n");
+/// Build a boot program and queue it behind the already installed first process. Returns
+/// its pid, or None (with a warning) if it could not be started.
+pub fn launch_boot_proc(e: &launcher::Entry) -> Option<usize> {
+    let Some(np) = (unsafe { build_proc(e.prog_id, e.role) }) else {
+        kwarn!("user: out of memory launching role={}", e.role);
+        return None;
+    };
+    // Boot programs are supervised by pid 0 like any other orphan.
+    match sched::spawn_proc(
+        np.tf as u64,
+        np.kstack_top,
+        np.cr3,
+        np.user_pages,
+        e.role,
+        0,
+        false,
+    ) {
+        Some(pid) => {
+            grant_sysinfo(pid, e.role);
+            kinfo!(
+                "user: launched pid={} prog={} role={}",
+                pid,
+                e.prog_id,
+                e.role
+            );
+            Some(pid)
+        }
+        None => {
+            kwarn!("user: no proc slot for role={}", e.role);
+            kstack_free(np.kstack_top - KSTACK_SIZE as u64);
+            unsafe { free_user_space(np.cr3) };
+            None
+        }
+    }
+}
+
+ktest! {
+    fn two_entry_manifest_spawns_both() {
+        // Each entry comes up runnable and entered at its own program: the yielder at its
+        // few bytes of code, init anywhere else.
+        let mut entries = [launcher::Entry { prog_id: 0, role: 0 }; launcher::MAX_ENTRIES];
+        let n = launcher::parse("init 0\nyield 1", &mut entries);
+        kassert!(n == 2, "manifest gave {} entries", n);
+        let pids = [launch_boot_proc(&entries[0]), launch_boot_proc(&entries[1])];
+        let runnable = pids.map(|pid| {
+            let state = pid.and_then(sched::proc_info).map(|(state, ..)| state);
+            state == Some(sched::ProcState::Runnable)
+        });
+        let entered = pids.map(|pid| {
+            let (cr3, tf) = (pid.and_then(sched::proc_cr3)?, pid.and_then(sched::proc_user_tf)?);
+            let rip = unsafe { (*(tf as *const TrapFrame)).rip };
+            let mut code = [0u8; YIELD_CODE.len()];
+            copy_from(cr3, &mut code, rip).ok()?;
+            Some((rip, code))
+        });
+        for pid in pids.into_iter().flatten() {
+            sched::release(pid);
+        }
+        kassert!(runnable == [true, true], "pids {:?} not both runnable", pids);
+        kassert!(entered[1] == Some((YIELD_CODE_VA, YIELD_CODE)), "yield entered elsewhere");
+        if !init_elf::INIT_ELF.is_empty() {
+            kassert!(
+                entered[0].is_some_and(|e| e != (YIELD_CODE_VA, YIELD_CODE)),
+                "init entry started the yielder"
+            );
+        }
+    }
+}
+
+/// Start `boot[0]` as pid 0 (entered directly) with the rest queued behind it.
+// Runs on USER_SWITCH_STACK with the kernel CR3 still loaded. Nothing from here on touches
+// the boot stack, boot info or memory map, so the low identity map goes before the first
+// user CR3 (which never had one) is loaded.
+extern "C" fn enter_from_switch_stack(cr3: u64, task_tf: u64, boot_low: u64) -> ! {
+    paging::drop_identity_map();
+    paging::identity_drop_self_test(boot_low);
 
+    let udata = ((gdt::UDATA_SEL as u64) | 3) as u16;
+    // Load CR3, load user DS/ES, then jump into the common trap-return path (pops regs and
+    // iretqs) to start task0.
     unsafe {
-        BOOT_KB.store(kernel_phys_base, core::sync::atomic::Ordering::Relaxed);
-        BOOT_KE.store(kernel_phys_end, core::sync::atomic::Ordering::Relaxed);
-        BOOT_MAX.store(max_phys_hint, core::sync::atomic::Ordering::Relaxed);
+        asm!(
+            "mov cr3, {cr3}",
+            "mov ds, ax",
+            "mov es, ax",
+            "mov rsp, {task_tf}",
+            "jmp {ret}",
+            in("ax") udata,
+            cr3 = in(reg) cr3,
+            task_tf = in(reg) task_tf,
+            ret = in(reg) (isr::mantra_trap_return as *const () as usize),
+            options(noreturn)
+        );
+    }
+}
 
-        // Build and enter the first userspace process (init role 0).
-        let (tf_rsp, kstack_top, cr3, entry) = build_proc_from_init(0, 0);
+pub fn enter_first_user(boot: &[launcher::Entry]) -> ! {
+    serial::write_str("user: setting up address space\n");
+    let first = boot
+        .first()
+        .unwrap_or_else(|| bug!("user: no boot programs"));
+
+    unsafe {
+        // Build and enter the first userspace process.
+        let np = build_proc(first.prog_id, first.role)
+            .unwrap_or_else(|| bug!("user: failed to build boot program {}", first.prog_id));
         serial::write_str("user: cr3=");
-        serial::write_hex_u64(cr3);
+        serial::write_hex_u64(np.cr3);
         serial::write_str(" entry=");
-        serial::write_hex_u64(entry);
+        serial::write_hex_u64(np.entry);
+        serial::write_str(" pages=");
+        serial::write_dec_u64(np.user_pages);
         serial::write_str("\n");
+        kdebug!(
+            "user: aslr={} stack_top={:#x} mmap_base={:#x}",
+            aslr_enabled(),
+            np.layout.stack_top,
+            np.layout.mmap_base
+        );
 
-        sched::install_first(tf_rsp, kstack_top, cr3);
-        gdt::set_rsp0(kstack_top);
+        sched::install_first(
+            np.tf as u64,
+            np.kstack_top,
+            np.cr3,
+            np.user_pages,
+            first.role,
+        );
+        grant_sysinfo(0, first.role);
+        for e in &boot[1..] {
+            let _ = launch_boot_proc(e);
+        }
+        gdt::set_rsp0(np.kstack_top);
 
-        let udata = ((gdt::UDATA_SEL as u64) | 3) as u16;
         let kstack_top = (&raw const USER_SWITCH_STACK as *const u8)
             .add(core::mem::size_of::<[u8; 16 * 1024]>()) as u64;
 
-        // Switch to a known kernel stack, load CR3, load user DS/ES, then jump into the common
-        // trap-return path (pops regs and iretqs) to start task0.
+        // Leave the boot stack for a known kernel stack and finish there; `boot` lives on
+        // the boot stack, so its address serves as a low one for the identity drop check.
         asm!(
             "cli",
             "mov rsp, {kstack}",
-            "mov cr3, {cr3}",
-            "mov ds, ax",
-            "mov es, ax",
-            "mov rsp, {task_tf}",
-            "jmp {ret}",
-            in("ax") udata,
+            "and rsp, -16",
+            "call {cont}",
             kstack = in(reg) kstack_top,
-            cr3 = in(reg) cr3,
-            task_tf = in(reg) tf_rsp,
-            ret = in(reg) (isr::mantra_trap_return as *const () as usize),
+            cont = sym enter_from_switch_stack,
+            in("rdi") np.cr3,
+            in("rsi") np.tf as u64,
+            in("rdx") boot.as_ptr() as u64,
             options(noreturn)
         );
     }
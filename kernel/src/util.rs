@@ -0,0 +1,65 @@
+// Small arithmetic helpers shared across the kernel.
+
+/// `x` rounded up to a multiple of `a`, or None if that would pass `u64::MAX` (or `a` is
+/// not a power of two). An `a` of 0 means no alignment.
+pub const fn align_up_checked(x: u64, a: u64) -> Option<u64> {
+    if a == 0 {
+        return Some(x);
+    }
+    if !a.is_power_of_two() {
+        return None;
+    }
+    match x.checked_add(a - 1) {
+        Some(v) => Some(v & !(a - 1)),
+        None => None,
+    }
+}
+
+/// `x` rounded down to a multiple of `a`, a power of two. An `a` of 0 means no alignment.
+pub const fn align_down(x: u64, a: u64) -> u64 {
+    if a == 0 {
+        return x;
+    }
+    x & !(a - 1)
+}
+
+ktest! {
+    fn align_up_checked_stops_at_the_top() {
+        let top_page = !0xfffu64;
+        kassert!(align_up_checked(top_page - 1, 0x1000) == Some(top_page));
+        kassert!(align_up_checked(top_page + 1, 0x1000).is_none());
+    }
+}
+
+ktest! {
+    fn align_rounds_and_refuses_to_wrap() {
+        // Rounding at the top of the address space must fail rather than wrap to a small value, and
+        // zero alignment must leave values alone.
+        const PAGE: u64 = 4096;
+        let top_page = !(PAGE - 1);
+        kassert!(
+            align_up_checked(top_page, PAGE) == Some(top_page)
+                && align_up_checked(top_page + 1, PAGE).is_none()
+                && align_up_checked(u64::MAX, 2).is_none(),
+            "align_up_checked wrapped at the top of the address space"
+        );
+        kassert!(
+            align_up_checked(1, PAGE) == Some(PAGE)
+                && align_up_checked(PAGE, PAGE) == Some(PAGE)
+                && align_up_checked(0, PAGE) == Some(0),
+            "align_up_checked rounded wrong"
+        );
+        kassert!(
+            align_down(u64::MAX, PAGE) == top_page && align_down(PAGE - 1, PAGE) == 0,
+            "align_down rounded wrong"
+        );
+        kassert!(
+            align_up_checked(u64::MAX, 0) == Some(u64::MAX) && align_down(u64::MAX, 0) == u64::MAX,
+            "zero alignment changed the value"
+        );
+        kassert!(
+            align_up_checked(5, 3).is_none(),
+            "non-power-of-two alignment accepted"
+        );
+    }
+}
@@ -22,19 +22,45 @@ pub struct BootInfo {
     // Loaded kernel physical range [kernel_phys_base, kernel_phys_end).
     pub kernel_phys_base: u64,
     pub kernel_phys_end: u64,
+
+    // Framebuffer pixel layout. `fb_bpp` is bytes per pixel; the masks are only
+    // meaningful for `PixelFormat::Bitmask` (UEFI PixelBitMask).
+    pub fb_bpp: u32,
+    pub fb_red_mask: u32,
+    pub fb_green_mask: u32,
+    pub fb_blue_mask: u32,
+
+    // The kernel ELF file as read from disk (section headers and symbol table included),
+    // kept in boot-reserved memory for symbolizing backtraces. Physical address; 0 if absent.
+    pub kernel_file_ptr: u64,
+    pub kernel_file_len: u64,
+
+    // Kernel command line, read from `\cmdline.txt` on the boot volume if present: words
+    // separated by whitespace. The first `cmdline_len` bytes of `cmdline` are valid.
+    pub cmdline_len: u32,
+    pub _reserved1: u32,
+    pub cmdline: [u8; BootInfo::CMDLINE_MAX],
 }
 
 impl BootInfo {
     pub const MAGIC: u32 = 0x4D_41_4E_54; // "MANT"
-    pub const VERSION: u32 = 2;
+    pub const VERSION: u32 = 7;
+    pub const CMDLINE_MAX: usize = 256;
 }
 
+// The kernel is linked at `KERNEL_VIRT_OFFSET + phys` (top 2 GiB, -mcmodel=kernel). The
+// bootloader loads PT_LOAD segments at their physical addresses and, before jumping to the
+// entry point, maps physical [0, KERNEL_MAP_SIZE) at this offset.
+pub const KERNEL_VIRT_OFFSET: u64 = 0xffff_ffff_8000_0000;
+pub const KERNEL_MAP_SIZE: u64 = 1024 * 1024 * 1024;
+
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PixelFormat {
     Unknown = 0,
-    Rgb = 1, // 0x00RRGGBB in memory as little-endian u32
-    Bgr = 2, // 0x00BBGGRR in memory as little-endian u32
+    Rgb = 1,     // 0x00RRGGBB in memory as little-endian u32
+    Bgr = 2,     // 0x00BBGGRR in memory as little-endian u32
+    Bitmask = 3, // channel positions given by fb_{red,green,blue}_mask
 }
 
 #[repr(u32)]
@@ -57,5 +83,19 @@ pub struct MemoryRegion {
     pub base: u64,
     pub len: u64,
     pub kind: u32, // RegionKind as u32
-    pub _reserved: u32,
+    pub attr: u32, // MemoryRegion::ATTR_* bits (from the UEFI descriptor)
+}
+
+impl MemoryRegion {
+    // Cacheability the firmware says the range supports.
+    pub const ATTR_UC: u32 = 1 << 0;
+    pub const ATTR_WC: u32 = 1 << 1;
+    pub const ATTR_WT: u32 = 1 << 2;
+    pub const ATTR_WB: u32 = 1 << 3;
+    // Used by UEFI runtime services; must stay mapped and never be handed to the PMM.
+    pub const ATTR_RUNTIME: u32 = 1 << 31;
+
+    pub fn is_runtime(&self) -> bool {
+        (self.attr & Self::ATTR_RUNTIME) != 0
+    }
 }
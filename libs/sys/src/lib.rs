@@ -4,14 +4,550 @@ pub mod syscall {
     pub const PUTC: u64 = 1;
     pub const YIELD_: u64 = 2;
     pub const WRITE: u64 = 3; // (ptr,len) -> bytes_written
+    pub const EXIT: u64 = 4; // () -> does not return
 
     // IPC (capability-based, bring-up API).
-    pub const IPC_EP_CREATE: u64 = 0x10;
+    pub const IPC_EP_CREATE: u64 = 0x10; // (depth, max_msg, flags) -> cap or err; 0 = default; flags = ep_flags::*
     pub const IPC_SEND: u64 = 0x11; // (cap, ptr, len) -> bytes_sent or err
     pub const IPC_RECV: u64 = 0x12; // (cap, ptr, max_len) -> bytes_recv or err
     pub const IPC_SEND_CAP: u64 = 0x13; // (cap, ptr, len, xfer_cap) -> bytes_sent or err
     pub const IPC_RECV_CAP: u64 = 0x14; // (cap, ptr, max_len) -> bytes_recv or err; out: rdx=received_cap (0 if none)
+    pub const IPC_SEND_MSG: u64 = 0x15; // (cap, tag, ptr, len) -> payload bytes_sent or err; prepends a MsgHeader
+    pub const IPC_EP_DESTROY: u64 = 0x16; // (cap) -> 0 or err; creator (or init) only, revokes every cap
+    pub const CAP_DROP: u64 = 0x17; // (cap) -> 0 or err; releases only the caller's cap
+    pub const IPC_CALL: u64 = 0x18; // (cap, ptr, len, max_reply) -> reply bytes or err; reply lands in ptr; out: rdx=reply cap (0 if none)
+    pub const IPC_REPLY: u64 = 0x19; // (ptr, len, xfer_cap) -> bytes_sent or err; answers the last call received
+    pub const EP_REGISTER: u64 = 0x53; // (cap, name_ptr, name_len) -> 0 or err; privileged only; publishes `cap`'s endpoint under the name
+    pub const EP_LOOKUP: u64 = 0x54; // (name_ptr, name_len) -> new cap to the named endpoint or err
+
+    // Screen console (not serial): bytes are drawn as glyphs, '\n' and '\r' move the cursor.
+    pub const CON_WRITE: u64 = 0x55; // (ptr, len, color) -> bytes_written or err; color = 0xRRGGBB for this write, u64::MAX keeps the console's
+    pub const FB_SET_GAMMA: u64 = 0x56; // (*const Gamma) -> 0 or err; privileged only; null restores the identity table
+    pub const FB_GET_GAMMA: u64 = 0x5c; // (*mut Gamma) -> 0 or err
+
+    // Introspection.
+    pub const CAP_LIST: u64 = 0x48; // (ptr, max_entries) -> entries written; entry = {cap: u32, ep: u32}
+    pub const GETRANDOM: u64 = 0x49; // (ptr, len) -> bytes written or err; at most 16 KiB per call
+    pub const NANOSLEEP: u64 = 0x4a; // (ns) -> 0; sub-tick precision when a LAPIC timer is available
+    pub const NICE: u64 = 0x4b; // (delta as i64) -> new priority or err; negative deltas need privilege
+    pub const YIELD_HINT: u64 = 0x4e; // (cap) -> 0 or err; yields, blocking until the next send if `cap`'s endpoint is empty
+    pub const PROC_REGS: u64 = 0x4f; // (pid, *mut Regs) -> 0 or err; the caller's children, or any proc if privileged
+    // 0x50 is held for MSYNC (docs/ROADMAP.md, M7).
+    pub const PARK: u64 = 0x51; // () -> 0 once unparked, or 1 at once if an unpark was already pending
+    pub const UNPARK: u64 = 0x52; // (pid) -> 0 if it was parked, 1 if the unpark is left pending, or err
+    pub const SCHED_STATS: u64 = 0x57; // (*mut SchedStats) -> 0 or err
+    pub const TRANSLATE: u64 = 0x58; // (pid, va) -> physical address or err; privileged only
+    pub const MEMMAP: u64 = 0x59; // (*mut MemMapEntry, max_entries, skip) -> entries written or err
+    pub const IRQ_BIND: u64 = 0x5a; // (irq, cap) -> 0 or err; privileged only; notifies `cap` with bit `irq`, cap 0 unbinds
+    pub const SYSCALL_INFO: u64 = 0x5b; // (*mut SyscallName, max_entries) -> syscalls implemented or err; fills entries in number order
 
     // Process management (bring-up).
-    pub const PROC_SPAWN: u64 = 0x20; // (prog_id, role, share_cap) -> pid or err
+    pub const PROC_SPAWN: u64 = 0x20; // (prog_id, role, *const SpawnCap, count, flags) -> pid or err; flags = spawn_flags::*
+    pub const PROC_INFO: u64 = 0x21; // (pid, *mut ProcInfo) -> 0 or err
+
+    // Numbers at or above this fail with `error::NOT_FOUND`, as do unassigned ones below it.
+    pub const NR_SYSCALLS: u64 = 0x5d;
+
+    // Every syscall above, for feature detection (compare against SYSCALL_INFO).
+    pub const ALL: &[u64] = &[
+        PUTC,
+        YIELD_,
+        WRITE,
+        EXIT,
+        IPC_EP_CREATE,
+        IPC_SEND,
+        IPC_RECV,
+        IPC_SEND_CAP,
+        IPC_RECV_CAP,
+        IPC_SEND_MSG,
+        IPC_EP_DESTROY,
+        CAP_DROP,
+        IPC_CALL,
+        IPC_REPLY,
+        EP_REGISTER,
+        EP_LOOKUP,
+        CON_WRITE,
+        FB_SET_GAMMA,
+        FB_GET_GAMMA,
+        CAP_LIST,
+        GETRANDOM,
+        NANOSLEEP,
+        NICE,
+        SCHED_STATS,
+        TRANSLATE,
+        MEMMAP,
+        YIELD_HINT,
+        PROC_REGS,
+        IRQ_BIND,
+        PARK,
+        UNPARK,
+        SYSCALL_INFO,
+        PROC_SPAWN,
+        PROC_INFO,
+    ];
+}
+
+// Layout written by `syscall::PROC_INFO`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct ProcInfo {
+    pub state: u64, // proc_state::*
+    pub mapped_pages: u64,
+    pub parent: u64, // u64::MAX for the first process
+    pub ticks: u64,  // timer ticks spent running
+}
+
+// Layout written by `syscall::PROC_REGS`: the registers saved when the proc was last
+// switched away from. For a proc stopped in a syscall, rip is just past its `int 0x80`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Regs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+// Entry written by `syscall::SYSCALL_INFO`: a syscall number and its constant's name,
+// NUL-padded.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SyscallName {
+    pub nr: u64,
+    pub name: [u8; 16],
+}
+
+impl SyscallName {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+}
+
+// Layout written by `syscall::SCHED_STATS`: counters since boot, procs per state, then
+// tick accounting.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SchedStats {
+    pub switches: u64,
+    pub yields: u64,
+    pub timer_irqs: u64,
+    pub timer_preemptions: u64,
+    pub syscall_preemptions: u64,
+    pub runnable: u64,
+    pub blocked: u64,
+    pub sleeping: u64,
+    pub zombie: u64,
+    pub ticks: u64,      // timer ticks since the scheduler started
+    pub idle_ticks: u64, // of those, ticks with nothing to run; busy = ticks - idle_ticks
+}
+
+pub mod ep_flags {
+    pub const FAIR: u64 = 1 << 0; // receive round-robins across senders instead of plain FIFO
+    pub const ALL: u64 = FAIR;
+}
+
+// Color transform for `syscall::FB_SET_GAMMA`/`FB_GET_GAMMA`: a channel value `v` the
+// kernel draws comes out as `r[v]`, `g[v]` or `b[v]`. Covers everything on the screen.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Gamma {
+    pub r: [u8; 256],
+    pub g: [u8; 256],
+    pub b: [u8; 256],
+}
+
+impl Gamma {
+    pub const IDENTITY: Gamma = {
+        let mut t = [0u8; 256];
+        let mut i = 0;
+        while i < 256 {
+            t[i] = i as u8;
+            i += 1;
+        }
+        Gamma { r: t, g: t, b: t }
+    };
+}
+
+// Longest endpoint name `syscall::EP_REGISTER` accepts; names are raw bytes, not NUL-terminated.
+pub const EP_NAME_MAX: usize = 16;
+
+// Entry written by `syscall::MEMMAP`: the boot memory map in bootloader order, then the
+// PMM's live free ranges (`mem_kind::FREE`). Page through it with `skip` until 0 comes back.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct MemMapEntry {
+    pub base: u64,
+    pub len: u64,
+    pub kind: u64, // mem_kind::*
+}
+
+pub mod mem_kind {
+    pub const UNKNOWN: u64 = 0;
+    pub const USABLE: u64 = 1;
+    pub const RESERVED: u64 = 2;
+    pub const ACPI_RECLAIM: u64 = 3;
+    pub const ACPI_NVS: u64 = 4;
+    pub const MMIO: u64 = 5;
+    pub const KERNEL: u64 = 6;
+    pub const BOOT: u64 = 7;
+    pub const FRAMEBUFFER: u64 = 8;
+    pub const FREE: u64 = 9; // not from the boot map: memory the PMM can still hand out
+
+    pub fn name(kind: u64) -> &'static str {
+        match kind {
+            USABLE => "usable",
+            RESERVED => "reserved",
+            ACPI_RECLAIM => "acpi-reclaim",
+            ACPI_NVS => "acpi-nvs",
+            MMIO => "mmio",
+            KERNEL => "kernel",
+            BOOT => "boot",
+            FRAMEBUFFER => "framebuffer",
+            FREE => "free",
+            _ => "unknown",
+        }
+    }
+}
+
+pub mod spawn_flags {
+    pub const DIE_WITH_PARENT: u64 = 1 << 0; // killed when the parent exits instead of moving to pid 0
+}
+
+// Entry read by `syscall::PROC_SPAWN`: one of the caller's caps to install in the child
+// before it runs. Entry i lands in the child's cap `spawn_caps::FIRST + i`. Caps carry no
+// rights or badges yet, so both must be 0.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SpawnCap {
+    pub cap: u32,
+    pub rights: u32,
+    pub badge: u64,
+}
+
+pub mod spawn_caps {
+    pub const MAX: usize = 8; // entries per PROC_SPAWN
+    pub const FIRST: u64 = 2; // cap 1 is left for `sysinfo::CAP`
+}
+
+// Header prepended by `syscall::IPC_SEND_MSG`; receivers strip it with `MsgHeader::parse`
+// and dispatch on `tag`. Encoded little-endian on the wire.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct MsgHeader {
+    pub tag: u32,
+    pub len: u32, // payload bytes following the header
+}
+
+impl MsgHeader {
+    pub const SIZE: usize = core::mem::size_of::<MsgHeader>();
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[..4].copy_from_slice(&self.tag.to_le_bytes());
+        b[4..].copy_from_slice(&self.len.to_le_bytes());
+        b
+    }
+
+    // Split a received message into header and payload; `None` if it is too short
+    // or was truncated in transit.
+    pub fn parse(msg: &[u8]) -> Option<(MsgHeader, &[u8])> {
+        let (hdr, rest) = msg.split_at_checked(Self::SIZE)?;
+        let tag = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+        let len = u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]);
+        let payload = rest.get(..len as usize)?;
+        Some((MsgHeader { tag, len }, payload))
+    }
+}
+
+// Tag of the message a receive returns while its endpoint has notification bits pending
+// (e.g. from a bound IRQ, `syscall::IRQ_BIND`); the payload is the bits as a u64 LE. Pending
+// bits come before queued messages and are cleared by the receive that returns them.
+pub const NOTIFY_TAG: u32 = u32::MAX;
+
+// Fixed address of the read-only `TimePage` the kernel maps into every process.
+pub const TIME_PAGE: u64 = 0x0000_7fff_ffff_f000;
+
+// Layout of the time page, rewritten by the kernel on every timer tick. `seq` is odd while
+// an update is in progress; `read` retries until it sees the same even value before and
+// after loading the other fields, so it never returns a torn pair.
+#[repr(C)]
+pub struct TimePage {
+    pub seq: core::sync::atomic::AtomicU64,
+    pub ticks: core::sync::atomic::AtomicU64, // timer ticks since the scheduler started
+    pub ns: core::sync::atomic::AtomicU64,    // TSC clock at that tick, 0 if uncalibrated
+    pub hz: core::sync::atomic::AtomicU64,    // ticks per second
+}
+
+impl TimePage {
+    /// The page at `TIME_PAGE`, for use inside a process.
+    ///
+    /// # Safety
+    /// Only valid in an address space the kernel set up, where the page is mapped.
+    pub unsafe fn get() -> &'static TimePage {
+        &*(TIME_PAGE as *const TimePage)
+    }
+
+    /// (ticks, ns) as of the last tick, without a syscall.
+    pub fn read(&self) -> (u64, u64) {
+        use core::sync::atomic::{fence, Ordering};
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            let ticks = self.ticks.load(Ordering::Relaxed);
+            let ns = self.ns.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return (ticks, ns);
+            }
+        }
+    }
+
+    /// Store a new (ticks, ns) pair. Kernel side, single writer.
+    pub fn write(&self, ticks: u64, ns: u64) {
+        use core::sync::atomic::{fence, Ordering};
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.ticks.store(ticks, Ordering::Relaxed);
+        self.ns.store(ns, Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+// Kernel introspection over IPC: privileged procs start with a cap to a kernel-served
+// endpoint in slot `CAP`. IPC_CALL it with a bare `MsgHeader` whose tag is a request
+// below; the reply is a `MsgHeader` with the same tag followed by the encoded answer
+// (little-endian u64 fields in declaration order). Other procs get `error::PERMISSION`.
+pub mod sysinfo {
+    pub const CAP: u64 = 1;
+
+    pub const LIST_PROCS: u32 = 1; // -> ProcEntry per live proc, as many as fit
+    pub const MEMINFO: u32 = 2; // -> MemInfo
+    pub const IPC_STATS: u32 = 3; // -> IpcStats
+    pub const EP_STATS: u32 = 4; // -> EpStats per endpoint with a queue, as many as fit
+    pub const TEST_REPORT: u32 = 5; // TestReport -> nothing; ends a MANTRA_QEMUEXIT=1 run
+
+    fn put(out: &mut [u8], words: &[u64]) {
+        for (b, w) in out.chunks_exact_mut(8).zip(words) {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+    }
+
+    fn get<const N: usize>(b: &[u8]) -> Option<[u64; N]> {
+        let b = b.get(..N * 8)?;
+        let mut words = [0u64; N];
+        for (w, c) in words.iter_mut().zip(b.chunks_exact(8)) {
+            *w = u64::from_le_bytes(c.try_into().ok()?);
+        }
+        Some(words)
+    }
+
+    #[derive(Copy, Clone, Default)]
+    pub struct MemInfo {
+        pub usable_bytes: u64,
+        pub free_bytes: u64,
+        pub heap_bytes: u64,
+        pub heap_used: u64,
+    }
+
+    impl MemInfo {
+        pub const SIZE: usize = 4 * 8;
+
+        pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+            let mut b = [0u8; Self::SIZE];
+            put(
+                &mut b,
+                &[
+                    self.usable_bytes,
+                    self.free_bytes,
+                    self.heap_bytes,
+                    self.heap_used,
+                ],
+            );
+            b
+        }
+
+        pub fn parse(b: &[u8]) -> Option<MemInfo> {
+            let [usable_bytes, free_bytes, heap_bytes, heap_used] = get(b)?;
+            Some(MemInfo {
+                usable_bytes,
+                free_bytes,
+                heap_bytes,
+                heap_used,
+            })
+        }
+    }
+
+    #[derive(Copy, Clone, Default)]
+    pub struct ProcEntry {
+        pub pid: u64,
+        pub state: u64, // proc_state::*
+        pub mapped_pages: u64,
+        pub parent: u64, // u64::MAX for the first process
+    }
+
+    impl ProcEntry {
+        pub const SIZE: usize = 4 * 8;
+
+        pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+            let mut b = [0u8; Self::SIZE];
+            put(
+                &mut b,
+                &[self.pid, self.state, self.mapped_pages, self.parent],
+            );
+            b
+        }
+
+        pub fn parse(b: &[u8]) -> Option<ProcEntry> {
+            let [pid, state, mapped_pages, parent] = get(b)?;
+            Some(ProcEntry {
+                pid,
+                state,
+                mapped_pages,
+                parent,
+            })
+        }
+    }
+
+    #[derive(Copy, Clone, Default)]
+    pub struct IpcStats {
+        pub endpoints: u64, // in use, including the introspection endpoint
+        pub max_endpoints: u64,
+        pub queued: u64, // messages waiting across all endpoints
+    }
+
+    impl IpcStats {
+        pub const SIZE: usize = 3 * 8;
+
+        pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+            let mut b = [0u8; Self::SIZE];
+            put(&mut b, &[self.endpoints, self.max_endpoints, self.queued]);
+            b
+        }
+
+        pub fn parse(b: &[u8]) -> Option<IpcStats> {
+            let [endpoints, max_endpoints, queued] = get(b)?;
+            Some(IpcStats {
+                endpoints,
+                max_endpoints,
+                queued,
+            })
+        }
+    }
+
+    /// Traffic on one endpoint since it was created.
+    #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+    pub struct EpStats {
+        pub endpoint: u64, // kernel endpoint ID, not a cap
+        pub depth: u64,
+        pub queued: u64,
+        pub sent: u64,       // messages queued
+        pub received: u64,   // messages dequeued
+        pub dropped: u64,    // sends refused with `error::FULL`
+        pub high_water: u64, // deepest the queue has been
+    }
+
+    impl EpStats {
+        pub const SIZE: usize = 7 * 8;
+
+        pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+            let mut b = [0u8; Self::SIZE];
+            put(
+                &mut b,
+                &[
+                    self.endpoint,
+                    self.depth,
+                    self.queued,
+                    self.sent,
+                    self.received,
+                    self.dropped,
+                    self.high_water,
+                ],
+            );
+            b
+        }
+
+        pub fn parse(b: &[u8]) -> Option<EpStats> {
+            let [endpoint, depth, queued, sent, received, dropped, high_water] = get(b)?;
+            Some(EpStats {
+                endpoint,
+                depth,
+                queued,
+                sent,
+                received,
+                dropped,
+                high_water,
+            })
+        }
+    }
+
+    /// Outcome of the boot program's checks, the request body of `TEST_REPORT`.
+    #[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+    pub struct TestReport {
+        pub checks: u64,
+        pub failed: u64,
+    }
+
+    impl TestReport {
+        pub const SIZE: usize = 2 * 8;
+
+        pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+            let mut b = [0u8; Self::SIZE];
+            put(&mut b, &[self.checks, self.failed]);
+            b
+        }
+
+        pub fn parse(b: &[u8]) -> Option<TestReport> {
+            let [checks, failed] = get(b)?;
+            Some(TestReport { checks, failed })
+        }
+    }
+}
+
+pub mod proc_state {
+    pub const RUNNABLE: u64 = 0;
+    pub const BLOCKED: u64 = 1;
+    pub const SLEEPING: u64 = 2;
+    pub const ZOMBIE: u64 = 3;
+    pub const DEAD: u64 = 4;
+}
+
+// Syscall error returns (values at the top of the u64 range; anything below is success).
+pub mod error {
+    pub const INVALID: u64 = u64::MAX; // bad cap, argument or user pointer
+    pub const FULL: u64 = u64::MAX - 1; // endpoint queue full
+    pub const EMPTY: u64 = u64::MAX - 2; // endpoint queue empty
+    pub const NO_ENDPOINTS: u64 = u64::MAX - 3; // kernel endpoint table exhausted
+    pub const NO_CAP_SLOTS: u64 = u64::MAX - 4; // caller's cap table is full
+    pub const NO_MEMORY: u64 = u64::MAX - 5; // kernel allocation failed
+    pub const PERMISSION: u64 = u64::MAX - 6; // caller lacks authority over the object
+    pub const LIMIT: u64 = u64::MAX - 7; // caller's resource budget (children, pages, caps, endpoints) is used up
+    pub const NOT_FOUND: u64 = u64::MAX - 8; // no syscall with that number, no endpoint with that name, or no screen console
+
+    // The top 4096 values are reserved for errors.
+    pub fn is_err(v: u64) -> bool {
+        v > u64::MAX - 4096
+    }
 }
@@ -21,14 +21,33 @@ cp -f "${ROOT_DIR}/target/x86_64-unknown-uefi/debug/mantra-boot.efi" \
   "${BUILD_DIR}/EFI/BOOT/BOOTX64.EFI"
 
 # Kernel (custom JSON target; build core/compiler_builtins from source)
-RUSTFLAGS="-C link-arg=-T${ROOT_DIR}/kernel/linker.ld" \
+# Leak tracking records call sites, and the double-fault test prints a backtrace, by
+# walking frame pointers.
+KERNEL_RUSTFLAGS="-C link-arg=-T${ROOT_DIR}/kernel/linker.ld"
+if [[ "${MANTRA_HEAPTRACK:-}" == "1" || "${MANTRA_DFTEST:-}" == "1" ]]; then
+  KERNEL_RUSTFLAGS+=" -C force-frame-pointers=yes"
+fi
+# MANTRA_KTEST=1 builds the in-kernel test harness, which runs instead of the programs.
+KERNEL_FEATURES=()
+if [[ "${MANTRA_KTEST:-}" == "1" ]]; then
+  KERNEL_FEATURES=(--features ktest)
+fi
+RUSTFLAGS="${KERNEL_RUSTFLAGS}" \
 MANTRA_INIT_ELF="${BUILD_DIR}/init.elf" cargo \
   -Z json-target-spec \
   -Z build-std=core,alloc,compiler_builtins \
   -Z build-std-features=compiler-builtins-mem \
-  build -p mantracore --target kernel/x86_64-mantra.json
+  build -p mantracore --target kernel/x86_64-mantra.json "${KERNEL_FEATURES[@]}"
 cp -f "${ROOT_DIR}/target/x86_64-mantra/debug/mantracore" "${BUILD_DIR}/kernel.elf"
 
+# Kernel command line, read by the bootloader from the boot volume. MANTRA_KTEST=1 adds
+# `ktest` so the kernel runs its tests instead of the boot programs.
+CMDLINE="${MANTRA_CMDLINE:-}"
+if [[ "${MANTRA_KTEST:-}" == "1" ]]; then
+  CMDLINE="${CMDLINE:+${CMDLINE} }ktest"
+fi
+printf '%s\n' "${CMDLINE}" >"${BUILD_DIR}/cmdline.txt"
+
 # If OVMF drops into the UEFI shell, this will auto-run our bootloader.
 cat >"${BUILD_DIR}/startup.nsh" <<'EOF'
 \EFI\BOOT\BOOTX64.EFI
@@ -38,3 +57,4 @@ echo "Built:"
 echo "  ${BUILD_DIR}/EFI/BOOT/BOOTX64.EFI"
 echo "  ${BUILD_DIR}/kernel.elf"
 echo "  ${BUILD_DIR}/init.elf"
+echo "  ${BUILD_DIR}/cmdline.txt (${CMDLINE:-empty})"
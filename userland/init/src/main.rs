@@ -2,7 +2,37 @@
 #![no_main]
 
 use core::arch::asm;
-use mantra_sys::syscall;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mantra_sys::{
+    ep_flags, error, mem_kind, proc_state, spawn_caps, syscall, sysinfo, MemMapEntry, MsgHeader,
+    ProcInfo, Regs, SchedStats, SpawnCap, SyscallName, TimePage, NOTIFY_TAG,
+};
+
+// Roles passed in rdi at entry.
+const ROLE_CLIENT: u64 = 1;
+const ROLE_ORPHAN: u64 = 2;
+
+// Demo RPC tags carried in `MsgHeader::tag`.
+const TAG_PING: u32 = 1;
+// TAG_ORPHAN payload, u64 LEs: pid of a child the sender abandons, its checks, its failed
+// checks and its stack pointer at entry.
+const TAG_ORPHAN: u32 = 2;
+const TAG_CONNECT: u32 = 3; // IPC_CALL; the reply carries a fresh session endpoint cap
+const TAG_PARK: u32 = 4; // send or IPC_CALL; the receiver UNPARKs the sender once it blocks
+
+// Checks this proc has run and how many failed. The client hands its tally over with
+// TAG_ORPHAN; the server adds it to its own and reports the sum to the kernel.
+static CHECKS: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+// Large enough that GETRANDOM crosses several preemption points.
+static mut BULK: [u8; 16 * 1024] = [0; 16 * 1024];
+
+// Bounds of .text, from linker.ld.
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
 
 #[inline(always)]
 unsafe fn syscall1(n: u64, a1: u64) -> u64 {
@@ -58,6 +88,22 @@ unsafe fn syscall4(n: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> u64 {
     rax
 }
 
+#[inline(always)]
+unsafe fn syscall5(n: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> u64 {
+    let mut rax = n;
+    asm!(
+        "int 0x80",
+        inout("rax") rax,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("rcx") a4,
+        in("r8") a5,
+        options(nostack)
+    );
+    rax
+}
+
 #[inline(always)]
 unsafe fn syscall3_ret_rdx(n: u64, a1: u64, a2: u64, a3: u64) -> (u64, u64) {
     let mut rax = n;
@@ -73,6 +119,29 @@ unsafe fn syscall3_ret_rdx(n: u64, a1: u64, a2: u64, a3: u64) -> (u64, u64) {
     (rax, rdx)
 }
 
+#[inline(always)]
+unsafe fn syscall4_ret_rdx(n: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> (u64, u64) {
+    let mut rax = n;
+    let mut rdx = a3;
+    asm!(
+        "int 0x80",
+        inout("rax") rax,
+        in("rdi") a1,
+        in("rsi") a2,
+        inlateout("rdx") rdx,
+        in("rcx") a4,
+        options(nostack)
+    );
+    (rax, rdx)
+}
+
+fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags)) };
+    ((hi as u64) << 32) | lo as u64
+}
+
 fn putc(b: u8) {
     unsafe {
         let _ = syscall1(syscall::PUTC, b as u64);
@@ -85,32 +154,251 @@ fn puts(s: &str) {
     }
 }
 
+// Count one check and print "<who>: <what> ok" or "... FAIL".
+fn check(who: &str, what: &str, ok: bool) {
+    CHECKS.fetch_add(1, Ordering::Relaxed);
+    if !ok {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+    puts(who);
+    puts(": ");
+    puts(what);
+    puts(if ok { " ok\n" } else { " FAIL\n" });
+}
+
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     let role: u64;
     unsafe { asm!("mov {}, rdi", out(reg) role, options(nomem, nostack, preserves_flags)) };
-    let ep: u64;
-    unsafe { asm!("mov {}, rsi", out(reg) ep, options(nomem, nostack, preserves_flags)) };
 
     if role == 0 {
         puts("init[0]: server start\n");
-        // Create an endpoint, then spawn the client and pass it a derived cap to the same endpoint.
-        let ep = unsafe { syscall1(syscall::IPC_EP_CREATE, 0) };
+        let sp = put_sp("init[0]");
+        // Two draws should never match; a repeat means the kernel RNG is stuck.
+        let mut r1 = [0u8; 32];
+        let mut r2 = [0u8; 32];
+        let n1 = unsafe { syscall2(syscall::GETRANDOM, r1.as_mut_ptr() as u64, r1.len() as u64) };
+        let n2 = unsafe { syscall2(syscall::GETRANDOM, r2.as_mut_ptr() as u64, r2.len() as u64) };
+        check("init[0]", "getrandom", n1 == r1.len() as u64 && n2 == r2.len() as u64 && r1 != r2);
+        // The shared time page follows the tick with no syscall involved: spin on plain
+        // loads until it moves (or a few billion cycles pass).
+        let time = unsafe { TimePage::get() };
+        let (t0, _) = time.read();
+        let (mut t1, start) = (t0, rdtsc());
+        while t1 == t0 && rdtsc().wrapping_sub(start) < 1 << 32 {
+            t1 = time.read().0;
+        }
+        puts("init[0]: time page ticks=");
+        put_hex(t1);
+        puts("\n");
+        check("init[0]", "time page advanced", t1 > t0);
+        // Ask the kernel introspection endpoint how much memory is left.
+        let req = MsgHeader { tag: sysinfo::MEMINFO, len: 0 }.to_bytes();
+        let mut info = [0u8; 64];
+        info[..req.len()].copy_from_slice(&req);
+        let got = unsafe {
+            syscall4(
+                syscall::IPC_CALL,
+                sysinfo::CAP,
+                info.as_mut_ptr() as u64,
+                req.len() as u64,
+                info.len() as u64,
+            )
+        };
+        let n = if error::is_err(got) { 0 } else { core::cmp::min(got as usize, info.len()) };
+        let mem = MsgHeader::parse(&info[..n]).and_then(|(_, body)| sysinfo::MemInfo::parse(body));
+        if let Some(mem) = mem {
+            puts("init[0]: meminfo free=");
+            put_hex(mem.free_bytes);
+            puts("\n");
+        }
+        check("init[0]", "meminfo", mem.is_some_and(|m| m.free_bytes > 0));
+        // Walk the memory map a page of entries at a time.
+        let mut map = [MemMapEntry::default(); 8];
+        let (mut total, mut usable, mut kernel, mut free, mut sane) = (0u64, 0u64, 0u64, 0u64, true);
+        loop {
+            let got = unsafe { syscall3(syscall::MEMMAP, map.as_mut_ptr() as u64, map.len() as u64, total) };
+            if error::is_err(got) {
+                sane = false;
+                break;
+            }
+            if got == 0 {
+                break;
+            }
+            for e in &map[..got as usize] {
+                sane &= e.len != 0 && e.base.checked_add(e.len).is_some();
+                match e.kind {
+                    mem_kind::USABLE => usable += 1,
+                    mem_kind::KERNEL => kernel += 1,
+                    mem_kind::FREE => free += 1,
+                    _ => {}
+                }
+            }
+            total += got;
+        }
+        puts("init[0]: memmap entries=");
+        put_hex(total);
+        puts(" ");
+        puts(mem_kind::name(mem_kind::USABLE));
+        puts("=");
+        put_hex(usable);
+        puts(" ");
+        puts(mem_kind::name(mem_kind::FREE));
+        puts("=");
+        put_hex(free);
+        puts("\n");
+        check("init[0]", "memmap", sane && usable > 0 && kernel > 0);
+        // Feature detection: the kernel should list every syscall we know, by name.
+        let mut names = [SyscallName::default(); 64];
+        let n = unsafe { syscall2(syscall::SYSCALL_INFO, names.as_mut_ptr() as u64, names.len() as u64) };
+        let listed = &names[..core::cmp::min(n as usize, names.len())];
+        let known = syscall::ALL.iter().all(|nr| listed.iter().any(|e| e.nr == *nr));
+        let named = listed.iter().any(|e| e.nr == syscall::PARK && e.name() == "PARK");
+        puts("init[0]: syscalls=");
+        put_hex(n);
+        puts("\n");
+        check("init[0]", "syscall info", n == syscall::ALL.len() as u64 && known && named);
+        // Bind the timer (IRQ 0) to a fresh endpoint: the blocking receive completes on the
+        // next tick with a notification carrying bit 0.
+        let irq_ep = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, 0) };
+        let bound = unsafe { syscall2(syscall::IRQ_BIND, 0, irq_ep) };
+        let mut note = [0u8; 32];
+        let got = unsafe { syscall3(syscall::IPC_RECV, irq_ep, note.as_mut_ptr() as u64, note.len() as u64) };
+        unsafe {
+            let _ = syscall2(syscall::IRQ_BIND, 0, 0);
+            let _ = syscall1(syscall::IPC_EP_DESTROY, irq_ep);
+        }
+        let n = if error::is_err(got) { 0 } else { core::cmp::min(got as usize, note.len()) };
+        let bits = match MsgHeader::parse(&note[..n]) {
+            Some((hdr, payload)) if hdr.tag == NOTIFY_TAG && payload.len() == 8 => {
+                u64::from_le_bytes(payload.try_into().unwrap_or([0; 8]))
+            }
+            _ => 0,
+        };
+        puts("init[0]: irq notify bits=");
+        put_hex(bits);
+        puts("\n");
+        check("init[0]", "irq notify", bound == 0 && bits & 1 != 0);
+        // Publish a log endpoint by name: the client finds it with EP_LOOKUP instead of being
+        // handed a cap. A name can only be taken once.
+        let log = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, 0) };
+        let (reg, dup) = unsafe {
+            (
+                syscall3(syscall::EP_REGISTER, log, b"log".as_ptr() as u64, 3),
+                syscall3(syscall::EP_REGISTER, log, b"log".as_ptr() as u64, 3),
+            )
+        };
+        check("init[0]", "registered log", reg == 0 && dup == error::INVALID);
+        // Create an endpoint, then spawn the client with caps to it and to two side channels
+        // the client reports back on; all three are in its table before it runs.
+        let ep = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, 0) };
         puts("init[0]: ep=");
         put_hex(ep);
         puts("\n");
+        let side = unsafe {
+            [
+                syscall3(syscall::IPC_EP_CREATE, 0, 0, 0),
+                syscall3(syscall::IPC_EP_CREATE, 0, 0, 0),
+            ]
+        };
+        let caps = [ep, side[0], side[1]].map(|cap| SpawnCap { cap: cap as u32, ..Default::default() });
 
-        let pid = unsafe { syscall3(syscall::PROC_SPAWN, 1, 1, ep) };
+        let pid = unsafe {
+            syscall5(
+                syscall::PROC_SPAWN,
+                1,
+                ROLE_CLIENT,
+                caps.as_ptr() as u64,
+                caps.len() as u64,
+                0,
+            )
+        };
         puts("init[0]: spawned pid=");
         put_hex(pid);
         puts("\n");
 
-        // Create a second endpoint and transfer its capability over `ep`.
-        let ep2 = unsafe { syscall1(syscall::IPC_EP_CREATE, 0) };
+        // Both instances run the same image: text frames are shared, and data frames stop
+        // being shared once written. (The server is the first boot program, pid 0.)
+        let text = _start as *const () as u64;
+        let data = core::ptr::addr_of!(BULK) as u64;
+        unsafe { core::ptr::write_volatile(&raw mut BULK[0], 1) };
+        let (t0, t1, d0, d1) = unsafe {
+            (
+                syscall2(syscall::TRANSLATE, 0, text),
+                syscall2(syscall::TRANSLATE, pid, text),
+                syscall2(syscall::TRANSLATE, 0, data),
+                syscall2(syscall::TRANSLATE, pid, data),
+            )
+        };
+        puts("init[0]: text frame=");
+        put_hex(t0);
+        puts("\n");
+        check(
+            "init[0]",
+            "text shared, data not",
+            !error::is_err(t0) && t0 == t1 && !error::is_err(d0) && !error::is_err(d1) && d0 != d1,
+        );
+
+        // Create a second endpoint and transfer its capability over `ep`. Every client ends
+        // up talking to it, so it round-robins across senders.
+        let ep2 = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, ep_flags::FAIR) };
         puts("init[0]: ep2=");
         put_hex(ep2);
         puts("\n");
 
+        // Nothing is queued for the client yet: waiting in YIELD_HINT it should stay blocked
+        // and be charged (next to) no ticks while we sleep for 50 ms.
+        let mut info = ProcInfo::default();
+        let info_ptr = &mut info as *mut ProcInfo as u64;
+        let r0 = unsafe { syscall2(syscall::PROC_INFO, pid, info_ptr) };
+        let before = info.ticks;
+        let mut idle0 = SchedStats::default();
+        let mut idle1 = SchedStats::default();
+        unsafe {
+            let _ = syscall1(syscall::SCHED_STATS, &mut idle0 as *mut SchedStats as u64);
+            let _ = syscall1(syscall::NANOSLEEP, 50_000_000);
+            let _ = syscall1(syscall::SCHED_STATS, &mut idle1 as *mut SchedStats as u64);
+        }
+        let r1 = unsafe { syscall2(syscall::PROC_INFO, pid, info_ptr) };
+        let spent = info.ticks.wrapping_sub(before);
+        puts("init[0]: client waited ticks=");
+        put_hex(spent);
+        puts("\n");
+        check("init[0]", "client idle", r0 == 0 && r1 == 0 && info.state == proc_state::BLOCKED && spent <= 1);
+
+        // With both of us waiting the CPU was mostly idle; spinning here it is mostly busy.
+        let mut busy0 = SchedStats::default();
+        unsafe {
+            let _ = syscall1(syscall::SCHED_STATS, &mut busy0 as *mut SchedStats as u64);
+        }
+        let mut busy1 = busy0;
+        while busy1.ticks - busy0.ticks < 10 {
+            unsafe {
+                let _ = syscall1(syscall::SCHED_STATS, &mut busy1 as *mut SchedStats as u64);
+            }
+        }
+        let (idle_total, idle_idle) = (idle1.ticks - idle0.ticks, idle1.idle_ticks - idle0.idle_ticks);
+        let (busy_total, busy_idle) = (busy1.ticks - busy0.ticks, busy1.idle_ticks - busy0.idle_ticks);
+        puts("init[0]: idle ticks sleeping=");
+        put_hex(idle_idle);
+        puts("/");
+        put_hex(idle_total);
+        puts(" spinning=");
+        put_hex(busy_idle);
+        puts("/");
+        put_hex(busy_total);
+        puts("\n");
+        check("init[0]", "idle ticks", idle_total > 0 && idle_idle * 2 >= idle_total && busy_idle * 4 <= busy_total);
+
+        // While it's stopped, its saved rip must point into our (shared) text.
+        let mut regs = Regs::default();
+        let r = unsafe { syscall2(syscall::PROC_REGS, pid, &mut regs as *mut Regs as u64) };
+        let text = core::ptr::addr_of!(__text_start) as u64..core::ptr::addr_of!(__text_end) as u64;
+        puts("init[0]: client rip=");
+        put_hex(regs.rip);
+        puts("\n");
+        check("init[0]", "client rip in text", r == 0 && text.contains(&regs.rip));
+
         let note = b"cap transfer: ep2\n";
         let sent = unsafe {
             syscall4(
@@ -125,23 +413,172 @@ pub extern "C" fn _start() -> ! {
         put_hex(sent);
         puts("\n");
 
+        // The creator may destroy its own endpoint.
+        let scratch = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, 0) };
+        let r = unsafe { syscall1(syscall::IPC_EP_DESTROY, scratch) };
+        check("init[0]", "destroy own ep", !error::is_err(scratch) && r == 0);
+
         let mut buf = [0u8; 64];
         loop {
             let got = unsafe { syscall3(syscall::IPC_RECV, ep2, buf.as_mut_ptr() as u64, buf.len() as u64) };
-            if got < 0x8000_0000_0000_0000 {
-                puts("init[0]: recv msg=");
+            if !error::is_err(got) {
                 let n = core::cmp::min(got as usize, buf.len());
-                unsafe {
-                    let _ = syscall2(syscall::WRITE, buf.as_ptr() as u64, n as u64);
+                // Tagged messages are routed on the tag; anything else is shown raw.
+                match MsgHeader::parse(&buf[..n]) {
+                    Some((hdr, payload)) if hdr.tag == TAG_PING => {
+                        puts("init[0]: ping len=");
+                        put_hex(payload.len() as u64);
+                        puts("\n");
+                    }
+                    Some((hdr, _)) if hdr.tag == TAG_CONNECT => {
+                        // Open a session: mint an endpoint and hand it back to this caller only.
+                        let session = unsafe { syscall3(syscall::IPC_EP_CREATE, 0, 0, 0) };
+                        let reply = b"session";
+                        let r = unsafe {
+                            syscall3(
+                                syscall::IPC_REPLY,
+                                reply.as_ptr() as u64,
+                                reply.len() as u64,
+                                session,
+                            )
+                        };
+                        puts("init[0]: connect -> session ep=");
+                        put_hex(session);
+                        puts(" reply=");
+                        put_hex(r);
+                        puts("\n");
+                        check("init[0]", "connect reply", !error::is_err(session) && r == 0);
+                    }
+                    Some((hdr, _)) if hdr.tag == TAG_PARK => {
+                        // Unpark the client once it blocks: in PARK that wakes it, while it
+                        // waits in a call the unpark is left pending (see init[1]).
+                        let mut info = ProcInfo::default();
+                        while unsafe { syscall2(syscall::PROC_INFO, pid, &mut info as *mut ProcInfo as u64) } == 0
+                            && info.state != proc_state::BLOCKED
+                        {
+                            unsafe {
+                                let _ = syscall1(syscall::YIELD_, 0);
+                            }
+                        }
+                        let r = unsafe { syscall1(syscall::UNPARK, pid) };
+                        puts("init[0]: unpark client=");
+                        put_hex(r);
+                        puts("\n");
+                        check("init[0]", "unpark", r == 0);
+                        unsafe {
+                            let _ = syscall3(syscall::IPC_REPLY, 0, 0, 0);
+                        }
+                    }
+                    Some((hdr, payload)) if hdr.tag == TAG_ORPHAN && payload.len() == 32 => {
+                        let mut words = [0u64; 4];
+                        for (w, b) in words.iter_mut().zip(payload.chunks_exact(8)) {
+                            *w = u64::from_le_bytes(b.try_into().unwrap_or([0; 8]));
+                        }
+                        let [orphan, checks, failed, client_sp] = words;
+                        CHECKS.fetch_add(checks, Ordering::Relaxed);
+                        FAILED.fetch_add(failed, Ordering::Relaxed);
+
+                        // Same image and entry path: only ASLR can move the client's stack.
+                        let aslr = option_env!("MANTRA_NOASLR") != Some("1");
+                        check("init[0]", "stack layout", (client_sp != sp) == aslr);
+
+                        // The client has exited by now; its child should be ours.
+                        let mut info = ProcInfo::default();
+                        let r = unsafe {
+                            syscall2(syscall::PROC_INFO, orphan, &mut info as *mut ProcInfo as u64)
+                        };
+                        puts("init[0]: orphan parent=");
+                        put_hex(if r == 0 { info.parent } else { r });
+                        puts("\n");
+                        check("init[0]", "orphan re-parented", r == 0 && info.parent == 0);
+
+                        // The client wrote one byte on each side channel it was spawned with.
+                        let mut got = [0u8; 2];
+                        for (cap, b) in side.iter().zip(got.iter_mut()) {
+                            let r = unsafe { syscall3(syscall::IPC_RECV, *cap, b as *mut u8 as u64, 1) };
+                            if r != 1 {
+                                *b = 0;
+                            }
+                        }
+                        check("init[0]", "spawn caps", got == *b"ab");
+
+                        // ... and one line on the endpoint it looked up by name.
+                        let mut line = [0u8; 16];
+                        let r = unsafe { syscall3(syscall::IPC_RECV, log, line.as_mut_ptr() as u64, line.len() as u64) };
+                        check("init[0]", "log by name", r == 9 && &line[..9] == b"hello log");
+
+                        let mut st = SchedStats::default();
+                        let r = unsafe {
+                            syscall1(syscall::SCHED_STATS, &mut st as *mut SchedStats as u64)
+                        };
+                        if r == 0 {
+                            puts("init[0]: sched switches=");
+                            put_hex(st.switches);
+                            puts(" yields=");
+                            put_hex(st.yields);
+                            puts(" preempts=");
+                            put_hex(st.timer_preemptions);
+                            puts(" runnable=");
+                            put_hex(st.runnable);
+                            puts("\n");
+                        }
+
+                        // That was the last check: hand the tally to the kernel.
+                        report();
+                    }
+                    _ => {
+                        puts("init[0]: recv msg=");
+                        unsafe {
+                            let _ = syscall2(syscall::WRITE, buf.as_ptr() as u64, n as u64);
+                        }
+                        puts("\n");
+                    }
                 }
-                puts("\n");
             }
             unsafe {
                 let _ = syscall1(syscall::YIELD_, 0);
             }
         }
+    } else if role == ROLE_ORPHAN {
+        // Background spinner: step out of the way of the interactive procs.
+        let prio = unsafe { syscall1(syscall::NICE, 4) };
+        puts("init[2]: niced to ");
+        put_hex(prio);
+        puts("\n");
+        loop {
+            unsafe {
+                let _ = syscall1(syscall::YIELD_, 0);
+            }
+        }
     } else {
         puts("init[1]: client start\n");
+        let sp = put_sp("init[1]");
+        // Spawned with the server's endpoint, then its two side channels.
+        let ep = spawn_caps::FIRST;
+        let side = [spawn_caps::FIRST + 1, spawn_caps::FIRST + 2];
+        let sent = unsafe {
+            [
+                syscall3(syscall::IPC_SEND, side[0], b"a".as_ptr() as u64, 1),
+                syscall3(syscall::IPC_SEND, side[1], b"b".as_ptr() as u64, 1),
+            ]
+        };
+        check("init[1]", "inherited caps", sent == [1, 1]);
+        // The server's log endpoint is found by name. Publishing names is for privileged
+        // procs, and unknown names are not found.
+        let (log, squat, missing) = unsafe {
+            (
+                syscall2(syscall::EP_LOOKUP, b"log".as_ptr() as u64, 3),
+                syscall3(syscall::EP_REGISTER, ep, b"console".as_ptr() as u64, 7),
+                syscall2(syscall::EP_LOOKUP, b"console".as_ptr() as u64, 7),
+            )
+        };
+        let line = b"hello log";
+        let sent = if error::is_err(log) {
+            log
+        } else {
+            unsafe { syscall3(syscall::IPC_SEND, log, line.as_ptr() as u64, line.len() as u64) }
+        };
+        check("init[1]", "lookup log", sent == line.len() as u64 && squat == error::PERMISSION && missing == error::NOT_FOUND);
         puts("init[1]: ep=");
         put_hex(ep);
         puts("\n");
@@ -156,8 +593,9 @@ pub extern "C" fn _start() -> ! {
                     buf.len() as u64,
                 )
             };
-            if got == u64::MAX - 2 {
-                unsafe { let _ = syscall1(syscall::YIELD_, 0); }
+            if got == error::EMPTY {
+                // Sleeps in the kernel until the server sends on `ep`.
+                unsafe { let _ = syscall1(syscall::YIELD_HINT, ep); }
                 continue;
             }
             break (got, new_cap);
@@ -180,15 +618,195 @@ pub extern "C" fn _start() -> ! {
         puts("init[1]: sent on new cap=");
         put_hex(sent);
         puts("\n");
-        loop {
-            unsafe {
-                let _ = syscall1(syscall::YIELD_, 0);
+
+        let payload = b"tagged";
+        let sent = unsafe {
+            syscall4(
+                syscall::IPC_SEND_MSG,
+                new_cap,
+                TAG_PING as u64,
+                payload.as_ptr() as u64,
+                payload.len() as u64,
+            )
+        };
+        puts("init[1]: sent tagged ping=");
+        put_hex(sent);
+        puts("\n");
+
+        // Call/reply: the server answers a connect with a session endpoint cap.
+        let mut call = [0u8; 32];
+        call[..MsgHeader::SIZE].copy_from_slice(
+            &MsgHeader {
+                tag: TAG_CONNECT,
+                len: 0,
             }
+            .to_bytes(),
+        );
+        let (got, session) = unsafe {
+            syscall4_ret_rdx(
+                syscall::IPC_CALL,
+                new_cap,
+                call.as_mut_ptr() as u64,
+                MsgHeader::SIZE as u64,
+                call.len() as u64,
+            )
+        };
+        puts("init[1]: connect reply bytes=");
+        put_hex(got);
+        puts(" session cap=");
+        put_hex(session);
+        puts("\n");
+        let hello = b"hello session";
+        let sent = unsafe {
+            syscall3(syscall::IPC_SEND, session, hello.as_ptr() as u64, hello.len() as u64)
+        };
+        let reply = b"session";
+        check(
+            "init[1]",
+            "session cap",
+            got == reply.len() as u64 && call[..reply.len()] == *reply && session != 0 && sent == hello.len() as u64,
+        );
+
+        // Long syscall while the server is runnable: the kernel may yield mid-copy
+        // (see the "syscall preemptions" debug log) and must still finish the request.
+        let bulk = unsafe { &mut *(&raw mut BULK) };
+        let got = unsafe { syscall2(syscall::GETRANDOM, bulk.as_mut_ptr() as u64, bulk.len() as u64) };
+        puts("init[1]: bulk getrandom=");
+        put_hex(got);
+        puts("\n");
+
+        // 500 us sleep: with a LAPIC timer it takes at least that and ends well inside one
+        // tick. The TSC cycles of one tick, timed between two edges, convert it to ns.
+        let time = unsafe { TimePage::get() };
+        let tick_ns = 1_000_000_000 / time.hz.load(Ordering::Relaxed).max(1);
+        let per_tick = match (tick_edge(time), tick_edge(time)) {
+            (Some(c0), Some(c1)) => c1.wrapping_sub(c0).max(1),
+            _ => 0,
+        };
+        let t0 = rdtsc();
+        unsafe {
+            let _ = syscall1(syscall::NANOSLEEP, 500_000);
+        }
+        let t1 = rdtsc();
+        let slept = t1.wrapping_sub(t0).saturating_mul(tick_ns) / per_tick.max(1);
+        puts("init[1]: nanosleep(500us) ns=");
+        put_hex(slept);
+        puts("\n");
+        // 10% under allows for the calibration landing a little after each edge.
+        check("init[1]", "nanosleep", per_tick > 0 && slept >= 450_000 && slept < tick_ns / 2);
+
+        // The server is not our child.
+        let mut regs = Regs::default();
+        let r = unsafe { syscall2(syscall::PROC_REGS, 0, &mut regs as *mut Regs as u64) };
+        check("init[1]", "regs of non-child refused", r == error::PERMISSION);
+
+        // The server unparks us while we wait in a call: that unpark is kept, so the park
+        // after it returns at once (1). Parking again then blocks until the server's next
+        // unpark, which resumes us exactly once (0): the kept unpark was used up.
+        let mut call = MsgHeader { tag: TAG_PARK, len: 0 }.to_bytes();
+        let (pending, woken) = unsafe {
+            let _ = syscall4(syscall::IPC_CALL, new_cap, call.as_mut_ptr() as u64, call.len() as u64, call.len() as u64);
+            let pending = syscall1(syscall::PARK, 0);
+            let _ = syscall4(syscall::IPC_SEND_MSG, new_cap, TAG_PARK as u64, 0, 0);
+            (pending, syscall1(syscall::PARK, 0))
+        };
+        check("init[1]", "park", pending == 1 && woken == 0);
+
+        // `ep` belongs to the server: tearing it down must be refused.
+        let r = unsafe { syscall1(syscall::IPC_EP_DESTROY, ep) };
+        check("init[1]", "destroy foreign ep refused", r == error::PERMISSION);
+
+        // Leave a child behind and exit: the kernel must hand it to pid 0.
+        let child = unsafe { syscall5(syscall::PROC_SPAWN, 1, ROLE_ORPHAN, 0, 0, 0) };
+        puts("init[1]: spawned orphan pid=");
+        put_hex(child);
+        puts("\n");
+
+        // The default budget allows two children: the second spawn works, the third hits
+        // the limit, and both children keep running.
+        let second = unsafe { syscall5(syscall::PROC_SPAWN, 1, ROLE_ORPHAN, 0, 0, 0) };
+        let third = unsafe { syscall5(syscall::PROC_SPAWN, 1, ROLE_ORPHAN, 0, 0, 0) };
+        let alive = [child, second].map(|pid| {
+            let mut info = ProcInfo::default();
+            let r = unsafe { syscall2(syscall::PROC_INFO, pid, &mut info as *mut ProcInfo as u64) };
+            r == 0 && info.state == proc_state::RUNNABLE
+        });
+        check("init[1]", "spawn limit", !error::is_err(second) && third == error::LIMIT && alive == [true, true]);
+
+        // We are about to exit, so lowering our priority costs nothing: 4 down from the
+        // default of 8. Taking it back is reserved for privileged procs.
+        let (prio, back) = unsafe { (syscall1(syscall::NICE, 4), syscall1(syscall::NICE, (-4i64) as u64)) };
+        puts("init[1]: niced to ");
+        put_hex(prio);
+        puts("\n");
+        check("init[1]", "nice", prio == 12 && back == error::PERMISSION);
+        let mut orphan = [0u8; 32];
+        let words = [child, CHECKS.load(Ordering::Relaxed), FAILED.load(Ordering::Relaxed), sp];
+        for (b, w) in orphan.chunks_exact_mut(8).zip(words) {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+        unsafe {
+            let _ = syscall4(
+                syscall::IPC_SEND_MSG,
+                new_cap,
+                TAG_ORPHAN as u64,
+                orphan.as_ptr() as u64,
+                orphan.len() as u64,
+            );
+            let _ = syscall1(syscall::EXIT, 0);
+        }
+        loop {
+            core::hint::spin_loop();
         }
     }
 
 }
 
+// TSC value just after the time page's tick next changes; None if it stays put for a few
+// billion cycles.
+fn tick_edge(time: &TimePage) -> Option<u64> {
+    let (t0, _) = time.read();
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < 1 << 32 {
+        if time.read().0 != t0 {
+            return Some(rdtsc());
+        }
+    }
+    None
+}
+
+// Report this proc's tally (the client's included by now) on the introspection endpoint.
+// The kernel logs it, and under QEMU (MANTRA_QEMUEXIT=1) exits with a pass or fail status.
+fn report() {
+    let (checks, failed) = (CHECKS.load(Ordering::Relaxed), FAILED.load(Ordering::Relaxed));
+    puts("init[0]: checks=");
+    put_hex(checks);
+    puts(" failed=");
+    put_hex(failed);
+    puts("\n");
+    let mut msg = [0u8; MsgHeader::SIZE + sysinfo::TestReport::SIZE];
+    let hdr = MsgHeader { tag: sysinfo::TEST_REPORT, len: sysinfo::TestReport::SIZE as u32 };
+    msg[..MsgHeader::SIZE].copy_from_slice(&hdr.to_bytes());
+    msg[MsgHeader::SIZE..].copy_from_slice(&sysinfo::TestReport { checks, failed }.to_bytes());
+    let r = unsafe {
+        syscall4(syscall::IPC_CALL, sysinfo::CAP, msg.as_mut_ptr() as u64, msg.len() as u64, msg.len() as u64)
+    };
+    if error::is_err(r) {
+        puts("init[0]: test report FAIL\n");
+    }
+}
+
+// Print and return the current stack pointer; with ASLR each process shows a different one.
+fn put_sp(who: &str) -> u64 {
+    let sp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+    puts(who);
+    puts(": sp=");
+    put_hex(sp);
+    puts("\n");
+    sp
+}
+
 fn put_hex(v: u64) {
     // Minimal hex printer via syscalls.
     let hex = *b"0123456789abcdef";